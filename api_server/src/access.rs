@@ -0,0 +1,287 @@
+use crate::errors::{self, Error};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_derive::Deserialize;
+use std::{
+    convert::TryFrom,
+    fmt, fs,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, PoisonError, RwLock},
+    time::{Duration, SystemTime},
+};
+
+/// AccessConfig is the [access] section. A client is refused when its address is in a deny
+/// range, or when there are allow ranges and its address is in none of them. The ranges in
+/// 'file', a TOML file of the same allow and deny lists, are added to these and read again
+/// whenever it changes. Clients of a Unix domain socket have no address and are let through
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AccessConfig {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+    pub file: Option<PathBuf>,
+    /// reload_secs is how often 'file' is checked for changes
+    pub reload_secs: u64,
+}
+
+impl Default for AccessConfig {
+    fn default() -> AccessConfig {
+        AccessConfig {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            file: None,
+            reload_secs: 10,
+        }
+    }
+}
+
+/// Cidr is a range of addresses, as 10.0.0.0/8 or 2001:db8::/32. A bare address is a range
+/// of one. IPv4 ranges match IPv4-mapped IPv6 addresses too
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    /// network and prefix are in IPv6 terms, an IPv4 range being mapped into ::ffff:0:0/96
+    network: u128,
+    prefix: u32,
+}
+
+impl Cidr {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+        mapped(address) & mask == self.network & mask
+    }
+}
+
+fn mapped(address: IpAddr) -> u128 {
+    match address {
+        IpAddr::V4(address) => u128::from(address.to_ipv6_mapped()),
+        IpAddr::V6(address) => u128::from(address),
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(range: &str) -> Result<Cidr, String> {
+        let invalid = || format!("invalid address range {:?}", range);
+        let (address, prefix) = match range.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (range, None),
+        };
+        let address: IpAddr = address.trim().parse().map_err(|_| invalid())?;
+        let (bits, offset) = match address {
+            IpAddr::V4(_) => (32, 96),
+            IpAddr::V6(_) => (128, 0),
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse::<u32>().map_err(|_| invalid())?,
+            None => bits,
+        };
+        if prefix > bits {
+            return Err(invalid());
+        }
+        Ok(Cidr {
+            network: mapped(address),
+            prefix: prefix + offset,
+        })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(range: String) -> Result<Cidr, String> {
+        range.parse()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let network = Ipv6Addr::from(self.network);
+        match network.to_ipv4_mapped() {
+            Some(network) if self.prefix >= 96 => write!(f, "{}/{}", network, self.prefix - 96),
+            _ => write!(f, "{}/{}", network, self.prefix),
+        }
+    }
+}
+
+/// Rules are allow and deny lists, as [access] and its file give them
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+struct Rules {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl Rules {
+    fn permits(&self, address: IpAddr) -> bool {
+        !self.deny.iter().any(|range| range.contains(address))
+            && (self.allow.is_empty() || self.allow.iter().any(|range| range.contains(address)))
+    }
+
+    fn extend(mut self, other: Rules) -> Rules {
+        self.allow.extend(other.allow);
+        self.deny.extend(other.deny);
+        self
+    }
+}
+
+/// AccessList holds the rules in force, swapped whole when the file changes
+pub struct AccessList {
+    config: Rules,
+    file: Option<PathBuf>,
+    rules: RwLock<Arc<Rules>>,
+    /// modified is when the file was last changed as of the rules in force
+    modified: RwLock<Option<SystemTime>>,
+}
+
+impl AccessList {
+    pub fn new(config: &AccessConfig) -> errors::Result<AccessList> {
+        let rules = Rules {
+            allow: config.allow.clone(),
+            deny: config.deny.clone(),
+        };
+        let list = AccessList {
+            rules: RwLock::new(Arc::new(rules.clone())),
+            config: rules,
+            file: config.file.clone(),
+            modified: RwLock::new(None),
+        };
+        // unlike a later reload, a broken file at startup is an error
+        let _ = list.reload()?;
+        Ok(list)
+    }
+
+    pub fn permits(&self, address: IpAddr) -> bool {
+        self.rules
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .permits(address)
+    }
+
+    /// reload reads the file again if it changed since it was last read, and reports whether
+    /// it did. When reading or parsing it fails, the rules in force are kept
+    pub fn reload(&self) -> errors::Result<bool> {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return Ok(false),
+        };
+        let modified = fs::metadata(file)?.modified()?;
+        if *self.modified.read().unwrap_or_else(PoisonError::into_inner) == Some(modified) {
+            return Ok(false);
+        }
+        let rules = self.config.clone().extend(read_rules(file)?);
+        *self.rules.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(rules);
+        *self
+            .modified
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Some(modified);
+        Ok(true)
+    }
+}
+
+fn read_rules(file: &Path) -> errors::Result<Rules> {
+    toml::from_str(&fs::read_to_string(file)?)
+        .map_err(|err| Error::msg(format!("Invalid access list {}: {}", file.display(), err)))
+}
+
+/// reload_periodically spawns a task that reloads 'list' every 'interval'. Nothing is spawned
+/// when the list has no file
+pub fn reload_periodically(list: Arc<AccessList>, interval: Duration) {
+    if list.file.is_none() {
+        return;
+    }
+    let mut ticks = tokio::time::interval(interval);
+    let _ = tokio::spawn(async move {
+        loop {
+            let _ = ticks.tick().await;
+            let list = Arc::clone(&list);
+            match tokio::task::spawn_blocking(move || list.reload()).await {
+                Ok(Ok(true)) => println!("Reloaded the access list"),
+                Ok(Err(err)) => {
+                    eprintln!(
+                        "Failed to reload the access list: {}",
+                        errors::describe(&err)
+                    )
+                }
+                _ => {}
+            }
+        }
+    });
+}
+
+/// enforce refuses requests from addresses 'list' doesn't permit with Error::Forbidden, before
+/// anything else looks at them
+pub async fn enforce(
+    State(list): State<Arc<AccessList>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(peer)| peer.ip());
+    match peer {
+        Some(address) if !list.permits(address) => Error::Forbidden.into_response(),
+        _ => next.run(request).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    fn cidrs(ranges: &[&str]) -> Vec<Cidr> {
+        ranges.iter().map(|range| range.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn matches_ranges() {
+        let ranges = cidrs(&["10.0.0.0/8", "2001:db8::/32", "::/0", "192.0.2.1"]);
+        assert!(ranges[0].contains(ip("10.1.2.3")));
+        assert!(ranges[0].contains(ip("::ffff:10.1.2.3")));
+        assert!(!ranges[0].contains(ip("11.0.0.1")));
+        assert!(ranges[1].contains(ip("2001:db8::1")));
+        assert!(!ranges[1].contains(ip("2001:db9::1")));
+        assert!(ranges[2].contains(ip("192.0.2.1")));
+        assert!(ranges[3].contains(ip("192.0.2.1")));
+        assert!(!ranges[3].contains(ip("192.0.2.2")));
+
+        let shown: Vec<String> = ranges.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            shown,
+            ["10.0.0.0/8", "2001:db8::/32", "::/0", "192.0.2.1/32"]
+        );
+
+        for invalid in &["10.0.0.0/33", "10.0.0/8", "fe80::/129", "host/8"] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let rules = Rules {
+            allow: cidrs(&["10.0.0.0/8"]),
+            deny: cidrs(&["10.0.0.13"]),
+        };
+        assert!(rules.permits(ip("10.0.0.12")));
+        assert!(!rules.permits(ip("10.0.0.13")));
+        assert!(!rules.permits(ip("192.0.2.1")));
+
+        let deny_only = Rules {
+            allow: Vec::new(),
+            deny: cidrs(&["192.0.2.0/24"]),
+        };
+        assert!(deny_only.permits(ip("198.51.100.1")));
+        assert!(!deny_only.permits(ip("192.0.2.1")));
+    }
+}
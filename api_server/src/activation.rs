@@ -0,0 +1,78 @@
+#[cfg(unix)]
+use std::{
+    env,
+    os::unix::{
+        io::{FromRawFd, IntoRawFd, RawFd},
+        net::UnixListener,
+    },
+    process,
+};
+use std::{io, net::TcpListener};
+
+/// SD_LISTEN_FDS_START is the first file descriptor systemd passes to an activated service
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Inherited is a listening socket passed down by systemd
+#[derive(Debug)]
+#[cfg_attr(not(unix), allow(dead_code))]
+pub enum Inherited {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// inherited_listener takes the first socket systemd passed this process through LISTEN_FDS,
+/// or None if it wasn't socket activated. Keeping the socket in systemd's hands lets requests
+/// queue up while the service restarts instead of being refused. The variables are removed,
+/// so worker processes don't mistake the socket for their own; changing the environment is
+/// only sound while no other thread can read it, so this must run before any is started
+#[cfg(unix)]
+pub fn inherited_listener() -> io::Result<Option<Inherited>> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+    let for_this_process = pid.and_then(|pid| pid.parse::<u32>().ok()) == Some(process::id());
+    let count = fds.and_then(|fds| fds.parse::<RawFd>().ok()).unwrap_or(0);
+    if !for_this_process || count < 1 {
+        return Ok(None);
+    }
+    // systemd hands the descriptor over for this process to own, and nothing else claims it
+    let tcp = unsafe { TcpListener::from_raw_fd(SD_LISTEN_FDS_START) };
+    // the descriptor arrives without close-on-exec, so it is swapped for a clone that has it
+    // and worker processes don't hold the socket open
+    let listener = if tcp.local_addr().is_ok() {
+        let listener = tcp.try_clone()?;
+        listener.set_nonblocking(true)?;
+        Inherited::Tcp(listener)
+    } else {
+        // only a TCP socket has an address std understands, so this is a Unix one
+        let unix = unsafe { UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+        let listener = unix.try_clone()?;
+        listener.set_nonblocking(true)?;
+        Inherited::Unix(listener)
+    };
+    Ok(Some(listener))
+}
+
+/// inherited_listener is always None off Unix, where there is no systemd to pass a socket
+#[cfg(not(unix))]
+pub fn inherited_listener() -> io::Result<Option<Inherited>> {
+    Ok(None)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignores_sockets_meant_for_another_process() -> io::Result<()> {
+        env::set_var("LISTEN_PID", (process::id() + 1).to_string());
+        env::set_var("LISTEN_FDS", "1");
+        assert!(inherited_listener()?.is_none());
+        assert!(env::var_os("LISTEN_FDS").is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,80 @@
+use crate::{cluster::NodeStats, errors};
+use axum::http::{header, HeaderMap};
+use no_captcha::{
+    worker::{WorkerPool, WorkerPoolStats},
+    CaptchaChallenge, CaptchaRegistry, RegistryStats,
+};
+use serde_derive::Serialize;
+use std::collections::HashMap;
+
+/// AdminStats is the snapshot GET /admin/stats answers with. Only the part describing the
+/// server's predictor is set: registry when it predicts in process, workers when it runs
+/// [workers], and nodes, the other nodes, when it runs [cluster]
+#[derive(Debug, Default, Serialize)]
+pub struct AdminStats {
+    pub registry: Option<RegistryStats>,
+    pub workers: Option<WorkerPoolStats>,
+    pub nodes: Option<Vec<NodeStats>>,
+    /// queue_depths are the predictions waiting to join a batch, by challenge
+    pub queue_depths: HashMap<CaptchaChallenge, usize>,
+}
+
+/// Stats is implemented by the predictors the server runs, to describe themselves in
+/// AdminStats
+pub trait Stats {
+    fn fill(&self, stats: &mut AdminStats) -> errors::Result<()>;
+}
+
+impl Stats for CaptchaRegistry {
+    fn fill(&self, stats: &mut AdminStats) -> errors::Result<()> {
+        stats.registry = Some(self.stats()?);
+        Ok(())
+    }
+}
+
+impl Stats for WorkerPool {
+    fn fill(&self, stats: &mut AdminStats) -> errors::Result<()> {
+        stats.workers = Some(self.stats());
+        Ok(())
+    }
+}
+
+/// authorized is whether 'headers' carry `Authorization: Bearer <token>`. The token is
+/// compared in constant time, so response times don't give it away a byte at a time
+pub fn authorized(headers: &HeaderMap, token: &str) -> bool {
+    let presented = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    same_token(presented, token)
+}
+
+/// same_token compares a presented token with an expected one in constant time
+pub fn same_token(presented: &str, expected: &str) -> bool {
+    presented.len() == expected.len()
+        && presented
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn requires_the_bearer_token() {
+        let with = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            let _ = headers.insert(header::AUTHORIZATION, HeaderValue::from_static(value));
+            headers
+        };
+        assert!(!authorized(&HeaderMap::new(), "secret"));
+        assert!(!authorized(&with("Bearer secreT"), "secret"));
+        assert!(!authorized(&with("secret"), "secret"));
+        assert!(authorized(&with("Bearer secret"), "secret"));
+    }
+}
@@ -0,0 +1,320 @@
+use crate::errors::{self, Error};
+use no_captcha::{schema::PredictionRecord, CaptchaChallenge};
+use rusqlite::{params, Connection};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// PURGE_INTERVAL is how often purge_periodically applies the retention policy
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// DEFAULT_LIMIT and MAX_LIMIT bound the records one query answers with
+const DEFAULT_LIMIT: u32 = 100;
+const MAX_LIMIT: u32 = 1000;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS requests (
+        id INTEGER PRIMARY KEY,
+        timestamp_ms INTEGER NOT NULL,
+        key TEXT,
+        challenge TEXT NOT NULL,
+        result TEXT NOT NULL,
+        latency_us INTEGER NOT NULL,
+        image_hash TEXT
+    );
+    CREATE INDEX IF NOT EXISTS requests_timestamp ON requests (timestamp_ms);
+    CREATE INDEX IF NOT EXISTS requests_key ON requests (key);
+    CREATE INDEX IF NOT EXISTS requests_image_hash ON requests (image_hash);
+";
+
+/// AuditConfig is the [audit] section. Without a retention policy records are kept forever
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuditConfig {
+    /// path is the SQLite database, created if it doesn't exist
+    pub path: PathBuf,
+    /// retention_days, when set, deletes records older than that many days
+    #[serde(default)]
+    pub retention_days: Option<u64>,
+    /// max_records, when set, deletes the oldest records beyond that many
+    #[serde(default)]
+    pub max_records: Option<u64>,
+}
+
+/// AuditRecord is the metadata of one recognition request. Requests whose body isn't valid
+/// JSON, or of an image type, are rejected before they are recorded
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuditRecord {
+    pub timestamp_ms: u64,
+    /// key is the name of the API key the request presented, with [quotas] set
+    pub key: Option<String>,
+    pub challenge: CaptchaChallenge,
+    /// result is "match" or "no_match", or the code of the error the request failed with
+    pub result: String,
+    /// latency_us is how long the server took to answer, in microseconds
+    pub latency_us: u64,
+    /// image_hash is the image_hash of the decoded image, missing if it couldn't be decoded
+    pub image_hash: Option<String>,
+}
+
+impl AuditRecord {
+    pub fn new(
+        key: Option<String>,
+        challenge: CaptchaChallenge,
+        image_hash: Option<String>,
+        result: &errors::Result<PredictionRecord>,
+        latency: Duration,
+    ) -> AuditRecord {
+        AuditRecord {
+            timestamp_ms: now_ms(),
+            key,
+            challenge,
+            result: match result {
+                Ok(record) if record.is_match => "match".to_string(),
+                Ok(_) => "no_match".to_string(),
+                Err(err) => err.error_code().to_string(),
+            },
+            latency_us: latency.as_micros() as u64,
+            image_hash,
+        }
+    }
+}
+
+/// AuditQuery filters the records GET /admin/audit answers with, newest first. Every filter is
+/// optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct AuditQuery {
+    pub key: Option<String>,
+    pub challenge: Option<CaptchaChallenge>,
+    pub result: Option<String>,
+    pub image_hash: Option<String>,
+    /// since_ms and until_ms bound the records' timestamps, in milliseconds since the epoch.
+    /// since_ms is inclusive, until_ms exclusive
+    pub since_ms: Option<u64>,
+    pub until_ms: Option<u64>,
+    /// limit is at most 1000, 100 by default
+    pub limit: Option<u32>,
+}
+
+/// AuditLog keeps an AuditRecord of every recognition request in SQLite
+pub struct AuditLog {
+    connection: Mutex<Connection>,
+    retention_days: Option<u64>,
+    max_records: Option<u64>,
+}
+
+impl AuditLog {
+    pub fn open(config: &AuditConfig) -> errors::Result<AuditLog> {
+        let connection = Connection::open(&config.path).map_err(Error::AuditLog)?;
+        AuditLog::new(connection, config)
+    }
+
+    fn new(connection: Connection, config: &AuditConfig) -> errors::Result<AuditLog> {
+        connection.execute_batch(SCHEMA).map_err(Error::AuditLog)?;
+        Ok(AuditLog {
+            connection: Mutex::new(connection),
+            retention_days: config.retention_days,
+            max_records: config.max_records,
+        })
+    }
+
+    pub fn record(&self, record: &AuditRecord) -> errors::Result<()> {
+        let _ = self
+            .connection()
+            .execute(
+                "INSERT INTO requests
+                    (timestamp_ms, key, challenge, result, latency_us, image_hash)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    record.timestamp_ms as i64,
+                    record.key,
+                    record.challenge.to_string(),
+                    record.result,
+                    record.latency_us as i64,
+                    record.image_hash,
+                ],
+            )
+            .map_err(Error::AuditLog)?;
+        Ok(())
+    }
+
+    pub fn query(&self, query: &AuditQuery) -> errors::Result<Vec<AuditRecord>> {
+        let connection = self.connection();
+        let mut statement = connection
+            .prepare_cached(
+                "SELECT timestamp_ms, key, challenge, result, latency_us, image_hash
+                    FROM requests
+                    WHERE (?1 IS NULL OR key = ?1)
+                        AND (?2 IS NULL OR challenge = ?2)
+                        AND (?3 IS NULL OR result = ?3)
+                        AND (?4 IS NULL OR image_hash = ?4)
+                        AND (?5 IS NULL OR timestamp_ms >= ?5)
+                        AND (?6 IS NULL OR timestamp_ms < ?6)
+                    ORDER BY id DESC
+                    LIMIT ?7",
+            )
+            .map_err(Error::AuditLog)?;
+        let rows = statement
+            .query_map(
+                params![
+                    query.key,
+                    query.challenge.as_ref().map(ToString::to_string),
+                    query.result,
+                    query.image_hash,
+                    query.since_ms.map(|since| since as i64),
+                    query.until_ms.map(|until| until as i64),
+                    query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT),
+                ],
+                |row| {
+                    let challenge: String = row.get(2)?;
+                    Ok(AuditRecord {
+                        timestamp_ms: row.get::<_, i64>(0)? as u64,
+                        key: row.get(1)?,
+                        // names are stored as displayed, which always parses back
+                        challenge: challenge
+                            .parse()
+                            .unwrap_or_else(|_| CaptchaChallenge::Other(challenge)),
+                        result: row.get(3)?,
+                        latency_us: row.get::<_, i64>(4)? as u64,
+                        image_hash: row.get(5)?,
+                    })
+                },
+            )
+            .map_err(Error::AuditLog)?;
+        rows.collect::<Result<_, _>>().map_err(Error::AuditLog)
+    }
+
+    /// purge deletes the records the retention policy no longer keeps and returns how many
+    pub fn purge(&self) -> errors::Result<usize> {
+        self.purge_at(now_ms())
+    }
+
+    fn purge_at(&self, now_ms: u64) -> errors::Result<usize> {
+        let connection = self.connection();
+        let mut purged = 0;
+        if let Some(days) = self.retention_days {
+            let cutoff = now_ms.saturating_sub(days * 24 * 60 * 60 * 1000);
+            purged += connection
+                .execute(
+                    "DELETE FROM requests WHERE timestamp_ms < ?1",
+                    params![cutoff as i64],
+                )
+                .map_err(Error::AuditLog)?;
+        }
+        if let Some(max_records) = self.max_records {
+            // ids only grow, so the newest records have the highest ones
+            purged += connection
+                .execute(
+                    "DELETE FROM requests WHERE id <= (
+                        SELECT id FROM requests ORDER BY id DESC LIMIT 1 OFFSET ?1
+                    )",
+                    params![max_records as i64],
+                )
+                .map_err(Error::AuditLog)?;
+        }
+        Ok(purged)
+    }
+
+    fn connection(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// purge_periodically spawns a task that purges 'log' every hour, starting now. Nothing is
+/// spawned when no retention policy is set
+pub fn purge_periodically(log: Arc<AuditLog>) {
+    if log.retention_days.is_none() && log.max_records.is_none() {
+        return;
+    }
+    let mut ticks = tokio::time::interval(PURGE_INTERVAL);
+    let _ = tokio::spawn(async move {
+        loop {
+            let _ = ticks.tick().await;
+            let log = Arc::clone(&log);
+            match tokio::task::spawn_blocking(move || log.purge()).await {
+                Ok(Ok(purged)) if purged > 0 => println!("Purged {} audit records", purged),
+                Ok(Err(err)) => {
+                    eprintln!("Failed to purge audit records: {}", errors::describe(&err))
+                }
+                _ => {}
+            }
+        }
+    });
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(timestamp_ms: u64, key: &str, challenge: CaptchaChallenge) -> AuditRecord {
+        AuditRecord {
+            timestamp_ms,
+            key: Some(key.to_string()),
+            challenge,
+            result: "match".to_string(),
+            latency_us: 1500,
+            image_hash: Some(format!("{:064x}", timestamp_ms)),
+        }
+    }
+
+    #[test]
+    fn queries_and_purges_records() -> errors::Result<()> {
+        let day = 24 * 60 * 60 * 1000;
+        let config = AuditConfig {
+            path: PathBuf::new(),
+            retention_days: Some(7),
+            max_records: Some(2),
+        };
+        let log = AuditLog::new(
+            Connection::open_in_memory().map_err(Error::AuditLog)?,
+            &config,
+        )?;
+        let records = [
+            record(day, "search", CaptchaChallenge::Bus),
+            record(9 * day, "search", CaptchaChallenge::Crosswalks),
+            record(10 * day, "ads", CaptchaChallenge::Bus),
+            record(11 * day, "search", CaptchaChallenge::Bus),
+        ];
+        for record in &records {
+            log.record(record)?;
+        }
+
+        let search = AuditQuery {
+            key: Some("search".to_string()),
+            ..AuditQuery::default()
+        };
+        assert_eq!(
+            log.query(&search)?,
+            vec![records[3].clone(), records[1].clone(), records[0].clone()]
+        );
+        let buses_since = AuditQuery {
+            challenge: Some(CaptchaChallenge::Bus),
+            since_ms: Some(2 * day),
+            ..AuditQuery::default()
+        };
+        assert_eq!(
+            log.query(&buses_since)?,
+            vec![records[3].clone(), records[2].clone()]
+        );
+
+        // the first record is too old, and then the second is one too many
+        assert_eq!(log.purge_at(12 * day)?, 2);
+        assert_eq!(
+            log.query(&AuditQuery::default())?,
+            vec![records[3].clone(), records[2].clone()]
+        );
+        Ok(())
+    }
+}
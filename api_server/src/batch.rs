@@ -0,0 +1,337 @@
+use crate::{
+    errors::{self, Error},
+    reload::SharedRegistry,
+};
+use no_captcha::{
+    format, sanitize::InputLimits, CaptchaChallenge, Prediction, Predictor, Priority,
+};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        mpsc, Arc, Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::sync::oneshot;
+
+/// BatchConfig controls how long a batch stays open after its first request arrives and how
+/// many requests it may collect before it is run regardless
+#[derive(Debug, Clone, Copy)]
+pub struct BatchConfig {
+    pub window: Duration,
+    pub max_batch_size: usize,
+    /// limits are checked on each request's image before it joins a batch, so one bad image
+    /// fails only the request that sent it
+    pub limits: InputLimits,
+}
+
+impl Default for BatchConfig {
+    fn default() -> BatchConfig {
+        BatchConfig {
+            window: Duration::from_millis(5),
+            max_batch_size: 32,
+            limits: InputLimits::default(),
+        }
+    }
+}
+
+/// QueueDepth tracks the jobs waiting in a worker's queue, reported by Batcher::queue_depths
+/// and, when built with the otel feature, as the nocap.batch.queue_depth metric
+#[derive(Clone)]
+struct QueueDepth {
+    jobs: Arc<AtomicI64>,
+    #[cfg(feature = "otel")]
+    counter: opentelemetry::metrics::UpDownCounter<i64>,
+    #[cfg(feature = "otel")]
+    challenge: opentelemetry::KeyValue,
+}
+
+impl QueueDepth {
+    #[cfg(feature = "otel")]
+    fn new(challenge: &CaptchaChallenge) -> QueueDepth {
+        QueueDepth {
+            jobs: Arc::default(),
+            counter: opentelemetry::global::meter("api_server")
+                .i64_up_down_counter("nocap.batch.queue_depth")
+                .with_description("Predictions waiting to join a batch")
+                .build(),
+            challenge: opentelemetry::KeyValue::new("nocap.challenge", challenge.to_string()),
+        }
+    }
+
+    #[cfg(not(feature = "otel"))]
+    fn new(_: &CaptchaChallenge) -> QueueDepth {
+        QueueDepth {
+            jobs: Arc::default(),
+        }
+    }
+
+    fn add(&self, jobs: i64) {
+        let _ = self.jobs.fetch_add(jobs, Ordering::Relaxed);
+        #[cfg(feature = "otel")]
+        self.counter.add(jobs, &[self.challenge.clone()]);
+    }
+
+    fn get(&self) -> usize {
+        self.jobs.load(Ordering::Relaxed).max(0) as usize
+    }
+}
+
+struct Job {
+    image: Vec<u8>,
+    reply: oneshot::Sender<errors::Result<Prediction>>,
+}
+
+/// Batcher collects concurrent predictions for the same challenge and runs them through the
+/// predictor as a single batched call. Each challenge gets its own worker thread, spawned the
+/// first time the challenge is requested
+pub struct Batcher<P> {
+    registry: SharedRegistry<P>,
+    config: BatchConfig,
+    workers: Mutex<HashMap<CaptchaChallenge, (mpsc::Sender<Job>, QueueDepth)>>,
+}
+
+impl<P> Batcher<P>
+where
+    P: Predictor + 'static,
+{
+    pub fn new(registry: SharedRegistry<P>, config: BatchConfig) -> Batcher<P> {
+        Batcher {
+            registry,
+            config,
+            workers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn predict(
+        &self,
+        challenge: CaptchaChallenge,
+        image: Vec<u8>,
+    ) -> errors::Result<Prediction> {
+        if !challenge.is_known() {
+            // any snake_case name parses as Other, so giving each its own worker thread would
+            // let clients spawn threads at will
+            return self
+                .predict_alone(challenge, image, Priority::Interactive)
+                .await;
+        }
+        let (reply, response) = oneshot::channel();
+        let (worker, queue_depth) = self.worker_for(challenge);
+        // counted before sending, so the worker never takes the depth below zero
+        queue_depth.add(1);
+        worker
+            .send(Job { image, reply })
+            .map_err(|_| Error::msg("Prediction batcher stopped"))?;
+        response
+            .await
+            .map_err(|_| Error::msg("Prediction batcher stopped"))?
+    }
+
+    /// predict_prioritized is predict at 'priority'. Batch work doesn't join the batches of
+    /// interactive requests, and waits behind them for the model (see Priority)
+    pub async fn predict_prioritized(
+        &self,
+        challenge: CaptchaChallenge,
+        image: Vec<u8>,
+        priority: Priority,
+    ) -> errors::Result<Prediction> {
+        match priority {
+            Priority::Interactive => self.predict(challenge, image).await,
+            Priority::Batch => self.predict_alone(challenge, image, priority).await,
+        }
+    }
+
+    /// predict_alone predicts 'image' in a batch of its own on the blocking pool
+    async fn predict_alone(
+        &self,
+        challenge: CaptchaChallenge,
+        image: Vec<u8>,
+        priority: Priority,
+    ) -> errors::Result<Prediction> {
+        let registry = self.registry.clone();
+        let limits = self.config.limits;
+        tokio::task::spawn_blocking(move || {
+            let image = prepare(&limits, image)?;
+            registry
+                .current()
+                .predict_batch_prioritized(&challenge, vec![image], priority)?
+                .pop()
+                .ok_or(no_captcha::errors::Error::MalformedOutput)
+        })
+        .await
+        .map_err(|_| Error::msg("Prediction failed"))?
+        .map_err(|err| {
+            eprintln!("Prediction failed: {}", errors::describe(&err));
+            Error::from(err)
+        })
+    }
+
+    /// queue_depths are the predictions waiting to join a batch, by challenge
+    pub fn queue_depths(&self) -> HashMap<CaptchaChallenge, usize> {
+        let workers = self.workers.lock().unwrap_or_else(PoisonError::into_inner);
+        workers
+            .iter()
+            .map(|(challenge, (_, queue_depth))| (challenge.clone(), queue_depth.get()))
+            .collect()
+    }
+
+    fn worker_for(&self, challenge: CaptchaChallenge) -> (mpsc::Sender<Job>, QueueDepth) {
+        let mut workers = self.workers.lock().unwrap_or_else(PoisonError::into_inner);
+        workers
+            .entry(challenge.clone())
+            .or_insert_with(|| {
+                let (sender, jobs) = mpsc::channel();
+                let registry = self.registry.clone();
+                let config = self.config;
+                let queue_depth = QueueDepth::new(&challenge);
+                let dequeued = queue_depth.clone();
+                let _ =
+                    thread::spawn(move || run_worker(registry, challenge, config, jobs, dequeued));
+                (sender, queue_depth)
+            })
+            .clone()
+    }
+}
+
+fn run_worker<P>(
+    registry: SharedRegistry<P>,
+    challenge: CaptchaChallenge,
+    config: BatchConfig,
+    jobs: mpsc::Receiver<Job>,
+    queue_depth: QueueDepth,
+) where
+    P: Predictor,
+{
+    while let Ok(first) = jobs.recv() {
+        let deadline = Instant::now() + config.window;
+        let mut batch = vec![first];
+        while batch.len() < config.max_batch_size {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            match jobs.recv_timeout(deadline - now) {
+                Ok(job) => batch.push(job),
+                Err(_) => break,
+            }
+        }
+
+        queue_depth.add(-(batch.len() as i64));
+        let mut images = Vec::with_capacity(batch.len());
+        let mut replies = Vec::with_capacity(batch.len());
+        for job in batch {
+            match prepare(&config.limits, job.image) {
+                Ok(image) => {
+                    images.push(image);
+                    replies.push(job.reply);
+                }
+                Err(err) => {
+                    let _ = job.reply.send(Err(Error::from(err)));
+                }
+            }
+        }
+        if replies.is_empty() {
+            continue;
+        }
+        let expected = replies.len();
+        let result = registry
+            .current()
+            .predict_batch(&challenge, images)
+            .and_then(|predictions| {
+                // zipping a short result would leave some callers without a reply
+                if predictions.len() == expected {
+                    Ok(predictions)
+                } else {
+                    Err(no_captcha::errors::Error::MalformedOutput)
+                }
+            });
+        match result {
+            Ok(predictions) => {
+                for (reply, prediction) in replies.into_iter().zip(predictions) {
+                    let _ = reply.send(Ok(prediction));
+                }
+            }
+            Err(err) => {
+                eprintln!(
+                    "Prediction of {} images for {} failed: {}",
+                    replies.len(),
+                    challenge,
+                    errors::describe(&err)
+                );
+                let err = Error::from(err);
+                for reply in replies {
+                    let _ = reply.send(Err(err.shared()));
+                }
+            }
+        }
+    }
+}
+
+/// prepare checks one request's image against 'limits' and converts it the way the models
+/// take it (see format::for_model), so the registry finds nothing to reject or convert in the
+/// batch
+fn prepare(limits: &InputLimits, image: Vec<u8>) -> no_captcha::errors::Result<Vec<u8>> {
+    limits
+        .check(&image)
+        .map_err(|rejection| no_captcha::errors::Error::RejectedImage(0, rejection))?;
+    let converted = match format::for_model(std::slice::from_ref(&image))? {
+        Cow::Borrowed(_) => None,
+        Cow::Owned(mut converted) => converted.pop(),
+    };
+    Ok(converted.unwrap_or(image))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use no_captcha::MockPredictor;
+
+    const BUS: &[u8] = include_bytes!("../../bus.jpg");
+
+    #[tokio::test]
+    async fn concurrent_requests_share_a_batch() -> errors::Result<()> {
+        let registry = SharedRegistry::new(MockPredictor::new());
+        let batcher = Batcher::new(
+            registry.clone(),
+            BatchConfig {
+                window: Duration::from_millis(50),
+                max_batch_size: 3,
+                ..BatchConfig::default()
+            },
+        );
+        let (first, second, third) = tokio::join!(
+            batcher.predict(CaptchaChallenge::Bus, BUS.to_vec()),
+            batcher.predict(CaptchaChallenge::Bus, BUS.to_vec()),
+            batcher.predict(CaptchaChallenge::Bus, BUS.to_vec()),
+        );
+        let _ = (first?, second?, third?);
+        assert_eq!(registry.current().calls(), vec![(CaptchaChallenge::Bus, 3)]);
+        assert_eq!(batcher.queue_depths().get(&CaptchaChallenge::Bus), Some(&0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bad_images_fail_only_their_own_request() -> errors::Result<()> {
+        let registry = SharedRegistry::new(MockPredictor::new());
+        let batcher = Batcher::new(
+            registry.clone(),
+            BatchConfig {
+                window: Duration::from_millis(50),
+                max_batch_size: 3,
+                ..BatchConfig::default()
+            },
+        );
+        let (first, bad, third) = tokio::join!(
+            batcher.predict(CaptchaChallenge::Bus, BUS.to_vec()),
+            batcher.predict(CaptchaChallenge::Bus, b"not an image".to_vec()),
+            batcher.predict(CaptchaChallenge::Bus, BUS.to_vec()),
+        );
+        let _ = (first?, third?);
+        assert!(bad.is_err());
+        assert_eq!(registry.current().calls(), vec![(CaptchaChallenge::Bus, 2)]);
+        Ok(())
+    }
+}
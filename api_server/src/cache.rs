@@ -0,0 +1,268 @@
+use crate::errors::{self, Error};
+use lru::LruCache;
+use no_captcha::{schema::PredictionRecord, CaptchaChallenge};
+use redis::{aio::MultiplexedConnection, AsyncCommands};
+use serde_derive::Deserialize;
+use std::{
+    num::NonZeroUsize,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// CacheConfig is the [cache] section. Predictions are cached by challenge and image_hash, in
+/// process and, with [cache.redis] set, in a Redis shared by every instance
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// capacity is how many predictions the in-process cache holds
+    pub capacity: usize,
+    /// ttl_secs is how long a prediction is answered from cache. Models swapped in by a reload
+    /// only answer for an image once its cached prediction has expired
+    pub ttl_secs: u64,
+    pub redis: Option<RedisConfig>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig {
+            capacity: 10_000,
+            ttl_secs: 60 * 60,
+            redis: None,
+        }
+    }
+}
+
+/// RedisConfig is the [cache.redis] section
+#[derive(Debug, Clone, Deserialize)]
+pub struct RedisConfig {
+    /// url is the Redis to connect to, as redis://host:6379/0
+    pub url: String,
+    /// prefix starts every key, so fleets serving different models can share a Redis
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    /// timeout_ms bounds each Redis command. A command that takes longer counts as failed
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// retry_secs is how long Redis is left alone after a command fails
+    #[serde(default = "default_retry_secs")]
+    pub retry_secs: u64,
+}
+
+fn default_prefix() -> String {
+    "nocap".to_string()
+}
+
+fn default_timeout_ms() -> u64 {
+    50
+}
+
+fn default_retry_secs() -> u64 {
+    30
+}
+
+/// PredictionCache answers repeated images without predicting them again. The in-process
+/// cache is looked up first, then Redis. While Redis is down the cache carries on in process
+pub struct PredictionCache {
+    local: Mutex<LruCache<(CaptchaChallenge, String), (Instant, PredictionRecord)>>,
+    ttl: Duration,
+    redis: Option<Redis>,
+}
+
+impl PredictionCache {
+    pub fn new(config: &CacheConfig) -> errors::Result<PredictionCache> {
+        let redis = match &config.redis {
+            Some(redis) => Some(Redis::new(redis)?),
+            None => None,
+        };
+        Ok(PredictionCache {
+            local: Mutex::new(LruCache::new(
+                NonZeroUsize::new(config.capacity).unwrap_or(NonZeroUsize::MIN),
+            )),
+            ttl: Duration::from_secs(config.ttl_secs),
+            redis,
+        })
+    }
+
+    /// get is the cached prediction for the image with 'image_hash'
+    pub async fn get(
+        &self,
+        challenge: &CaptchaChallenge,
+        image_hash: &str,
+    ) -> Option<PredictionRecord> {
+        let key = (challenge.clone(), image_hash.to_string());
+        if let Some(record) = self.get_local(&key) {
+            return Some(record);
+        }
+        let redis = self.redis.as_ref()?;
+        let record = redis.get(&redis.key(challenge, image_hash)).await?;
+        self.put_local(key, record.clone());
+        Some(record)
+    }
+
+    pub async fn put(
+        &self,
+        challenge: &CaptchaChallenge,
+        image_hash: &str,
+        record: &PredictionRecord,
+    ) {
+        self.put_local((challenge.clone(), image_hash.to_string()), record.clone());
+        if let Some(redis) = &self.redis {
+            redis
+                .set(&redis.key(challenge, image_hash), record, self.ttl)
+                .await;
+        }
+    }
+
+    fn get_local(&self, key: &(CaptchaChallenge, String)) -> Option<PredictionRecord> {
+        let mut local = self.local.lock().unwrap_or_else(PoisonError::into_inner);
+        match local.get(key).cloned() {
+            Some((stored, record)) if stored.elapsed() < self.ttl => Some(record),
+            Some(_) => {
+                let _ = local.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn put_local(&self, key: (CaptchaChallenge, String), record: PredictionRecord) {
+        let _ = self
+            .local
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .put(key, (Instant::now(), record));
+    }
+}
+
+/// Redis is the cache shared with other instances. After a command fails it is left alone for
+/// retry_secs, so an outage doesn't cost every request a timeout
+struct Redis {
+    client: redis::Client,
+    prefix: String,
+    timeout: Duration,
+    retry: Duration,
+    /// connection is dropped when a command fails, and made again once retry has passed
+    connection: tokio::sync::Mutex<Option<MultiplexedConnection>>,
+    down_until: Mutex<Option<Instant>>,
+}
+
+impl Redis {
+    fn new(config: &RedisConfig) -> errors::Result<Redis> {
+        // only checks the URL, so a server started while Redis is down still starts
+        let client = redis::Client::open(config.url.as_str())
+            .map_err(|err| Error::msg(format!("Invalid Redis URL: {}", err)))?;
+        Ok(Redis {
+            client,
+            prefix: config.prefix.clone(),
+            timeout: Duration::from_millis(config.timeout_ms),
+            retry: Duration::from_secs(config.retry_secs),
+            connection: tokio::sync::Mutex::new(None),
+            down_until: Mutex::new(None),
+        })
+    }
+
+    fn key(&self, challenge: &CaptchaChallenge, image_hash: &str) -> String {
+        format!("{}:{}:{}", self.prefix, challenge, image_hash)
+    }
+
+    async fn get(&self, key: &str) -> Option<PredictionRecord> {
+        let mut connection = self.connection().await?;
+        let get = connection.get::<_, Option<Vec<u8>>>(key);
+        match tokio::time::timeout(self.timeout, get).await {
+            Ok(Ok(value)) => serde_json::from_slice(&value?).ok(),
+            _ => {
+                self.failed().await;
+                None
+            }
+        }
+    }
+
+    async fn set(&self, key: &str, record: &PredictionRecord, ttl: Duration) {
+        let value = match serde_json::to_vec(record) {
+            Ok(value) => value,
+            Err(_) => return,
+        };
+        let mut connection = match self.connection().await {
+            Some(connection) => connection,
+            None => return,
+        };
+        let set = connection.set_ex::<_, _, ()>(key, value, ttl.as_secs().max(1));
+        if !matches!(tokio::time::timeout(self.timeout, set).await, Ok(Ok(()))) {
+            self.failed().await;
+        }
+    }
+
+    /// connection is the connection to Redis, or None while Redis is considered down
+    async fn connection(&self) -> Option<MultiplexedConnection> {
+        if self.is_down() {
+            return None;
+        }
+        let mut connection = self.connection.lock().await;
+        // checked again, as requests waiting on the lock would each try to connect otherwise
+        if connection.is_none() && !self.is_down() {
+            let connect = self.client.get_multiplexed_async_connection();
+            match tokio::time::timeout(self.timeout, connect).await {
+                Ok(Ok(connected)) => *connection = Some(connected),
+                _ => self.back_off(),
+            }
+        }
+        connection.clone()
+    }
+
+    fn is_down(&self) -> bool {
+        self.down_until
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    async fn failed(&self) {
+        *self.connection.lock().await = None;
+        self.back_off();
+    }
+
+    fn back_off(&self) {
+        *self
+            .down_until
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(Instant::now() + self.retry);
+        println!(
+            "Redis is unavailable, caching in process for {}s",
+            self.retry.as_secs()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use no_captcha::Prediction;
+
+    #[tokio::test]
+    async fn caches_in_process_until_expiry() -> errors::Result<()> {
+        let cache = PredictionCache::new(&CacheConfig {
+            capacity: 1,
+            ..CacheConfig::default()
+        })?;
+        let record = PredictionRecord::new(CaptchaChallenge::Bus, &Prediction::new(0.9, 0.1));
+        assert_eq!(cache.get(&CaptchaChallenge::Bus, "a").await, None);
+        cache.put(&CaptchaChallenge::Bus, "a", &record).await;
+        assert_eq!(
+            cache.get(&CaptchaChallenge::Bus, "a").await,
+            Some(record.clone())
+        );
+        assert_eq!(cache.get(&CaptchaChallenge::Cars, "a").await, None);
+
+        // a capacity of one evicts "a" for "b"
+        cache.put(&CaptchaChallenge::Bus, "b", &record).await;
+        assert_eq!(cache.get(&CaptchaChallenge::Bus, "a").await, None);
+
+        let expired = PredictionCache::new(&CacheConfig {
+            ttl_secs: 0,
+            ..CacheConfig::default()
+        })?;
+        expired.put(&CaptchaChallenge::Bus, "a", &record).await;
+        assert_eq!(expired.get(&CaptchaChallenge::Bus, "a").await, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,200 @@
+use crate::{
+    admin::{AdminStats, Stats},
+    errors::{self, Error},
+    reload::SharedRegistry,
+};
+use no_captcha::{
+    errors::Result as NoCaptchaResult,
+    remote::{ProxyConfig, RemoteRegistry},
+    CaptchaChallenge, Prediction, Predictor, Priority,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// ClusterConfig is the [cluster] section. Every instance is given the same nodes, each owning
+/// some challenges, and its own name as 'node'. An instance loads the models of the challenges
+/// it owns and forwards requests for the others to their owner. Forwarded requests arrive at
+/// the owner as any other request, so [quotas] belongs on the instances clients call only
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClusterConfig {
+    pub node: String,
+    pub nodes: Vec<ClusterNode>,
+    /// proxy, when set, is used for requests to other nodes instead of [proxy]
+    #[serde(default)]
+    pub proxy: Option<ProxyConfig>,
+}
+
+/// ClusterNode is one instance of the cluster. 'url' is where the other instances reach it,
+/// e.g. http://10.0.0.2:5000
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ClusterNode {
+    pub name: String,
+    pub url: String,
+    pub challenges: Vec<CaptchaChallenge>,
+}
+
+impl ClusterConfig {
+    /// local_challenges are the challenges 'node' owns. It fails unless 'node' is one of the
+    /// nodes and every challenge has a single owner
+    pub fn local_challenges(&self) -> errors::Result<HashSet<CaptchaChallenge>> {
+        let mut owners = HashMap::new();
+        for node in &self.nodes {
+            for challenge in &node.challenges {
+                if let Some(owner) = owners.insert(challenge, &node.name) {
+                    return Err(Error::msg(format!(
+                        "{} is owned by both {} and {}",
+                        challenge, owner, node.name
+                    )));
+                }
+            }
+        }
+        self.nodes
+            .iter()
+            .find(|node| node.name == self.node)
+            .map(|node| node.challenges.iter().cloned().collect())
+            .ok_or_else(|| Error::msg(format!("{} is not one of the cluster's nodes", self.node)))
+    }
+}
+
+/// Shard is the predictor of a cluster instance: its own challenges are predicted by the local
+/// registry, every other node's are sent to that node at the priority they were asked at.
+/// Challenges no node owns are left to the local registry, which fails them as it would
+/// outside a cluster. The local registry is shared, so reloads swap it under the shard
+pub struct Shard {
+    local: SharedRegistry,
+    peers: Vec<Peer>,
+    /// owners indexes the peer owning each challenge
+    owners: HashMap<CaptchaChallenge, usize>,
+}
+
+struct Peer {
+    node: ClusterNode,
+    client: RemoteRegistry,
+}
+
+/// NodeStats is what an instance knows of another node of its cluster
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NodeStats {
+    #[serde(flatten)]
+    pub node: ClusterNode,
+    /// healthy is whether the node answered its latest health check
+    pub healthy: bool,
+}
+
+impl Shard {
+    /// new sends requests for other nodes' challenges through 'proxy'
+    pub fn new(
+        local: SharedRegistry,
+        config: &ClusterConfig,
+        proxy: &ProxyConfig,
+    ) -> errors::Result<Shard> {
+        let mut peers = Vec::new();
+        let mut owners = HashMap::new();
+        for node in config.nodes.iter().filter(|node| node.name != config.node) {
+            for challenge in &node.challenges {
+                let _ = owners.insert(challenge.clone(), peers.len());
+            }
+            peers.push(Peer {
+                client: RemoteRegistry::builder(node.url.clone())
+                    .proxy(proxy.clone())
+                    .build()?,
+                node: node.clone(),
+            });
+        }
+        Ok(Shard {
+            local,
+            peers,
+            owners,
+        })
+    }
+
+    fn owner(&self, challenge: &CaptchaChallenge) -> Option<&Peer> {
+        self.owners.get(challenge).map(|&peer| &self.peers[peer])
+    }
+}
+
+impl Predictor for Shard {
+    fn predict_batch(
+        &self,
+        challenge: &CaptchaChallenge,
+        images: Vec<Vec<u8>>,
+    ) -> NoCaptchaResult<Vec<Prediction>> {
+        match self.owner(challenge) {
+            Some(peer) => peer.client.predict_batch(challenge, images),
+            None => self.local.current().predict_batch(challenge, images),
+        }
+    }
+
+    fn predict_batch_prioritized(
+        &self,
+        challenge: &CaptchaChallenge,
+        images: Vec<Vec<u8>>,
+        priority: Priority,
+    ) -> NoCaptchaResult<Vec<Prediction>> {
+        match self.owner(challenge) {
+            Some(peer) => peer
+                .client
+                .predict_batch_prioritized(challenge, images, priority),
+            None => self
+                .local
+                .current()
+                .predict_batch_prioritized(challenge, images, priority),
+        }
+    }
+}
+
+impl Stats for Shard {
+    fn fill(&self, stats: &mut AdminStats) -> errors::Result<()> {
+        self.local.current().fill(stats)?;
+        stats.nodes = Some(
+            self.peers
+                .iter()
+                .map(|peer| NodeStats {
+                    node: peer.node.clone(),
+                    healthy: !peer.client.healthy_upstreams().is_empty(),
+                })
+                .collect(),
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(name: &str, challenges: Vec<CaptchaChallenge>) -> ClusterNode {
+        ClusterNode {
+            name: name.to_string(),
+            url: format!("http://{}:5000", name),
+            challenges,
+        }
+    }
+
+    #[test]
+    fn assigns_each_challenge_one_owner() -> errors::Result<()> {
+        let mut config = ClusterConfig {
+            node: "a".to_string(),
+            nodes: vec![
+                node("a", vec![CaptchaChallenge::Bus, CaptchaChallenge::Cars]),
+                node("b", vec![CaptchaChallenge::Crosswalks]),
+            ],
+            proxy: None,
+        };
+        assert_eq!(
+            config.local_challenges()?,
+            [CaptchaChallenge::Bus, CaptchaChallenge::Cars]
+                .iter()
+                .cloned()
+                .collect()
+        );
+
+        config.node = "c".to_string();
+        assert!(config.local_challenges().is_err());
+
+        config.node = "a".to_string();
+        config.nodes[1].challenges.push(CaptchaChallenge::Bus);
+        assert!(config.local_challenges().is_err());
+        Ok(())
+    }
+}
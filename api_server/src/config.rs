@@ -0,0 +1,321 @@
+use crate::{
+    access::AccessConfig,
+    audit::AuditConfig,
+    cache::CacheConfig,
+    cluster::ClusterConfig,
+    errors::{self, Error},
+    quota::QuotaConfig,
+};
+use no_captcha::{
+    concurrency::ConcurrencyLimits, detect::ClassifierConfig, devices::DevicePlacement,
+    discovery::Discovery, loading::LoadLimits, prediction_log::PredictionLogConfig,
+    remote::ProxyConfig, resize::ResizeOptions, sanitize::InputLimits, LogLevel,
+};
+use serde::{Deserialize, Deserializer};
+use serde_derive::Deserialize;
+use std::{ffi::OsString, path::PathBuf};
+
+/// WORKER_FLAG, as the first argument, starts the binary as one of the inference workers a
+/// server configured with [workers] spawns, rather than as a server
+pub const WORKER_FLAG: &str = "--worker";
+
+/// Config is read from the TOML file named by the first command line argument (or the
+/// NOCAP_CONFIG environment variable). Every field is optional:
+///
+/// ```toml
+/// models_dir = "../models/"
+/// reload = "changed"
+/// idle_ttl_secs = 600
+/// update_check_secs = 60
+/// graph_cache_dir = "/var/cache/nocap/graphs"
+/// tensorflow_log_level = "error"
+/// admin_token = "change-me"
+///
+/// [discovery]
+/// max_depth = 1
+///
+/// [input_limits]
+/// max_bytes = 1048576
+/// max_pixels = 1000000
+///
+/// [resize]
+/// interpolation = "bicubic"
+/// fit = "stretch"
+///
+/// [loading]
+/// concurrency = 2
+/// memory_budget = 2147483648
+///
+/// [devices]
+/// default = 1
+///
+/// [devices.challenges]
+/// bus = 0
+/// crosswalks = 0
+///
+/// [concurrency]
+/// global = 4
+/// per_challenge = 2
+/// overflow = "reject"
+///
+/// [concurrency.overrides]
+/// crosswalks = 1
+///
+/// [prediction_log]
+/// path = "/var/log/nocap/predictions.jsonl"
+/// format = "jsonl"
+///
+/// [prediction_log.rotation]
+/// max_bytes = 67108864
+/// max_files = 5
+///
+/// [workers]
+/// count = 4
+///
+/// [quotas]
+/// path = "/var/lib/nocap/usage"
+/// signature_window_secs = 300
+///
+/// [[quotas.keys]]
+/// name = "search-team"
+/// key = "a-long-random-key"
+/// daily = 10000
+/// monthly = 200000
+///
+/// [[quotas.keys]]
+/// name = "ads-team"
+/// secret = "a-long-random-secret"
+///
+/// [audit]
+/// path = "/var/lib/nocap/audit.sqlite"
+/// retention_days = 90
+/// max_records = 10000000
+///
+/// [cache]
+/// capacity = 10000
+/// ttl_secs = 3600
+///
+/// [cache.redis]
+/// url = "redis://cache.internal:6379/0"
+/// prefix = "nocap"
+/// timeout_ms = 50
+/// retry_secs = 30
+///
+/// [cluster]
+/// node = "gpu-a"
+///
+/// [[cluster.nodes]]
+/// name = "gpu-a"
+/// url = "http://10.0.0.1:5000"
+/// challenges = ["bus", "crosswalks", "traffic_lights"]
+///
+/// [[cluster.nodes]]
+/// name = "gpu-b"
+/// url = "http://10.0.0.2:5000"
+/// challenges = ["cars", "bicycles", "motorcycles"]
+///
+/// [cluster.proxy]
+/// url = "http://egress.internal:3128"
+///
+/// [proxy]
+/// url = "socks5://egress.internal:1080"
+/// no_proxy = "localhost,.internal"
+///
+/// [challenge_classifier]
+/// path = "../classifiers/challenge_type"
+/// min_confidence = 0.8
+///
+/// [access]
+/// allow = ["10.0.0.0/8", "2001:db8::/32"]
+/// deny = ["10.13.0.0/16"]
+/// file = "/etc/nocap/access.toml"
+/// reload_secs = 10
+///
+/// [listen]
+/// type = "unix"
+/// path = "/run/nocap/nocap.sock"
+/// mode = "660"
+/// ```
+///
+/// When systemd starts the server through socket activation, the socket it passes is served
+/// instead of [listen].
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub models_dir: PathBuf,
+    /// discovery controls how models_dir is searched, e.g. for models under versioned
+    /// subdirectories
+    pub discovery: Discovery,
+    pub listen: Listen,
+    pub reload: ReloadMode,
+    /// idle_ttl_secs, when set, unloads models that haven't served a prediction for that many
+    /// seconds. They are loaded again by their next prediction
+    pub idle_ttl_secs: Option<u64>,
+    /// update_check_secs, when set, checks that often for models changed on disk and swaps
+    /// in the changed ones, as a changed reload does but without waiting for SIGHUP
+    pub update_check_secs: Option<u64>,
+    /// graph_cache_dir, when set, loads models from frozen graphs kept there (see
+    /// no_captcha::graph_cache::GraphCache)
+    pub graph_cache_dir: Option<PathBuf>,
+    /// tensorflow_log_level is the least severe TensorFlow message logged, fatal by default
+    pub tensorflow_log_level: LogLevel,
+    pub input_limits: InputLimits,
+    /// resize controls how inputs are resized to each model's input resolution
+    pub resize: ResizeOptions,
+    /// loading bounds how many models load at once, at startup and on reload
+    pub loading: LoadLimits,
+    /// devices pins each challenge's models to a GPU, which needs graph_cache_dir set too
+    pub devices: DevicePlacement,
+    /// concurrency caps the predictions running at once, per challenge and overall. With
+    /// workers, each worker applies the caps to its own predictions
+    pub concurrency: ConcurrencyLimits,
+    /// prediction_log, when set, logs every prediction for offline analysis. It isn't
+    /// available with workers
+    pub prediction_log: Option<PredictionLogConfig>,
+    /// workers, when set, runs inference in that many worker subprocesses instead of in the
+    /// server. Feedback and reloading aren't available in this mode
+    pub workers: Option<WorkerConfig>,
+    /// admin_token is the bearer token the admin routes, such as GET /admin/stats, require.
+    /// Without one they refuse every request
+    pub admin_token: Option<String>,
+    /// quotas, when set, requires an API key or a signature for POST /recognize and counts
+    /// each key's requests against its daily and monthly quotas
+    pub quotas: Option<QuotaConfig>,
+    /// audit, when set, records the metadata of every recognition request in SQLite, for
+    /// GET /admin/audit to query
+    pub audit: Option<AuditConfig>,
+    /// cache, when set, answers repeated images from cache instead of predicting them again
+    pub cache: Option<CacheConfig>,
+    /// cluster, when set, loads only the models of the challenges this instance owns and
+    /// forwards requests for the others to the instances owning them. Reloading, idle_ttl_secs
+    /// and prediction_log apply to the models this instance owns. Feedback isn't available in
+    /// this mode, and it can't be combined with workers
+    pub cluster: Option<ClusterConfig>,
+    /// access, when set, refuses clients by address before their requests are read
+    pub access: Option<AccessConfig>,
+    /// proxy routes the server's outbound HTTP through proxies. A component's own proxy
+    /// section, such as [cluster.proxy], takes its place for that component
+    pub proxy: Option<ProxyConfig>,
+    /// challenge_classifier, when set, loads the model POST /detect tells the challenge of a
+    /// screenshot with
+    pub challenge_classifier: Option<ClassifierConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            models_dir: PathBuf::from("../models/"),
+            discovery: Discovery::default(),
+            listen: Listen::default(),
+            reload: ReloadMode::Full,
+            idle_ttl_secs: None,
+            update_check_secs: None,
+            graph_cache_dir: None,
+            tensorflow_log_level: LogLevel::Fatal,
+            input_limits: InputLimits::default(),
+            resize: ResizeOptions::default(),
+            loading: LoadLimits::default(),
+            devices: DevicePlacement::default(),
+            concurrency: ConcurrencyLimits::default(),
+            prediction_log: None,
+            workers: None,
+            admin_token: None,
+            quotas: None,
+            audit: None,
+            cache: None,
+            cluster: None,
+            access: None,
+            proxy: None,
+            challenge_classifier: None,
+        }
+    }
+}
+
+/// Listen selects between a TCP address and a Unix domain socket, which is only available on
+/// Unix. The socket's permission bits are given as an octal string and applied before the
+/// socket appears at its path
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Listen {
+    Tcp {
+        address: String,
+    },
+    Unix {
+        path: PathBuf,
+        #[serde(default = "default_socket_mode", deserialize_with = "octal_mode")]
+        mode: u32,
+    },
+}
+
+impl Default for Listen {
+    fn default() -> Listen {
+        Listen::Tcp {
+            address: String::from("127.0.0.1:5000"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct WorkerConfig {
+    pub count: usize,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> WorkerConfig {
+        WorkerConfig {
+            count: std::thread::available_parallelism().map_or(1, |count| count.get()),
+        }
+    }
+}
+
+/// ReloadMode picks what SIGHUP reloads: every model, or only those whose files changed on
+/// disk since they were loaded
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReloadMode {
+    Full,
+    Changed,
+}
+
+fn default_socket_mode() -> u32 {
+    0o660
+}
+
+fn octal_mode<'de, D>(deserializer: D) -> Result<u32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let mode = String::deserialize(deserializer)?;
+    u32::from_str_radix(&mode, 8).map_err(serde::de::Error::custom)
+}
+
+impl Config {
+    pub fn load() -> errors::Result<Config> {
+        let path = match std::env::args_os()
+            .skip(1)
+            .find(|arg| arg != WORKER_FLAG)
+            .or_else(|| std::env::var_os("NOCAP_CONFIG"))
+        {
+            Some(path) => path,
+            None => return Ok(Config::default()),
+        };
+        let contents = std::fs::read_to_string(path)?;
+        toml::from_str(&contents).map_err(|err| Error::msg(format!("Invalid config: {}", err)))
+    }
+}
+
+/// is_worker is whether this process was started with WORKER_FLAG
+pub fn is_worker() -> bool {
+    std::env::args_os()
+        .nth(1)
+        .is_some_and(|arg| arg == WORKER_FLAG)
+}
+
+/// worker_args are the arguments a worker is started with: WORKER_FLAG followed by the
+/// server's own, so it reads the same config
+pub fn worker_args() -> Vec<OsString> {
+    std::iter::once(OsString::from(WORKER_FLAG))
+        .chain(std::env::args_os().skip(1))
+        .collect()
+}
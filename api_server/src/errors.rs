@@ -1,19 +1,72 @@
-use no_captcha::errors::Error as NoCaptchaError;
-use serde::Serialize;
+use axum::{
+    extract::{rejection::JsonRejection, FromRequest},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use no_captcha::errors::{Error as NoCaptchaError, ERROR_CODES};
+use serde::{ser::SerializeMap, Serialize, Serializer};
 use serde_derive::Serialize;
-use std::io::Error as IOError;
+use std::{error::Error as _, io::Error as IOError};
 
-#[derive(Debug, Serialize)]
-#[serde(tag = "err", content = "meta")]
-#[serde(rename_all = "snake_case")]
+/// Error is serialized as `{"code": code, "message": message, "details": details}`, 'details'
+/// being the messages of the error's sources or null. The codes of no_captcha errors are their
+/// error_code, so clients can match on them without knowing the server's internals. Every code
+/// is listed by GET /errors
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
+    #[error("invalid recognition request")]
     InvalidRecognitionRequest,
+    /// UnsupportedMediaType is an image body declared as a type other than an image's
+    #[error("unsupported content type")]
+    UnsupportedMediaType,
+    /// InvalidUpload is an uploaded archive that can't be read, or holds no labeled images
+    #[error("invalid upload: {0}")]
+    InvalidUpload(String),
+    /// UploadTooLarge is an upload, or the archive it unpacks to, over the size limits
+    #[error("the upload is too large")]
+    UploadTooLarge,
+    /// InvalidFeedback is feedback about a challenge without a known name, or with an image
+    /// hash that isn't a hex encoded SHA-256
+    #[error("invalid feedback: {0}")]
+    InvalidFeedback(&'static str),
+    /// UnknownJob is a job id that was never issued, or whose job was forgotten
+    #[error("no such job")]
+    UnknownJob,
+    /// Unauthorized is a request to an admin route without the configured admin_token
+    #[error("missing or wrong admin token")]
+    Unauthorized,
+    /// Forbidden is a request from an address [access] refuses
+    #[error("requests from this address are not allowed")]
+    Forbidden,
+    /// InvalidApiKey is a request without a configured key in X-API-Key, or signed by one,
+    /// with [quotas] set
+    #[error("missing or unknown API key")]
+    InvalidApiKey,
+    /// QuotaExceeded is a request over its key's daily or monthly quota
+    #[error("the API key's {0} quota is used up")]
+    QuotaExceeded(&'static str),
+    /// InvalidSignature is a signed request whose signature doesn't hold, or was used before
+    #[error("invalid request signature: {0}")]
+    InvalidSignature(&'static str),
+    #[error("{0}")]
     Generic(String),
 
-    #[serde(skip)]
-    IOError(IOError),
-    #[serde(skip)]
+    #[error("I/O error")]
+    IOError(#[source] IOError),
+    #[error("usage store error")]
+    UsageStore(#[source] sled::Error),
+    #[error("audit log error")]
+    AuditLog(#[source] rusqlite::Error),
+    #[error(transparent)]
     NoCAPTCHA(NoCaptchaError),
+    /// Shared is an error that failed a whole batch, copied to each request in it
+    #[error("{message}")]
+    Shared {
+        code: &'static str,
+        status: StatusCode,
+        message: String,
+        details: Option<String>,
+    },
 }
 
 impl Error {
@@ -23,6 +76,208 @@ impl Error {
     {
         Error::Generic(source.into())
     }
+
+    /// error_code is the stable code sent as "code"
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            Error::InvalidRecognitionRequest => "invalid_recognition_request",
+            Error::UnsupportedMediaType => "unsupported_media_type",
+            Error::InvalidUpload(_) => "invalid_upload",
+            Error::UploadTooLarge => "upload_too_large",
+            Error::InvalidFeedback(_) => "invalid_feedback",
+            Error::UnknownJob => "unknown_job",
+            Error::Unauthorized => "unauthorized",
+            Error::Forbidden => "forbidden",
+            Error::InvalidApiKey => "invalid_api_key",
+            Error::QuotaExceeded(_) => "quota_exceeded",
+            Error::InvalidSignature(_) => "invalid_signature",
+            Error::Generic(_) => "generic",
+            Error::IOError(_) => "io",
+            Error::UsageStore(_) => "usage_store",
+            Error::AuditLog(_) => "audit_log",
+            Error::NoCAPTCHA(error) => error.error_code(),
+            Error::Shared { code, .. } => code,
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            Error::InvalidRecognitionRequest => StatusCode::BAD_REQUEST,
+            Error::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Error::InvalidUpload(_) | Error::InvalidFeedback(_) => StatusCode::BAD_REQUEST,
+            Error::UploadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::UnknownJob => StatusCode::NOT_FOUND,
+            Error::Unauthorized | Error::InvalidApiKey | Error::InvalidSignature(_) => {
+                StatusCode::UNAUTHORIZED
+            }
+            Error::QuotaExceeded(_) => StatusCode::TOO_MANY_REQUESTS,
+            Error::Forbidden => StatusCode::FORBIDDEN,
+            Error::NoCAPTCHA(error) if error.is_client_error() => StatusCode::BAD_REQUEST,
+            Error::NoCAPTCHA(NoCaptchaError::Overloaded(_)) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Shared { status, .. } => *status,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// shared copies the error's code, status, message and details into an Error::Shared,
+    /// for answering several requests with the one error
+    pub fn shared(&self) -> Error {
+        Error::Shared {
+            code: self.error_code(),
+            status: self.status(),
+            message: self.to_string(),
+            details: self.details(),
+        }
+    }
+
+    /// details joins the messages of the error's sources, outermost first
+    fn details(&self) -> Option<String> {
+        if let Error::Shared { details, .. } = self {
+            return details.clone();
+        }
+        let mut sources = Vec::new();
+        let mut source = self.source();
+        while let Some(error) = source {
+            sources.push(error.to_string());
+            source = error.source();
+        }
+        if sources.is_empty() {
+            None
+        } else {
+            Some(sources.join(": "))
+        }
+    }
+}
+
+/// describe is the message of 'error' followed by those of its sources, for logging errors no
+/// response carries
+pub fn describe(error: &dyn std::error::Error) -> String {
+    let mut description = error.to_string();
+    let mut source = error.source();
+    while let Some(error) = source {
+        description.push_str(": ");
+        description.push_str(&error.to_string());
+        source = error.source();
+    }
+    description
+}
+
+/// ErrorDescription documents one code an error response can carry
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ErrorDescription {
+    pub code: &'static str,
+    /// status is the HTTP status the code is answered with
+    pub status: u16,
+    pub description: &'static str,
+}
+
+/// catalog is every code an error response can carry: the server's own, then no_captcha's
+pub fn catalog() -> Vec<ErrorDescription> {
+    let own = [
+        ErrorDescription {
+            code: "invalid_recognition_request",
+            status: StatusCode::BAD_REQUEST.as_u16(),
+            description: "the request body is not valid JSON of the expected shape",
+        },
+        ErrorDescription {
+            code: "unsupported_media_type",
+            status: StatusCode::UNSUPPORTED_MEDIA_TYPE.as_u16(),
+            description: "an image body's Content-Type is not image/png, image/jpeg, image/gif, \
+                image/webp or application/octet-stream",
+        },
+        ErrorDescription {
+            code: "invalid_upload",
+            status: StatusCode::BAD_REQUEST.as_u16(),
+            description: "the uploaded ZIP can't be read, or holds no labeled images",
+        },
+        ErrorDescription {
+            code: "upload_too_large",
+            status: StatusCode::PAYLOAD_TOO_LARGE.as_u16(),
+            description: "the upload, or what it unpacks to, is over the size limits",
+        },
+        ErrorDescription {
+            code: "invalid_feedback",
+            status: StatusCode::BAD_REQUEST.as_u16(),
+            description: "feedback names an unknown challenge, or its image_hash isn't a \
+                lowercase hex encoded SHA-256",
+        },
+        ErrorDescription {
+            code: "unknown_job",
+            status: StatusCode::NOT_FOUND.as_u16(),
+            description: "no job has the id, or it finished long enough ago to be forgotten",
+        },
+        ErrorDescription {
+            code: "unauthorized",
+            status: StatusCode::UNAUTHORIZED.as_u16(),
+            description: "an admin route was called without the admin token",
+        },
+        ErrorDescription {
+            code: "forbidden",
+            status: StatusCode::FORBIDDEN.as_u16(),
+            description: "the client's address is denied, or missing from the allow list",
+        },
+        ErrorDescription {
+            code: "invalid_api_key",
+            status: StatusCode::UNAUTHORIZED.as_u16(),
+            description: "the X-API-Key header is missing or holds no configured key",
+        },
+        ErrorDescription {
+            code: "quota_exceeded",
+            status: StatusCode::TOO_MANY_REQUESTS.as_u16(),
+            description: "the API key has used up its daily or monthly quota",
+        },
+        ErrorDescription {
+            code: "invalid_signature",
+            status: StatusCode::UNAUTHORIZED.as_u16(),
+            description: "the request's signature is malformed, expired, wrong or replayed",
+        },
+        ErrorDescription {
+            code: "generic",
+            status: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+            description: "the request failed; the message says why",
+        },
+        ErrorDescription {
+            code: "usage_store",
+            status: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+            description: "the API key usage store could not be read or written",
+        },
+        ErrorDescription {
+            code: "audit_log",
+            status: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+            description: "the audit log could not be read or written",
+        },
+    ];
+    own.iter()
+        .cloned()
+        .chain(ERROR_CODES.iter().map(|listed| {
+            // as Error::status answers them
+            let status = if listed.client_error {
+                StatusCode::BAD_REQUEST
+            } else if listed.code == "overloaded" {
+                StatusCode::SERVICE_UNAVAILABLE
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            };
+            ErrorDescription {
+                code: listed.code,
+                status: status.as_u16(),
+                description: listed.description,
+            }
+        }))
+        .collect()
+}
+
+impl Serialize for Error {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(Some(3))?;
+        map.serialize_entry("code", self.error_code())?;
+        map.serialize_entry("message", &self.to_string())?;
+        map.serialize_entry("details", &self.details())?;
+        map.end()
+    }
 }
 
 impl From<NoCaptchaError> for Error {
@@ -37,16 +292,26 @@ impl From<IOError> for Error {
     }
 }
 
-impl tide::IntoResponse for Error {
-    fn into_response(self) -> tide::Response {
-        tide::Response::new(500)
-            .set_header("Content-Type", "application/json")
-            .body_json(&self)
-            .unwrap()
+impl From<JsonRejection> for Error {
+    fn from(_: JsonRejection) -> Error {
+        Error::InvalidRecognitionRequest
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> axum::response::Response {
+        (self.status(), axum::Json(self)).into_response()
     }
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// JsonBody extracts a JSON request body, rejecting malformed bodies with
+/// Error::InvalidRecognitionRequest instead of axum's plain text rejection
+#[derive(FromRequest)]
+#[from_request(via(axum::Json), rejection(Error))]
+pub struct JsonBody<T>(pub T);
+
 #[derive(Serialize)]
 pub struct Response<T>(Result<T>)
 where
@@ -64,18 +329,78 @@ where
     }
 }
 
-impl<T> tide::IntoResponse for Response<T>
+impl<T> IntoResponse for Response<T>
 where
-    T: Serialize + Send,
+    T: Serialize,
 {
-    fn into_response(self) -> tide::Response {
+    fn into_response(self) -> axum::response::Response {
         if let Err(e) = self.0 {
             e.into_response()
         } else {
-            tide::Response::new(200)
-                .set_header("Content-Type", "application/json")
-                .body_json(&self)
-                .unwrap()
+            axum::Json(self).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use no_captcha::sanitize::Rejection;
+
+    #[test]
+    fn mirrors_no_captcha_codes() -> serde_json::Result<()> {
+        let err = Error::from(NoCaptchaError::RejectedImage(0, Rejection::Unreadable));
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            serde_json::to_value(&err.shared())?,
+            serde_json::json!({
+                "code": "rejected_image",
+                "message": "image 0 was rejected",
+                "details": "unreadable image header",
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(&Error::InvalidRecognitionRequest)?,
+            serde_json::json!({
+                "code": "invalid_recognition_request",
+                "message": "invalid recognition request",
+                "details": null,
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn catalogs_every_status() {
+        let catalog = catalog();
+        for err in [
+            Error::InvalidRecognitionRequest,
+            Error::UnsupportedMediaType,
+            Error::InvalidUpload("not a ZIP".to_string()),
+            Error::UploadTooLarge,
+            Error::InvalidFeedback("unknown challenge"),
+            Error::UnknownJob,
+            Error::Unauthorized,
+            Error::Forbidden,
+            Error::InvalidApiKey,
+            Error::QuotaExceeded("daily"),
+            Error::InvalidSignature("request was replayed"),
+            Error::msg("failed"),
+            Error::from(NoCaptchaError::RejectedImage(0, Rejection::Unreadable)),
+            Error::from(NoCaptchaError::Overloaded(
+                no_captcha::CaptchaChallenge::Bus,
+            )),
+            Error::from(NoCaptchaError::MalformedOutput),
+        ] {
+            let listed = catalog
+                .iter()
+                .find(|listed| listed.code == err.error_code());
+            assert_eq!(
+                listed.map(|listed| listed.status),
+                Some(err.status().as_u16()),
+                "{}",
+                err.error_code()
+            );
         }
     }
 }
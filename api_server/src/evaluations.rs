@@ -0,0 +1,210 @@
+use crate::errors::{self, Error};
+use no_captcha::{
+    dataset::LABEL_DIRS,
+    evaluation::{self, Evaluation},
+    CaptchaChallenge, Predictor,
+};
+use serde_derive::Deserialize;
+use std::{
+    collections::BTreeSet,
+    fs::{self, File},
+    io::{self, Cursor, Read},
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// MAX_UPLOAD_BYTES bounds the ZIP POST /admin/evaluations accepts
+pub const MAX_UPLOAD_BYTES: usize = 256 * 1024 * 1024;
+
+/// MAX_UNPACKED_BYTES and MAX_ENTRIES bound what a ZIP unpacks to, as a small archive can
+/// expand to far more than its size
+const MAX_UNPACKED_BYTES: u64 = 1024 * 1024 * 1024;
+const MAX_ENTRIES: usize = 100_000;
+
+/// GRID is the grid folder images outside one are unpacked into
+const GRID: &str = "upload";
+
+/// EvaluationQuery is the query of POST /admin/evaluations. 'challenge' evaluates only that
+/// challenge, and is needed for a ZIP of bare "matches" and "not matches" folders
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct EvaluationQuery {
+    pub challenge: Option<CaptchaChallenge>,
+}
+
+/// evaluate_upload evaluates 'predictor' on the labeled images in 'zip', one Evaluation per
+/// challenge found, in name order. The ZIP may be laid out like test_data/, be a folder of it,
+/// a challenge's folder, or hold the "matches" and "not matches" folders alone
+pub fn evaluate_upload<P>(
+    predictor: &P,
+    zip: &[u8],
+    query: &EvaluationQuery,
+) -> errors::Result<Vec<Evaluation>>
+where
+    P: Predictor + ?Sized,
+{
+    let scratch = Scratch::new()?;
+    let challenges = unpack(zip, &scratch.0, query.challenge.as_ref())?;
+    if challenges.is_empty() {
+        return Err(Error::InvalidUpload(
+            "no images in \"matches\" or \"not matches\" folders".to_string(),
+        ));
+    }
+    challenges
+        .iter()
+        .map(|challenge| {
+            evaluation::evaluate(predictor, &scratch.0, challenge).map_err(Error::from)
+        })
+        .collect()
+}
+
+/// unpack writes the labeled images of 'zip' under 'root' laid out like test_data/, and
+/// returns the challenges they are for
+fn unpack(
+    zip: &[u8],
+    root: &Path,
+    only: Option<&CaptchaChallenge>,
+) -> errors::Result<Vec<CaptchaChallenge>> {
+    let invalid = |err: zip::result::ZipError| Error::InvalidUpload(err.to_string());
+    let mut archive = zip::ZipArchive::new(Cursor::new(zip)).map_err(invalid)?;
+    if archive.len() > MAX_ENTRIES {
+        return Err(Error::UploadTooLarge);
+    }
+    let only = only.map(|challenge| challenge.to_string().replace('_', " "));
+    let mut folders = BTreeSet::new();
+    let mut unpacked = 0;
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index).map_err(invalid)?;
+        // enclosed_name refuses names that would land outside 'root'
+        let name = match entry.enclosed_name() {
+            Some(name) if entry.is_file() => name,
+            _ => continue,
+        };
+        let path = match labeled_path(&name, only.as_deref())? {
+            Some(path) => path,
+            None => continue,
+        };
+        if only.as_ref().is_some_and(|only| path[1] != *only) {
+            continue;
+        }
+        let dest = root.join(path.iter().collect::<PathBuf>());
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let limit = MAX_UNPACKED_BYTES - unpacked;
+        // one byte over the limit is enough to tell it was crossed
+        unpacked += io::copy(
+            &mut entry.by_ref().take(limit + 1),
+            &mut File::create(&dest)?,
+        )?;
+        if unpacked > MAX_UNPACKED_BYTES {
+            return Err(Error::UploadTooLarge);
+        }
+        let _ = folders.insert(path[1].clone());
+    }
+    Ok(folders
+        .into_iter()
+        .map(|folder| {
+            let name = folder.replace(' ', "_");
+            name.parse().unwrap_or(CaptchaChallenge::Other(name))
+        })
+        .collect())
+}
+
+/// labeled_path maps the name of a ZIP entry to [grid, challenge folder, label, file], or None
+/// for entries that aren't directly in a label folder. Entries outside a grid folder go in
+/// GRID, and entries outside a challenge folder in the folder of 'challenge'
+fn labeled_path(name: &Path, challenge: Option<&str>) -> errors::Result<Option<Vec<String>>> {
+    let mut parts = Vec::new();
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_string_lossy().into_owned()),
+            _ => return Ok(None),
+        }
+    }
+    // macOS leaves metadata beside every file it zips
+    if parts
+        .iter()
+        .any(|part| part.starts_with('.') || part == "__MACOSX")
+    {
+        return Ok(None);
+    }
+    let label = match parts.len().checked_sub(2) {
+        Some(label) if LABEL_DIRS.iter().any(|(dir, _)| *dir == parts[label]) => label,
+        _ => return Ok(None),
+    };
+    Ok(Some(match label {
+        0 => {
+            let challenge = challenge.ok_or_else(|| {
+                Error::InvalidUpload(
+                    "images outside a challenge folder need the challenge query".to_string(),
+                )
+            })?;
+            [GRID.to_string(), challenge.to_string()]
+                .iter()
+                .cloned()
+                .chain(parts)
+                .collect()
+        }
+        1 => std::iter::once(GRID.to_string()).chain(parts).collect(),
+        _ => parts.split_off(label - 2),
+    }))
+}
+
+/// Scratch is a directory under the system temp directory, removed with everything in it when
+/// dropped
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new() -> errors::Result<Scratch> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let dir = std::env::temp_dir().join("nocap-evaluations").join(format!(
+            "{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir)?;
+        Ok(Scratch(dir))
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labeled(name: &str, challenge: Option<&str>) -> Option<String> {
+        labeled_path(Path::new(name), challenge)
+            .unwrap()
+            .map(|parts| parts.join("/"))
+    }
+
+    #[test]
+    fn lays_entries_out_like_test_data() {
+        assert_eq!(
+            labeled("test_data/4x4/bus/matches/a.png", None).as_deref(),
+            Some("4x4/bus/matches/a.png")
+        );
+        assert_eq!(
+            labeled("3x3/palm trees/not matches/b.jpg", None).as_deref(),
+            Some("3x3/palm trees/not matches/b.jpg")
+        );
+        assert_eq!(
+            labeled("bus/matches/a.png", None).as_deref(),
+            Some("upload/bus/matches/a.png")
+        );
+        assert_eq!(
+            labeled("matches/a.png", Some("bus")).as_deref(),
+            Some("upload/bus/matches/a.png")
+        );
+        assert!(labeled_path(Path::new("matches/a.png"), None).is_err());
+        assert_eq!(labeled("bus/README.md", None), None);
+        assert_eq!(labeled("bus/matches/nested/a.png", None), None);
+        assert_eq!(labeled("__MACOSX/bus/matches/._a.png", None), None);
+    }
+}
@@ -0,0 +1,176 @@
+use crate::errors::{self, Error};
+use serde_derive::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// MAX_FINISHED is how many finished jobs are kept for their submitters to collect, the oldest
+/// being forgotten first
+const MAX_FINISHED: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// Job is work too long to answer a request with. The request is answered with the running
+/// job at once, and the job is polled by its id until it has finished
+#[derive(Debug, Clone, Serialize)]
+pub struct Job<T> {
+    pub id: u64,
+    pub status: JobStatus,
+    pub started_ms: u64,
+    pub finished_ms: Option<u64>,
+    /// result is set once the job has succeeded
+    pub result: Option<T>,
+    /// error is set once the job has failed, as an error response would carry it
+    pub error: Option<serde_json::Value>,
+}
+
+/// Jobs runs jobs on the blocking pool and keeps them until they are collected or forgotten
+pub struct Jobs<T> {
+    table: Mutex<Table<T>>,
+}
+
+struct Table<T> {
+    next_id: u64,
+    jobs: BTreeMap<u64, Job<T>>,
+}
+
+impl<T> Default for Jobs<T> {
+    fn default() -> Jobs<T> {
+        Jobs {
+            table: Mutex::new(Table {
+                next_id: 1,
+                jobs: BTreeMap::new(),
+            }),
+        }
+    }
+}
+
+impl<T> Jobs<T>
+where
+    T: Clone + Send + 'static,
+{
+    /// spawn runs 'work' as a new job and returns the job, still running
+    pub fn spawn<F>(self: &Arc<Self>, work: F) -> Job<T>
+    where
+        F: FnOnce() -> errors::Result<T> + Send + 'static,
+    {
+        let job = {
+            let mut table = self.table.lock().unwrap_or_else(PoisonError::into_inner);
+            let job = Job {
+                id: table.next_id,
+                status: JobStatus::Running,
+                started_ms: now_ms(),
+                finished_ms: None,
+                result: None,
+                error: None,
+            };
+            table.next_id += 1;
+            let _ = table.jobs.insert(job.id, job.clone());
+            job
+        };
+        let jobs = Arc::clone(self);
+        let id = job.id;
+        let _ = tokio::spawn(async move {
+            // a job that panicked fails rather than running forever
+            let result = tokio::task::spawn_blocking(work)
+                .await
+                .map_err(|err| Error::msg(err.to_string()))
+                .and_then(|result| result);
+            jobs.finish(id, result);
+        });
+        job
+    }
+
+    pub fn get(&self, id: u64) -> Option<Job<T>> {
+        self.table
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .jobs
+            .get(&id)
+            .cloned()
+    }
+
+    fn finish(&self, id: u64, result: errors::Result<T>) {
+        let mut table = self.table.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(job) = table.jobs.get_mut(&id) {
+            job.finished_ms = Some(now_ms());
+            match result {
+                Ok(result) => {
+                    job.status = JobStatus::Succeeded;
+                    job.result = Some(result);
+                }
+                Err(err) => {
+                    job.status = JobStatus::Failed;
+                    job.error = serde_json::to_value(&err).ok();
+                }
+            }
+        }
+        // ids only grow, so the first finished jobs are the oldest
+        let finished: Vec<u64> = table
+            .jobs
+            .values()
+            .filter(|job| job.status != JobStatus::Running)
+            .map(|job| job.id)
+            .collect();
+        for id in finished
+            .iter()
+            .take(finished.len().saturating_sub(MAX_FINISHED))
+        {
+            let _ = table.jobs.remove(id);
+        }
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn finished(jobs: &Jobs<u32>, id: u64) -> Job<u32> {
+        loop {
+            match jobs.get(id) {
+                Some(job) if job.status != JobStatus::Running => return job,
+                _ => tokio::time::sleep(Duration::from_millis(5)).await,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_the_latest_finished_jobs() {
+        let jobs = Arc::new(Jobs::default());
+        let job = jobs.spawn(|| Ok(7));
+        assert_eq!(job.status, JobStatus::Running);
+        let job = finished(&jobs, job.id).await;
+        assert_eq!((job.status, job.result), (JobStatus::Succeeded, Some(7)));
+
+        let failed = jobs.spawn(|| Err(Error::msg("no images")));
+        let failed = finished(&jobs, failed.id).await;
+        assert_eq!(failed.status, JobStatus::Failed);
+        assert_eq!(
+            failed.error.map(|error| error["code"].clone()),
+            Some(serde_json::json!("generic"))
+        );
+
+        for _ in 0..MAX_FINISHED {
+            let id = jobs.spawn(|| Ok(0)).id;
+            let _ = finished(&jobs, id).await;
+        }
+        assert!(jobs.get(job.id).is_none());
+        assert!(jobs.get(failed.id).is_none());
+    }
+}
@@ -1,10 +1,121 @@
-use async_std::task;
-use tide::Request;
-use no_captcha::{CaptchaRegistry, CaptchaChallenge};
-use serde_derive::{Serialize, Deserialize};
+use axum::{
+    body::{self, Body, Bytes},
+    extract::{self, connect_info::IntoMakeServiceWithConnectInfo, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use base64::Engine;
+use no_captcha::{
+    detect::{ChallengeClassifier, Detection},
+    evaluation::Evaluation,
+    feedback::Accuracy,
+    graph_cache::GraphCache,
+    prediction_log::PredictionLog,
+    sanitize::InputLimits,
+    schema::PredictionRecord,
+    self_test::ModelReport,
+    worker::{self, WorkerCommand, WorkerPool},
+    CaptchaChallenge, CaptchaRegistry, Predictor, Priority, RegistryBuilder,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use std::{fs, path::Path};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tower_http::{compression::CompressionLayer, decompression::RequestDecompressionLayer};
 
+mod access;
+mod activation;
+mod admin;
+mod audit;
+mod batch;
+mod cache;
+mod cluster;
+mod config;
 mod errors;
-use errors::Error;
+mod evaluations;
+mod jobs;
+mod quota;
+mod readiness;
+mod reload;
+mod signing;
+#[cfg(feature = "otel")]
+mod telemetry;
+use access::AccessList;
+use activation::Inherited;
+use admin::{AdminStats, Stats};
+use audit::{AuditLog, AuditQuery, AuditRecord};
+use batch::{BatchConfig, Batcher};
+use cache::PredictionCache;
+use cluster::Shard;
+use config::{Config, Listen};
+use errors::{Error, JsonBody};
+use evaluations::EvaluationQuery;
+use jobs::{Job, Jobs};
+use quota::{Quotas, Usage};
+use readiness::Readiness;
+use reload::SharedRegistry;
+use signing::{SignedBy, Verifier};
+
+/// AppState is shared by every handler. Recognition works with any Predictor, while the
+/// feedback and readiness routes need a CaptchaRegistry
+struct AppState<P = CaptchaRegistry> {
+    registry: SharedRegistry<P>,
+    batcher: Batcher<P>,
+    readiness: Readiness<P>,
+    /// admin_token is Config::admin_token
+    admin_token: Option<String>,
+    /// quotas, with [quotas] set, authenticates recognition requests and counts them
+    quotas: Option<Quotas>,
+    /// audit, with [audit] set, records every recognition request
+    audit: Option<Arc<AuditLog>>,
+    /// cache, with [cache] set, answers repeated images
+    cache: Option<PredictionCache>,
+    /// classifier, with [challenge_classifier] set, answers POST /detect
+    classifier: Option<Arc<ChallengeClassifier>>,
+    /// input_limits are Config::input_limits, checked on screenshots before POST /detect
+    /// decodes them
+    input_limits: InputLimits,
+    /// evaluations are the jobs of POST /admin/evaluations
+    evaluations: Arc<Jobs<Vec<Evaluation>>>,
+}
+
+impl<P> AppState<P>
+where
+    P: Predictor + 'static,
+{
+    fn new(
+        registry: SharedRegistry<P>,
+        batch_config: BatchConfig,
+        admin_token: Option<String>,
+        quotas: Option<Quotas>,
+        audit: Option<Arc<AuditLog>>,
+        cache: Option<PredictionCache>,
+        classifier: Option<Arc<ChallengeClassifier>>,
+    ) -> AppState<P> {
+        AppState {
+            input_limits: batch_config.limits,
+            batcher: Batcher::new(registry.clone(), batch_config),
+            registry,
+            readiness: Readiness::default(),
+            admin_token,
+            quotas,
+            audit,
+            cache,
+            classifier,
+            evaluations: Arc::new(Jobs::default()),
+        }
+    }
+}
 
 /// RecaptchaRequest represents the main ways of consuming the API
 /// 1. Base64 Image upload
@@ -14,6 +125,10 @@ struct RecognitionRequest {
 
     #[serde(flatten)]
     image: Image,
+
+    /// priority is Interactive unless given, as batch work forwarded by a cluster node is
+    #[serde(default)]
+    priority: Priority,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -24,36 +139,688 @@ enum Image {
     Bytes(Vec<u8>),
 }
 
-async fn handle_raw_image_upload(mut req: Request<CaptchaRegistry>) -> errors::Response<no_captcha::Prediction> {
-    Ok(match req.body_json::<RecognitionRequest>().await {
-        Ok(RecognitionRequest { image: Image::Base64(data), challenge }) => {
-            match base64::decode(&data) {
-                Ok(decoded_base64) => {
-                    let input_str = unsafe { String::from_utf8_unchecked(decoded_base64) };
-                    match req.state().predict(&challenge, input_str) {
-                        Ok(prediction) => prediction,
-                        Err(_) => return Err(Error::msg("Prediction failed")).into(),
-                    }
-                }
-                Err(_) => return Err(Error::msg("Invalid image Base64")).into(),
-            }
-        },
-        Err(err) => {
-            dbg!(&err);
-            return Err(Error::InvalidRecognitionRequest).into();
-        }
-        _ => unimplemented!(),
-    }).into()
-}
-
-async fn async_main() -> errors::Result<()> {
-    let registry = CaptchaRegistry::load_from_models_dir("../models/")?;
-    let mut app = tide::with_state(registry);
-    app.at("/recognize").post(handle_raw_image_upload);
-    app.listen("127.0.0.1:5000").await?;
-    Ok(())
+/// DetectRequest is a Base64 screenshot of the challenge widget, with the challenge's prompt
+/// text when the client has it
+#[derive(Deserialize, Debug)]
+struct DetectRequest {
+    screenshot: String,
+    #[serde(default)]
+    prompt: Option<String>,
+}
+
+/// FeedbackRequest reports whether an earlier prediction was right. 'image_hash' is the hex
+/// encoded SHA-256 of the raw image bytes
+#[derive(Deserialize, Debug)]
+struct FeedbackRequest {
+    challenge: CaptchaChallenge,
+    image_hash: String,
+    was_correct: bool,
+}
+
+/// IMAGE_CONTENT_TYPES are the types POST /recognize/{challenge} accepts a body declared as.
+/// The image's format is detected from its bytes either way
+const IMAGE_CONTENT_TYPES: [&str; 5] = [
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "application/octet-stream",
+];
+
+async fn handle_raw_image_upload<P>(
+    State(state): State<Arc<AppState<P>>>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedBy>>,
+    JsonBody(request): JsonBody<RecognitionRequest>,
+) -> errors::Response<PredictionRecord>
+where
+    P: Predictor + 'static,
+{
+    let signed = signed.as_ref().map(|Extension(signed)| signed);
+    recognize_audited(&state, &headers, signed, request).await
+}
+
+/// handle_image_body recognizes the image sent as the request body, for the challenge in the
+/// path, sparing clients the JSON and Base64
+async fn handle_image_body<P>(
+    State(state): State<Arc<AppState<P>>>,
+    extract::Path(challenge): extract::Path<String>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedBy>>,
+    body: Bytes,
+) -> errors::Response<PredictionRecord>
+where
+    P: Predictor + 'static,
+{
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase());
+    match content_type {
+        Some(content_type) if IMAGE_CONTENT_TYPES.contains(&content_type.as_str()) => {}
+        _ => return Err(Error::UnsupportedMediaType).into(),
+    }
+    let challenge = match challenge.parse() {
+        Ok(challenge) => challenge,
+        Err(_) => return Err(Error::InvalidRecognitionRequest).into(),
+    };
+    let request = RecognitionRequest {
+        challenge,
+        image: Image::Bytes(body.to_vec()),
+        priority: Priority::default(),
+    };
+    let signed = signed.as_ref().map(|Extension(signed)| signed);
+    recognize_audited(&state, &headers, signed, request).await
+}
+
+/// recognize_audited recognizes 'request', recording it with [audit] set
+async fn recognize_audited<P>(
+    state: &AppState<P>,
+    headers: &HeaderMap,
+    signed: Option<&SignedBy>,
+    request: RecognitionRequest,
+) -> errors::Response<PredictionRecord>
+where
+    P: Predictor + 'static,
+{
+    let started = Instant::now();
+    let challenge = request.challenge.clone();
+    let mut trail = Trail::default();
+    let result = recognize(state, headers, signed, request, &mut trail).await;
+    if let Some(audit) = &state.audit {
+        let record = AuditRecord::new(
+            trail.key,
+            challenge,
+            trail.image_hash,
+            &result,
+            started.elapsed(),
+        );
+        // SQLite blocks, so the insert runs off the runtime's worker threads
+        let audit = Arc::clone(audit);
+        match tokio::task::spawn_blocking(move || audit.record(&record)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => eprintln!(
+                "Failed to write an audit record: {}",
+                errors::describe(&err)
+            ),
+            Err(err) => eprintln!("Failed to write an audit record: {}", err),
+        }
+    }
+    result.into()
+}
+
+/// Trail is what recognize learned about a request before it finished, for its AuditRecord
+#[derive(Debug, Default)]
+struct Trail {
+    key: Option<String>,
+    image_hash: Option<String>,
+}
+
+async fn recognize<P>(
+    state: &AppState<P>,
+    headers: &HeaderMap,
+    signed: Option<&SignedBy>,
+    request: RecognitionRequest,
+    trail: &mut Trail,
+) -> errors::Result<PredictionRecord>
+where
+    P: Predictor + 'static,
+{
+    if let Some(quotas) = &state.quotas {
+        let key = quotas.authenticate(headers, signed)?;
+        trail.key = Some(key.name.clone());
+        // counted before predicting, so a request is paid for whether or not it succeeds
+        let _ = quotas.record(key)?;
+    }
+    let RecognitionRequest {
+        challenge,
+        image,
+        priority,
+    } = request;
+    let image = match image {
+        Image::Base64(data) => base64::engine::general_purpose::STANDARD
+            .decode(&data)
+            .map_err(|_| Error::msg("Invalid image Base64"))?,
+        Image::Bytes(image) => image,
+    };
+    if state.audit.is_some() || state.cache.is_some() {
+        trail.image_hash = Some(no_captcha::image_hash(&image));
+    }
+    let cached = match (&state.cache, &trail.image_hash) {
+        (Some(cache), Some(hash)) => cache.get(&challenge, hash).await,
+        _ => None,
+    };
+    if let Some(record) = cached {
+        return Ok(record);
+    }
+    let prediction = state
+        .batcher
+        .predict_prioritized(challenge.clone(), image, priority)
+        .await?;
+    let record = PredictionRecord::new(challenge, &prediction);
+    if let (Some(cache), Some(hash)) = (&state.cache, &trail.image_hash) {
+        cache.put(&record.challenge, hash, &record).await;
+    }
+    Ok(record)
+}
+
+/// handle_detect answers with the challenge a screenshot shows (see
+/// ChallengeClassifier::detect), or null when neither its banner nor its prompt tells
+async fn handle_detect<P>(
+    State(state): State<Arc<AppState<P>>>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedBy>>,
+    JsonBody(request): JsonBody<DetectRequest>,
+) -> errors::Result<Json<Option<Detection>>>
+where
+    P: Predictor + 'static,
+{
+    if let Some(quotas) = &state.quotas {
+        let signed = signed.as_ref().map(|Extension(signed)| signed);
+        let _ = quotas.record(quotas.authenticate(&headers, signed)?)?;
+    }
+    let classifier = match &state.classifier {
+        Some(classifier) => Arc::clone(classifier),
+        None => return Err(Error::msg("No challenge classifier is configured")),
+    };
+    let screenshot = base64::engine::general_purpose::STANDARD
+        .decode(&request.screenshot)
+        .map_err(|_| Error::msg("Invalid screenshot Base64"))?;
+    // as for recognition, so an oversized screenshot is refused before it is decoded
+    state
+        .input_limits
+        .check(&screenshot)
+        .map_err(|rejection| no_captcha::errors::Error::RejectedImage(0, rejection))?;
+    let detection = tokio::task::spawn_blocking(move || {
+        classifier.detect(&screenshot, request.prompt.as_deref())
+    })
+    .await
+    .map_err(|err| Error::msg(err.to_string()))??;
+    Ok(Json(detection))
+}
+
+/// handle_feedback records whether an earlier prediction was right, for requests carrying the
+/// admin token or, with [quotas] set, an API key. Feedback isn't counted against quotas
+async fn handle_feedback(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedBy>>,
+    JsonBody(request): JsonBody<FeedbackRequest>,
+) -> errors::Response<Accuracy> {
+    let signed = signed.as_ref().map(|Extension(signed)| signed);
+    record_feedback(&state, &headers, signed, request).into()
+}
+
+fn record_feedback(
+    state: &AppState,
+    headers: &HeaderMap,
+    signed: Option<&SignedBy>,
+    request: FeedbackRequest,
+) -> errors::Result<Accuracy> {
+    match (&state.admin_token, &state.quotas) {
+        (Some(token), _) if admin::authorized(headers, token) => {}
+        (_, Some(quotas)) => {
+            let _ = quotas.authenticate(headers, signed)?;
+        }
+        (Some(_), None) => return Err(Error::Unauthorized),
+        (None, None) => {}
+    }
+    // any snake_case name parses as Other, so accepting them would let clients grow the
+    // feedback log by a challenge per request
+    if !request.challenge.is_known() {
+        return Err(Error::InvalidFeedback("unknown challenge"));
+    }
+    if !is_image_hash(&request.image_hash) {
+        return Err(Error::InvalidFeedback(
+            "image_hash must be a lowercase hex encoded SHA-256",
+        ));
+    }
+    let registry = state.registry.current();
+    registry.record_feedback(&request.challenge, &request.image_hash, request.was_correct);
+    Ok(registry.accuracy(&request.challenge))
+}
+
+/// is_image_hash tells whether 'hash' is shaped like no_captcha::image_hash's output
+fn is_image_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+async fn handle_accuracy_report(
+    State(state): State<Arc<AppState>>,
+) -> errors::Response<HashMap<CaptchaChallenge, Accuracy>> {
+    Ok(state.registry.current().accuracy_report()).into()
+}
+
+/// handle_health answers the health checks of load balancers and remote clients
+async fn handle_health() -> &'static str {
+    "ok"
+}
+
+/// handle_errors lists every code an error response can carry, with its status and meaning
+async fn handle_errors() -> Json<Vec<errors::ErrorDescription>> {
+    Json(errors::catalog())
+}
+
+/// handle_ready answers readiness probes with the self test of every model, failing with 503
+/// until all of them pass
+async fn handle_ready(
+    State(state): State<Arc<AppState>>,
+) -> errors::Result<(StatusCode, Json<Vec<ModelReport>>)> {
+    let reports = state.readiness.check(state.registry.current()).await?;
+    let status = if reports.iter().all(ModelReport::passed) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok((status, Json(reports)))
+}
+
+/// handle_admin_stats answers operators with a snapshot of the predictor, its queues and its
+/// models, for requests carrying the admin token
+async fn handle_admin_stats<P>(
+    State(state): State<Arc<AppState<P>>>,
+    headers: HeaderMap,
+) -> errors::Result<Json<AdminStats>>
+where
+    P: Predictor + Stats + 'static,
+{
+    match &state.admin_token {
+        Some(token) if admin::authorized(&headers, token) => {}
+        _ => return Err(Error::Unauthorized),
+    }
+    let mut stats = AdminStats {
+        queue_depths: state.batcher.queue_depths(),
+        ..AdminStats::default()
+    };
+    state.registry.current().fill(&mut stats)?;
+    Ok(Json(stats))
+}
+
+/// handle_usage answers a client with its API key's usage
+async fn handle_usage<P>(
+    State(state): State<Arc<AppState<P>>>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedBy>>,
+) -> errors::Result<Json<Usage>>
+where
+    P: Predictor + 'static,
+{
+    let quotas = state.quotas.as_ref().ok_or(Error::InvalidApiKey)?;
+    let signed = signed.as_ref().map(|Extension(signed)| signed);
+    Ok(Json(quotas.usage(quotas.authenticate(&headers, signed)?)?))
+}
+
+/// handle_admin_usage answers operators with the usage of every API key, for requests
+/// carrying the admin token
+async fn handle_admin_usage<P>(
+    State(state): State<Arc<AppState<P>>>,
+    headers: HeaderMap,
+) -> errors::Result<Json<Vec<Usage>>>
+where
+    P: Predictor + 'static,
+{
+    match &state.admin_token {
+        Some(token) if admin::authorized(&headers, token) => {}
+        _ => return Err(Error::Unauthorized),
+    }
+    match &state.quotas {
+        Some(quotas) => Ok(Json(quotas.usage_report()?)),
+        None => Ok(Json(Vec::new())),
+    }
+}
+
+/// handle_admin_audit answers operators with the audited recognition requests matching the
+/// query, for requests carrying the admin token
+async fn handle_admin_audit<P>(
+    State(state): State<Arc<AppState<P>>>,
+    headers: HeaderMap,
+    Query(query): Query<AuditQuery>,
+) -> errors::Result<Json<Vec<AuditRecord>>>
+where
+    P: Predictor + 'static,
+{
+    match &state.admin_token {
+        Some(token) if admin::authorized(&headers, token) => {}
+        _ => return Err(Error::Unauthorized),
+    }
+    let audit = match &state.audit {
+        Some(audit) => Arc::clone(audit),
+        None => return Ok(Json(Vec::new())),
+    };
+    let records = tokio::task::spawn_blocking(move || audit.query(&query))
+        .await
+        .map_err(|err| Error::msg(err.to_string()))??;
+    Ok(Json(records))
+}
+
+/// handle_admin_evaluation starts a job evaluating the server's models on the labeled images
+/// of the ZIP in the body (see evaluations::evaluate_upload), for requests carrying the admin
+/// token. It answers with the running job, to be polled at GET /admin/evaluations/{id}
+async fn handle_admin_evaluation<P>(
+    State(state): State<Arc<AppState<P>>>,
+    headers: HeaderMap,
+    Query(query): Query<EvaluationQuery>,
+    body: Body,
+) -> errors::Result<(StatusCode, Json<Job<Vec<Evaluation>>>)>
+where
+    P: Predictor + 'static,
+{
+    match &state.admin_token {
+        Some(token) if admin::authorized(&headers, token) => {}
+        _ => return Err(Error::Unauthorized),
+    }
+    let zip = body::to_bytes(body, evaluations::MAX_UPLOAD_BYTES)
+        .await
+        .map_err(|_| Error::UploadTooLarge)?;
+    let predictor = state.registry.current();
+    let job = state
+        .evaluations
+        .spawn(move || evaluations::evaluate_upload(&*predictor, &zip, &query));
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// handle_admin_evaluation_job answers with an evaluation job, its report once it has
+/// succeeded, for requests carrying the admin token
+async fn handle_admin_evaluation_job<P>(
+    State(state): State<Arc<AppState<P>>>,
+    extract::Path(id): extract::Path<String>,
+    headers: HeaderMap,
+) -> errors::Result<Json<Job<Vec<Evaluation>>>>
+where
+    P: Predictor + 'static,
+{
+    match &state.admin_token {
+        Some(token) if admin::authorized(&headers, token) => {}
+        _ => return Err(Error::Unauthorized),
+    }
+    id.parse()
+        .ok()
+        .and_then(|id| state.evaluations.get(id))
+        .map(Json)
+        .ok_or(Error::UnknownJob)
+}
+
+/// recognition_routes serves predictions from any Predictor that can report its stats
+fn recognition_routes<P>() -> Router<Arc<AppState<P>>>
+where
+    P: Predictor + Stats + 'static,
+{
+    Router::new()
+        .route("/recognize", post(handle_raw_image_upload::<P>))
+        .route("/recognize/{challenge}", post(handle_image_body::<P>))
+        .route("/detect", post(handle_detect::<P>))
+        .route("/health", get(handle_health))
+        .route("/errors", get(handle_errors))
+        .route("/usage", get(handle_usage::<P>))
+        .route("/admin/stats", get(handle_admin_stats::<P>))
+        .route("/admin/usage", get(handle_admin_usage::<P>))
+        .route("/admin/audit", get(handle_admin_audit::<P>))
+        .route("/admin/evaluations", post(handle_admin_evaluation::<P>))
+        .route(
+            "/admin/evaluations/{id}",
+            get(handle_admin_evaluation_job::<P>),
+        )
 }
 
 fn main() -> errors::Result<()> {
-    task::block_on(async_main())
+    // taken before the runtime starts its threads, as it clears LISTEN_* from the environment,
+    // and before any worker is spawned, so none of them inherits the socket
+    let inherited = activation::inherited_listener()?;
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(inherited))
+}
+
+async fn run(inherited: Option<Inherited>) -> errors::Result<()> {
+    let config = Config::load()?;
+    #[cfg(feature = "otel")]
+    let _telemetry = telemetry::init(if config::is_worker() {
+        "worker"
+    } else {
+        "server"
+    })?;
+    if config::is_worker() {
+        // stdout belongs to the worker protocol from here on
+        let registry = registry_builder(&config).load_from_models_dir(&config.models_dir)?;
+        return Ok(worker::serve_worker(&registry)?);
+    }
+    let quotas = config.quotas.clone().map(Quotas::open).transpose()?;
+    let audit = match &config.audit {
+        Some(audit) => {
+            let audit = Arc::new(AuditLog::open(audit)?);
+            audit::purge_periodically(Arc::clone(&audit));
+            Some(audit)
+        }
+        None => None,
+    };
+    let cache = config
+        .cache
+        .as_ref()
+        .map(PredictionCache::new)
+        .transpose()?;
+    let classifier = match &config.challenge_classifier {
+        Some(classifier) => Some(Arc::new(ChallengeClassifier::load(classifier)?)),
+        None => None,
+    };
+    let mut app = match (&config.workers, &config.cluster) {
+        (Some(_), Some(_)) => {
+            return Err(Error::msg("[workers] and [cluster] can't be used together"));
+        }
+        (Some(workers), None) => {
+            let command = WorkerCommand {
+                program: std::env::current_exe()?,
+                args: config::worker_args(),
+            };
+            let pool = WorkerPool::spawn(command, workers.count)?;
+            println!("Started {} inference workers", pool.size());
+            recognition_routes().with_state(Arc::new(AppState::new(
+                SharedRegistry::new(pool),
+                BatchConfig {
+                    limits: config.input_limits,
+                    ..BatchConfig::default()
+                },
+                config.admin_token.clone(),
+                quotas,
+                audit,
+                cache,
+                classifier,
+            )))
+        }
+        (None, Some(cluster)) => {
+            let local = load_managed(
+                &config,
+                registry_builder(&config).only_challenges(cluster.local_challenges()?),
+            )?;
+            println!("Serving as node {} of a cluster", cluster.node);
+            let proxy = cluster
+                .proxy
+                .as_ref()
+                .or(config.proxy.as_ref())
+                .cloned()
+                .unwrap_or_default();
+            let cluster = cluster.clone();
+            // the peers' clients are blocking, and can't be made on the runtime's threads
+            let shard = tokio::task::spawn_blocking(move || Shard::new(local, &cluster, &proxy))
+                .await
+                .map_err(|err| Error::msg(err.to_string()))??;
+            recognition_routes().with_state(Arc::new(AppState::new(
+                SharedRegistry::new(shard),
+                BatchConfig {
+                    limits: config.input_limits,
+                    ..BatchConfig::default()
+                },
+                config.admin_token.clone(),
+                quotas,
+                audit,
+                cache,
+                classifier,
+            )))
+        }
+        (None, None) => {
+            let registry = load_managed(&config, registry_builder(&config))?;
+            let state = Arc::new(AppState::new(
+                registry,
+                BatchConfig {
+                    limits: config.input_limits,
+                    ..BatchConfig::default()
+                },
+                config.admin_token.clone(),
+                quotas,
+                audit,
+                cache,
+                classifier,
+            ));
+            // tested before listening, so broken models are reported before any traffic
+            let reports = state.readiness.check(state.registry.current()).await?;
+            let passed = reports.iter().filter(|report| report.passed()).count();
+            println!(
+                "{} of {} models passed their self test",
+                passed,
+                reports.len()
+            );
+            recognition_routes()
+                .route(
+                    "/feedback",
+                    get(handle_accuracy_report).post(handle_feedback),
+                )
+                .route("/ready", get(handle_ready))
+                .with_state(state)
+        }
+    };
+    if let Some(quotas) = &config.quotas {
+        let verifier = Verifier::new(
+            &quotas.keys,
+            Duration::from_secs(quotas.signature_window_secs),
+        )
+        .body_limit("/admin/evaluations", evaluations::MAX_UPLOAD_BYTES);
+        // inside decompression, as bodies are signed uncompressed
+        app = app.layer(middleware::from_fn_with_state(
+            Arc::new(verifier),
+            signing::verify,
+        ));
+    }
+    app = app
+        .layer(RequestDecompressionLayer::new())
+        .layer(CompressionLayer::new());
+    if let Some(access) = &config.access {
+        let list = Arc::new(AccessList::new(access)?);
+        access::reload_periodically(
+            Arc::clone(&list),
+            Duration::from_secs(access.reload_secs.max(1)),
+        );
+        // the outermost layer, so refused requests are never decompressed or parsed
+        app = app.layer(middleware::from_fn_with_state(list, access::enforce));
+    }
+    match inherited {
+        Some(Inherited::Tcp(listener)) => {
+            let listener = TcpListener::from_std(listener)?;
+            println!("Server is listening on: http://{}", listener.local_addr()?);
+            axum::serve(listener, with_peer_address(app)).await?;
+        }
+        #[cfg(unix)]
+        Some(Inherited::Unix(listener)) => {
+            println!("Server is listening on a socket from systemd");
+            axum::serve(UnixListener::from_std(listener)?, app).await?;
+        }
+        None => serve_configured(config.listen, app).await?,
+    }
+    Ok(())
+}
+
+/// registry_builder configures a registry as the server's config asks, for it to load
+fn registry_builder(config: &Config) -> RegistryBuilder {
+    let builder = CaptchaRegistry::builder()
+        .input_limits(config.input_limits)
+        .resize(config.resize)
+        .load_limits(config.loading)
+        .model_discovery(config.discovery)
+        .device_placement(config.devices.clone())
+        .concurrency_limits(config.concurrency.clone())
+        .tensorflow_log_level(config.tensorflow_log_level);
+    match &config.graph_cache_dir {
+        Some(dir) => builder.graph_cache(GraphCache::new(dir)),
+        None => builder,
+    }
+}
+
+/// load_managed loads the registry 'builder' describes from models_dir with the config's
+/// idle_ttl_secs and prediction_log, and keeps it current: reloaded on SIGHUP, its idle models
+/// evicted and, with update_check_secs set, its changed models refreshed
+fn load_managed(config: &Config, mut builder: RegistryBuilder) -> errors::Result<SharedRegistry> {
+    if let Some(secs) = config.idle_ttl_secs {
+        builder = builder.idle_ttl(Duration::from_secs(secs));
+    }
+    if let Some(log) = config.prediction_log.clone() {
+        builder = builder.prediction_log(PredictionLog::new(log));
+    }
+    let registry = SharedRegistry::new(builder.load_from_models_dir(&config.models_dir)?);
+    reload::reload_on_sighup(registry.clone(), config.models_dir.clone(), config.reload)?;
+    reload::evict_idle_models(registry.clone());
+    if let Some(secs) = config.update_check_secs {
+        reload::refresh_on_update(registry.clone(), Duration::from_secs(secs.max(1)));
+    }
+    Ok(registry)
+}
+
+/// serve_configured binds the socket [listen] describes and serves 'app' on it
+async fn serve_configured(listen: Listen, app: Router) -> errors::Result<()> {
+    match listen {
+        Listen::Tcp { address } => {
+            let listener = TcpListener::bind(&address).await?;
+            println!("Server is listening on: http://{}", listener.local_addr()?);
+            axum::serve(listener, with_peer_address(app)).await?;
+        }
+        #[cfg(unix)]
+        Listen::Unix { path, mode } => {
+            let listener = bind_unix(&path, mode)?;
+            println!("Server is listening on: unix:{}", path.display());
+            axum::serve(listener, app).await?;
+        }
+        #[cfg(not(unix))]
+        Listen::Unix { .. } => {
+            return Err(Error::msg("Unix domain sockets are only supported on Unix"))
+        }
+    }
+    Ok(())
+}
+
+/// with_peer_address serves 'app' with each TCP client's address, for [access] to check
+fn with_peer_address(app: Router) -> IntoMakeServiceWithConnectInfo<Router, SocketAddr> {
+    app.into_make_service_with_connect_info::<SocketAddr>()
+}
+
+/// bind_unix binds a Unix domain socket, replacing any stale socket file left behind by a
+/// previous run, and applies the configured permission bits. Anything at 'path' that isn't a
+/// socket is left alone, and fails the bind. The socket is bound inside a directory only this
+/// user can enter, given its mode there and only then moved to 'path', so it is never
+/// reachable with looser permissions than configured
+#[cfg(unix)]
+fn bind_unix(path: &Path, mode: u32) -> std::io::Result<UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} exists and isn't a socket", path.display()),
+            ))
+        }
+        Err(_) => {}
+    }
+    let staging = path.with_file_name(format!(".nocap-bind-{}", std::process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join("sock");
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(mode))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    if bound.is_err() {
+        let _ = fs::remove_file(&staged);
+    }
+    let _ = fs::remove_dir(&staging);
+    bound
 }
@@ -0,0 +1,285 @@
+use crate::{
+    admin,
+    errors::{self, Error},
+    signing::SignedBy,
+};
+use axum::http::HeaderMap;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    convert::TryFrom,
+    path::PathBuf,
+    sync::{Mutex, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// API_KEY_HEADER is the header a request presents its API key in
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// QuotaConfig is the [quotas] section. Request counts are kept in a sled database at 'path',
+/// so they survive restarts
+#[derive(Debug, Clone, Deserialize)]
+pub struct QuotaConfig {
+    pub path: PathBuf,
+    pub keys: Vec<ApiKey>,
+    /// signature_window_secs is how far a signed request's timestamp may be from the server's
+    /// clock, either way
+    #[serde(default = "default_signature_window_secs")]
+    pub signature_window_secs: u64,
+}
+
+fn default_signature_window_secs() -> u64 {
+    5 * 60
+}
+
+/// ApiKey is one client's key. Usage is reported under 'name', so the key itself is never sent
+/// back. A client presents 'key' in X-API-Key, or signs its requests with 'secret' instead, as
+/// signing describes. Each key must be distinct, and a quota left out is unlimited
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiKey {
+    pub name: String,
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default)]
+    pub daily: Option<u64>,
+    #[serde(default)]
+    pub monthly: Option<u64>,
+}
+
+/// Usage is a key's requests in the current day and month, both in UTC
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Usage {
+    pub name: String,
+    /// day is the current day, as 2024-01-31
+    pub day: String,
+    pub daily: u64,
+    pub daily_limit: Option<u64>,
+    /// month is the current month, as 2024-01
+    pub month: String,
+    pub monthly: u64,
+    pub monthly_limit: Option<u64>,
+}
+
+/// Quotas authenticates API keys and counts their requests. The counts of past days and months
+/// are kept, for accounting
+pub struct Quotas {
+    keys: Vec<ApiKey>,
+    db: sled::Db,
+    /// counting makes checking a key's quotas and counting its request one step
+    counting: Mutex<()>,
+}
+
+impl Quotas {
+    pub fn open(config: QuotaConfig) -> errors::Result<Quotas> {
+        let db = sled::open(&config.path).map_err(Error::UsageStore)?;
+        Ok(Quotas::new(config.keys, db))
+    }
+
+    fn new(keys: Vec<ApiKey>, db: sled::Db) -> Quotas {
+        Quotas {
+            keys,
+            db,
+            counting: Mutex::new(()),
+        }
+    }
+
+    /// authenticate finds the key a request was signed with, or else the key presented in the
+    /// X-API-Key header
+    pub fn authenticate(
+        &self,
+        headers: &HeaderMap,
+        signed: Option<&SignedBy>,
+    ) -> errors::Result<&ApiKey> {
+        if let Some(SignedBy(name)) = signed {
+            return self
+                .keys
+                .iter()
+                .find(|key| &key.name == name)
+                .ok_or(Error::InvalidApiKey);
+        }
+        let presented = headers
+            .get(API_KEY_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .ok_or(Error::InvalidApiKey)?;
+        // every key is compared, so the time taken doesn't tell which one nearly matched
+        self.keys
+            .iter()
+            .filter(|key| {
+                key.key
+                    .as_ref()
+                    .is_some_and(|key| admin::same_token(presented, key))
+            })
+            .last()
+            .ok_or(Error::InvalidApiKey)
+    }
+
+    /// record counts a request made with 'key' and returns the key's usage including it. A
+    /// request over either quota fails with Error::QuotaExceeded and isn't counted
+    pub fn record(&self, key: &ApiKey) -> errors::Result<Usage> {
+        self.record_at(key, now())
+    }
+
+    pub fn usage(&self, key: &ApiKey) -> errors::Result<Usage> {
+        self.usage_at(key, now())
+    }
+
+    /// usage_report is the usage of every key, in the order they are configured
+    pub fn usage_report(&self) -> errors::Result<Vec<Usage>> {
+        let now = now();
+        self.keys
+            .iter()
+            .map(|key| self.usage_at(key, now))
+            .collect()
+    }
+
+    fn record_at(&self, key: &ApiKey, now: u64) -> errors::Result<Usage> {
+        let _counting = self.counting.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut usage = self.usage_at(key, now)?;
+        if key.daily.is_some_and(|limit| usage.daily >= limit) {
+            return Err(Error::QuotaExceeded("daily"));
+        }
+        if key.monthly.is_some_and(|limit| usage.monthly >= limit) {
+            return Err(Error::QuotaExceeded("monthly"));
+        }
+        usage.daily += 1;
+        usage.monthly += 1;
+        self.store(&key.name, &usage.day, usage.daily)?;
+        self.store(&key.name, &usage.month, usage.monthly)?;
+        Ok(usage)
+    }
+
+    fn usage_at(&self, key: &ApiKey, now: u64) -> errors::Result<Usage> {
+        let (year, month, day) = civil_date(now / SECONDS_PER_DAY);
+        let day = format!("{:04}-{:02}-{:02}", year, month, day);
+        let month = day[..7].to_string();
+        Ok(Usage {
+            name: key.name.clone(),
+            daily: self.count(&key.name, &day)?,
+            daily_limit: key.daily,
+            monthly: self.count(&key.name, &month)?,
+            monthly_limit: key.monthly,
+            day,
+            month,
+        })
+    }
+
+    fn count(&self, name: &str, period: &str) -> errors::Result<u64> {
+        let stored = self
+            .db
+            .get(counter(name, period))
+            .map_err(Error::UsageStore)?;
+        Ok(stored
+            .and_then(|bytes| <[u8; 8]>::try_from(bytes.as_ref()).ok())
+            .map_or(0, u64::from_be_bytes))
+    }
+
+    fn store(&self, name: &str, period: &str, count: u64) -> errors::Result<()> {
+        let _ = self
+            .db
+            .insert(counter(name, period), &count.to_be_bytes()[..])
+            .map_err(Error::UsageStore)?;
+        Ok(())
+    }
+}
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// counter is the database key of a name's count for a day or a month. A NUL can't be part of
+/// a TOML key name, so names can't run into periods
+fn counter(name: &str, period: &str) -> String {
+    format!("{}\0{}", name, period)
+}
+
+/// civil_date is the (year, month, day) of the day 'days' after 1970-01-01, in the proleptic
+/// Gregorian calendar (Howard Hinnant's civil_from_days)
+fn civil_date(days: u64) -> (u64, u64, u64) {
+    // shifted so eras of 400 years start on March 1st, leaving the leap day at their end
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn dates_days_since_the_epoch() {
+        assert_eq!(civil_date(0), (1970, 1, 1));
+        assert_eq!(civil_date(11_016), (2000, 2, 29));
+        assert_eq!(civil_date(19_417), (2023, 3, 1));
+        assert_eq!(civil_date(20_376), (2025, 10, 15));
+    }
+
+    #[test]
+    fn enforces_daily_and_monthly_quotas() -> errors::Result<()> {
+        let db = sled::Config::new()
+            .temporary(true)
+            .open()
+            .map_err(Error::UsageStore)?;
+        let key = ApiKey {
+            name: "search".to_string(),
+            key: Some("secret".to_string()),
+            secret: None,
+            daily: Some(2),
+            monthly: Some(3),
+        };
+        let quotas = Quotas::new(vec![key.clone()], db);
+
+        let mut headers = HeaderMap::new();
+        assert!(quotas.authenticate(&headers, None).is_err());
+        let signed = SignedBy("search".to_string());
+        assert_eq!(quotas.authenticate(&headers, Some(&signed))?.name, "search");
+        let _ = headers.insert(API_KEY_HEADER, HeaderValue::from_static("secret"));
+        assert_eq!(quotas.authenticate(&headers, None)?.name, "search");
+
+        // 2025-10-15 and 2025-10-16, then 2025-11-01
+        let (first, second, next_month) = (
+            20_376 * SECONDS_PER_DAY,
+            20_377 * SECONDS_PER_DAY,
+            20_393 * SECONDS_PER_DAY,
+        );
+        assert_eq!(quotas.record_at(&key, first)?.daily, 1);
+        assert_eq!(quotas.record_at(&key, first + 60)?.daily, 2);
+        assert!(matches!(
+            quotas.record_at(&key, first + 120),
+            Err(Error::QuotaExceeded("daily"))
+        ));
+        let usage = quotas.record_at(&key, second)?;
+        assert_eq!(
+            (usage.day.as_str(), usage.daily, usage.monthly),
+            ("2025-10-16", 1, 3)
+        );
+        assert!(matches!(
+            quotas.record_at(&key, second + 60),
+            Err(Error::QuotaExceeded("monthly"))
+        ));
+        let usage = quotas.record_at(&key, next_month)?;
+        assert_eq!(
+            (usage.month.as_str(), usage.daily, usage.monthly),
+            ("2025-11", 1, 1)
+        );
+        Ok(())
+    }
+}
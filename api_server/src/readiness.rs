@@ -0,0 +1,48 @@
+use crate::errors;
+use no_captcha::{self_test::ModelReport, CaptchaRegistry};
+use std::sync::{Arc, Weak};
+use tokio::sync::Mutex;
+
+/// Readiness remembers the self test of the registry last checked, so probes only run the
+/// models again once a reload has swapped in a new registry. The registry is held weakly, so
+/// one that was reloaded away is freed rather than kept loaded for the next probe. The Weak
+/// keeps its allocation, so a later registry can't reuse the address and pass for it
+#[derive(Debug)]
+pub struct Readiness<P = CaptchaRegistry> {
+    tested: Mutex<Option<(Weak<P>, Vec<ModelReport>)>>,
+}
+
+impl<P> Default for Readiness<P> {
+    fn default() -> Readiness<P> {
+        Readiness {
+            tested: Mutex::new(None),
+        }
+    }
+}
+
+impl Readiness {
+    /// check returns the self test reports of 'registry', testing it unless it was the last
+    /// registry checked. Concurrent probes wait for the one test rather than each running it
+    pub async fn check(&self, registry: Arc<CaptchaRegistry>) -> errors::Result<Vec<ModelReport>> {
+        let mut tested = self.tested.lock().await;
+        if let Some((last, reports)) = &*tested {
+            if Weak::ptr_eq(last, &Arc::downgrade(&registry)) {
+                return Ok(reports.clone());
+            }
+        }
+        let testing = Arc::clone(&registry);
+        let reports = tokio::task::spawn_blocking(move || testing.self_test())
+            .await
+            .map_err(|err| errors::Error::msg(err.to_string()))??;
+        for report in reports.iter().filter(|report| !report.passed()) {
+            println!(
+                "Self test failed for {} ({}): {}",
+                report.challenge,
+                report.path.display(),
+                report.failure.as_deref().unwrap_or_default()
+            );
+        }
+        *tested = Some((Arc::downgrade(&registry), reports.clone()));
+        Ok(reports)
+    }
+}
@@ -0,0 +1,129 @@
+use crate::{config::ReloadMode, errors};
+use no_captcha::{CaptchaRegistry, Predictor};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, PoisonError, RwLock},
+    time::Duration,
+};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// SharedRegistry is the predictor currently serving traffic. Reloads build a complete registry
+/// off to the side and swap it in, so in-flight predictions keep the registry they started with
+#[derive(Debug)]
+pub struct SharedRegistry<P = CaptchaRegistry>(Arc<RwLock<Arc<P>>>);
+
+impl<P> Clone for SharedRegistry<P> {
+    fn clone(&self) -> SharedRegistry<P> {
+        SharedRegistry(Arc::clone(&self.0))
+    }
+}
+
+impl<P> SharedRegistry<P>
+where
+    P: Predictor,
+{
+    pub fn new(predictor: P) -> SharedRegistry<P> {
+        SharedRegistry(Arc::new(RwLock::new(Arc::new(predictor))))
+    }
+
+    pub fn current(&self) -> Arc<P> {
+        Arc::clone(&self.0.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn replace(&self, predictor: P) {
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(predictor);
+    }
+}
+
+impl SharedRegistry<CaptchaRegistry> {
+    /// reload rebuilds the registry from 'models_dir'. On failure the current registry is kept
+    pub fn reload(&self, models_dir: &Path, mode: ReloadMode) {
+        let reloaded = match mode {
+            ReloadMode::Full => self.current().reload(models_dir),
+            ReloadMode::Changed => self.current().reload_changed(models_dir),
+        };
+        match reloaded {
+            Ok(registry) => {
+                self.replace(registry);
+                println!("Reloaded models from {}", models_dir.display());
+            }
+            Err(err) => eprintln!(
+                "Failed to reload models from {}: {}",
+                models_dir.display(),
+                errors::describe(&err)
+            ),
+        }
+    }
+
+    /// refresh_updated swaps in a registry with the models changed on disk since they were
+    /// loaded (see CaptchaRegistry::check_for_updates), if there are any
+    pub fn refresh_updated(&self) {
+        let current = self.current();
+        let refreshed = match current.check_for_updates() {
+            Ok(updated) if updated.is_empty() => return,
+            Ok(updated) => current.refresh().map(|registry| (registry, updated)),
+            Err(err) => Err(err),
+        };
+        match refreshed {
+            Ok((registry, updated)) => {
+                self.replace(registry);
+                println!("Refreshed models for {:?}", updated);
+            }
+            Err(err) => eprintln!("Failed to refresh models: {}", errors::describe(&err)),
+        }
+    }
+}
+
+/// reload_on_sighup spawns a task that reloads 'registry' every time the process receives
+/// SIGHUP. Loading runs on the blocking pool so it never stalls request handling
+pub fn reload_on_sighup(
+    registry: SharedRegistry,
+    models_dir: PathBuf,
+    mode: ReloadMode,
+) -> std::io::Result<()> {
+    let mut hangups = signal(SignalKind::hangup())?;
+    let _ = tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            let registry = registry.clone();
+            let models_dir = models_dir.clone();
+            let _ = tokio::task::spawn_blocking(move || registry.reload(&models_dir, mode)).await;
+        }
+    });
+    Ok(())
+}
+
+/// evict_idle_models spawns a task that periodically unloads the models of 'registry' that
+/// have outlived the registry's idle_ttl. Nothing is spawned when no idle_ttl is set
+pub fn evict_idle_models(registry: SharedRegistry) {
+    let ttl = match registry.current().idle_ttl() {
+        Some(ttl) => ttl,
+        None => return,
+    };
+    // checking at half the TTL keeps a model from outliving it by more than half again
+    let mut ticks = tokio::time::interval((ttl / 2).max(Duration::from_secs(1)));
+    let _ = tokio::spawn(async move {
+        loop {
+            let _ = ticks.tick().await;
+            let registry = registry.current();
+            // dropping a session frees its memory synchronously, so keep it off the runtime
+            let evicted = tokio::task::spawn_blocking(move || registry.evict_idle())
+                .await
+                .unwrap_or(0);
+            if evicted > 0 {
+                println!("Evicted {} idle models", evicted);
+            }
+        }
+    });
+}
+
+/// refresh_on_update spawns a task that calls refresh_updated on 'registry' every 'interval'
+pub fn refresh_on_update(registry: SharedRegistry, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    let _ = tokio::spawn(async move {
+        loop {
+            let _ = ticks.tick().await;
+            let registry = registry.clone();
+            let _ = tokio::task::spawn_blocking(move || registry.refresh_updated()).await;
+        }
+    });
+}
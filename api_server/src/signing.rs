@@ -0,0 +1,281 @@
+use crate::{
+    admin,
+    errors::{self, Error},
+    quota::ApiKey,
+};
+use axum::{
+    body::{self, Body},
+    extract::{Request, State},
+    http::{HeaderMap, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// KEY_HEADER, TIMESTAMP_HEADER and SIGNATURE_HEADER make up a signed request: the name of the
+/// key it's signed with, the Unix time in seconds it was signed at, and the hex encoded
+/// HMAC-SHA256, under the key's secret, of the timestamp, a '.', the method, a space, the path
+/// with its query, a newline and the uncompressed body. Covering the method and path keeps a
+/// signature from being replayed against another route
+pub const KEY_HEADER: &str = "x-nocap-key";
+pub const TIMESTAMP_HEADER: &str = "x-nocap-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-nocap-signature";
+
+/// DEFAULT_BODY_LIMIT is axum's default body limit, which the bodies of routes without a limit
+/// of their own (see Verifier::body_limit) are held to
+const DEFAULT_BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+/// SignedBy is the name of the key a request was verified to be signed with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedBy(pub String);
+
+/// Verifier checks signed requests against the secrets of the keys that have one. A request
+/// is only accepted within 'window' of its timestamp, and only once
+pub struct Verifier {
+    secrets: HashMap<String, Vec<u8>>,
+    window: Duration,
+    seen: Mutex<Seen>,
+    body_limits: HashMap<&'static str, usize>,
+}
+
+impl Verifier {
+    pub fn new(keys: &[ApiKey], window: Duration) -> Verifier {
+        Verifier {
+            secrets: keys
+                .iter()
+                .filter_map(|key| Some((key.name.clone(), key.secret.clone()?.into_bytes())))
+                .collect(),
+            window,
+            seen: Mutex::new(Seen::default()),
+            body_limits: HashMap::new(),
+        }
+    }
+
+    /// body_limit reads signed bodies sent to 'path' up to 'limit' bytes rather than
+    /// DEFAULT_BODY_LIMIT, for routes that take larger bodies themselves
+    pub fn body_limit(mut self, path: &'static str, limit: usize) -> Verifier {
+        let _ = self.body_limits.insert(path, limit);
+        self
+    }
+
+    fn body_limit_for(&self, path: &str) -> usize {
+        self.body_limits
+            .get(path)
+            .copied()
+            .unwrap_or(DEFAULT_BODY_LIMIT)
+    }
+
+    /// verify checks the signature 'headers' carry for a request to 'target', its method and
+    /// path with query, with 'body', and returns the key's name
+    pub fn verify(
+        &self,
+        headers: &HeaderMap,
+        target: (&Method, &str),
+        body: &[u8],
+    ) -> errors::Result<String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.verify_at(headers, target, body, now)
+    }
+
+    fn verify_at(
+        &self,
+        headers: &HeaderMap,
+        target: (&Method, &str),
+        body: &[u8],
+        now: u64,
+    ) -> errors::Result<String> {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .ok_or(Error::InvalidSignature("missing signature header"))
+        };
+        let name = header(KEY_HEADER)?;
+        let timestamp = header(TIMESTAMP_HEADER)?;
+        let signature = header(SIGNATURE_HEADER)?.to_ascii_lowercase();
+        let signed_at = timestamp
+            .parse::<u64>()
+            .map_err(|_| Error::InvalidSignature("timestamp isn't a Unix time"))?;
+        if signed_at.abs_diff(now) > self.window.as_secs() {
+            return Err(Error::InvalidSignature("timestamp is outside the window"));
+        }
+        let secret = self
+            .secrets
+            .get(name)
+            .ok_or(Error::InvalidSignature("no such key"))?;
+        if !admin::same_token(&signature, &sign(secret, timestamp, target, body)) {
+            return Err(Error::InvalidSignature("signature doesn't match"));
+        }
+        // remembered only once verified, so forged requests can't fill the cache
+        let first_use = self
+            .seen
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(signature, signed_at + self.window.as_secs(), now);
+        if !first_use {
+            return Err(Error::InvalidSignature("request was replayed"));
+        }
+        Ok(name.to_string())
+    }
+}
+
+/// sign is the hex encoded HMAC-SHA256 under 'secret' of the string described at KEY_HEADER.
+/// Neither a method nor a path can hold a space or a newline, so no two requests sign alike
+fn sign(secret: &[u8], timestamp: &str, (method, path): (&Method, &str), body: &[u8]) -> String {
+    // HMAC takes keys of any length, so this never fails
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC key");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(method.as_str().as_bytes());
+    mac.update(b" ");
+    mac.update(path.as_bytes());
+    mac.update(b"\n");
+    mac.update(body);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Seen are the signatures accepted within the window, so none is accepted twice
+#[derive(Debug, Default)]
+struct Seen {
+    signatures: HashSet<String>,
+    /// expiries are in the order signatures were accepted, for forgetting them
+    expiries: VecDeque<(u64, String)>,
+}
+
+impl Seen {
+    /// insert remembers 'signature' until 'expires', reporting whether it was new. Signatures
+    /// outside the window are refused by their timestamp, so they needn't be remembered
+    fn insert(&mut self, signature: String, expires: u64, now: u64) -> bool {
+        while let Some((expiry, _)) = self.expiries.front() {
+            if *expiry >= now {
+                break;
+            }
+            if let Some((_, expired)) = self.expiries.pop_front() {
+                let _ = self.signatures.remove(&expired);
+            }
+        }
+        if !self.signatures.insert(signature.clone()) {
+            return false;
+        }
+        self.expiries.push_back((expires, signature));
+        true
+    }
+}
+
+/// verify checks the signature of requests carrying one, refusing them with
+/// Error::InvalidSignature unless it holds, and marks those that pass with SignedBy. Requests
+/// without a signature pass untouched, to be authenticated by their API key
+pub async fn verify(
+    State(verifier): State<Arc<Verifier>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !request.headers().contains_key(SIGNATURE_HEADER) {
+        return next.run(request).await;
+    }
+    let (mut parts, body) = request.into_parts();
+    let limit = verifier.body_limit_for(parts.uri.path());
+    let bytes = match body::to_bytes(body, limit).await {
+        Ok(bytes) => bytes,
+        Err(_) => return Error::InvalidRecognitionRequest.into_response(),
+    };
+    let target = parts
+        .uri
+        .path_and_query()
+        .map_or(parts.uri.path(), |target| target.as_str());
+    match verifier.verify(&parts.headers, (&parts.method, target), &bytes) {
+        Ok(name) => {
+            let _ = parts.extensions.insert(SignedBy(name));
+            next.run(Request::from_parts(parts, Body::from(bytes)))
+                .await
+        }
+        Err(err) => err.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn signed(name: &'static str, secret: &str, timestamp: u64, body: &[u8]) -> HeaderMap {
+        let target = (&Method::POST, "/recognize");
+        let signature = sign(secret.as_bytes(), &timestamp.to_string(), target, body);
+        let mut headers = HeaderMap::new();
+        let _ = headers.insert(KEY_HEADER, HeaderValue::from_static(name));
+        let _ = headers.insert(TIMESTAMP_HEADER, HeaderValue::from(timestamp));
+        let _ = headers.insert(SIGNATURE_HEADER, HeaderValue::from_str(&signature).unwrap());
+        headers
+    }
+
+    #[test]
+    fn verifies_signatures_once() -> errors::Result<()> {
+        let key = ApiKey {
+            name: "search".to_string(),
+            key: None,
+            secret: Some("shh".to_string()),
+            daily: None,
+            monthly: None,
+        };
+        let verifier = Verifier::new(&[key], Duration::from_secs(300));
+        let body = br#"{"challenge":"bus"}"#;
+        let now = 1_700_000_000;
+        let target = (&Method::POST, "/recognize");
+
+        let headers = signed("search", "shh", now - 10, body);
+        assert_eq!(verifier.verify_at(&headers, target, body, now)?, "search");
+        assert!(
+            verifier.verify_at(&headers, target, body, now).is_err(),
+            "replayed"
+        );
+        assert!(verifier
+            .verify_at(&signed("search", "shh", now, body), target, b"{}", now)
+            .is_err());
+        assert!(verifier
+            .verify_at(&signed("search", "wrong", now, body), target, body, now)
+            .is_err());
+        assert!(verifier
+            .verify_at(&signed("search", "shh", now - 301, body), target, body, now)
+            .is_err());
+        assert!(verifier
+            .verify_at(&signed("other", "shh", now, body), target, body, now)
+            .is_err());
+        assert!(verifier
+            .verify_at(&HeaderMap::new(), target, body, now)
+            .is_err());
+
+        // the signature covers the method and the path
+        let headers = signed("search", "shh", now - 5, body);
+        assert!(verifier
+            .verify_at(&headers, (&Method::POST, "/recognize/bus"), body, now)
+            .is_err());
+        assert!(verifier
+            .verify_at(&headers, (&Method::PUT, "/recognize"), body, now)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reads_bodies_up_to_the_route_limit() {
+        let verifier = Verifier::new(&[], Duration::from_secs(300))
+            .body_limit("/admin/evaluations", 256 * 1024 * 1024);
+        assert_eq!(
+            verifier.body_limit_for("/admin/evaluations"),
+            256 * 1024 * 1024
+        );
+        assert_eq!(verifier.body_limit_for("/recognize"), DEFAULT_BODY_LIMIT);
+    }
+}
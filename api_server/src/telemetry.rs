@@ -0,0 +1,56 @@
+use crate::errors::{self, Error};
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::{MetricExporter, SpanExporter};
+use opentelemetry_sdk::{
+    metrics::{PeriodicReader, SdkMeterProvider},
+    runtime,
+    trace::TracerProvider,
+    Resource,
+};
+
+/// Telemetry exports the spans and metrics of the server and of no_captcha over OTLP, to the
+/// collector named by the standard OTEL_EXPORTER_OTLP_* environment variables (localhost:4317
+/// by default). Dropping it flushes what hasn't been exported yet
+pub struct Telemetry {
+    tracer_provider: TracerProvider,
+    meter_provider: SdkMeterProvider,
+}
+
+/// init installs the global tracer and meter providers. 'role' tells the server apart from
+/// its inference workers
+pub fn init(role: &'static str) -> errors::Result<Telemetry> {
+    let resource = Resource::new(vec![
+        KeyValue::new("service.name", "nocap"),
+        KeyValue::new("nocap.role", role),
+    ]);
+    let spans = SpanExporter::builder()
+        .with_tonic()
+        .build()
+        .map_err(|err| Error::msg(format!("Invalid OTLP span exporter: {}", err)))?;
+    let tracer_provider = TracerProvider::builder()
+        .with_batch_exporter(spans, runtime::Tokio)
+        .with_resource(resource.clone())
+        .build();
+    let metrics = MetricExporter::builder()
+        .with_tonic()
+        .build()
+        .map_err(|err| Error::msg(format!("Invalid OTLP metric exporter: {}", err)))?;
+    let meter_provider = SdkMeterProvider::builder()
+        .with_reader(PeriodicReader::builder(metrics, runtime::Tokio).build())
+        .with_resource(resource)
+        .build();
+    let _ = global::set_tracer_provider(tracer_provider.clone());
+    global::set_meter_provider(meter_provider.clone());
+    Ok(Telemetry {
+        tracer_provider,
+        meter_provider,
+    })
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        // nothing is left to report a failed flush to on the way out
+        let _ = self.tracer_provider.shutdown();
+        let _ = self.meter_provider.shutdown();
+    }
+}
@@ -0,0 +1,79 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use image::{imageops::FilterType, ImageOutputFormat};
+use no_captcha::{
+    format,
+    resize::{self, InputSize, ResizeOptions},
+};
+
+/// TILE is a lossy WebP of a 3x3 challenge tile, which image can decode but not encode. The PNG
+/// and JPEG inputs are made from it
+const TILE: &[u8] = include_bytes!("fixtures/tile.webp");
+
+/// TILE_SIZES are the sides of 3x3 and 4x4 challenge tiles
+const TILE_SIZES: [u32; 2] = [100, 112];
+
+/// INPUT_SIZE is the input size of the models the tiles are resized for
+const INPUT_SIZE: InputSize = InputSize {
+    width: 224,
+    height: 224,
+};
+
+/// inputs are the tile in each format and size, named like "png_100"
+fn inputs() -> Vec<(String, Vec<u8>)> {
+    let decoded = image::load_from_memory(TILE).expect("the fixture is a valid WebP");
+    let mut inputs = vec![("webp_100".to_string(), TILE.to_vec())];
+    for size in TILE_SIZES.iter() {
+        let tile = decoded.resize_exact(*size, *size, FilterType::Triangle);
+        for (name, format) in [
+            ("png", ImageOutputFormat::Png),
+            ("jpeg", ImageOutputFormat::Jpeg(85)),
+        ] {
+            let mut encoded = Vec::new();
+            tile.write_to(&mut encoded, format)
+                .expect("encoding to memory should not fail");
+            inputs.push((format!("{}_{}", name, size), encoded));
+        }
+    }
+    inputs
+}
+
+/// preprocessing_benchmark times each step images go through before session.run, so CPU time
+/// spent on image handling can be told apart from time spent in TensorFlow
+pub fn preprocessing_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Preprocessing");
+    for (name, image) in inputs() {
+        let images = [image];
+        group.bench_with_input(BenchmarkId::new("decode", &name), &images, |b, images| {
+            b.iter(|| image::load_from_memory(&images[0]).expect("decodes"))
+        });
+        group.bench_with_input(
+            BenchmarkId::new("normalize", &name),
+            &images,
+            |b, images| b.iter(|| format::for_model(images).expect("normalizes").len()),
+        );
+        group.bench_with_input(BenchmarkId::new("resize", &name), &images, |b, images| {
+            b.iter(|| {
+                resize::resize_all(images, INPUT_SIZE, ResizeOptions::default())
+                    .expect("resizes")
+                    .len()
+            })
+        });
+        // both steps, as CaptchaModel::run takes them
+        group.bench_with_input(
+            BenchmarkId::new("preprocess", &name),
+            &images,
+            |b, images| {
+                b.iter(|| {
+                    let normalized = format::for_model(images).expect("normalizes");
+                    resize::resize_all(&normalized, INPUT_SIZE, ResizeOptions::default())
+                        .expect("resizes")
+                        .len()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, preprocessing_benchmark);
+criterion_main!(benches);
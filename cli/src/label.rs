@@ -0,0 +1,101 @@
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEventKind},
+    terminal,
+};
+use no_captcha::{
+    dataset, errors,
+    harvest::{Harvester, Retention},
+    Predictor,
+};
+use std::{
+    io::{self, Write},
+    path::Path,
+};
+
+/// Tally counts what a labeling session did
+#[derive(Debug, Default)]
+pub struct Tally {
+    pub matches: usize,
+    pub not_matches: usize,
+    pub skipped: usize,
+}
+
+/// Answer is a key pressed for one tile
+enum Answer {
+    Label(bool),
+    Skip,
+    Quit,
+}
+
+/// run walks the unlabeled tiles of the harvest at 'root', showing the model's guess for each,
+/// and files them under the label typed: y or n, enter to accept the guess, s to skip and q to
+/// stop. The terminal is only in raw mode while waiting for a key
+pub fn run<P>(predictor: &P, root: &Path) -> errors::Result<Tally>
+where
+    P: Predictor + ?Sized,
+{
+    let harvester = Harvester::new(root, Retention::default());
+    let unlabeled = harvester.unlabeled()?;
+    let mut tally = Tally::default();
+    let mut stdout = io::stdout();
+    for (index, image) in unlabeled.iter().enumerate() {
+        let guess = predictor
+            .predict(&image.challenge, dataset::read_image(&image.path)?)?
+            .is_mainly_affirmative();
+        write!(
+            stdout,
+            "[{}/{}] {} ({}, {})\n  model guesses {}. [y]es [n]o [enter] accept [s]kip [q]uit: ",
+            index + 1,
+            unlabeled.len(),
+            image.path.display(),
+            image.challenge,
+            image.grid,
+            if guess { "match" } else { "no match" },
+        )?;
+        stdout.flush()?;
+        let answer = read_answer(guess)?;
+        match answer {
+            Answer::Label(matches) => {
+                let _ = harvester.label(image, matches)?;
+                if matches {
+                    tally.matches += 1;
+                } else {
+                    tally.not_matches += 1;
+                }
+                writeln!(stdout, "{}", if matches { "match" } else { "no match" })?;
+            }
+            Answer::Skip => {
+                tally.skipped += 1;
+                writeln!(stdout, "skipped")?;
+            }
+            Answer::Quit => {
+                writeln!(stdout)?;
+                break;
+            }
+        }
+    }
+    Ok(tally)
+}
+
+/// read_answer waits for a key that answers for a tile the model guessed 'guess' for
+fn read_answer(guess: bool) -> errors::Result<Answer> {
+    terminal::enable_raw_mode()?;
+    let answer = loop {
+        let key = match event::read() {
+            Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => key,
+            Ok(_) => continue,
+            Err(err) => break Err(err),
+        };
+        match key.code {
+            KeyCode::Char('y') => break Ok(Answer::Label(true)),
+            KeyCode::Char('n') => break Ok(Answer::Label(false)),
+            KeyCode::Enter => break Ok(Answer::Label(guess)),
+            KeyCode::Char('s') | KeyCode::Char(' ') => break Ok(Answer::Skip),
+            KeyCode::Char('q') | KeyCode::Esc => break Ok(Answer::Quit),
+            _ => {}
+        }
+    };
+    // restored before any error is returned, so the shell isn't left in raw mode
+    terminal::disable_raw_mode()?;
+    Ok(answer?)
+}
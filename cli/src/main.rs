@@ -0,0 +1,839 @@
+use clap::{Parser, Subcommand, ValueEnum};
+#[cfg(feature = "train")]
+use no_captcha::train::{self, FineTune};
+use no_captcha::{
+    benchmark::Benchmark,
+    cluster, convert, dataset,
+    embedding::Embedder,
+    errors,
+    evaluation::{self, Evaluation, Winner},
+    export, grid,
+    loading::LoadLimits,
+    memory,
+    multicrop::{self, MultiCropConfig},
+    quantize,
+    report::{self, ChallengeReport},
+    schema::PredictionRecord,
+    sidecar::Sidecar,
+    watch::{self, Watch, Watcher},
+    CaptchaChallenge, CaptchaRegistry, GridSize, Predictor,
+};
+use output::{ImagePrediction, OutputFormat};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+#[cfg(feature = "label")]
+mod label;
+mod output;
+mod pipe;
+
+#[cfg(feature = "alloc-stats")]
+#[global_allocator]
+static ALLOCATOR: memory::CountingAllocator = memory::CountingAllocator;
+
+/// GATE_FAILED is the exit code of an eval, compare or dataset validate that fails its gate,
+/// told apart from the 1 any error exits with
+const GATE_FAILED: i32 = 2;
+
+/// nocap works with the models and datasets of a no_captcha deployment
+#[derive(Parser, Debug)]
+#[command(name = "nocap", version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Copy a models directory into TF Serving's <model>/<version>/ layout and write its
+    /// models.config
+    ExportServing {
+        /// Directory holding one model directory per challenge
+        #[arg(long, default_value = "models/")]
+        models_dir: PathBuf,
+        /// Directory to write the TF Serving layout into
+        #[arg(long)]
+        out: PathBuf,
+        /// Version directory to export every model under
+        #[arg(long, default_value_t = 1)]
+        version: u64,
+        /// Where TF Serving will find the exported directory, if not at --out
+        #[arg(long)]
+        base_path: Option<PathBuf>,
+    },
+    /// Convert every model to another format, optionally checking the converted models score
+    /// a set of fixture images like the originals
+    Convert {
+        #[arg(long, value_enum)]
+        to: Format,
+        #[arg(long, default_value = "models/")]
+        models_dir: PathBuf,
+        #[arg(long)]
+        out: PathBuf,
+        /// Directory of images to validate the converted models on
+        #[arg(long)]
+        fixtures: Option<PathBuf>,
+        /// Largest score difference a converted model may show on the fixtures
+        #[arg(long, default_value_t = 1e-3)]
+        tolerance: f32,
+        /// Python interpreter with tf2onnx (and onnxruntime, for validation) installed
+        #[arg(long, default_value = "python3")]
+        python: PathBuf,
+        #[arg(long, default_value_t = 13)]
+        opset: u32,
+    },
+    /// Produce reduced precision TFLite variants of every model and report what they cost in
+    /// size and accuracy
+    Quantize {
+        #[arg(long, default_value = "models/")]
+        models_dir: PathBuf,
+        #[arg(long)]
+        out: PathBuf,
+        /// Precisions to produce; all of them by default
+        #[arg(long, value_enum)]
+        precision: Vec<Precision>,
+        /// Dataset to measure the accuracy delta on
+        #[arg(long, default_value = "test_data/")]
+        test_data: PathBuf,
+        /// Skip measuring accuracy
+        #[arg(long)]
+        no_eval: bool,
+        /// Python interpreter with tensorflow installed
+        #[arg(long, default_value = "python3")]
+        python: PathBuf,
+    },
+    /// Load every model once and serve predictions to other local processes, which connect
+    /// with no_captcha::sidecar::SidecarClient
+    Sidecar {
+        #[arg(long, default_value = "models/")]
+        models_dir: PathBuf,
+        /// Unix socket to listen on
+        #[arg(long, default_value = "/tmp/nocap.sock")]
+        socket: PathBuf,
+    },
+    /// Predict images as they appear in a directory, writing a JSON result for each
+    Watch {
+        #[arg(long)]
+        challenge: CaptchaChallenge,
+        /// Directory to watch for new images
+        #[arg(long)]
+        dir: PathBuf,
+        /// Directory to write <image>.json results into
+        #[arg(long)]
+        out: PathBuf,
+        /// Move predicted images into match/ and no_match/ under --dir
+        #[arg(long)]
+        sort: bool,
+        /// Milliseconds between looking for new images
+        #[arg(long, default_value_t = 1000)]
+        interval_ms: u64,
+        #[arg(long, default_value = "models/")]
+        models_dir: PathBuf,
+    },
+    /// Answer newline delimited JSON requests on stdin with predictions on stdout, keeping the
+    /// models loaded until stdin closes
+    Pipe {
+        #[arg(long, default_value = "models/")]
+        models_dir: PathBuf,
+    },
+    /// Predict image files against a challenge
+    Predict {
+        #[arg(long)]
+        challenge: CaptchaChallenge,
+        images: Vec<PathBuf>,
+        #[arg(long, default_value = "models/")]
+        models_dir: PathBuf,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+        /// Take the images as challenge screenshots and predict each of their tiles, merging
+        /// in overlapping crops at several scales on 4x4 grids
+        #[arg(long)]
+        multi_crop: bool,
+    },
+    /// Measure each model's accuracy on a labeled dataset. Exits with 2 if any accuracy is
+    /// below --min-accuracy
+    Eval {
+        /// Challenges to evaluate; every challenge with test images by default
+        #[arg(long)]
+        challenge: Vec<CaptchaChallenge>,
+        #[arg(long, default_value = "models/")]
+        models_dir: PathBuf,
+        #[arg(long, default_value = "test_data/")]
+        test_data: PathBuf,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+        /// Lowest accuracy, between 0 and 1, every challenge must reach
+        #[arg(long)]
+        min_accuracy: Option<f64>,
+        /// Print a running count and accuracy to stderr as each batch is scored
+        #[arg(long)]
+        progress: bool,
+        /// Also write a self-contained HTML report with ROC curves and the misclassified
+        /// images to this file
+        #[arg(long)]
+        html: Option<PathBuf>,
+    },
+    /// Report the peak resident memory and, built with the alloc-stats feature, the heap
+    /// allocations of loading the models and then of sustained prediction
+    Profile {
+        #[arg(long)]
+        challenge: CaptchaChallenge,
+        #[arg(long)]
+        image: PathBuf,
+        #[arg(long, default_value_t = 1000)]
+        iterations: usize,
+        /// Copies of the image scored by each prediction
+        #[arg(long, default_value_t = 1)]
+        batch_size: usize,
+        #[arg(long, default_value = "models/")]
+        models_dir: PathBuf,
+        /// Most models to load at once
+        #[arg(long)]
+        load_concurrency: Option<usize>,
+        /// Most bytes of models, by their size on disk, to load at once
+        #[arg(long)]
+        memory_budget: Option<u64>,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    /// Cross-validate each challenge's match threshold: tune it on all folds but one, score the
+    /// held-out fold, and print the per-fold and mean accuracies
+    CrossValidate {
+        /// Challenges to cross-validate; every challenge with test images by default
+        #[arg(long)]
+        challenge: Vec<CaptchaChallenge>,
+        #[arg(long, default_value = "models/")]
+        models_dir: PathBuf,
+        #[arg(long, default_value = "test_data/")]
+        test_data: PathBuf,
+        #[arg(long, default_value_t = 5)]
+        folds: usize,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    /// Time repeated predictions of one image and print latency percentiles and throughput
+    Bench {
+        #[arg(long)]
+        challenge: CaptchaChallenge,
+        #[arg(long)]
+        image: PathBuf,
+        #[arg(long, default_value_t = 1000)]
+        iterations: usize,
+        /// Predictions run first and reported apart from the measured ones
+        #[arg(long, default_value_t = 10)]
+        warmup: usize,
+        /// Copies of the image scored by each prediction
+        #[arg(long, default_value_t = 1)]
+        batch_size: usize,
+        #[arg(long, default_value = "models/")]
+        models_dir: PathBuf,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    /// Evaluate two models directories on the same labeled dataset and test each challenge's
+    /// accuracy delta for significance. Exits with 2 if any challenge got significantly worse
+    Compare {
+        #[arg(long)]
+        baseline: PathBuf,
+        #[arg(long)]
+        candidate: PathBuf,
+        #[arg(long, default_value = "test_data/")]
+        test_data: PathBuf,
+        /// Challenges to compare; every challenge with test images by default
+        #[arg(long)]
+        challenge: Vec<CaptchaChallenge>,
+        /// Significance level a regression must reach to fail the comparison
+        #[arg(long, default_value_t = 0.05)]
+        alpha: f64,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    /// Slice a challenge screenshot into its tiles, skipping the header banner and the
+    /// borders between tiles
+    Split {
+        screenshot: PathBuf,
+        /// Layout of the grid, 3x3 or 4x4, found from the borders between the tiles when left out
+        #[arg(long)]
+        grid: Option<GridSize>,
+        /// Directory to write <screenshot>_<index>.png tiles into, in row-major order
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Label harvested tiles one key press at a time, filing each into its label folder
+    #[cfg(feature = "label")]
+    Label {
+        /// Harvest directory, laid out as <root>/<grid>/<challenge>/unlabeled/
+        #[arg(long, default_value = "harvest/")]
+        root: PathBuf,
+        #[arg(long, default_value = "models/")]
+        models_dir: PathBuf,
+    },
+    /// Retrain a model's final dense layer on a labeled dataset and save the updated model
+    #[cfg(feature = "train")]
+    Train {
+        #[arg(long)]
+        challenge: CaptchaChallenge,
+        /// Model directory to start from
+        #[arg(long)]
+        model: PathBuf,
+        /// Operation feeding the final dense layer
+        #[arg(long)]
+        embedding_operation: String,
+        #[arg(long, default_value = "test_data/")]
+        dataset: PathBuf,
+        /// Directory to save the updated model to
+        #[arg(long)]
+        out: PathBuf,
+        #[arg(long, default_value_t = 200)]
+        epochs: usize,
+        #[arg(long, default_value_t = 0.01)]
+        learning_rate: f32,
+        /// Python interpreter with tensorflow installed, used to save the model
+        #[arg(long, default_value = "python3")]
+        python: PathBuf,
+    },
+    /// Group a directory of unlabeled tiles by similarity, copying each group into its own
+    /// folder for labeling in bulk
+    Cluster {
+        dir: PathBuf,
+        /// Model to embed the tiles with
+        #[arg(long)]
+        embedder: PathBuf,
+        /// Operation whose activations are the embedding, e.g. the input to the final dense
+        /// layer
+        #[arg(long)]
+        output_operation: String,
+        /// Number of clusters
+        #[arg(long, default_value_t = 8)]
+        k: usize,
+        /// Directory to write cluster_<n>/ folders into
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Create or check a dataset laid out as <root>/<grid>/<challenge>/{matches,not matches}/
+    Dataset {
+        #[command(subcommand)]
+        action: DatasetCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum DatasetCommand {
+    /// Create the folders for every challenge in both grid sizes
+    Init {
+        #[arg(long, default_value = "test_data/")]
+        root: PathBuf,
+    },
+    /// Report misplaced folders, files that aren't images and imbalanced labels. Exits with 2
+    /// if anything but a warning was found
+    Validate {
+        #[arg(long, default_value = "test_data/")]
+        root: PathBuf,
+    },
+    /// Count the images of each challenge, their sizes and formats, and list the files that
+    /// fail to decode
+    Stats {
+        #[arg(long, default_value = "test_data/")]
+        root: PathBuf,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Format {
+    Onnx,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Precision {
+    Fp16,
+    Int8,
+}
+
+impl From<Precision> for quantize::Precision {
+    fn from(precision: Precision) -> quantize::Precision {
+        match precision {
+            Precision::Fp16 => quantize::Precision::Float16,
+            Precision::Int8 => quantize::Precision::Int8,
+        }
+    }
+}
+
+fn main() -> errors::Result<()> {
+    match Cli::parse().command {
+        Command::ExportServing {
+            models_dir,
+            out,
+            version,
+            base_path,
+        } => {
+            let exported = export::export_tf_serving(
+                &models_dir,
+                &out,
+                &export::ServingExport { version, base_path },
+            )?;
+            for model in &exported {
+                println!("{} -> {}", model.name, model.path.display());
+            }
+            println!("Wrote {}", out.join("models.config").display());
+        }
+        Command::Convert {
+            to: Format::Onnx,
+            models_dir,
+            out,
+            fixtures,
+            tolerance,
+            python,
+            opset,
+        } => {
+            let converted = convert::convert_to_onnx(
+                &models_dir,
+                &out,
+                &convert::OnnxConversion {
+                    python,
+                    opset,
+                    fixtures,
+                },
+            )?;
+            let mut mismatched = 0;
+            for model in &converted {
+                match model.max_score_delta {
+                    Some(delta) if delta > tolerance => {
+                        mismatched += 1;
+                        println!(
+                            "{} MISMATCH (max score delta {:.6})",
+                            model.path.display(),
+                            delta
+                        );
+                    }
+                    Some(delta) => {
+                        println!("{} ok (max score delta {:.6})", model.path.display(), delta)
+                    }
+                    None => println!("{}", model.path.display()),
+                }
+            }
+            if mismatched > 0 {
+                eprintln!("{} converted model(s) exceed the tolerance", mismatched);
+                process::exit(1);
+            }
+        }
+        Command::Quantize {
+            models_dir,
+            out,
+            precision,
+            test_data,
+            no_eval,
+            python,
+        } => {
+            let mut quantization = quantize::Quantization {
+                python,
+                test_data: if no_eval { None } else { Some(test_data) },
+                ..quantize::Quantization::default()
+            };
+            if !precision.is_empty() {
+                quantization.precisions = precision.into_iter().map(Into::into).collect();
+            }
+            for model in quantize::quantize(&models_dir, &out, &quantization)? {
+                let size = format!(
+                    "{:.1} MB -> {:.1} MB",
+                    model.original_size as f64 / 1e6,
+                    model.size as f64 / 1e6
+                );
+                match (model.original_accuracy, model.accuracy) {
+                    (Some(original), Some(accuracy)) => println!(
+                        "{} {} accuracy {:.1}% -> {:.1}% ({:+.1} points)",
+                        model.path.display(),
+                        size,
+                        100.0 * original,
+                        100.0 * accuracy,
+                        100.0 * (accuracy - original)
+                    ),
+                    _ => println!("{} {}", model.path.display(), size),
+                }
+            }
+        }
+        Command::Sidecar { models_dir, socket } => {
+            let registry = CaptchaRegistry::load_from_models_dir(&models_dir)?;
+            let sidecar = Sidecar::bind(socket)?;
+            println!("Serving predictions on {}", sidecar.path().display());
+            sidecar.serve(Arc::new(registry))?;
+        }
+        Command::Watch {
+            challenge,
+            dir,
+            out,
+            sort,
+            interval_ms,
+            models_dir,
+        } => {
+            let registry = CaptchaRegistry::load_from_models_dir(&models_dir)?;
+            let mut watcher = Watcher::new(Watch {
+                challenge,
+                dir,
+                out,
+                sort,
+            });
+            loop {
+                for result in watcher.scan(&registry)? {
+                    let verdict = if result.prediction.is_match {
+                        watch::MATCH_DIR
+                    } else {
+                        watch::NO_MATCH_DIR
+                    };
+                    println!("{} {}", result.image, verdict);
+                }
+                thread::sleep(Duration::from_millis(interval_ms));
+            }
+        }
+        Command::Predict {
+            challenge,
+            images,
+            models_dir,
+            format,
+            multi_crop,
+        } => {
+            let registry = CaptchaRegistry::load_from_models_dir(&models_dir)?;
+            if multi_crop {
+                let config = MultiCropConfig::default();
+                let mut rows = Vec::new();
+                for image in &images {
+                    let screenshot = fs::read(image)?;
+                    let (_, predictions) =
+                        multicrop::predict_grid(&registry, &challenge, &screenshot, &config)?;
+                    rows.extend(predictions.iter().enumerate().map(|(index, prediction)| {
+                        ImagePrediction {
+                            image: format!("{}#{}", image.display(), index),
+                            prediction: PredictionRecord::new(challenge.clone(), prediction),
+                        }
+                    }));
+                }
+                output::write(format, &rows, &mut io::stdout())?;
+                return Ok(());
+            }
+            let contents = images
+                .iter()
+                .map(dataset::read_image)
+                .collect::<errors::Result<Vec<_>>>()?;
+            let predictions = registry.predict_batch(&challenge, contents)?;
+            let rows: Vec<ImagePrediction> = images
+                .iter()
+                .zip(&predictions)
+                .map(|(image, prediction)| ImagePrediction {
+                    image: image.display().to_string(),
+                    prediction: PredictionRecord::new(challenge.clone(), prediction),
+                })
+                .collect();
+            output::write(format, &rows, &mut io::stdout())?;
+        }
+        Command::Eval {
+            challenge,
+            models_dir,
+            test_data,
+            format,
+            min_accuracy,
+            progress,
+            html,
+        } => {
+            let registry = CaptchaRegistry::load_from_models_dir(&models_dir)?;
+            let challenges = or_with_test_images(challenge, &test_data)?;
+            let mut evaluations = Vec::with_capacity(challenges.len());
+            if let Some(html) = html {
+                // the report needs every image's score, so it isn't streamed
+                let mut reports = Vec::with_capacity(challenges.len());
+                for challenge in &challenges {
+                    let scored = evaluation::score_images(&registry, &test_data, challenge)?;
+                    reports.push(ChallengeReport {
+                        evaluation: Evaluation::from_scored(challenge, &scored),
+                        scored,
+                    });
+                }
+                fs::write(&html, report::html(&reports)?)?;
+                evaluations.extend(reports.into_iter().map(|report| report.evaluation));
+            } else {
+                for challenge in &challenges {
+                    let show_progress = |running: &Evaluation| {
+                        if progress {
+                            eprint!(
+                                "\r{}: {} images, {:.4} accuracy",
+                                running.challenge, running.images, running.accuracy
+                            );
+                        }
+                    };
+                    evaluations.push(evaluation::evaluate_streaming(
+                        &registry,
+                        &test_data,
+                        challenge,
+                        show_progress,
+                    )?);
+                    if progress {
+                        eprintln!();
+                    }
+                }
+            }
+            output::write(format, &evaluations, &mut io::stdout())?;
+            if let Some(min_accuracy) = min_accuracy {
+                if evaluations
+                    .iter()
+                    .any(|evaluation| evaluation.accuracy < min_accuracy)
+                {
+                    process::exit(GATE_FAILED);
+                }
+            }
+        }
+        Command::Profile {
+            challenge,
+            image,
+            iterations,
+            batch_size,
+            models_dir,
+            load_concurrency,
+            memory_budget,
+            format,
+        } => {
+            let image = dataset::read_image(&image)?;
+            let limits = LoadLimits {
+                concurrency: load_concurrency,
+                memory_budget,
+            };
+            let (registry, load) = memory::profile("load", || {
+                CaptchaRegistry::builder()
+                    .load_limits(limits)
+                    .load_from_models_dir(&models_dir)
+            })?;
+            let benchmark = Benchmark {
+                warmup: 0,
+                iterations,
+                batch_size,
+            };
+            let (_, predict) =
+                memory::profile("predict", || benchmark.run(&registry, &challenge, &image))?;
+            output::write(format, &[load, predict], &mut io::stdout())?;
+        }
+        Command::CrossValidate {
+            challenge,
+            models_dir,
+            test_data,
+            folds,
+            format,
+        } => {
+            let registry = CaptchaRegistry::load_from_models_dir(&models_dir)?;
+            let mut validations = Vec::new();
+            for challenge in &or_with_test_images(challenge, &test_data)? {
+                let scored = evaluation::score_images(&registry, &test_data, challenge)?;
+                validations.push(evaluation::cross_validate(challenge, &scored, folds));
+            }
+            let mut stdout = io::stdout();
+            if format == OutputFormat::Json {
+                serde_json::to_writer_pretty(&mut stdout, &validations)?;
+                println!();
+                return Ok(());
+            }
+            let folds: Vec<_> = validations
+                .iter()
+                .flat_map(|validation| validation.folds.iter().cloned())
+                .collect();
+            output::write(format, &folds, &mut stdout)?;
+            println!();
+            output::write(format, &validations, &mut stdout)?;
+        }
+        Command::Bench {
+            challenge,
+            image,
+            iterations,
+            warmup,
+            batch_size,
+            models_dir,
+            format,
+        } => {
+            let registry = CaptchaRegistry::load_from_models_dir(&models_dir)?;
+            let benchmark = Benchmark {
+                warmup,
+                iterations,
+                batch_size,
+            };
+            let report = benchmark.run(&registry, &challenge, &dataset::read_image(&image)?)?;
+            output::write(format, &[report], &mut io::stdout())?;
+        }
+        Command::Compare {
+            baseline,
+            candidate,
+            test_data,
+            challenge,
+            alpha,
+            format,
+        } => {
+            let baseline = CaptchaRegistry::load_from_models_dir(&baseline)?;
+            let candidate = CaptchaRegistry::load_from_models_dir(&candidate)?;
+            let comparisons = or_with_test_images(challenge, &test_data)?
+                .iter()
+                .map(|challenge| evaluation::compare(&baseline, &candidate, &test_data, challenge))
+                .collect::<errors::Result<Vec<_>>>()?;
+            output::write(format, &comparisons, &mut io::stdout())?;
+            for comparison in &comparisons {
+                match comparison.winner(alpha) {
+                    Some(Winner::Candidate) => {
+                        eprintln!("{}: the candidate is better", comparison.challenge)
+                    }
+                    Some(Winner::Baseline) => {
+                        eprintln!("{}: the baseline is better", comparison.challenge)
+                    }
+                    None => eprintln!(
+                        "{}: no significant difference at alpha {}, no winner",
+                        comparison.challenge, alpha
+                    ),
+                }
+            }
+            if comparisons
+                .iter()
+                .any(|comparison| comparison.winner(alpha) == Some(Winner::Baseline))
+            {
+                process::exit(GATE_FAILED);
+            }
+        }
+        Command::Split {
+            screenshot,
+            grid,
+            out,
+        } => {
+            let image = fs::read(&screenshot)?;
+            let tiles = match grid {
+                Some(grid) => grid::split(&image, grid)?,
+                None => {
+                    let (layout, tiles) = grid::split_located(&image)?;
+                    eprintln!("Found a {} grid", layout.grid);
+                    tiles
+                }
+            };
+            fs::create_dir_all(&out)?;
+            let stem = screenshot
+                .file_stem()
+                .map_or_else(|| "tile".into(), |stem| stem.to_string_lossy());
+            for (index, tile) in tiles.iter().enumerate() {
+                let path = out.join(format!("{}_{}.png", stem, index));
+                tile.save(&path).map_err(errors::Error::from)?;
+                println!("{}", path.display());
+            }
+        }
+        Command::Dataset {
+            action: DatasetCommand::Init { root },
+        } => {
+            let created = dataset::init(&root)?;
+            println!("Created {} folders under {}", created.len(), root.display());
+        }
+        Command::Dataset {
+            action: DatasetCommand::Validate { root },
+        } => {
+            let issues = dataset::validate(&root)?;
+            for issue in &issues {
+                let severity = if issue.is_warning() {
+                    "warning"
+                } else {
+                    "error"
+                };
+                println!("{}: {}", severity, issue);
+            }
+            if issues.iter().any(|issue| !issue.is_warning()) {
+                process::exit(GATE_FAILED);
+            }
+        }
+        Command::Dataset {
+            action: DatasetCommand::Stats { root, format },
+        } => {
+            let stats = dataset::stats(&root)?;
+            let mut stdout = io::stdout();
+            if format == OutputFormat::Json {
+                serde_json::to_writer_pretty(&mut stdout, &stats)?;
+                println!();
+                return Ok(());
+            }
+            output::write(format, &stats.classes, &mut stdout)?;
+            println!();
+            output::write(format, &stats.dimensions, &mut stdout)?;
+            println!();
+            output::write(format, &stats.formats, &mut stdout)?;
+            for path in &stats.corrupt {
+                eprintln!("corrupt: {}", path.display());
+            }
+        }
+        #[cfg(feature = "label")]
+        Command::Label { root, models_dir } => {
+            let registry = CaptchaRegistry::load_from_models_dir(&models_dir)?;
+            let tally = label::run(&registry, &root)?;
+            println!(
+                "Labeled {} matches and {} not matches, skipped {}",
+                tally.matches, tally.not_matches, tally.skipped
+            );
+        }
+        #[cfg(feature = "train")]
+        Command::Train {
+            challenge,
+            model,
+            embedding_operation,
+            dataset,
+            out,
+            epochs,
+            learning_rate,
+            python,
+        } => {
+            let options = FineTune {
+                python,
+                embedding_operation,
+                epochs,
+                learning_rate,
+                ..FineTune::default()
+            };
+            let report = train::fine_tune(&model, &dataset, &challenge, &out, &options)?;
+            println!(
+                "Saved {} trained on {} images: loss {:.4}, training accuracy {:.4}",
+                out.display(),
+                report.images,
+                report.loss,
+                report.train_accuracy
+            );
+        }
+        Command::Cluster {
+            dir,
+            embedder,
+            output_operation,
+            k,
+            out,
+        } => {
+            let embedder = Embedder::load(&embedder, output_operation)?;
+            for cluster in cluster::cluster_images(&embedder, &dir, &out, k)? {
+                println!("{}: {} images", cluster.dir.display(), cluster.images);
+            }
+        }
+        Command::Pipe { models_dir } => {
+            let registry = CaptchaRegistry::load_from_models_dir(&models_dir)?;
+            let stdin = io::stdin();
+            let stdout = io::stdout();
+            pipe::serve(&registry, stdin.lock(), &mut stdout.lock())?;
+        }
+    }
+    Ok(())
+}
+
+/// or_with_test_images is 'challenges', or when none are given every challenge with test
+/// images in 'test_data'
+fn or_with_test_images(
+    challenges: Vec<CaptchaChallenge>,
+    test_data: &Path,
+) -> errors::Result<Vec<CaptchaChallenge>> {
+    if !challenges.is_empty() {
+        return Ok(challenges);
+    }
+    let mut with_images = Vec::new();
+    for challenge in CaptchaChallenge::all() {
+        if !dataset::labeled_images(test_data, &challenge)?.is_empty() {
+            with_images.push(challenge);
+        }
+    }
+    Ok(with_images)
+}
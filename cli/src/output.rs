@@ -0,0 +1,350 @@
+use clap::ValueEnum;
+use no_captcha::{
+    benchmark::BenchmarkReport,
+    dataset::{ClassStats, DimensionCount, FormatCount},
+    evaluation::{Comparison, CrossValidation, Evaluation, Fold},
+    memory::PhaseMemory,
+    schema::PredictionRecord,
+};
+use serde::Serialize;
+use serde_derive::Serialize;
+use std::io::{self, Write};
+
+/// OutputFormat is how predict and eval print their results: JSON for programs, CSV for
+/// spreadsheets and a padded table for people
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Json,
+    Csv,
+    Table,
+}
+
+/// Row is a result that can be printed in every OutputFormat
+pub trait Row: Serialize {
+    const HEADERS: &'static [&'static str];
+
+    /// cells are the row's values in the order of HEADERS
+    fn cells(&self) -> Vec<String>;
+}
+
+/// write prints 'rows' in 'format': a JSON array, CSV with a header line, or a table
+pub fn write<R, W>(format: OutputFormat, rows: &[R], writer: &mut W) -> io::Result<()>
+where
+    R: Row,
+    W: Write,
+{
+    match format {
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut *writer, rows)?;
+            writeln!(writer)
+        }
+        OutputFormat::Csv => {
+            let header: Vec<String> = R::HEADERS.iter().map(|header| header.to_string()).collect();
+            for cells in std::iter::once(header).chain(rows.iter().map(Row::cells)) {
+                let fields: Vec<String> = cells.iter().map(|cell| csv_field(cell)).collect();
+                writeln!(writer, "{}", fields.join(","))?;
+            }
+            Ok(())
+        }
+        OutputFormat::Table => {
+            let cells: Vec<Vec<String>> = rows.iter().map(Row::cells).collect();
+            let widths: Vec<usize> = R::HEADERS
+                .iter()
+                .enumerate()
+                .map(|(column, header)| {
+                    cells
+                        .iter()
+                        .map(|row| row[column].len())
+                        .chain(std::iter::once(header.len()))
+                        .max()
+                        .unwrap_or(0)
+                })
+                .collect();
+            let header: Vec<String> = R::HEADERS.iter().map(|header| header.to_string()).collect();
+            for row in std::iter::once(&header).chain(&cells) {
+                let padded: Vec<String> = row
+                    .iter()
+                    .zip(&widths)
+                    .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                    .collect();
+                writeln!(writer, "{}", padded.join("  ").trim_end())?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// ImagePrediction is the result of predicting one image file
+#[derive(Debug, Serialize)]
+pub struct ImagePrediction {
+    pub image: String,
+    #[serde(flatten)]
+    pub prediction: PredictionRecord,
+}
+
+impl Row for ImagePrediction {
+    const HEADERS: &'static [&'static str] = &["image", "match", "affirmative", "negative"];
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.image.clone(),
+            self.prediction.is_match.to_string(),
+            format!("{:.4}", self.prediction.scores.affirmative),
+            format!("{:.4}", self.prediction.scores.negative),
+        ]
+    }
+}
+
+impl Row for Evaluation {
+    const HEADERS: &'static [&'static str] = &[
+        "challenge",
+        "images",
+        "correct",
+        "false_positives",
+        "false_negatives",
+        "accuracy",
+    ];
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.challenge.to_string(),
+            self.images.to_string(),
+            self.correct.to_string(),
+            self.false_positives.to_string(),
+            self.false_negatives.to_string(),
+            format!("{:.4}", self.accuracy),
+        ]
+    }
+}
+
+impl Row for Comparison {
+    const HEADERS: &'static [&'static str] = &[
+        "challenge",
+        "images",
+        "baseline_accuracy",
+        "candidate_accuracy",
+        "delta",
+        "baseline_only",
+        "candidate_only",
+        "p_value",
+        "delta_low",
+        "delta_high",
+    ];
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.challenge.to_string(),
+            self.images.to_string(),
+            format!("{:.4}", self.baseline_accuracy),
+            format!("{:.4}", self.candidate_accuracy),
+            format!("{:+.4}", self.delta),
+            self.baseline_only.to_string(),
+            self.candidate_only.to_string(),
+            format!("{:.4}", self.p_value),
+            format!("{:+.4}", self.delta_interval.0),
+            format!("{:+.4}", self.delta_interval.1),
+        ]
+    }
+}
+
+impl Row for Fold {
+    const HEADERS: &'static [&'static str] = &[
+        "challenge",
+        "fold",
+        "threshold",
+        "images",
+        "false_positives",
+        "false_negatives",
+        "accuracy",
+    ];
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.evaluation.challenge.to_string(),
+            self.fold.to_string(),
+            format!("{:.4}", self.threshold),
+            self.evaluation.images.to_string(),
+            self.evaluation.false_positives.to_string(),
+            self.evaluation.false_negatives.to_string(),
+            format!("{:.4}", self.evaluation.accuracy),
+        ]
+    }
+}
+
+impl Row for CrossValidation {
+    const HEADERS: &'static [&'static str] = &[
+        "challenge",
+        "images",
+        "folds",
+        "mean_accuracy",
+        "accuracy_std",
+        "mean_threshold",
+        "threshold_std",
+    ];
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.challenge.to_string(),
+            self.images.to_string(),
+            self.folds.len().to_string(),
+            format!("{:.4}", self.mean_accuracy),
+            format!("{:.4}", self.accuracy_std),
+            format!("{:.4}", self.mean_threshold),
+            format!("{:.4}", self.threshold_std),
+        ]
+    }
+}
+
+impl Row for BenchmarkReport {
+    const HEADERS: &'static [&'static str] = &[
+        "challenge",
+        "iterations",
+        "batch_size",
+        "first_ms",
+        "warmup_mean_ms",
+        "mean_ms",
+        "p50_ms",
+        "p90_ms",
+        "p99_ms",
+        "max_ms",
+        "images_per_sec",
+    ];
+
+    fn cells(&self) -> Vec<String> {
+        let mut cells = vec![
+            self.challenge.to_string(),
+            self.iterations.to_string(),
+            self.batch_size.to_string(),
+        ];
+        cells.extend(
+            [
+                self.first_ms,
+                self.warmup_mean_ms,
+                self.mean_ms,
+                self.p50_ms,
+                self.p90_ms,
+                self.p99_ms,
+                self.max_ms,
+                self.images_per_sec,
+            ]
+            .iter()
+            .map(|value| format!("{:.2}", value)),
+        );
+        cells
+    }
+}
+
+impl Row for ClassStats {
+    const HEADERS: &'static [&'static str] = &["grid", "challenge", "matches", "not_matches"];
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.grid.clone(),
+            self.challenge.clone(),
+            self.matches.to_string(),
+            self.not_matches.to_string(),
+        ]
+    }
+}
+
+impl Row for DimensionCount {
+    const HEADERS: &'static [&'static str] = &["width", "height", "images"];
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.width.to_string(),
+            self.height.to_string(),
+            self.images.to_string(),
+        ]
+    }
+}
+
+impl Row for FormatCount {
+    const HEADERS: &'static [&'static str] = &["format", "images"];
+
+    fn cells(&self) -> Vec<String> {
+        vec![self.format.to_string(), self.images.to_string()]
+    }
+}
+
+impl Row for PhaseMemory {
+    const HEADERS: &'static [&'static str] = &[
+        "phase",
+        "peak_rss_bytes",
+        "rss_bytes",
+        "allocations",
+        "allocated_bytes",
+        "peak_heap_bytes",
+    ];
+
+    fn cells(&self) -> Vec<String> {
+        let optional =
+            |value: Option<u64>| value.map_or_else(String::new, |value| value.to_string());
+        vec![
+            self.phase.clone(),
+            optional(self.peak_rss_bytes),
+            optional(self.rss_bytes),
+            optional(self.allocations),
+            optional(self.allocated_bytes),
+            optional(self.peak_heap_bytes),
+        ]
+    }
+}
+
+/// csv_field quotes a field containing a separator, quote or line break
+fn csv_field(field: &str) -> String {
+    if field.contains(|c| matches!(c, ',' | '"' | '\n' | '\r')) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Score {
+        name: &'static str,
+        value: u32,
+    }
+
+    impl Row for Score {
+        const HEADERS: &'static [&'static str] = &["name", "value"];
+
+        fn cells(&self) -> Vec<String> {
+            vec![self.name.to_string(), self.value.to_string()]
+        }
+    }
+
+    fn render(format: OutputFormat) -> io::Result<String> {
+        let rows = [
+            Score {
+                name: "bus, red",
+                value: 7,
+            },
+            Score {
+                name: "car",
+                value: 12,
+            },
+        ];
+        let mut output = Vec::new();
+        write(format, &rows, &mut output)?;
+        Ok(String::from_utf8_lossy(&output).into_owned())
+    }
+
+    #[test]
+    fn renders_each_format() -> io::Result<()> {
+        assert_eq!(
+            render(OutputFormat::Csv)?,
+            "name,value\n\"bus, red\",7\ncar,12\n"
+        );
+        assert_eq!(
+            render(OutputFormat::Table)?,
+            "name      value\nbus, red  7\ncar       12\n"
+        );
+        assert!(render(OutputFormat::Json)?.contains("\"value\": 12"));
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+use base64::Engine;
+use no_captcha::{errors, schema::PredictionRecord, CaptchaChallenge, Predictor};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::{BufRead, Write};
+
+/// Request is one line of `nocap pipe` input:
+///
+/// ```json
+/// {"id": 7, "challenge": "bus", "images": ["<base64>", "<base64>"]}
+/// ```
+///
+/// 'id' is any JSON value and is echoed back, so callers can have several requests in flight
+#[derive(Debug, Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    challenge: CaptchaChallenge,
+    images: Vec<String>,
+}
+
+/// Response is one line of output per request, holding either a PredictionRecord per image or
+/// the error that failed the request
+#[derive(Debug, Serialize)]
+struct Response {
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    predictions: Option<Vec<PredictionRecord>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ResponseError>,
+}
+
+#[derive(Debug, Serialize)]
+struct ResponseError {
+    code: &'static str,
+    message: String,
+}
+
+/// serve answers newline delimited JSON requests from 'input' on 'output' until 'input' ends.
+/// A request that fails is answered with its error and the next one is read as usual
+pub fn serve<P, R, W>(predictor: &P, input: R, output: &mut W) -> errors::Result<()>
+where
+    P: Predictor + ?Sized,
+    R: BufRead,
+    W: Write,
+{
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                let id = request.id.clone();
+                match predict(predictor, request) {
+                    Ok(predictions) => Response {
+                        id,
+                        predictions: Some(predictions),
+                        error: None,
+                    },
+                    Err(err) => Response::failed(id, err),
+                }
+            }
+            Err(err) => Response::failed(Value::Null, errors::Error::from(err).into()),
+        };
+        serde_json::to_writer(&mut *output, &response)?;
+        output.write_all(b"\n")?;
+        output.flush()?;
+    }
+    Ok(())
+}
+
+fn predict<P>(predictor: &P, request: Request) -> Result<Vec<PredictionRecord>, ResponseError>
+where
+    P: Predictor + ?Sized,
+{
+    let engine = base64::engine::general_purpose::STANDARD;
+    let mut images = Vec::with_capacity(request.images.len());
+    for (index, image) in request.images.iter().enumerate() {
+        images.push(engine.decode(image).map_err(|err| ResponseError {
+            code: "invalid_base64",
+            message: format!("image {} is not valid base64: {}", index, err),
+        })?);
+    }
+    Ok(predictor
+        .predict_batch(&request.challenge, images)?
+        .iter()
+        .map(|prediction| PredictionRecord::new(request.challenge.clone(), prediction))
+        .collect())
+}
+
+impl Response {
+    fn failed(id: Value, error: ResponseError) -> Response {
+        Response {
+            id,
+            predictions: None,
+            error: Some(error),
+        }
+    }
+}
+
+impl From<errors::Error> for ResponseError {
+    fn from(err: errors::Error) -> ResponseError {
+        ResponseError {
+            code: err.error_code(),
+            message: err.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use no_captcha::{MockPredictor, Prediction};
+
+    #[test]
+    fn answers_each_line() -> errors::Result<()> {
+        let mock = MockPredictor::new().with_image("bus", Prediction::new(0.9, 0.1));
+        let input = concat!(
+            r#"{"id": 1, "challenge": "bus", "images": ["YnVz"]}"#,
+            "\nnot json\n",
+            r#"{"id": "b", "challenge": "bus", "images": ["%"]}"#,
+            "\n"
+        );
+        let mut output = Vec::new();
+        serve(&mock, input.as_bytes(), &mut output)?;
+
+        let lines: Vec<Value> = output
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_slice)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["id"], 1);
+        assert_eq!(lines[0]["predictions"][0]["match"], true);
+        assert_eq!(lines[1]["error"]["code"], "invalid_json");
+        assert_eq!(lines[2]["id"], "b");
+        assert_eq!(lines[2]["error"]["code"], "invalid_base64");
+        Ok(())
+    }
+}
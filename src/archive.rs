@@ -0,0 +1,187 @@
+use crate::{cache, errors, integrity, resize};
+use flate2::read::GzDecoder;
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+/// ARCHIVE_EXTENSIONS are the suffixes of model archives load_from_models_dir accepts beside
+/// model directories, e.g. bus.tar.gz
+const ARCHIVE_EXTENSIONS: &[&str] = &[".tar.gz", ".tgz", ".zip"];
+
+/// archive_stem returns the name an archive's model is for, "bus" for "bus.tar.gz", or None if
+/// 'file_name' isn't an archive
+pub(crate) fn archive_stem(file_name: &str) -> Option<&str> {
+    ARCHIVE_EXTENSIONS
+        .iter()
+        .find_map(|extension| file_name.strip_suffix(extension))
+        .filter(|stem| !stem.is_empty())
+}
+
+/// extract unpacks a model archive under the per-user cache::dir and returns the model
+/// directory inside it. The destination is named after the archive's SHA-256, so an archive
+/// already extracted, by this process or an earlier one, is not unpacked again and a changed
+/// archive never reuses stale files. An earlier extraction is only reused while it still has
+/// the integrity::fingerprint recorded beside it when it was unpacked; one that was altered
+/// since is unpacked again. An archive may hold the model at its root or inside a single top
+/// level directory
+pub(crate) fn extract(archive: &Path) -> errors::Result<PathBuf> {
+    let mut digest = integrity::sha256_file(archive)?;
+    digest.truncate(24);
+    let name = file_name(archive);
+    let extracted = format!("{}-{}", archive_stem(&name).unwrap_or(&name), digest);
+    let root = cache::dir()?.join("archives");
+    cache::private_dir(&root)?;
+    let dir = root.join(&extracted);
+    let marker = root.join(format!("{}.fingerprint", extracted));
+
+    if !is_intact(&dir, &marker)? {
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        // unpacked beside the destination and renamed into place, so a crash mid-extraction
+        // never leaves a partial model that would be trusted later
+        let partial = cache::partial_path(&dir);
+        fs::create_dir_all(&partial)?;
+        if let Err(err) = unpack(archive, &partial) {
+            let _ = fs::remove_dir_all(&partial);
+            return Err(err);
+        }
+        // renaming keeps every mtime, so the fingerprint holds for the destination
+        let partial_marker = cache::partial_path(&marker);
+        fs::write(&partial_marker, integrity::fingerprint(&partial)?)?;
+        fs::rename(&partial_marker, &marker)?;
+        if let Err(err) = fs::rename(&partial, &dir) {
+            let _ = fs::remove_dir_all(&partial);
+            // another process may have won the race with the same archive
+            if !dir.exists() {
+                return Err(err.into());
+            }
+        }
+    }
+    model_root(&dir)
+}
+
+/// is_intact reports whether 'dir' holds an earlier extraction that still has the fingerprint
+/// recorded in 'marker'
+fn is_intact(dir: &Path, marker: &Path) -> errors::Result<bool> {
+    if !dir.is_dir() {
+        return Ok(false);
+    }
+    let recorded = match fs::read_to_string(marker) {
+        Ok(recorded) => recorded,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err.into()),
+    };
+    Ok(integrity::fingerprint(dir)? == recorded)
+}
+
+fn unpack(archive: &Path, dest: &Path) -> errors::Result<()> {
+    let name = file_name(archive);
+    if name.ends_with(".zip") {
+        zip::ZipArchive::new(File::open(archive)?)?.extract(dest)?;
+    } else {
+        // unpack_in refuses entries that would land outside 'dest'
+        let mut tar = tar::Archive::new(GzDecoder::new(File::open(archive)?));
+        for entry in tar.entries()? {
+            let _ = entry?.unpack_in(dest)?;
+        }
+    }
+    Ok(())
+}
+
+/// has_manifest tells whether an archive holds a resize::MANIFEST beside its model, at its root
+/// or inside a single top level directory, without unpacking it
+pub(crate) fn has_manifest(archive: &Path) -> errors::Result<bool> {
+    let is_manifest = |entry: &Path| {
+        entry.file_name() == Some(resize::MANIFEST.as_ref())
+            && entry.components().filter(|c| c.as_os_str() != ".").count() <= 2
+    };
+    if file_name(archive).ends_with(".zip") {
+        let zip = zip::ZipArchive::new(File::open(archive)?)?;
+        return Ok(zip.file_names().any(|name| is_manifest(Path::new(name))));
+    }
+    let mut tar = tar::Archive::new(GzDecoder::new(File::open(archive)?));
+    for entry in tar.entries()? {
+        if is_manifest(&entry?.path()?) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// model_root descends into a lone top level directory when the archive wrapped its model in
+/// one (bus.tar.gz holding bus/saved_model.pb)
+fn model_root(dir: &Path) -> errors::Result<PathBuf> {
+    if dir.join("saved_model.pb").exists() {
+        return Ok(dir.to_path_buf());
+    }
+    let mut entries = Vec::new();
+    for entry in dir.read_dir()? {
+        entries.push(entry?);
+    }
+    match entries.as_slice() {
+        [only] if only.file_type()?.is_dir() => Ok(only.path()),
+        _ => Ok(dir.to_path_buf()),
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+
+    #[test]
+    fn recognizes_archive_names() {
+        assert_eq!(archive_stem("bus.tar.gz"), Some("bus"));
+        assert_eq!(archive_stem("traffic_lights.zip"), Some("traffic_lights"));
+        assert_eq!(archive_stem("bus"), None);
+        assert_eq!(archive_stem(".tgz"), None);
+    }
+
+    #[test]
+    fn extracts_wrapped_models() -> errors::Result<()> {
+        let dir = std::env::temp_dir().join(format!("nocap-archive-test-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let archive = dir.join("bus.tar.gz");
+
+        let mut builder = tar::Builder::new(GzEncoder::new(
+            File::create(&archive)?,
+            Compression::default(),
+        ));
+        let contents = b"graph";
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, "bus/saved_model.pb", &contents[..])?;
+        let manifest = br#"{"input_width": 224, "input_height": 224}"#;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(manifest.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, "bus/manifest.json", &manifest[..])?;
+        builder.into_inner()?.finish()?;
+
+        assert!(has_manifest(&archive)?);
+        let model = extract(&archive)?;
+        assert_eq!(fs::read(model.join("saved_model.pb"))?, contents);
+        assert_eq!(extract(&archive)?, model);
+        // an extraction altered since it was unpacked is unpacked again
+        fs::write(model.join("saved_model.pb"), b"tampered")?;
+        assert_eq!(extract(&archive)?, model);
+        assert_eq!(fs::read(model.join("saved_model.pb"))?, contents);
+        fs::remove_dir_all(&dir)?;
+        if let Some(extracted) = model.parent() {
+            fs::remove_dir_all(extracted)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+use crate::{ensemble::Aggregation, errors, Prediction};
+use image::{imageops::FilterType, DynamicImage, GenericImageView, ImageOutputFormat};
+use serde_derive::Deserialize;
+
+/// Augmentation is one transformation of an image fed to the model alongside the original
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Augmentation {
+    /// Identity passes the image through unchanged
+    Identity,
+    HorizontalFlip,
+    VerticalFlip,
+    /// CenterCrop keeps the central 'scale' (0.0 to 1.0) of each side and scales it back up to
+    /// the original size
+    CenterCrop {
+        scale: f32,
+    },
+}
+
+impl Augmentation {
+    fn apply(self, image: &DynamicImage) -> DynamicImage {
+        match self {
+            Augmentation::Identity => image.clone(),
+            Augmentation::HorizontalFlip => image.fliph(),
+            Augmentation::VerticalFlip => image.flipv(),
+            Augmentation::CenterCrop { scale } => {
+                let (width, height) = image.dimensions();
+                let scale = scale.max(0.0).min(1.0);
+                let crop_width = ((width as f32 * scale) as u32).max(1);
+                let crop_height = ((height as f32 * scale) as u32).max(1);
+                image
+                    .crop_imm(
+                        (width - crop_width) / 2,
+                        (height - crop_height) / 2,
+                        crop_width,
+                        crop_height,
+                    )
+                    .resize_exact(width, height, FilterType::Triangle)
+            }
+        }
+    }
+}
+
+/// TestTimeAugmentation runs every augmentation of an image through the model and aggregates
+/// the scores, trading a run per augmentation for steadier predictions
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TestTimeAugmentation {
+    pub augmentations: Vec<Augmentation>,
+    #[serde(default)]
+    pub aggregation: Aggregation,
+}
+
+impl Default for TestTimeAugmentation {
+    fn default() -> TestTimeAugmentation {
+        TestTimeAugmentation {
+            augmentations: vec![Augmentation::Identity, Augmentation::HorizontalFlip],
+            aggregation: Aggregation::Mean,
+        }
+    }
+}
+
+impl TestTimeAugmentation {
+    /// expand returns every augmentation of every image, PNG encoded, grouped by image
+    pub(crate) fn expand(&self, images: &[Vec<u8>]) -> errors::Result<Vec<Vec<u8>>> {
+        let mut expanded = Vec::with_capacity(images.len() * self.augmentations.len());
+        for image in images {
+            let decoded = image::load_from_memory(image)?;
+            for augmentation in &self.augmentations {
+                let mut encoded = Vec::new();
+                augmentation
+                    .apply(&decoded)
+                    .write_to(&mut encoded, ImageOutputFormat::Png)?;
+                expanded.push(encoded);
+            }
+        }
+        Ok(expanded)
+    }
+
+    /// collapse aggregates the predictions for an expanded batch back into one per image
+    pub(crate) fn collapse(&self, predictions: &[Prediction]) -> Vec<Prediction> {
+        predictions
+            .chunks(self.augmentations.len().max(1))
+            .map(|group| self.aggregation.aggregate(group))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn augmentations_keep_dimensions() {
+        let image = DynamicImage::new_rgb8(12, 8);
+        for augmentation in &[
+            Augmentation::Identity,
+            Augmentation::HorizontalFlip,
+            Augmentation::VerticalFlip,
+            Augmentation::CenterCrop { scale: 0.5 },
+            Augmentation::CenterCrop { scale: 0.0 },
+        ] {
+            assert_eq!(augmentation.apply(&image).dimensions(), (12, 8));
+        }
+    }
+
+    #[test]
+    fn expand_and_collapse() -> errors::Result<()> {
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(4, 4).write_to(&mut png, ImageOutputFormat::Png)?;
+        let images = vec![png; 2];
+        let tta = TestTimeAugmentation::default();
+        assert_eq!(tta.expand(&images)?.len(), 4);
+
+        let predictions = [0.2, 0.4, 0.6, 1.0]
+            .iter()
+            .map(|&affirmative_confidence| Prediction {
+                affirmative_confidence,
+                negative_confidence: 1.0 - affirmative_confidence,
+            })
+            .collect::<Vec<_>>();
+        let collapsed = tta.collapse(&predictions);
+        assert_eq!(collapsed.len(), 2);
+        assert!((collapsed[0].affirmative_confidence - 0.3).abs() < 1e-6);
+        assert!((collapsed[1].affirmative_confidence - 0.8).abs() < 1e-6);
+        Ok(())
+    }
+}
@@ -0,0 +1,131 @@
+use crate::{errors, CaptchaChallenge, Predictor};
+use serde_derive::Serialize;
+use std::time::{Duration, Instant};
+
+/// Benchmark times repeated predictions of one image, for sizing hardware without criterion.
+/// The warm-up predictions, which pay for lazy loading and TensorFlow's first-run graph
+/// optimizations, are reported separately from the measured ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Benchmark {
+    pub warmup: usize,
+    pub iterations: usize,
+    /// batch_size is how many copies of the image each prediction scores
+    pub batch_size: usize,
+}
+
+impl Default for Benchmark {
+    fn default() -> Benchmark {
+        Benchmark {
+            warmup: 10,
+            iterations: 1000,
+            batch_size: 1,
+        }
+    }
+}
+
+/// BenchmarkReport summarizes a Benchmark. Latencies are per prediction, in milliseconds
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BenchmarkReport {
+    pub challenge: CaptchaChallenge,
+    pub iterations: usize,
+    pub batch_size: usize,
+    /// first_ms is the first warm-up prediction, usually much the slowest
+    pub first_ms: f64,
+    pub warmup_mean_ms: f64,
+    pub mean_ms: f64,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+    /// images_per_sec is the throughput over the measured predictions
+    pub images_per_sec: f64,
+}
+
+impl Benchmark {
+    /// run predicts 'image' against 'challenge' warmup + iterations times
+    pub fn run<P>(
+        &self,
+        predictor: &P,
+        challenge: &CaptchaChallenge,
+        image: &[u8],
+    ) -> errors::Result<BenchmarkReport>
+    where
+        P: Predictor + ?Sized,
+    {
+        let batch = vec![image.to_vec(); self.batch_size.max(1)];
+        let time = || -> errors::Result<Duration> {
+            let started = Instant::now();
+            let _ = predictor.predict_batch(challenge, batch.clone())?;
+            Ok(started.elapsed())
+        };
+        let warmup = (0..self.warmup)
+            .map(|_| time())
+            .collect::<errors::Result<Vec<_>>>()?;
+        let mut latencies = (0..self.iterations.max(1))
+            .map(|_| time())
+            .collect::<errors::Result<Vec<_>>>()?;
+        latencies.sort();
+        let total: Duration = latencies.iter().sum();
+        Ok(BenchmarkReport {
+            challenge: challenge.clone(),
+            iterations: latencies.len(),
+            batch_size: batch.len(),
+            first_ms: warmup.first().map_or(0.0, |first| millis(*first)),
+            warmup_mean_ms: mean_ms(&warmup),
+            mean_ms: mean_ms(&latencies),
+            p50_ms: percentile_ms(&latencies, 50.0),
+            p90_ms: percentile_ms(&latencies, 90.0),
+            p99_ms: percentile_ms(&latencies, 99.0),
+            max_ms: latencies.last().map_or(0.0, |max| millis(*max)),
+            images_per_sec: (latencies.len() * batch.len()) as f64
+                / total.as_secs_f64().max(f64::EPSILON),
+        })
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn mean_ms(latencies: &[Duration]) -> f64 {
+    let total: Duration = latencies.iter().sum();
+    millis(total) / latencies.len().max(1) as f64
+}
+
+/// percentile_ms is the nearest-rank percentile of 'sorted'
+fn percentile_ms(sorted: &[Duration], percentile: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (percentile / 100.0 * sorted.len() as f64).ceil() as usize;
+    millis(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockPredictor;
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let sorted: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert!((percentile_ms(&sorted, 50.0) - 50.0).abs() < 1e-9);
+        assert!((percentile_ms(&sorted, 99.0) - 99.0).abs() < 1e-9);
+        assert!((percentile_ms(&sorted[..1], 90.0) - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn reports_every_measured_prediction() -> errors::Result<()> {
+        let mock = MockPredictor::new();
+        let report = Benchmark {
+            warmup: 2,
+            iterations: 20,
+            batch_size: 4,
+        }
+        .run(&mock, &CaptchaChallenge::Bus, "tile")?;
+        assert_eq!((report.iterations, report.batch_size), (20, 4));
+        assert!(report.p50_ms <= report.p99_ms && report.p99_ms <= report.max_ms);
+        assert_eq!(mock.calls().len(), 22);
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+use crate::errors;
+use std::{
+    collections::hash_map::RandomState,
+    env, fs,
+    hash::{BuildHasher, Hasher},
+    io,
+    path::{Path, PathBuf},
+    process,
+};
+
+/// dir is the per-user directory models are extracted under: $XDG_CACHE_HOME/nocap,
+/// ~/.cache/nocap, or failing both nocap-cache in the system temp directory. It is made
+/// private to the current user (see private_dir)
+pub(crate) fn dir() -> errors::Result<PathBuf> {
+    let base = env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var_os("HOME")
+                .filter(|home| !home.is_empty())
+                .map(|home| PathBuf::from(home).join(".cache"))
+        });
+    let dir = match base {
+        Some(base) => base.join("nocap"),
+        None => env::temp_dir().join("nocap-cache"),
+    };
+    private_dir(&dir)?;
+    Ok(dir)
+}
+
+/// private_dir creates 'dir', along with any missing parents, and makes sure it is a directory
+/// only the current user can enter (0700). Models are loaded from what is extracted there, and
+/// in a shared location another user could have created the directory first or swapped its
+/// contents. Only the owner may change a directory's mode, so one created by someone else is
+/// refused rather than used
+pub(crate) fn private_dir(dir: &Path) -> errors::Result<()> {
+    if let Some(parent) = dir.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match create_private(dir) {
+        Err(err) if err.kind() != io::ErrorKind::AlreadyExists => return Err(err.into()),
+        _ => {}
+    }
+    // symlink_metadata, so a link planted in its place isn't followed
+    if !fs::symlink_metadata(dir)?.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and isn't a directory", dir.display()),
+        )
+        .into());
+    }
+    restrict(dir)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn create_private(dir: &Path) -> io::Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+    fs::DirBuilder::new().mode(0o700).create(dir)
+}
+
+#[cfg(not(unix))]
+fn create_private(dir: &Path) -> io::Result<()> {
+    fs::create_dir(dir)
+}
+
+#[cfg(unix)]
+fn restrict(dir: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o700))
+}
+
+#[cfg(not(unix))]
+fn restrict(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// partial_path is a name beside 'path' to write it under before renaming it into place. It is
+/// unique to this process and call, so concurrent writers of the same destination never write
+/// into, or rename away, each other's partial copy
+pub(crate) fn partial_path(path: &Path) -> PathBuf {
+    let random = RandomState::new().build_hasher().finish();
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(format!(".{}-{:016x}.partial", process::id(), random));
+    PathBuf::from(partial)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn creates_private_directories() -> errors::Result<()> {
+        let root = env::temp_dir().join(format!("nocap-cache-test-{}", process::id()));
+        let dir = root.join("models");
+        private_dir(&dir)?;
+        private_dir(&dir)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&dir)?.permissions().mode() & 0o777, 0o700);
+        }
+
+        let file = root.join("file");
+        fs::write(&file, b"")?;
+        assert!(private_dir(&file).is_err());
+
+        let destination = dir.join("saved_model.pb");
+        let partial = partial_path(&destination);
+        assert_eq!(partial.parent(), Some(dir.as_path()));
+        assert_ne!(partial, partial_path(&destination));
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+#[cfg(feature = "tensorflow")]
+use crate::{dataset, embedding::Embedder, errors};
+#[cfg(feature = "tensorflow")]
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// MAX_ITERATIONS bounds how many rounds k-means refines its clusters for
+const MAX_ITERATIONS: usize = 100;
+
+/// BATCH_SIZE bounds how many images are embedded at once
+#[cfg(feature = "tensorflow")]
+const BATCH_SIZE: usize = 64;
+
+/// kmeans groups 'points' into at most 'k' clusters by cosine similarity, returning each
+/// point's cluster. Seeding picks the points farthest apart, so the result is deterministic.
+/// A 'k' of 0 is taken as 1
+pub fn kmeans(points: &[Vec<f32>], k: usize) -> Vec<usize> {
+    if points.is_empty() {
+        return Vec::new();
+    }
+    let points: Vec<Vec<f32>> = points.iter().map(|point| normalized(point)).collect();
+    let k = k.max(1).min(points.len());
+    let mut centroids = vec![points[0].clone()];
+    while centroids.len() < k {
+        let farthest = points
+            .iter()
+            .max_by(|a, b| {
+                let (a, b) = (nearest(a, &centroids).1, nearest(b, &centroids).1);
+                a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal)
+            })
+            .cloned()
+            .unwrap_or_default();
+        centroids.push(farthest);
+    }
+    let mut assignments = vec![usize::MAX; points.len()];
+    for _ in 0..MAX_ITERATIONS {
+        let mut changed = false;
+        for (assignment, point) in assignments.iter_mut().zip(&points) {
+            let (cluster, _) = nearest(point, &centroids);
+            changed |= *assignment != cluster;
+            *assignment = cluster;
+        }
+        if !changed {
+            break;
+        }
+        for (cluster, centroid) in centroids.iter_mut().enumerate() {
+            let mut sum = vec![0.0; centroid.len()];
+            for (point, _) in points
+                .iter()
+                .zip(&assignments)
+                .filter(|(_, assignment)| **assignment == cluster)
+            {
+                for (total, value) in sum.iter_mut().zip(point) {
+                    *total += value;
+                }
+            }
+            // a cluster left without points keeps its centroid
+            if sum.iter().any(|value| *value != 0.0) {
+                *centroid = normalized(&sum);
+            }
+        }
+    }
+    assignments
+}
+
+/// nearest is the centroid closest to 'point' and its cosine distance, for unit vectors
+fn nearest(point: &[f32], centroids: &[Vec<f32>]) -> (usize, f32) {
+    centroids
+        .iter()
+        .map(|centroid| 1.0 - point.iter().zip(centroid).map(|(a, b)| a * b).sum::<f32>())
+        .enumerate()
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+        .unwrap_or((0, 0.0))
+}
+
+fn normalized(vector: &[f32]) -> Vec<f32> {
+    let length = vector.iter().map(|value| value * value).sum::<f32>().sqrt();
+    if length == 0.0 {
+        return vector.to_vec();
+    }
+    vector.iter().map(|value| value / length).collect()
+}
+
+/// Cluster is one group of images copied out by cluster_images
+#[cfg(feature = "tensorflow")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cluster {
+    pub dir: PathBuf,
+    pub images: usize,
+}
+
+/// cluster_images embeds every image in 'dir' with 'embedder', groups them with kmeans and
+/// copies each into out/cluster_<n>/, so similar tiles can be labeled in bulk. The images in
+/// 'dir' are left where they are
+#[cfg(feature = "tensorflow")]
+pub fn cluster_images<P, Q>(
+    embedder: &Embedder,
+    dir: P,
+    out: Q,
+    k: usize,
+) -> errors::Result<Vec<Cluster>>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let mut paths = Vec::new();
+    for entry in dir.as_ref().read_dir()? {
+        let path = entry?.path();
+        if path.is_file() && dataset::is_image(&path)? {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    let mut embeddings = Vec::with_capacity(paths.len());
+    for batch in paths.chunks(BATCH_SIZE) {
+        let images = batch
+            .iter()
+            .map(dataset::read_image)
+            .collect::<errors::Result<Vec<_>>>()?;
+        embeddings.extend(embedder.embed(&images)?);
+    }
+    let assignments = kmeans(&embeddings, k);
+    let mut clusters: Vec<Cluster> = (0..k.max(1).min(paths.len()))
+        .map(|index| Cluster {
+            dir: out.as_ref().join(format!("cluster_{}", index)),
+            images: 0,
+        })
+        .collect();
+    for (path, cluster) in paths.iter().zip(assignments) {
+        let cluster = &mut clusters[cluster];
+        fs::create_dir_all(&cluster.dir)?;
+        let _ = fs::copy(path, cluster.dir.join(path.file_name().unwrap_or_default()))?;
+        cluster.images += 1;
+    }
+    clusters.retain(|cluster| cluster.images > 0);
+    Ok(clusters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn separates_distinct_directions() {
+        let points = vec![
+            vec![1.0, 0.1],
+            vec![0.1, 1.0],
+            vec![2.0, 0.0],
+            vec![0.0, 3.0],
+            vec![0.9, 0.2],
+        ];
+        let assignments = kmeans(&points, 2);
+        assert_eq!(assignments[0], assignments[2]);
+        assert_eq!(assignments[0], assignments[4]);
+        assert_eq!(assignments[1], assignments[3]);
+        assert_ne!(assignments[0], assignments[1]);
+        assert!(kmeans(&[], 3).is_empty());
+    }
+}
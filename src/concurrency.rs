@@ -0,0 +1,161 @@
+use crate::{errors, CaptchaChallenge};
+use serde_derive::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::{Condvar, Mutex, PoisonError},
+};
+
+/// ConcurrencyLimits caps the inferences a registry runs at once, so one busy challenge can't
+/// occupy every core of a CPU-only host. All limits are off by default
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct ConcurrencyLimits {
+    /// global caps the inferences running at once across every challenge
+    pub global: Option<usize>,
+    /// per_challenge caps the inferences running at once for any one challenge
+    pub per_challenge: Option<usize>,
+    /// overrides replaces per_challenge for the challenges it names
+    pub overrides: HashMap<CaptchaChallenge, usize>,
+    /// overflow is what happens to an inference over a limit
+    pub overflow: Overflow,
+}
+
+/// Overflow decides between waiting for a limit and failing with Error::Overloaded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    #[default]
+    Queue,
+    Reject,
+}
+
+impl ConcurrencyLimits {
+    fn limit_for(&self, challenge: &CaptchaChallenge) -> Option<usize> {
+        self.overrides
+            .get(challenge)
+            .copied()
+            .or(self.per_challenge)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Running {
+    total: usize,
+    by_challenge: HashMap<CaptchaChallenge, usize>,
+    /// waiting counts the inferences queued for a limit, by challenge
+    waiting: HashMap<CaptchaChallenge, usize>,
+}
+
+/// Limiter holds inferences to ConcurrencyLimits
+#[derive(Debug, Default)]
+pub(crate) struct Limiter {
+    limits: ConcurrencyLimits,
+    running: Mutex<Running>,
+    finished: Condvar,
+}
+
+impl Limiter {
+    pub(crate) fn new(limits: ConcurrencyLimits) -> Limiter {
+        Limiter {
+            limits,
+            ..Limiter::default()
+        }
+    }
+
+    /// acquire waits until an inference for 'challenge' fits within the limits, or fails
+    /// straight away if it doesn't and they reject overflow. The inference counts against the
+    /// limits until the returned slot is dropped
+    pub(crate) fn acquire(&self, challenge: &CaptchaChallenge) -> errors::Result<Slot<'_>> {
+        let mut running = self.running.lock()?;
+        if !self.fits(&running, challenge) {
+            if self.limits.overflow == Overflow::Reject {
+                return Err(errors::Error::Overloaded(challenge.clone()));
+            }
+            *running.waiting.entry(challenge.clone()).or_insert(0) += 1;
+            while !self.fits(&running, challenge) {
+                running = self.finished.wait(running)?;
+            }
+            if let Some(waiting) = running.waiting.get_mut(challenge) {
+                *waiting -= 1;
+            }
+        }
+        running.total += 1;
+        *running.by_challenge.entry(challenge.clone()).or_insert(0) += 1;
+        Ok(Slot {
+            limiter: self,
+            challenge: challenge.clone(),
+        })
+    }
+
+    /// counts are the inferences running for 'challenge' and those waiting for a limit
+    pub(crate) fn counts(&self, challenge: &CaptchaChallenge) -> (usize, usize) {
+        let running = self.running.lock().unwrap_or_else(PoisonError::into_inner);
+        let count =
+            |counts: &HashMap<CaptchaChallenge, usize>| counts.get(challenge).copied().unwrap_or(0);
+        (count(&running.by_challenge), count(&running.waiting))
+    }
+
+    fn fits(&self, running: &Running, challenge: &CaptchaChallenge) -> bool {
+        let for_challenge = running.by_challenge.get(challenge).copied().unwrap_or(0);
+        // a limit of zero admits one inference at a time rather than none ever
+        self.limits
+            .global
+            .is_none_or(|limit| running.total < limit.max(1))
+            && self
+                .limits
+                .limit_for(challenge)
+                .is_none_or(|limit| for_challenge < limit.max(1))
+    }
+}
+
+pub(crate) struct Slot<'a> {
+    limiter: &'a Limiter,
+    challenge: CaptchaChallenge,
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        let mut running = self
+            .limiter
+            .running
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        running.total -= 1;
+        if let Some(count) = running.by_challenge.get_mut(&self.challenge) {
+            *count -= 1;
+        }
+        drop(running);
+        self.limiter.finished.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_over_either_limit() -> errors::Result<()> {
+        let mut overrides = HashMap::new();
+        let _ = overrides.insert(CaptchaChallenge::Taxis, 2);
+        let limiter = Limiter::new(ConcurrencyLimits {
+            global: Some(3),
+            per_challenge: Some(1),
+            overrides,
+            overflow: Overflow::Reject,
+        });
+        let bus = limiter.acquire(&CaptchaChallenge::Bus)?;
+        assert!(matches!(
+            limiter.acquire(&CaptchaChallenge::Bus),
+            Err(errors::Error::Overloaded(CaptchaChallenge::Bus))
+        ));
+        let _taxis = (
+            limiter.acquire(&CaptchaChallenge::Taxis)?,
+            limiter.acquire(&CaptchaChallenge::Taxis)?,
+        );
+        assert!(limiter.acquire(&CaptchaChallenge::Cars).is_err());
+        assert_eq!(limiter.counts(&CaptchaChallenge::Taxis), (2, 0));
+        drop(bus);
+        let _cars = limiter.acquire(&CaptchaChallenge::Cars)?;
+        Ok(())
+    }
+}
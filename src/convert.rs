@@ -0,0 +1,167 @@
+use crate::{errors, export, format, CaptchaChallenge, CaptchaModel};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// ONNX_SCORES runs a converted model with onnxruntime over the images given as arguments and
+/// prints the scores as JSON, one [affirmative, negative] pair per image
+const ONNX_SCORES: &str = r#"
+import json, sys
+import numpy as np
+import onnxruntime as ort
+
+session = ort.InferenceSession(sys.argv[1])
+images = np.array([open(path, "rb").read() for path in sys.argv[2:]], dtype=object)
+outputs = session.run(None, {session.get_inputs()[0].name: images})
+names = [output.name for output in session.get_outputs()]
+scores = next((o for n, o in zip(names, outputs) if n.startswith("scores")), outputs[0])
+print(json.dumps(np.asarray(scores, dtype=float).reshape(len(images), -1).tolist()))
+"#;
+
+/// OnnxConversion configures convert_to_onnx. Conversion shells out to tf2onnx, and
+/// validation to onnxruntime, both of which must be importable by 'python'
+#[derive(Debug, Clone)]
+pub struct OnnxConversion {
+    pub python: PathBuf,
+    pub opset: u32,
+    /// fixtures is a directory of images to compare the converted models against the
+    /// originals on. Without it, models are converted but not validated
+    pub fixtures: Option<PathBuf>,
+}
+
+impl Default for OnnxConversion {
+    fn default() -> OnnxConversion {
+        OnnxConversion {
+            python: PathBuf::from("python3"),
+            opset: 13,
+            fixtures: None,
+        }
+    }
+}
+
+/// ConvertedModel is one model written by convert_to_onnx
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConvertedModel {
+    pub challenge: CaptchaChallenge,
+    pub path: PathBuf,
+    /// max_score_delta is the largest difference between the original and converted model's
+    /// scores over the fixtures, when validated
+    pub max_score_delta: Option<f32>,
+}
+
+/// convert_to_onnx converts every model under 'models_dir' into '<out_dir>/<name>.onnx', named
+/// the same way export::export_tf_serving names them, and validates each against the fixtures
+/// if given
+pub fn convert_to_onnx<P, Q>(
+    models_dir: P,
+    out_dir: Q,
+    conversion: &OnnxConversion,
+) -> errors::Result<Vec<ConvertedModel>>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let out_dir = out_dir.as_ref();
+    fs::create_dir_all(out_dir)?;
+    let fixtures = match &conversion.fixtures {
+        Some(dir) => fixture_paths(dir)?,
+        None => Vec::new(),
+    };
+
+    let mut converted = Vec::new();
+    for (challenge, name, member) in export::named_models(models_dir.as_ref())? {
+        let path = out_dir.join(format!("{}.onnx", name));
+        run_tf2onnx(conversion, &member, &path)?;
+        let max_score_delta = if fixtures.is_empty() {
+            None
+        } else {
+            Some(score_delta(conversion, &member, &path, &fixtures)?)
+        };
+        converted.push(ConvertedModel {
+            challenge,
+            path,
+            max_score_delta,
+        });
+    }
+    converted.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(converted)
+}
+
+fn run_tf2onnx(
+    conversion: &OnnxConversion,
+    saved_model: &Path,
+    output: &Path,
+) -> errors::Result<()> {
+    let result = Command::new(&conversion.python)
+        .args(&["-m", "tf2onnx.convert", "--saved-model"])
+        .arg(saved_model)
+        .arg("--output")
+        .arg(output)
+        .arg("--opset")
+        .arg(conversion.opset.to_string())
+        .output()?;
+    if !result.status.success() {
+        return Err(errors::Error::ConversionFailed(format!(
+            "tf2onnx failed on {}: {}",
+            saved_model.display(),
+            String::from_utf8_lossy(&result.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+/// score_delta runs the fixtures through both the SavedModel and the converted model and
+/// returns the largest difference between any of their scores
+fn score_delta(
+    conversion: &OnnxConversion,
+    saved_model: &Path,
+    onnx: &Path,
+    fixtures: &[PathBuf],
+) -> errors::Result<f32> {
+    let mut images = Vec::with_capacity(fixtures.len());
+    for fixture in fixtures {
+        images.push(fs::read(fixture)?);
+    }
+    let expected = CaptchaModel::load(saved_model)?.run(&format::for_model(&images)?)?;
+
+    let result = Command::new(&conversion.python)
+        .arg("-c")
+        .arg(ONNX_SCORES)
+        .arg(onnx)
+        .args(fixtures)
+        .output()?;
+    if !result.status.success() {
+        return Err(errors::Error::ConversionFailed(format!(
+            "onnxruntime failed on {}: {}",
+            onnx.display(),
+            String::from_utf8_lossy(&result.stderr).trim()
+        )));
+    }
+    let actual: Vec<Vec<f32>> = serde_json::from_slice(&result.stdout)?;
+    if actual.len() != expected.len() || actual.iter().any(|scores| scores.len() != 2) {
+        return Err(errors::Error::MalformedOutput);
+    }
+    Ok(expected
+        .iter()
+        .zip(&actual)
+        .map(|(expected, actual)| {
+            (expected.affirmative_confidence() - actual[0])
+                .abs()
+                .max((expected.negative_confidence() - actual[1]).abs())
+        })
+        .fold(0.0, f32::max))
+}
+
+fn fixture_paths(dir: &Path) -> errors::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in dir.read_dir()? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
@@ -0,0 +1,469 @@
+use crate::{errors, format::TileFormat, CaptchaChallenge, GridSize};
+use image::GenericImageView;
+use serde_derive::Serialize;
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+/// LABEL_DIRS are the folders a challenge's images are sorted into, with whether their images
+/// show the challenge's object
+pub const LABEL_DIRS: &[(&str, bool)] = &[("matches", true), ("not matches", false)];
+
+/// IMBALANCE_RATIO is how many times more images one label may have than the other before
+/// validate warns about it
+const IMBALANCE_RATIO: usize = 4;
+
+/// LabeledImage is one image from a dataset laid out like test_data/, with whether it shows
+/// the challenge's object
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabeledImage {
+    pub path: PathBuf,
+    pub matches: bool,
+}
+
+/// labeled_images lists the images for 'challenge' across every grid size of a dataset laid
+/// out like test_data/:
+///
+/// ```text
+/// <root>/<grid>/<challenge with spaces>/matches/<image>
+/// <root>/<grid>/<challenge with spaces>/not matches/<image>
+/// ```
+pub fn labeled_images<P>(root: P, challenge: &CaptchaChallenge) -> errors::Result<Vec<LabeledImage>>
+where
+    P: AsRef<Path>,
+{
+    let mut images = stream_labeled_images(root, challenge)?.collect::<errors::Result<Vec<_>>>()?;
+    images.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(images)
+}
+
+/// stream_labeled_images walks the same images as labeled_images, but lazily and in directory
+/// order, holding one directory listing open at a time rather than every path in memory
+pub fn stream_labeled_images<P>(
+    root: P,
+    challenge: &CaptchaChallenge,
+) -> errors::Result<LabeledImages>
+where
+    P: AsRef<Path>,
+{
+    let folder = challenge.to_string().replace('_', " ");
+    let mut dirs = Vec::new();
+    for grid in subdirectories(root.as_ref())? {
+        for (label_dir, matches) in LABEL_DIRS {
+            dirs.push((grid.join(&folder).join(label_dir), *matches));
+        }
+    }
+    // popped from the back, so reversed to walk the grids in order
+    dirs.reverse();
+    Ok(LabeledImages {
+        dirs,
+        current: None,
+    })
+}
+
+/// LabeledImages is the iterator returned by stream_labeled_images
+#[derive(Debug)]
+pub struct LabeledImages {
+    dirs: Vec<(PathBuf, bool)>,
+    current: Option<(fs::ReadDir, bool)>,
+}
+
+impl Iterator for LabeledImages {
+    type Item = errors::Result<LabeledImage>;
+
+    fn next(&mut self) -> Option<errors::Result<LabeledImage>> {
+        loop {
+            if let Some((entries, matches)) = &mut self.current {
+                let matches = *matches;
+                for entry in entries {
+                    let entry = match entry {
+                        Ok(entry) => entry,
+                        Err(err) => return Some(Err(err.into())),
+                    };
+                    match entry.file_type() {
+                        Ok(file_type) if file_type.is_file() => {
+                            return Some(Ok(LabeledImage {
+                                path: entry.path(),
+                                matches,
+                            }))
+                        }
+                        Ok(_) => {}
+                        Err(err) => return Some(Err(err.into())),
+                    }
+                }
+            }
+            self.current = None;
+            let (dir, matches) = self.dirs.pop()?;
+            if dir.is_dir() {
+                match dir.read_dir() {
+                    Ok(entries) => self.current = Some((entries, matches)),
+                    Err(err) => return Some(Err(err.into())),
+                }
+            }
+        }
+    }
+}
+
+/// read_image loads an image the way predictions take it
+pub fn read_image<P>(path: P) -> errors::Result<Vec<u8>>
+where
+    P: AsRef<Path>,
+{
+    Ok(fs::read(path)?)
+}
+
+/// init creates the skeleton of a dataset laid out like test_data/, a folder for each label of
+/// every known challenge in both grid sizes, returning the folders it had to create
+pub fn init<P>(root: P) -> errors::Result<Vec<PathBuf>>
+where
+    P: AsRef<Path>,
+{
+    let mut created = Vec::new();
+    for grid in &[GridSize::ThreeByThree, GridSize::FourByFour] {
+        for challenge in CaptchaChallenge::all() {
+            let folder = challenge.to_string().replace('_', " ");
+            for (label_dir, _) in LABEL_DIRS {
+                let dir = root
+                    .as_ref()
+                    .join(grid.to_string())
+                    .join(&folder)
+                    .join(label_dir);
+                if !dir.is_dir() {
+                    fs::create_dir_all(&dir)?;
+                    created.push(dir);
+                }
+            }
+        }
+    }
+    Ok(created)
+}
+
+/// Issue is something validate found wrong with a dataset
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Issue {
+    /// UnknownGrid is a top level folder named after no GridSize
+    UnknownGrid(PathBuf),
+    /// UnknownChallenge is a challenge folder named after no known challenge. Its images are
+    /// still used for a model of that name, so it is only a warning
+    UnknownChallenge(PathBuf),
+    /// MissingLabel is a label folder missing from a challenge folder
+    MissingLabel(PathBuf),
+    /// NotAnImage is a file in a label folder that isn't an image in a format tiles come in
+    NotAnImage(PathBuf),
+    /// Imbalanced is a challenge folder with more than IMBALANCE_RATIO times as many images
+    /// of one label as of the other
+    Imbalanced {
+        dir: PathBuf,
+        matches: usize,
+        not_matches: usize,
+    },
+}
+
+impl Issue {
+    /// is_warning is whether the dataset is usable in spite of the issue
+    pub fn is_warning(&self) -> bool {
+        matches!(self, Issue::UnknownChallenge(_) | Issue::Imbalanced { .. })
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::UnknownGrid(path) => write!(f, "{} is not a grid size", path.display()),
+            Issue::UnknownChallenge(path) => {
+                write!(f, "{} is not a known challenge", path.display())
+            }
+            Issue::MissingLabel(path) => write!(f, "{} is missing", path.display()),
+            Issue::NotAnImage(path) => write!(f, "{} is not an image", path.display()),
+            Issue::Imbalanced {
+                dir,
+                matches,
+                not_matches,
+            } => write!(
+                f,
+                "{} is imbalanced: {} matches, {} not matches",
+                dir.display(),
+                matches,
+                not_matches
+            ),
+        }
+    }
+}
+
+/// validate checks a dataset against the layout labeled_images expects, listing every issue
+/// found in path order
+pub fn validate<P>(root: P) -> errors::Result<Vec<Issue>>
+where
+    P: AsRef<Path>,
+{
+    let mut issues = Vec::new();
+    for grid in subdirectories(root.as_ref())? {
+        let grid_name = grid.file_name().unwrap_or_default().to_string_lossy();
+        if grid_name.parse::<GridSize>().is_err() {
+            issues.push(Issue::UnknownGrid(grid.clone()));
+            continue;
+        }
+        for challenge_dir in subdirectories(&grid)? {
+            let folder = challenge_dir
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy();
+            match folder.replace(' ', "_").parse::<CaptchaChallenge>() {
+                Ok(CaptchaChallenge::Other(_)) | Err(_) => {
+                    issues.push(Issue::UnknownChallenge(challenge_dir.clone()))
+                }
+                Ok(_) => {}
+            }
+            let mut counts = [0; 2];
+            for (count, (label_dir, _)) in counts.iter_mut().zip(LABEL_DIRS) {
+                let dir = challenge_dir.join(label_dir);
+                if !dir.is_dir() {
+                    issues.push(Issue::MissingLabel(dir));
+                    continue;
+                }
+                let mut files = Vec::new();
+                for entry in dir.read_dir()? {
+                    let entry = entry?;
+                    if entry.file_type()?.is_file() {
+                        files.push(entry.path());
+                    }
+                }
+                files.sort();
+                for file in files {
+                    if is_image(&file)? {
+                        *count += 1;
+                    } else {
+                        issues.push(Issue::NotAnImage(file));
+                    }
+                }
+            }
+            let [matches, not_matches] = counts;
+            if matches.max(not_matches) > IMBALANCE_RATIO * matches.min(not_matches).max(1) {
+                issues.push(Issue::Imbalanced {
+                    dir: challenge_dir,
+                    matches,
+                    not_matches,
+                });
+            }
+        }
+    }
+    Ok(issues)
+}
+
+/// DatasetStats summarizes the images of a dataset laid out like test_data/, as a check before
+/// training on it
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DatasetStats {
+    /// classes counts the images of each challenge folder, in path order
+    pub classes: Vec<ClassStats>,
+    /// dimensions counts images by size, most common first
+    pub dimensions: Vec<DimensionCount>,
+    /// formats counts images by the format of their leading bytes, most common first
+    pub formats: Vec<FormatCount>,
+    /// corrupt lists the files in label folders that could not be decoded, in path order
+    pub corrupt: Vec<PathBuf>,
+}
+
+/// ClassStats counts the images of one challenge folder that decoded
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClassStats {
+    pub grid: String,
+    pub challenge: String,
+    pub matches: usize,
+    pub not_matches: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DimensionCount {
+    pub width: u32,
+    pub height: u32,
+    pub images: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FormatCount {
+    /// format is a MIME type, or "unknown" for files in no format tiles come in
+    pub format: &'static str,
+    pub images: usize,
+}
+
+/// stats decodes every file in the label folders of a dataset, one at a time, and summarizes
+/// them. Folders that don't fit the layout are left to validate to report
+pub fn stats<P>(root: P) -> errors::Result<DatasetStats>
+where
+    P: AsRef<Path>,
+{
+    let mut stats = DatasetStats::default();
+    let mut dimensions = HashMap::new();
+    let mut formats = HashMap::new();
+    for grid in subdirectories(root.as_ref())? {
+        for challenge_dir in subdirectories(&grid)? {
+            let mut counts = [0; 2];
+            for (count, (label_dir, _)) in counts.iter_mut().zip(LABEL_DIRS) {
+                let dir = challenge_dir.join(label_dir);
+                if !dir.is_dir() {
+                    continue;
+                }
+                for entry in dir.read_dir()? {
+                    let entry = entry?;
+                    if !entry.file_type()?.is_file() {
+                        continue;
+                    }
+                    let image = fs::read(entry.path())?;
+                    let format =
+                        TileFormat::detect(&image).map_or("unknown", TileFormat::mime_type);
+                    *formats.entry(format).or_insert(0) += 1;
+                    match image::load_from_memory(&image) {
+                        Ok(decoded) => {
+                            *count += 1;
+                            *dimensions.entry(decoded.dimensions()).or_insert(0) += 1;
+                        }
+                        Err(_) => stats.corrupt.push(entry.path()),
+                    }
+                }
+            }
+            let name = |path: &Path| {
+                path.file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .into_owned()
+            };
+            stats.classes.push(ClassStats {
+                grid: name(&grid),
+                challenge: name(&challenge_dir),
+                matches: counts[0],
+                not_matches: counts[1],
+            });
+        }
+    }
+    stats.dimensions = dimensions
+        .into_iter()
+        .map(|((width, height), images)| DimensionCount {
+            width,
+            height,
+            images,
+        })
+        .collect();
+    stats.dimensions.sort_by(|a, b| {
+        b.images
+            .cmp(&a.images)
+            .then((a.width, a.height).cmp(&(b.width, b.height)))
+    });
+    stats.formats = formats
+        .into_iter()
+        .map(|(format, images)| FormatCount { format, images })
+        .collect();
+    stats
+        .formats
+        .sort_by(|a, b| b.images.cmp(&a.images).then(a.format.cmp(b.format)));
+    stats.corrupt.sort();
+    Ok(stats)
+}
+
+/// is_image sniffs the leading bytes of 'path' for an image format
+pub(crate) fn is_image(path: &Path) -> errors::Result<bool> {
+    let mut header = Vec::with_capacity(32);
+    let _ = File::open(path)?.take(32).read_to_end(&mut header)?;
+    Ok(TileFormat::detect(&header).is_some())
+}
+
+fn subdirectories(path: &Path) -> errors::Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    if !path.is_dir() {
+        return Ok(dirs);
+    }
+    for entry in path.read_dir()? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            dirs.push(entry.path());
+        }
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_both_labels_across_grids() -> errors::Result<()> {
+        let images = labeled_images("test_data", &CaptchaChallenge::TrafficLights)?;
+        assert!(images.iter().any(|image| image.matches));
+        assert!(images.iter().any(|image| !image.matches));
+        assert!(images
+            .iter()
+            .all(|image| image.path.to_string_lossy().contains("traffic lights")));
+        Ok(())
+    }
+
+    #[test]
+    fn counts_sizes_formats_and_corrupt_files() -> errors::Result<()> {
+        let root = std::env::temp_dir().join(format!("nocap-stats-{}", std::process::id()));
+        let bus = root.join("3x3/bus");
+        for label_dir in &["matches", "not matches"] {
+            fs::create_dir_all(bus.join(label_dir))?;
+        }
+        for (index, size) in [10, 10, 20].iter().enumerate() {
+            image::RgbImage::new(*size, *size).save(bus.join(format!("matches/{}.png", index)))?;
+        }
+        fs::write(bus.join("not matches/broken.png"), b"\x89PNG\r\n\x1a\n")?;
+
+        let stats = stats(&root)?;
+        assert_eq!(
+            (stats.classes[0].matches, stats.classes[0].not_matches),
+            (3, 0)
+        );
+        assert_eq!(
+            (stats.dimensions[0].width, stats.dimensions[0].images),
+            (10, 2)
+        );
+        assert_eq!(
+            (stats.formats[0].format, stats.formats[0].images),
+            ("image/png", 4)
+        );
+        assert_eq!(stats.corrupt, vec![bus.join("not matches/broken.png")]);
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn validates_an_initialized_tree() -> errors::Result<()> {
+        let root = std::env::temp_dir().join(format!("nocap-dataset-{}", std::process::id()));
+        assert!(!init(&root)?.is_empty());
+        assert!(init(&root)?.is_empty());
+        assert_eq!(validate(&root)?, vec![]);
+
+        let bus = root.join("3x3/bus");
+        fs::write(bus.join("matches/tile.png"), b"\x89PNG\r\n\x1a\n tile")?;
+        fs::write(bus.join("matches/notes.txt"), b"notes")?;
+        fs::remove_dir(bus.join("not matches"))?;
+        fs::create_dir_all(root.join("5x5"))?;
+        let cars = root.join("3x3/cars/matches");
+        for index in 0..5 {
+            fs::write(
+                cars.join(format!("{}.png", index)),
+                b"\x89PNG\r\n\x1a\n tile",
+            )?;
+        }
+        assert_eq!(
+            validate(&root)?,
+            vec![
+                Issue::NotAnImage(bus.join("matches/notes.txt")),
+                Issue::MissingLabel(bus.join("not matches")),
+                Issue::Imbalanced {
+                    dir: root.join("3x3/cars"),
+                    matches: 5,
+                    not_matches: 0,
+                },
+                Issue::UnknownGrid(root.join("5x5")),
+            ]
+        );
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
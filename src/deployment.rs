@@ -0,0 +1,221 @@
+use crate::{errors, image_hash, CaptchaChallenge, CaptchaModel, Prediction};
+use serde_derive::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+/// Deployment decides how a candidate model takes part in serving a challenge
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Deployment {
+    /// AbTest answers 'fraction' (0.0 to 1.0) of images with the candidate instead of the
+    /// primary model. Images are assigned by content hash, so the same image always gets the
+    /// same model
+    AbTest { fraction: f32 },
+    /// Shadow runs the candidate alongside the primary model without ever returning its
+    /// predictions, recording where the two disagree
+    Shadow,
+}
+
+/// ShadowStats counts how often a shadowed candidate agreed with the primary model
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct ShadowStats {
+    pub compared: usize,
+    pub disagreed: usize,
+}
+
+#[derive(Serialize)]
+struct Diff<'a> {
+    challenge: &'a CaptchaChallenge,
+    hash: String,
+    primary: &'a Prediction,
+    candidate: &'a Prediction,
+}
+
+/// Candidate is a model being validated against a challenge's primary model
+#[derive(Debug)]
+pub struct Candidate {
+    model: Mutex<CaptchaModel>,
+    deployment: Deployment,
+    diff_log: Option<PathBuf>,
+    compared: AtomicUsize,
+    disagreed: AtomicUsize,
+}
+
+impl Candidate {
+    pub fn load<P>(dir: P, deployment: Deployment) -> errors::Result<Candidate>
+    where
+        P: AsRef<Path>,
+    {
+        Ok(Candidate {
+            model: Mutex::new(CaptchaModel::load(dir)?),
+            deployment,
+            diff_log: None,
+            compared: AtomicUsize::new(0),
+            disagreed: AtomicUsize::new(0),
+        })
+    }
+
+    /// with_diff_log appends every shadow disagreement to 'path' as a JSON line
+    pub fn with_diff_log<P>(mut self, path: P) -> Candidate
+    where
+        P: Into<PathBuf>,
+    {
+        self.diff_log = Some(path.into());
+        self
+    }
+
+    pub fn deployment(&self) -> Deployment {
+        self.deployment
+    }
+
+    pub fn shadow_stats(&self) -> ShadowStats {
+        ShadowStats {
+            compared: self.compared.load(Ordering::Relaxed),
+            disagreed: self.disagreed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// serve answers 'images' according to the deployment, calling 'primary' for whatever the
+    /// primary model is responsible for
+    pub(crate) fn serve<F>(
+        &self,
+        challenge: &CaptchaChallenge,
+        images: &[Vec<u8>],
+        primary: F,
+    ) -> errors::Result<Vec<Prediction>>
+    where
+        F: FnOnce(&[Vec<u8>]) -> errors::Result<Vec<Prediction>>,
+    {
+        match self.deployment {
+            Deployment::AbTest { fraction } => self.split(images, fraction, primary),
+            Deployment::Shadow => {
+                let predictions = primary(images)?;
+                // the candidate failing, even with a poisoned lock, must never cost the caller
+                // their prediction
+                let shadowed = self
+                    .model
+                    .lock()
+                    .ok()
+                    .and_then(|model| model.run(images).ok());
+                if let Some(shadowed) = shadowed {
+                    self.compare(challenge, images, &predictions, &shadowed);
+                }
+                Ok(predictions)
+            }
+        }
+    }
+
+    fn split<F>(
+        &self,
+        images: &[Vec<u8>],
+        fraction: f32,
+        primary: F,
+    ) -> errors::Result<Vec<Prediction>>
+    where
+        F: FnOnce(&[Vec<u8>]) -> errors::Result<Vec<Prediction>>,
+    {
+        let (candidate_images, primary_images): (Vec<(usize, &Vec<u8>)>, Vec<(usize, &Vec<u8>)>) =
+            images
+                .iter()
+                .enumerate()
+                .partition(|(_, image)| in_fraction(image, fraction));
+
+        let mut predictions = vec![None; images.len()];
+        if !candidate_images.is_empty() {
+            let answered = self.model.lock()?.run(&owned(&candidate_images))?;
+            fill(&mut predictions, &candidate_images, answered);
+        }
+        if !primary_images.is_empty() {
+            let answered = primary(&owned(&primary_images))?;
+            fill(&mut predictions, &primary_images, answered);
+        }
+        predictions
+            .into_iter()
+            .map(|prediction| prediction.ok_or(errors::Error::MalformedOutput))
+            .collect()
+    }
+
+    fn compare(
+        &self,
+        challenge: &CaptchaChallenge,
+        images: &[Vec<u8>],
+        primary: &[Prediction],
+        candidate: &[Prediction],
+    ) {
+        for ((image, primary), candidate) in images.iter().zip(primary).zip(candidate) {
+            self.compared.fetch_add(1, Ordering::Relaxed);
+            if primary.is_mainly_affirmative() == candidate.is_mainly_affirmative() {
+                continue;
+            }
+            self.disagreed.fetch_add(1, Ordering::Relaxed);
+            if let Some(path) = &self.diff_log {
+                let diff = Diff {
+                    challenge,
+                    hash: image_hash(image),
+                    primary,
+                    candidate,
+                };
+                // losing a diff line is preferable to failing the prediction
+                let _ = append_line(path, &diff);
+            }
+        }
+    }
+}
+
+fn owned(assigned: &[(usize, &Vec<u8>)]) -> Vec<Vec<u8>> {
+    assigned.iter().map(|(_, image)| (*image).clone()).collect()
+}
+
+fn fill(
+    predictions: &mut [Option<Prediction>],
+    assigned: &[(usize, &Vec<u8>)],
+    answered: Vec<Prediction>,
+) {
+    for ((index, _), prediction) in assigned.iter().zip(answered) {
+        predictions[*index] = Some(prediction);
+    }
+}
+
+fn append_line(path: &Path, diff: &Diff) -> errors::Result<()> {
+    let mut line = serde_json::to_vec(diff)?;
+    line.push(b'\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(&line)?;
+    Ok(())
+}
+
+/// in_fraction maps an image onto [0, 1) by its content hash and checks whether it lands
+/// below 'fraction'
+fn in_fraction(image: &[u8], fraction: f32) -> bool {
+    let digest = Sha256::digest(image);
+    let bucket = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]);
+    (f64::from(bucket) / (f64::from(u32::MAX) + 1.0)) < f64::from(fraction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fraction_bounds() {
+        let images: Vec<Vec<u8>> = (0..100u8).map(|n| vec![n; 8]).collect();
+        assert!(images.iter().all(|image| !in_fraction(image, 0.0)));
+        assert!(images.iter().all(|image| in_fraction(image, 1.0)));
+
+        let half = images
+            .iter()
+            .filter(|image| in_fraction(image, 0.5))
+            .count();
+        assert!(half > 25 && half < 75, "{} of 100 routed", half);
+    }
+}
@@ -0,0 +1,185 @@
+use crate::{errors, grid, prompt, registry, CaptchaChallenge, CaptchaModel};
+use image::{DynamicImage, ImageOutputFormat};
+use serde_derive::{Deserialize, Serialize};
+use std::{fs, path::PathBuf, sync::Mutex};
+use tensorflow::Tensor;
+
+/// LABELS_FILE names, one per line beside the classifier's saved_model.pb, the challenge each
+/// column of its scores stands for
+pub const LABELS_FILE: &str = "labels.txt";
+
+/// ClassifierConfig is where a ChallengeClassifier is loaded from. It must be kept outside the
+/// models directory, where it would be taken for a challenge's model
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClassifierConfig {
+    pub path: PathBuf,
+    /// min_confidence is the score below which the banner's classification gives way to the
+    /// prompt text
+    #[serde(default = "default_min_confidence")]
+    pub min_confidence: f32,
+}
+
+fn default_min_confidence() -> f32 {
+    0.8
+}
+
+/// Source is what a Detection was made from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    Banner,
+    Prompt,
+}
+
+/// Detection is the challenge a screenshot was found to show
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Detection {
+    pub challenge: CaptchaChallenge,
+    /// confidence is the classifier's score for the challenge it found in the banner, even
+    /// when the prompt decided, or 0 when the banner couldn't be classified
+    pub confidence: f32,
+    pub source: Source,
+}
+
+/// ChallengeClassifier tells which challenge a screenshot of the widget shows from its header
+/// banner, so the tiles can be predicted without parsing the prompt. Its SavedModel takes
+/// encoded images as the challenge models do, and its "scores" output has a probability for
+/// each challenge in LABELS_FILE
+#[derive(Debug)]
+pub struct ChallengeClassifier {
+    model: Mutex<CaptchaModel>,
+    labels: Vec<CaptchaChallenge>,
+    min_confidence: f32,
+}
+
+impl ChallengeClassifier {
+    pub fn load(config: &ClassifierConfig) -> errors::Result<ChallengeClassifier> {
+        let labels = fs::read_to_string(config.path.join(LABELS_FILE))?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<CaptchaChallenge>, _>>()?;
+        Ok(ChallengeClassifier {
+            model: Mutex::new(CaptchaModel::load(&config.path)?),
+            labels,
+            min_confidence: config.min_confidence,
+        })
+    }
+
+    /// classify returns the likeliest challenge for the banner of 'screenshot', with its score
+    pub fn classify(&self, screenshot: &[u8]) -> errors::Result<(CaptchaChallenge, f32)> {
+        let mut encoded = Vec::new();
+        DynamicImage::ImageRgb8(grid::banner(screenshot)?)
+            .write_to(&mut encoded, ImageOutputFormat::Png)?;
+        let images = [encoded];
+        let model = self.model.lock()?;
+        let input_operation = model.graph.operation_by_name_required("Placeholder")?;
+        let input_tensor = registry::image_tensor(&images)?;
+
+        let mut output_step = tensorflow::SessionRunArgs::new();
+        output_step.add_feed(&input_operation, 0, &input_tensor);
+        let scores_out =
+            output_step.request_fetch(&model.graph.operation_by_name_required("scores")?, 0);
+
+        model.session.run(&mut output_step)?;
+        let scores: Tensor<f32> = output_step.fetch(scores_out)?;
+        if scores.len() != self.labels.len() {
+            return Err(errors::Error::MalformedOutput);
+        }
+        likeliest(&self.labels, &scores).ok_or(errors::Error::MalformedOutput)
+    }
+
+    /// detect classifies the banner of 'screenshot', falling back on the challenge named by
+    /// 'prompt' when the classifier isn't confident or fails. It is None when neither tells,
+    /// and the classifier's error only when it failed and the prompt names no challenge
+    pub fn detect(
+        &self,
+        screenshot: &[u8],
+        prompt: Option<&str>,
+    ) -> errors::Result<Option<Detection>> {
+        decide(self.classify(screenshot), self.min_confidence, prompt)
+    }
+}
+
+fn likeliest(labels: &[CaptchaChallenge], scores: &[f32]) -> Option<(CaptchaChallenge, f32)> {
+    labels
+        .iter()
+        .zip(scores)
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(challenge, score)| (challenge.clone(), *score))
+}
+
+fn decide(
+    classified: errors::Result<(CaptchaChallenge, f32)>,
+    min_confidence: f32,
+    prompt: Option<&str>,
+) -> errors::Result<Option<Detection>> {
+    let confidence = match classified {
+        Ok((challenge, confidence)) if confidence >= min_confidence => {
+            return Ok(Some(Detection {
+                challenge,
+                confidence,
+                source: Source::Banner,
+            }));
+        }
+        Ok((_, confidence)) => confidence,
+        Err(err) if prompt.and_then(prompt::parse).is_none() => return Err(err),
+        Err(_) => 0.0,
+    };
+    Ok(prompt.and_then(prompt::parse).map(|challenge| Detection {
+        challenge,
+        confidence,
+        source: Source::Prompt,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn falls_back_on_the_prompt() -> errors::Result<()> {
+        let labels = [CaptchaChallenge::Bus, CaptchaChallenge::Crosswalks];
+        let classified = likeliest(&labels, &[0.1, 0.9]).unwrap();
+        assert_eq!(classified, (CaptchaChallenge::Crosswalks, 0.9));
+
+        let confident = decide(
+            Ok(classified.clone()),
+            0.8,
+            Some("Select all images with a bus"),
+        )?;
+        assert_eq!(
+            confident.map(|detection| (detection.challenge, detection.source)),
+            Some((CaptchaChallenge::Crosswalks, Source::Banner))
+        );
+        let unsure = decide(
+            Ok(classified.clone()),
+            0.95,
+            Some("Select all images with a bus"),
+        )?;
+        assert_eq!(
+            unsure.map(|detection| (detection.challenge, detection.source)),
+            Some((CaptchaChallenge::Bus, Source::Prompt))
+        );
+        assert_eq!(decide(Ok(classified.clone()), 0.95, None)?, None);
+        assert_eq!(decide(Ok(classified), 0.95, Some("Click verify"))?, None);
+
+        let failed = decide(
+            Err(errors::Error::MalformedOutput),
+            0.8,
+            Some("Select all images with a bus"),
+        )?;
+        assert_eq!(
+            failed.map(|detection| (detection.challenge, detection.confidence)),
+            Some((CaptchaChallenge::Bus, 0.0))
+        );
+        assert!(decide(
+            Err(errors::Error::MalformedOutput),
+            0.8,
+            Some("Click verify")
+        )
+        .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+use crate::CaptchaChallenge;
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+
+/// DevicePlacement assigns challenges to GPUs by index, so a multi-GPU host can spread its
+/// models instead of placing them all on the first GPU. Challenges it doesn't name are placed
+/// on 'default', or left to TensorFlow when that isn't set either.
+///
+/// TensorFlow can't give sessions in one process different visible devices, so a pinned model
+/// is imported from its frozen graph with the GPU as its default device, which needs a
+/// graph_cache::GraphCache. Operations without a GPU kernel, such as image decoding, still run
+/// on the CPU
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct DevicePlacement {
+    pub default: Option<usize>,
+    pub challenges: HashMap<CaptchaChallenge, usize>,
+}
+
+impl DevicePlacement {
+    /// pin places the models of 'challenge' on the GPU numbered 'gpu'
+    pub fn pin(mut self, challenge: CaptchaChallenge, gpu: usize) -> DevicePlacement {
+        let _ = self.challenges.insert(challenge, gpu);
+        self
+    }
+
+    /// gpu_for is the GPU the models of 'challenge' are placed on, if any
+    pub fn gpu_for(&self, challenge: &CaptchaChallenge) -> Option<usize> {
+        self.challenges.get(challenge).copied().or(self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pinned_challenges_override_the_default() {
+        let placement = DevicePlacement {
+            default: Some(1),
+            ..DevicePlacement::default()
+        }
+        .pin(CaptchaChallenge::Bus, 0);
+        assert_eq!(placement.gpu_for(&CaptchaChallenge::Bus), Some(0));
+        assert_eq!(placement.gpu_for(&CaptchaChallenge::Cars), Some(1));
+        assert_eq!(
+            DevicePlacement::default().gpu_for(&CaptchaChallenge::Bus),
+            None
+        );
+    }
+}
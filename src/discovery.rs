@@ -0,0 +1,222 @@
+use crate::{archive, ensemble, errors, CaptchaChallenge};
+use serde_derive::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Discovery controls how a models directory is searched for challenge directories and model
+/// archives. Symlinks are followed either way, broken ones skipped, as are hidden entries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct Discovery {
+    /// max_depth is how many levels of other directories to search below the models directory,
+    /// e.g. 1 finds models/v3/bus/. 0, the default, only searches the models directory itself.
+    /// When a challenge is found more than once the shallowest copy wins, and between copies
+    /// at the same depth the one whose path sorts last (models/v3/bus/ over models/v2/bus/)
+    pub max_depth: usize,
+}
+
+/// Found is what a search of a models directory turned up, one entry per challenge
+#[derive(Debug, Default)]
+pub(crate) struct Found {
+    pub(crate) directories: HashMap<CaptchaChallenge, PathBuf>,
+    pub(crate) archives: HashMap<CaptchaChallenge, PathBuf>,
+}
+
+/// discover searches 'root' for challenge directories and model archives
+pub(crate) fn discover(root: &Path, discovery: &Discovery) -> errors::Result<Found> {
+    let mut search = Search::default();
+    search.walk(root, 0, discovery.max_depth)?;
+    Ok(Found {
+        directories: search.directories.into_iter().filter_map(pick).collect(),
+        archives: search.archives.into_iter().filter_map(pick).collect(),
+    })
+}
+
+type Candidates = HashMap<CaptchaChallenge, Vec<(usize, PathBuf)>>;
+
+#[derive(Debug, Default)]
+struct Search {
+    directories: Candidates,
+    archives: Candidates,
+    // canonical paths of the directories searched, so symlink cycles are searched once
+    visited: HashSet<PathBuf>,
+}
+
+impl Search {
+    fn walk(&mut self, dir: &Path, depth: usize, max_depth: usize) -> errors::Result<()> {
+        if !self.visited.insert(dir.canonicalize()?) {
+            return Ok(());
+        }
+        let mut subdirectories = Vec::new();
+        for entry in dir.read_dir()? {
+            let entry = entry?;
+            // entries that don't name a challenge, including names that aren't valid UTF-8,
+            // are skipped rather than treated as errors
+            let file_name = entry.file_name();
+            let name = match file_name.to_str() {
+                Some(name) if !name.starts_with('.') => name,
+                _ => continue,
+            };
+            let path = entry.path();
+            // fs::metadata follows symlinks, where DirEntry::file_type doesn't
+            let metadata = match fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+            if !metadata.is_dir() {
+                let challenge = match archive::archive_stem(name).map(str::parse) {
+                    Some(Ok(challenge)) => challenge,
+                    _ => continue,
+                };
+                if accept(&path, &challenge, archive::has_manifest) {
+                    self.archives
+                        .entry(challenge)
+                        .or_default()
+                        .push((depth, path));
+                }
+                continue;
+            }
+            match name.parse::<CaptchaChallenge>() {
+                Ok(challenge) if accept(&path, &challenge, is_other_challenge) => {
+                    self.directories
+                        .entry(challenge)
+                        .or_default()
+                        .push((depth, path));
+                }
+                _ => subdirectories.push(path),
+            }
+        }
+        if depth < max_depth {
+            subdirectories.sort();
+            for subdirectory in subdirectories {
+                self.walk(&subdirectory, depth + 1, max_depth)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// accept decides whether 'path', named after 'challenge', holds that challenge's model. Any
+/// snake_case name parses as Other, so a known challenge is always accepted while an Other one
+/// has to pass 'check'. A path 'check' fails on is reported and skipped, so one unreadable
+/// entry doesn't abort the whole search
+fn accept<F>(path: &Path, challenge: &CaptchaChallenge, check: F) -> bool
+where
+    F: FnOnce(&Path) -> errors::Result<bool>,
+{
+    if challenge.is_known() {
+        return true;
+    }
+    match check(path) {
+        Ok(accepted) => accepted,
+        Err(err) => {
+            eprintln!("Skipping {}: {}", path.display(), err);
+            false
+        }
+    }
+}
+
+/// is_other_challenge tells a directory holding an unknown challenge's model from one grouping
+/// models, e.g. models/v3/ holding models/v3/bus/. It is a challenge if it holds
+/// saved_model.pb itself, or has ensemble::members and none of its subdirectories name a
+/// known challenge
+fn is_other_challenge(dir: &Path) -> errors::Result<bool> {
+    if dir.join("saved_model.pb").exists() {
+        return Ok(true);
+    }
+    for entry in dir.read_dir()? {
+        let entry = entry?;
+        let known = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<CaptchaChallenge>().ok())
+            .is_some_and(|challenge| challenge.is_known());
+        if known && entry.path().is_dir() {
+            return Ok(false);
+        }
+    }
+    Ok(!ensemble::members(dir)?.is_empty())
+}
+
+/// pick applies the precedence rules of Discovery::max_depth to the copies of a challenge
+fn pick(
+    (challenge, candidates): (CaptchaChallenge, Vec<(usize, PathBuf)>),
+) -> Option<(CaptchaChallenge, PathBuf)> {
+    candidates
+        .into_iter()
+        .max_by(|(a_depth, a_path), (b_depth, b_path)| {
+            b_depth.cmp(a_depth).then_with(|| a_path.cmp(b_path))
+        })
+        .map(|(_, path)| (challenge, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefers_shallow_then_later_copies() -> errors::Result<()> {
+        let root = std::env::temp_dir().join(format!("nocap-discovery-{}", std::process::id()));
+        for dir in &[
+            "bus",
+            "v2/bus",
+            "v2/taxis",
+            "v3/taxis",
+            "v3/deep/trucks",
+            ".hidden/chimneys",
+        ] {
+            fs::create_dir_all(root.join(dir))?;
+        }
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(root.join("v3"), root.join("v3/loop"))?;
+            std::os::unix::fs::symlink(root.join("missing"), root.join("v3/broken"))?;
+        }
+
+        let shallow = discover(&root, &Discovery::default())?;
+        assert_eq!(shallow.directories.len(), 1);
+        assert!(shallow.directories.contains_key(&CaptchaChallenge::Bus));
+
+        let deep = discover(&root, &Discovery { max_depth: 1 })?;
+        assert_eq!(deep.directories[&CaptchaChallenge::Bus], root.join("bus"));
+        assert_eq!(
+            deep.directories[&CaptchaChallenge::Taxis],
+            root.join("v3/taxis")
+        );
+        assert!(!deep.directories.contains_key(&CaptchaChallenge::Trucks));
+        assert!(!deep.directories.contains_key(&CaptchaChallenge::Chimneys));
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn tells_version_directories_from_other_challenges() -> errors::Result<()> {
+        let root = std::env::temp_dir().join(format!("nocap-discovery-v-{}", std::process::id()));
+        for dir in &["v3/bus", "v3/hot_air_balloons"] {
+            fs::create_dir_all(root.join(dir))?;
+            fs::write(root.join(dir).join("saved_model.pb"), b"graph")?;
+        }
+        // neither a known challenge nor an archive holding a manifest
+        fs::write(root.join("snapshot.tar.gz"), b"not an archive")?;
+
+        let found = discover(&root, &Discovery { max_depth: 1 })?;
+        assert_eq!(
+            found.directories[&CaptchaChallenge::Bus],
+            root.join("v3/bus")
+        );
+        assert_eq!(
+            found.directories[&CaptchaChallenge::Other("hot_air_balloons".to_owned())],
+            root.join("v3/hot_air_balloons")
+        );
+        assert!(!found
+            .directories
+            .contains_key(&CaptchaChallenge::Other("v3".to_owned())));
+        assert!(found.archives.is_empty());
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,131 @@
+use crate::{cache, errors};
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+/// EmbeddedFile is a file of a models directory compiled into the binary, usually through
+/// embed_models!
+#[derive(Debug, Clone, Copy)]
+pub struct EmbeddedFile {
+    /// path is relative to the models directory, e.g. "bus/saved_model.pb"
+    pub path: &'static str,
+    pub contents: &'static [u8],
+}
+
+/// embed_models! compiles files of a models directory into the binary, for
+/// RegistryBuilder::load_embedded. The directory is given relative to the invoking source file,
+/// followed by the files to embed relative to it:
+///
+/// ```ignore
+/// static MODELS: &[EmbeddedFile] = embed_models!("../models";
+///     "bus/saved_model.pb",
+///     "traffic_lights/saved_model.pb",
+///     "traffic_lights/variables/variables.index",
+///     "traffic_lights/variables/variables.data-00000-of-00001",
+/// );
+/// ```
+#[macro_export]
+macro_rules! embed_models {
+    ($root:literal; $($path:literal),* $(,)?) => {
+        &[$($crate::embedded::EmbeddedFile {
+            path: $path,
+            contents: include_bytes!(concat!($root, "/", $path)),
+        }),*]
+    };
+}
+
+/// extract writes 'files' out as a models directory under the per-user cache::dir and returns
+/// its path. TensorFlow can only restore a SavedModel from disk, so embedded models have to
+/// pass through the filesystem. The directory is named after a hash of the files, so a binary
+/// reuses what an earlier run extracted instead of writing it again
+pub fn extract(files: &[EmbeddedFile]) -> errors::Result<PathBuf> {
+    let mut hasher = Sha256::new();
+    for file in files {
+        hasher.input(file.path.as_bytes());
+        hasher.input(&(file.contents.len() as u64).to_be_bytes());
+        hasher.input(file.contents);
+    }
+    let digest: String = hasher
+        .result()
+        .iter()
+        .take(8)
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let dir = cache::dir()?.join("models").join(digest);
+    cache::private_dir(&dir)?;
+    extract_to(files, &dir)?;
+    Ok(dir)
+}
+
+/// extract_to writes 'files' out under 'dir', skipping files already there with the same
+/// contents
+pub fn extract_to(files: &[EmbeddedFile], dir: &Path) -> errors::Result<()> {
+    for file in files {
+        let relative = Path::new(file.path);
+        // embedded paths come from the binary's own build, but never let one escape 'dir'
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(errors::Error::IOError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "embedded model path {:?} leaves the models directory",
+                    file.path
+                ),
+            )));
+        }
+        let path = dir.join(relative);
+        let extracted = fs::read(&path)
+            .map(|contents| contents == file.contents)
+            .unwrap_or(false);
+        if extracted {
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // written beside the destination and renamed into place, so a concurrent or crashed
+        // run never leaves a truncated model behind
+        let partial = cache::partial_path(&path);
+        fs::write(&partial, file.contents)?;
+        fs::rename(&partial, &path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_files_once() -> errors::Result<()> {
+        static FILES: &[EmbeddedFile] = &[
+            EmbeddedFile {
+                path: "bus/saved_model.pb",
+                contents: b"graph",
+            },
+            EmbeddedFile {
+                path: "bus/variables/variables.index",
+                contents: b"index",
+            },
+        ];
+        let dir = extract(FILES)?;
+        assert_eq!(fs::read(dir.join("bus/saved_model.pb"))?, b"graph");
+        assert_eq!(extract(FILES)?, dir);
+        // a file of the right size but other contents is written again
+        fs::write(dir.join("bus/saved_model.pb"), b"grapx")?;
+        assert_eq!(extract(FILES)?, dir);
+        assert_eq!(fs::read(dir.join("bus/saved_model.pb"))?, b"graph");
+
+        let escaping = [EmbeddedFile {
+            path: "../outside",
+            contents: b"",
+        }];
+        assert!(extract_to(&escaping, &dir).is_err());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
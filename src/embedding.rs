@@ -0,0 +1,77 @@
+use crate::{errors, format, registry, CaptchaModel};
+use std::sync::Mutex;
+use tensorflow::Tensor;
+
+/// Embedder runs images through a SavedModel up to an intermediate layer and returns that
+/// layer's activations as a feature vector. Any of the challenge models can serve as an
+/// embedder by naming the operation feeding their final dense layer
+#[derive(Debug)]
+pub struct Embedder {
+    model: Mutex<CaptchaModel>,
+    output_operation: String,
+}
+
+impl Embedder {
+    pub fn load<P, S>(dir: P, output_operation: S) -> errors::Result<Embedder>
+    where
+        P: AsRef<std::path::Path>,
+        S: Into<String>,
+    {
+        Ok(Embedder {
+            model: Mutex::new(CaptchaModel::load(dir)?),
+            output_operation: output_operation.into(),
+        })
+    }
+
+    /// embed returns one feature vector per image, in the order they were given
+    pub fn embed(&self, images: &[Vec<u8>]) -> errors::Result<Vec<Vec<f32>>> {
+        let images = format::for_model(images)?;
+        let images = images.as_ref();
+        let model = self.model.lock()?;
+        let input_operation = model.graph.operation_by_name_required("Placeholder")?;
+        let input_tensor = registry::image_tensor(images)?;
+
+        let mut output_step = tensorflow::SessionRunArgs::new();
+        output_step.add_feed(&input_operation, 0, &input_tensor);
+        let embeddings_out = output_step.request_fetch(
+            &model
+                .graph
+                .operation_by_name_required(&self.output_operation)?,
+            0,
+        );
+
+        model.session.run(&mut output_step)?;
+        let embeddings: Tensor<f32> = output_step.fetch(embeddings_out)?;
+        rows(embeddings.dims(), &embeddings, images.len())
+    }
+}
+
+/// rows splits the [images, width] activations of a batch into one vector per image
+fn rows(dims: &[u64], activations: &[f32], images: usize) -> errors::Result<Vec<Vec<f32>>> {
+    let width = match dims {
+        [rows, width] if *rows == images as u64 && *width > 0 => *width as usize,
+        _ => return Err(errors::Error::MalformedOutput),
+    };
+    if activations.len() != images * width {
+        return Err(errors::Error::MalformedOutput);
+    }
+    Ok(activations.chunks(width).map(|row| row.to_vec()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_activations_per_image() -> errors::Result<()> {
+        let activations = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+        assert_eq!(
+            rows(&[2, 3], &activations, 2)?,
+            vec![vec![1.0, 2.0, 3.0], vec![4.0, 5.0, 6.0]]
+        );
+        assert!(rows(&[3, 2], &activations, 2).is_err());
+        assert!(rows(&[2, 0], &[], 2).is_err());
+        assert!(rows(&[6], &activations, 2).is_err());
+        Ok(())
+    }
+}
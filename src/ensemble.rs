@@ -0,0 +1,103 @@
+use crate::{errors, Prediction};
+use serde_derive::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Aggregation decides how the predictions of an ensemble's members are combined into one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregation {
+    /// Mean averages every member's confidences
+    #[default]
+    Mean,
+    /// Max takes the most affirmative member's confidence, so one confident member is enough
+    Max,
+    /// MajorityVote scores the fraction of members that are mainly affirmative. Ties are
+    /// negative
+    MajorityVote,
+}
+
+impl Aggregation {
+    pub fn aggregate(self, members: &[Prediction]) -> Prediction {
+        if members.is_empty() {
+            return Prediction {
+                affirmative_confidence: 0.0,
+                negative_confidence: 1.0,
+            };
+        }
+        let count = members.len() as f32;
+        match self {
+            Aggregation::Mean => Prediction {
+                affirmative_confidence: members
+                    .iter()
+                    .map(|p| p.affirmative_confidence)
+                    .sum::<f32>()
+                    / count,
+                negative_confidence: members.iter().map(|p| p.negative_confidence).sum::<f32>()
+                    / count,
+            },
+            Aggregation::Max => Prediction {
+                affirmative_confidence: members
+                    .iter()
+                    .map(|p| p.affirmative_confidence)
+                    .fold(f32::MIN, f32::max),
+                negative_confidence: members
+                    .iter()
+                    .map(|p| p.negative_confidence)
+                    .fold(f32::MAX, f32::min),
+            },
+            Aggregation::MajorityVote => {
+                let votes = members.iter().filter(|p| p.is_mainly_affirmative()).count() as f32;
+                Prediction {
+                    affirmative_confidence: votes / count,
+                    negative_confidence: 1.0 - votes / count,
+                }
+            }
+        }
+    }
+}
+
+/// members lists the model directories making up a challenge: the challenge directory
+/// itself when it holds a saved_model.pb, otherwise every subdirectory that does
+/// (e.g. bus/resnet/, bus/mobilenet/)
+pub(crate) fn members(challenge_dir: &Path) -> errors::Result<Vec<PathBuf>> {
+    if challenge_dir.join("saved_model.pb").exists() {
+        return Ok(vec![challenge_dir.to_path_buf()]);
+    }
+    let mut members = Vec::new();
+    for entry in challenge_dir.read_dir()? {
+        let member = entry?.path();
+        if member.join("saved_model.pb").exists() {
+            members.push(member);
+        }
+    }
+    members.sort();
+    Ok(members)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prediction(affirmative_confidence: f32) -> Prediction {
+        Prediction {
+            affirmative_confidence,
+            negative_confidence: 1.0 - affirmative_confidence,
+        }
+    }
+
+    #[test]
+    fn strategies() {
+        let members = [prediction(0.9), prediction(0.4), prediction(0.2)];
+
+        let mean = Aggregation::Mean.aggregate(&members);
+        assert!((mean.affirmative_confidence - 0.5).abs() < 1e-6);
+
+        let max = Aggregation::Max.aggregate(&members);
+        assert!((max.affirmative_confidence - 0.9).abs() < 1e-6);
+        assert!(max.is_mainly_affirmative());
+
+        let vote = Aggregation::MajorityVote.aggregate(&members);
+        assert!((vote.affirmative_confidence - 1.0 / 3.0).abs() < 1e-6);
+        assert!(!vote.is_mainly_affirmative());
+    }
+}
@@ -1,37 +1,427 @@
-use std::{io::Error as IOError, sync::PoisonError};
+use serde_derive::Serialize;
+use std::{io::Error as IOError, path::PathBuf, sync::PoisonError};
 use strum::ParseError;
 
-#[derive(Debug)]
+/// Error is everything that can go wrong in no_captcha. The underlying error of a variant, when
+/// it wraps one, is available through std::error::Error::source, and error_code gives each
+/// variant a stable name for consumers that can't match on the enum itself
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
-    IOError(IOError),
-    TensorflowError(tensorflow::Code),
+    #[error("I/O error")]
+    IOError(#[from] IOError),
+    #[cfg(feature = "tensorflow")]
+    #[error("TensorFlow failed with {code:?}: {message}")]
+    TensorflowError {
+        code: tensorflow::Code,
+        message: String,
+    },
+    #[error("no model is loaded for challenge {0}")]
     ModelLoad(crate::CaptchaChallenge),
-    StrumParseError(ParseError),
+    #[error("unknown challenge")]
+    StrumParseError(#[from] ParseError),
+    #[error("invalid JSON")]
+    JsonError(#[from] serde_json::Error),
+    #[error("invalid image")]
+    ImageError(#[from] image::ImageError),
+    #[cfg(feature = "remote")]
+    #[error("remote prediction failed")]
+    Remote(#[from] crate::remote::RemoteError),
+    #[error("a lock was poisoned by a panicking thread")]
     MutexError,
+    #[error("the model's output did not have the expected shape")]
+    MalformedOutput,
+    #[error("tile {0} is outside the grid")]
+    InvalidTile(usize),
+    #[error("conversion failed: {0}")]
+    ConversionFailed(String),
+    /// TrainingFailed is a train::fine_tune without images to train on or whose model could
+    /// not be saved
+    #[cfg(feature = "train")]
+    #[error("training failed: {0}")]
+    TrainingFailed(String),
+    /// RejectedImage is the index of an image in its batch that failed sanitize::InputLimits
+    #[error("image {0} was rejected")]
+    RejectedImage(usize, #[source] crate::sanitize::Rejection),
+    /// ArchiveError is a model archive that could not be read
+    #[error("invalid model archive")]
+    ArchiveError(#[from] zip::result::ZipError),
+    /// WorkerFailed is an error reported by, or the death of, a worker::WorkerPool process or
+    /// the sidecar a sidecar::SidecarClient sends to
+    #[error("inference worker failed: {0}")]
+    WorkerFailed(String),
+    /// ThreadPool is a loading::LoadPool whose threads could not be started
+    #[cfg(feature = "parallel")]
+    #[error("could not start the loading thread pool")]
+    ThreadPool(#[from] rayon::ThreadPoolBuildError),
+    /// Cancelled is a predictor::PredictOptions whose CancellationToken was cancelled
+    #[error("prediction was cancelled")]
+    Cancelled,
+    /// DeadlineExceeded is a predictor::PredictOptions whose deadline passed
+    #[error("prediction deadline exceeded")]
+    DeadlineExceeded,
+    /// InvalidChecksums is an integrity::CHECKSUMS file that could not be parsed
+    #[error("invalid checksums file")]
+    InvalidChecksums(#[source] toml::de::Error),
+    /// ChecksumMismatch is a model file whose SHA-256 differs from integrity::CHECKSUMS, with
+    /// 'actual' None when the file is missing altogether
+    #[error(
+        "{} does not match its checksum {expected} (found {})",
+        path.display(),
+        actual.as_deref().unwrap_or("no file")
+    )]
+    ChecksumMismatch {
+        path: PathBuf,
+        expected: String,
+        actual: Option<String>,
+    },
+    /// PluginLoad is a plugins::Plugins library that could not be loaded
+    #[cfg(feature = "plugins")]
+    #[error("could not load plugin")]
+    PluginLoad(#[from] libloading::Error),
+    /// IncompatiblePlugin is a plugin built for another plugins::API_VERSION or CORE_VERSION
+    #[error("plugin {} is incompatible: {1}", .0.display())]
+    IncompatiblePlugin(PathBuf, String),
+    /// GridNotFound is a screenshot grid::split could find no tiles in
+    #[error("no grid of tiles was found in the screenshot")]
+    GridNotFound,
+    /// BannerNotFound is a screenshot grid::banner could find no header banner in
+    #[error("no header banner was found in the screenshot")]
+    BannerNotFound,
+    /// EmbeddingLength is an embedding with another number of dimensions than the rest of its
+    /// gallery::Gallery
+    #[error("embedding has {found} dimensions where {expected} were expected")]
+    EmbeddingLength { expected: usize, found: usize },
+    /// UnfrozenPinnedModel is a model pinned to a GPU by devices::DevicePlacement in a registry
+    /// without a graph_cache::GraphCache to freeze it in
+    #[error("{} is pinned to a GPU but no graph cache is configured", .0.display())]
+    UnfrozenPinnedModel(PathBuf),
+    /// Overloaded is an inference over the concurrency::ConcurrencyLimits of its challenge,
+    /// with overflow rejected
+    #[error("too many predictions are running for {0}")]
+    Overloaded(crate::CaptchaChallenge),
+    /// Vetoed is a prediction refused by one of a registry's hooks::Hooks
+    #[error("prediction was vetoed: {0}")]
+    Vetoed(String),
 }
 
-impl From<ParseError> for Error {
-    fn from(error: ParseError) -> Error {
-        Error::StrumParseError(error)
+impl Error {
+    /// error_code is a stable, snake_case name for the kind of error. Codes are never renamed
+    /// or reused, so API consumers can rely on them where messages may change
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            Error::IOError(_) => "io",
+            #[cfg(feature = "tensorflow")]
+            Error::TensorflowError { .. } if self.is_transient() => "tensorflow_transient",
+            #[cfg(feature = "tensorflow")]
+            Error::TensorflowError { .. } => "tensorflow",
+            Error::ModelLoad(_) => "model_not_loaded",
+            Error::StrumParseError(_) => "unknown_challenge",
+            Error::JsonError(_) => "invalid_json",
+            Error::ImageError(_) => "invalid_image",
+            #[cfg(feature = "remote")]
+            Error::Remote(_) => "remote",
+            Error::MutexError => "poisoned_lock",
+            Error::MalformedOutput => "malformed_output",
+            Error::InvalidTile(_) => "invalid_tile",
+            Error::ConversionFailed(_) => "conversion_failed",
+            #[cfg(feature = "train")]
+            Error::TrainingFailed(_) => "training_failed",
+            Error::RejectedImage(..) => "rejected_image",
+            Error::ArchiveError(_) => "invalid_archive",
+            Error::WorkerFailed(_) => "worker_failed",
+            #[cfg(feature = "parallel")]
+            Error::ThreadPool(_) => "thread_pool",
+            Error::Cancelled => "cancelled",
+            Error::DeadlineExceeded => "deadline_exceeded",
+            Error::InvalidChecksums(_) => "invalid_checksums",
+            Error::ChecksumMismatch { .. } => "checksum_mismatch",
+            #[cfg(feature = "plugins")]
+            Error::PluginLoad(_) => "plugin_load",
+            Error::IncompatiblePlugin(..) => "incompatible_plugin",
+            Error::GridNotFound => "grid_not_found",
+            Error::BannerNotFound => "banner_not_found",
+            Error::EmbeddingLength { .. } => "embedding_length",
+            Error::UnfrozenPinnedModel(_) => "unfrozen_pinned_model",
+            Error::Overloaded(_) => "overloaded",
+            Error::Vetoed(_) => "vetoed",
+        }
     }
+
+    /// is_transient is whether trying the same thing again may succeed, as when TensorFlow
+    /// briefly runs out of GPU memory. Transient TensorFlow errors have their own error_code
+    pub fn is_transient(&self) -> bool {
+        #[cfg(feature = "tensorflow")]
+        if let Error::TensorflowError { code, .. } = self {
+            return matches!(
+                code,
+                tensorflow::Code::ResourceExhausted | tensorflow::Code::Unavailable
+            );
+        }
+        false
+    }
+
+    /// is_client_error is whether the error was caused by the input to a prediction rather
+    /// than by the models or the machine running them
+    pub fn is_client_error(&self) -> bool {
+        matches!(
+            self,
+            Error::ModelLoad(_)
+                | Error::StrumParseError(_)
+                | Error::ImageError(_)
+                | Error::InvalidTile(_)
+                | Error::RejectedImage(..)
+                | Error::Vetoed(_)
+        )
+    }
+}
+
+/// ErrorCode describes one of the codes Error::error_code returns
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ErrorCode {
+    pub code: &'static str,
+    pub description: &'static str,
+    /// client_error is Error::is_client_error for the errors with the code
+    pub client_error: bool,
 }
 
+/// ERROR_CODES lists every code Error::error_code can return, whatever features are enabled,
+/// for documenting them to API consumers
+pub const ERROR_CODES: &[ErrorCode] = &[
+    ErrorCode {
+        code: "io",
+        description: "reading or writing a file or socket failed",
+        client_error: false,
+    },
+    ErrorCode {
+        code: "tensorflow_transient",
+        description: "TensorFlow ran out of GPU memory or was unavailable; retrying may succeed",
+        client_error: false,
+    },
+    ErrorCode {
+        code: "tensorflow",
+        description: "TensorFlow failed",
+        client_error: false,
+    },
+    ErrorCode {
+        code: "model_not_loaded",
+        description: "no model is loaded for the challenge",
+        client_error: true,
+    },
+    ErrorCode {
+        code: "unknown_challenge",
+        description: "the challenge is not one the models know",
+        client_error: true,
+    },
+    ErrorCode {
+        code: "invalid_json",
+        description: "JSON could not be parsed",
+        client_error: false,
+    },
+    ErrorCode {
+        code: "invalid_image",
+        description: "the image could not be decoded",
+        client_error: true,
+    },
+    ErrorCode {
+        code: "remote",
+        description: "a prediction forwarded to another server failed",
+        client_error: false,
+    },
+    ErrorCode {
+        code: "poisoned_lock",
+        description: "a lock was poisoned by a panicking thread",
+        client_error: false,
+    },
+    ErrorCode {
+        code: "malformed_output",
+        description: "the model's output did not have the expected shape",
+        client_error: false,
+    },
+    ErrorCode {
+        code: "invalid_tile",
+        description: "the tile index is outside the grid",
+        client_error: true,
+    },
+    ErrorCode {
+        code: "conversion_failed",
+        description: "converting a model failed",
+        client_error: false,
+    },
+    ErrorCode {
+        code: "training_failed",
+        description: "retraining a model failed",
+        client_error: false,
+    },
+    ErrorCode {
+        code: "rejected_image",
+        description: "the image exceeds the input limits or isn't a supported format",
+        client_error: true,
+    },
+    ErrorCode {
+        code: "invalid_archive",
+        description: "a model archive could not be read",
+        client_error: false,
+    },
+    ErrorCode {
+        code: "worker_failed",
+        description: "an inference worker process failed or died",
+        client_error: false,
+    },
+    ErrorCode {
+        code: "thread_pool",
+        description: "the loading thread pool could not be started",
+        client_error: false,
+    },
+    ErrorCode {
+        code: "cancelled",
+        description: "the prediction was cancelled",
+        client_error: false,
+    },
+    ErrorCode {
+        code: "deadline_exceeded",
+        description: "the prediction's deadline passed",
+        client_error: false,
+    },
+    ErrorCode {
+        code: "invalid_checksums",
+        description: "a models directory's checksums file could not be parsed",
+        client_error: false,
+    },
+    ErrorCode {
+        code: "checksum_mismatch",
+        description: "a model file does not match its checksum",
+        client_error: false,
+    },
+    ErrorCode {
+        code: "plugin_load",
+        description: "a plugin library could not be loaded",
+        client_error: false,
+    },
+    ErrorCode {
+        code: "incompatible_plugin",
+        description: "a plugin was built for another version",
+        client_error: false,
+    },
+    ErrorCode {
+        code: "grid_not_found",
+        description: "no grid of tiles was found in the screenshot",
+        client_error: false,
+    },
+    ErrorCode {
+        code: "banner_not_found",
+        description: "no header banner was found in the screenshot",
+        client_error: false,
+    },
+    ErrorCode {
+        code: "embedding_length",
+        description: "an embedding's length differs from the rest of its gallery",
+        client_error: false,
+    },
+    ErrorCode {
+        code: "unfrozen_pinned_model",
+        description: "a model is pinned to a GPU without a graph cache to freeze it in",
+        client_error: false,
+    },
+    ErrorCode {
+        code: "overloaded",
+        description: "too many predictions are running for the challenge",
+        client_error: false,
+    },
+    ErrorCode {
+        code: "vetoed",
+        description: "the prediction was refused by a hook",
+        client_error: true,
+    },
+];
+
 impl<T> From<PoisonError<T>> for Error {
     fn from(_: PoisonError<T>) -> Error {
         Error::MutexError
     }
 }
 
-impl From<IOError> for Error {
-    fn from(source: IOError) -> Error {
-        Error::IOError(source)
-    }
-}
-
+#[cfg(feature = "tensorflow")]
 impl From<tensorflow::Status> for Error {
     fn from(status: tensorflow::Status) -> Error {
-        Error::TensorflowError(status.code())
+        Error::TensorflowError {
+            code: status.code(),
+            message: status.to_string(),
+        }
     }
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sanitize::Rejection;
+    use std::error::Error as _;
+
+    #[test]
+    fn keeps_the_source_and_code() {
+        let err = Error::RejectedImage(2, Rejection::TooManyBytes(100));
+        assert_eq!(err.error_code(), "rejected_image");
+        assert!(err.is_client_error());
+        assert_eq!(err.to_string(), "image 2 was rejected");
+        assert!(err.source().is_some());
+
+        let err = Error::from(std::io::Error::new(std::io::ErrorKind::Other, "disk full"));
+        assert_eq!(err.error_code(), "io");
+        assert!(!err.is_client_error());
+        assert_eq!(
+            err.source().map(ToString::to_string).as_deref(),
+            Some("disk full")
+        );
+    }
+
+    #[test]
+    fn lists_every_code() {
+        let errors = [
+            Error::from(std::io::Error::new(std::io::ErrorKind::Other, "disk full")),
+            Error::ModelLoad(crate::CaptchaChallenge::Bus),
+            Error::MalformedOutput,
+            Error::InvalidTile(16),
+            Error::RejectedImage(0, Rejection::Unreadable),
+            Error::Cancelled,
+            Error::GridNotFound,
+            Error::BannerNotFound,
+            Error::EmbeddingLength {
+                expected: 128,
+                found: 64,
+            },
+            Error::Overloaded(crate::CaptchaChallenge::Bus),
+            Error::Vetoed(String::new()),
+        ];
+        for err in &errors {
+            let listed = ERROR_CODES
+                .iter()
+                .find(|listed| listed.code == err.error_code());
+            assert_eq!(
+                listed.map(|listed| listed.client_error),
+                Some(err.is_client_error()),
+                "{}",
+                err.error_code()
+            );
+        }
+        let mut codes: Vec<&str> = ERROR_CODES.iter().map(|listed| listed.code).collect();
+        codes.sort_unstable();
+        codes.dedup();
+        assert_eq!(codes.len(), ERROR_CODES.len());
+    }
+
+    #[cfg(feature = "tensorflow")]
+    #[test]
+    fn distinguishes_transient_tensorflow_errors() {
+        let tensorflow_error = |code| Error::TensorflowError {
+            code,
+            message: String::new(),
+        };
+        let exhausted = tensorflow_error(tensorflow::Code::ResourceExhausted);
+        assert!(exhausted.is_transient());
+        assert_eq!(exhausted.error_code(), "tensorflow_transient");
+        let invalid = tensorflow_error(tensorflow::Code::InvalidArgument);
+        assert!(!invalid.is_transient());
+        assert_eq!(invalid.error_code(), "tensorflow");
+    }
+}
@@ -0,0 +1,588 @@
+use crate::{
+    dataset::{self, LabeledImage},
+    errors,
+    scheduling::Priority,
+    CaptchaChallenge, Predictor, MATCH_THRESHOLD,
+};
+use serde_derive::Serialize;
+use std::path::{Path, PathBuf};
+
+/// BATCH_SIZE bounds how many test images are predicted at once
+const BATCH_SIZE: usize = 64;
+
+/// BOOTSTRAP_RESAMPLES is how many times compare resamples the test images to estimate its
+/// confidence intervals
+const BOOTSTRAP_RESAMPLES: usize = 1000;
+
+/// Evaluation is how a predictor fared on the test images of one challenge
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Evaluation {
+    pub challenge: CaptchaChallenge,
+    pub images: usize,
+    pub correct: usize,
+    /// false_positives are images predicted to match that don't
+    pub false_positives: usize,
+    /// false_negatives are matching images predicted not to
+    pub false_negatives: usize,
+    pub accuracy: f64,
+}
+
+/// evaluate predicts every test image for 'challenge' in a dataset laid out like test_data/
+/// (see dataset::labeled_images) and compares the predictions with the labels. A challenge
+/// without test images is evaluated as 0 of 0 correct, with an accuracy of 0
+pub fn evaluate<P, R>(
+    predictor: &P,
+    root: R,
+    challenge: &CaptchaChallenge,
+) -> errors::Result<Evaluation>
+where
+    P: Predictor + ?Sized,
+    R: AsRef<Path>,
+{
+    let test_images = dataset::labeled_images(root, challenge)?;
+    let predicted = predict_matches(predictor, challenge, &test_images)?;
+    let mut evaluation = Evaluation::new(challenge);
+    for (LabeledImage { matches, .. }, predicted) in test_images.iter().zip(predicted) {
+        evaluation.record(predicted, *matches);
+    }
+    Ok(evaluation)
+}
+
+/// evaluate_streaming evaluates as evaluate does, but reads and predicts one batch of images at
+/// a time, so memory stays flat however large the dataset. 'progress' is called with the
+/// running evaluation after every batch
+pub fn evaluate_streaming<P, R, F>(
+    predictor: &P,
+    root: R,
+    challenge: &CaptchaChallenge,
+    mut progress: F,
+) -> errors::Result<Evaluation>
+where
+    P: Predictor + ?Sized,
+    R: AsRef<Path>,
+    F: FnMut(&Evaluation),
+{
+    let mut test_images = dataset::stream_labeled_images(root, challenge)?;
+    let mut evaluation = Evaluation::new(challenge);
+    loop {
+        let batch = test_images
+            .by_ref()
+            .take(BATCH_SIZE)
+            .collect::<errors::Result<Vec<_>>>()?;
+        if batch.is_empty() {
+            return Ok(evaluation);
+        }
+        let predicted = predict_matches(predictor, challenge, &batch)?;
+        for (LabeledImage { matches, .. }, predicted) in batch.iter().zip(predicted) {
+            evaluation.record(predicted, *matches);
+        }
+        progress(&evaluation);
+    }
+}
+
+/// ScoredImage is a test image with the score a predictor gave it, for reports that need more
+/// than an Evaluation's counts
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ScoredImage {
+    pub path: PathBuf,
+    pub matches: bool,
+    pub affirmative: f32,
+    /// predicted is whether the prediction was taken to match (see
+    /// Prediction::is_mainly_affirmative)
+    pub predicted: bool,
+}
+
+/// score_images predicts every test image for 'challenge', one batch at a time as
+/// evaluate_streaming does, keeping each image's score
+pub fn score_images<P, R>(
+    predictor: &P,
+    root: R,
+    challenge: &CaptchaChallenge,
+) -> errors::Result<Vec<ScoredImage>>
+where
+    P: Predictor + ?Sized,
+    R: AsRef<Path>,
+{
+    let mut test_images = dataset::stream_labeled_images(root, challenge)?;
+    let mut scored = Vec::new();
+    loop {
+        let batch = test_images
+            .by_ref()
+            .take(BATCH_SIZE)
+            .collect::<errors::Result<Vec<_>>>()?;
+        if batch.is_empty() {
+            break;
+        }
+        let images = batch
+            .iter()
+            .map(|image| dataset::read_image(&image.path))
+            .collect::<errors::Result<Vec<_>>>()?;
+        let predictions =
+            predictor.predict_batch_prioritized(challenge, images, Priority::Batch)?;
+        scored.extend(
+            batch
+                .into_iter()
+                .zip(predictions)
+                .map(|(image, prediction)| ScoredImage {
+                    path: image.path,
+                    matches: image.matches,
+                    affirmative: prediction.affirmative_confidence(),
+                    predicted: prediction.is_mainly_affirmative(),
+                }),
+        );
+    }
+    scored.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(scored)
+}
+
+impl Evaluation {
+    /// from_scored counts the mistakes among images scored by score_images
+    pub fn from_scored(challenge: &CaptchaChallenge, scored: &[ScoredImage]) -> Evaluation {
+        let mut evaluation = Evaluation::new(challenge);
+        for image in scored {
+            evaluation.record(image.predicted, image.matches);
+        }
+        evaluation
+    }
+
+    fn new(challenge: &CaptchaChallenge) -> Evaluation {
+        Evaluation {
+            challenge: challenge.clone(),
+            images: 0,
+            correct: 0,
+            false_positives: 0,
+            false_negatives: 0,
+            accuracy: 0.0,
+        }
+    }
+
+    /// record counts one image by whether it was predicted to match and whether it does
+    fn record(&mut self, predicted: bool, actual: bool) {
+        self.images += 1;
+        match (predicted, actual) {
+            (predicted, actual) if predicted == actual => self.correct += 1,
+            (true, false) => self.false_positives += 1,
+            _ => self.false_negatives += 1,
+        }
+        self.accuracy = self.correct as f64 / self.images as f64;
+    }
+}
+
+/// Fold is how the threshold tuned on the other folds fared on one held-out fold
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Fold {
+    pub fold: usize,
+    /// threshold is the affirmative score at or above which an image was taken to match
+    pub threshold: f32,
+    pub evaluation: Evaluation,
+}
+
+/// CrossValidation is a k-fold cross-validation of a predictor's match threshold on the test
+/// images of one challenge
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CrossValidation {
+    pub challenge: CaptchaChallenge,
+    pub images: usize,
+    pub folds: Vec<Fold>,
+    /// mean_accuracy and accuracy_std are over the held-out folds, the standard deviation
+    /// being the sample one
+    pub mean_accuracy: f64,
+    pub accuracy_std: f64,
+    pub mean_threshold: f32,
+    pub threshold_std: f32,
+}
+
+/// cross_validate splits images scored by score_images into 'folds' folds, stratified by
+/// label, and for each fold tunes the threshold on the others and evaluates it on that one.
+/// Unlike tuning on the whole test set, the accuracies are of images the threshold wasn't
+/// picked on. The split depends only on the images' paths, so it is the same every run.
+/// Fewer than 2 folds are taken as 2, and folds left without images are skipped
+pub fn cross_validate(
+    challenge: &CaptchaChallenge,
+    scored: &[ScoredImage],
+    folds: usize,
+) -> CrossValidation {
+    let fold_count = folds.max(2);
+    let mut sorted: Vec<&ScoredImage> = scored.iter().collect();
+    sorted.sort_by(|a, b| a.path.cmp(&b.path));
+    // dealing out the matches and then the rest keeps each label spread evenly over the folds
+    let mut assigned: Vec<(usize, &ScoredImage)> = Vec::with_capacity(sorted.len());
+    let (matches, not_matches): (Vec<_>, Vec<_>) =
+        sorted.into_iter().partition(|image| image.matches);
+    for (index, image) in matches.into_iter().chain(not_matches).enumerate() {
+        assigned.push((index % fold_count, image));
+    }
+
+    let mut folds = Vec::with_capacity(fold_count);
+    for fold in 0..fold_count {
+        let (held_out, training): (Vec<_>, Vec<_>) =
+            assigned.iter().partition(|(index, _)| *index == fold);
+        if held_out.is_empty() {
+            continue;
+        }
+        let training: Vec<&ScoredImage> = training.into_iter().map(|&(_, image)| image).collect();
+        let threshold = tune_threshold(&training);
+        let mut evaluation = Evaluation::new(challenge);
+        for (_, image) in held_out {
+            evaluation.record(image.affirmative >= threshold, image.matches);
+        }
+        folds.push(Fold {
+            fold,
+            threshold,
+            evaluation,
+        });
+    }
+
+    let accuracies: Vec<f64> = folds.iter().map(|fold| fold.evaluation.accuracy).collect();
+    let thresholds: Vec<f64> = folds.iter().map(|fold| f64::from(fold.threshold)).collect();
+    let (mean_accuracy, accuracy_std) = mean_and_std(&accuracies);
+    let (mean_threshold, threshold_std) = mean_and_std(&thresholds);
+    CrossValidation {
+        challenge: challenge.clone(),
+        images: scored.len(),
+        folds,
+        mean_accuracy,
+        accuracy_std,
+        mean_threshold: mean_threshold as f32,
+        threshold_std: threshold_std as f32,
+    }
+}
+
+/// tune_threshold is the threshold most accurate on 'training': MATCH_THRESHOLD or one halfway
+/// between two neighbouring scores. Of equally accurate thresholds the one nearest
+/// MATCH_THRESHOLD wins, so a fold without enough images to tell keeps the default
+fn tune_threshold(training: &[&ScoredImage]) -> f32 {
+    let mut sorted: Vec<(f32, bool)> = training
+        .iter()
+        .map(|image| (image.affirmative, image.matches))
+        .collect();
+    sorted.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+    let default_correct = sorted
+        .iter()
+        .filter(|(affirmative, matches)| (*affirmative >= MATCH_THRESHOLD) == *matches)
+        .count();
+    let mut best = (default_correct, MATCH_THRESHOLD);
+    // sweeping up the scores, everything below the threshold is predicted not to match
+    let mut correct = sorted.iter().filter(|(_, matches)| *matches).count();
+    for pair in sorted.windows(2) {
+        let ((below, below_matches), (above, _)) = (pair[0], pair[1]);
+        if below_matches {
+            correct -= 1;
+        } else {
+            correct += 1;
+        }
+        if below == above {
+            continue;
+        }
+        let threshold = below + (above - below) / 2.0;
+        let nearer = (threshold - MATCH_THRESHOLD).abs() < (best.1 - MATCH_THRESHOLD).abs();
+        if correct > best.0 || (correct == best.0 && nearer) {
+            best = (correct, threshold);
+        }
+    }
+    best.1
+}
+
+/// mean_and_std is the mean and sample standard deviation of 'values', 0 for fewer than 2
+fn mean_and_std(values: &[f64]) -> (f64, f64) {
+    if values.is_empty() {
+        return (0.0, 0.0);
+    }
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    if values.len() < 2 {
+        return (mean, 0.0);
+    }
+    let variance = values
+        .iter()
+        .map(|value| (value - mean).powi(2))
+        .sum::<f64>()
+        / (values.len() - 1) as f64;
+    (mean, variance.sqrt())
+}
+
+/// Comparison is how a candidate predictor fared against a baseline on the same test images
+/// of one challenge
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Comparison {
+    pub challenge: CaptchaChallenge,
+    pub images: usize,
+    pub baseline_accuracy: f64,
+    pub candidate_accuracy: f64,
+    /// delta is candidate_accuracy less baseline_accuracy
+    pub delta: f64,
+    /// baseline_only and candidate_only count the images only that predictor got right,
+    /// which are all the significance test looks at
+    pub baseline_only: usize,
+    pub candidate_only: usize,
+    /// p_value is the two-sided exact McNemar test of the predictors being equally accurate.
+    /// A small p_value means the delta is unlikely to be down to the particular test images
+    pub p_value: f64,
+    /// baseline_interval, candidate_interval and delta_interval are 95% bootstrap confidence
+    /// intervals, as (low, high), for the accuracies and their delta
+    pub baseline_interval: (f64, f64),
+    pub candidate_interval: (f64, f64),
+    pub delta_interval: (f64, f64),
+}
+
+/// Winner is the predictor a Comparison found significantly more accurate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Winner {
+    Baseline,
+    Candidate,
+}
+
+impl Comparison {
+    /// winner is the more accurate predictor, if the difference is significant at 'alpha'.
+    /// An insignificant difference has no winner, however large the delta looks
+    pub fn winner(&self, alpha: f64) -> Option<Winner> {
+        if self.p_value >= alpha || self.delta == 0.0 {
+            return None;
+        }
+        Some(if self.delta > 0.0 {
+            Winner::Candidate
+        } else {
+            Winner::Baseline
+        })
+    }
+}
+
+/// compare evaluates 'baseline' and 'candidate' on the test images for 'challenge', as
+/// evaluate does, and tests whether their accuracies differ
+pub fn compare<B, C, R>(
+    baseline: &B,
+    candidate: &C,
+    root: R,
+    challenge: &CaptchaChallenge,
+) -> errors::Result<Comparison>
+where
+    B: Predictor + ?Sized,
+    C: Predictor + ?Sized,
+    R: AsRef<Path>,
+{
+    let test_images = dataset::labeled_images(root, challenge)?;
+    let baseline = predict_matches(baseline, challenge, &test_images)?;
+    let candidate = predict_matches(candidate, challenge, &test_images)?;
+    let (mut baseline_correct, mut candidate_correct) = (0, 0);
+    let (mut baseline_only, mut candidate_only) = (0, 0);
+    let mut outcomes = Vec::with_capacity(test_images.len());
+    for ((image, baseline), candidate) in test_images.iter().zip(baseline).zip(candidate) {
+        let (baseline, candidate) = (baseline == image.matches, candidate == image.matches);
+        baseline_correct += baseline as usize;
+        candidate_correct += candidate as usize;
+        match (baseline, candidate) {
+            (true, false) => baseline_only += 1,
+            (false, true) => candidate_only += 1,
+            _ => {}
+        }
+        outcomes.push((baseline, candidate));
+    }
+    let [baseline_interval, candidate_interval, delta_interval] = bootstrap(&outcomes);
+    let images = test_images.len().max(1) as f64;
+    let baseline_accuracy = baseline_correct as f64 / images;
+    let candidate_accuracy = candidate_correct as f64 / images;
+    Ok(Comparison {
+        challenge: challenge.clone(),
+        images: test_images.len(),
+        baseline_accuracy,
+        candidate_accuracy,
+        delta: candidate_accuracy - baseline_accuracy,
+        baseline_only,
+        candidate_only,
+        p_value: mcnemar_exact(baseline_only, candidate_only),
+        baseline_interval,
+        candidate_interval,
+        delta_interval,
+    })
+}
+
+/// bootstrap resamples whether each predictor got each image right, returning 95% percentile
+/// intervals for the baseline accuracy, the candidate accuracy and their delta. The resampling
+/// is seeded, so a comparison reports the same intervals every time it is run
+fn bootstrap(outcomes: &[(bool, bool)]) -> [(f64, f64); 3] {
+    if outcomes.is_empty() {
+        return [(0.0, 0.0); 3];
+    }
+    // xorshift64*, which is plenty for picking indices
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    let mut next_index = || {
+        state ^= state >> 12;
+        state ^= state << 25;
+        state ^= state >> 27;
+        (state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 32) as usize % outcomes.len()
+    };
+    let count = outcomes.len() as f64;
+    let mut samples = [
+        Vec::with_capacity(BOOTSTRAP_RESAMPLES),
+        Vec::with_capacity(BOOTSTRAP_RESAMPLES),
+        Vec::with_capacity(BOOTSTRAP_RESAMPLES),
+    ];
+    for _ in 0..BOOTSTRAP_RESAMPLES {
+        let (mut baseline, mut candidate) = (0, 0);
+        for _ in 0..outcomes.len() {
+            let (baseline_correct, candidate_correct) = outcomes[next_index()];
+            baseline += baseline_correct as usize;
+            candidate += candidate_correct as usize;
+        }
+        let (baseline, candidate) = (baseline as f64 / count, candidate as f64 / count);
+        samples[0].push(baseline);
+        samples[1].push(candidate);
+        samples[2].push(candidate - baseline);
+    }
+    let interval = |samples: &mut Vec<f64>| {
+        samples.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        let at = |quantile: f64| samples[((samples.len() - 1) as f64 * quantile).round() as usize];
+        (at(0.025), at(0.975))
+    };
+    let [baseline, candidate, delta] = &mut samples;
+    [interval(baseline), interval(candidate), interval(delta)]
+}
+
+/// predict_matches is whether 'predictor' takes each of 'test_images' to match 'challenge'
+fn predict_matches<P>(
+    predictor: &P,
+    challenge: &CaptchaChallenge,
+    test_images: &[LabeledImage],
+) -> errors::Result<Vec<bool>>
+where
+    P: Predictor + ?Sized,
+{
+    let mut matches = Vec::with_capacity(test_images.len());
+    for batch in test_images.chunks(BATCH_SIZE) {
+        let images = batch
+            .iter()
+            .map(|image| dataset::read_image(&image.path))
+            .collect::<errors::Result<Vec<_>>>()?;
+        let predictions =
+            predictor.predict_batch_prioritized(challenge, images, Priority::Batch)?;
+        matches.extend(
+            predictions
+                .iter()
+                .map(|prediction| prediction.is_mainly_affirmative()),
+        );
+    }
+    Ok(matches)
+}
+
+/// mcnemar_exact is the two-sided p-value of 'b' and 'c' discordant pairs under a fair coin,
+/// summed in log space so large test sets don't underflow
+fn mcnemar_exact(b: usize, c: usize) -> f64 {
+    let n = b + c;
+    if n == 0 {
+        return 1.0;
+    }
+    let tail = b.min(c);
+    let mut ln_choose = 0.0;
+    let mut p = 0.0;
+    for i in 0..=tail {
+        if i > 0 {
+            ln_choose += ((n - i + 1) as f64).ln() - (i as f64).ln();
+        }
+        p += (ln_choose - n as f64 * std::f64::consts::LN_2).exp();
+    }
+    (2.0 * p).min(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MockPredictor, Prediction};
+    use std::fs;
+
+    #[test]
+    fn exact_mcnemar_matches_the_binomial_tail() {
+        assert!((mcnemar_exact(0, 0) - 1.0).abs() < 1e-12);
+        // 2 * (1 + 10 + 45) / 1024
+        assert!((mcnemar_exact(2, 8) - 112.0 / 1024.0).abs() < 1e-12);
+        assert!((mcnemar_exact(8, 2) - mcnemar_exact(2, 8)).abs() < 1e-12);
+        assert!(mcnemar_exact(0, 3000) < 1e-300);
+    }
+
+    #[test]
+    fn bootstrap_intervals_cover_the_accuracy() {
+        // the baseline gets 80 of 100 right, the candidate 90, agreeing on 75
+        let outcomes: Vec<(bool, bool)> = (0..100)
+            .map(|index| (index < 80, index < 75 || index >= 85))
+            .collect();
+        let [baseline, candidate, delta] = bootstrap(&outcomes);
+        assert!(baseline.0 < 0.8 && 0.8 < baseline.1);
+        assert!(candidate.0 < 0.9 && 0.9 < candidate.1);
+        assert!(delta.0 < 0.1 && 0.1 < delta.1);
+        assert_eq!(bootstrap(&outcomes), [baseline, candidate, delta]);
+    }
+
+    #[test]
+    fn cross_validation_tunes_on_the_other_folds() {
+        // the classes separate between 0.65 and 0.75 rather than at the default threshold
+        let scored: Vec<ScoredImage> = (0..20)
+            .map(|index| {
+                let matches = index >= 14;
+                let affirmative = if matches {
+                    0.75 + (index - 14) as f32 / 40.0
+                } else {
+                    index as f32 / 20.0
+                };
+                ScoredImage {
+                    path: PathBuf::from(format!("{:02}.png", index)),
+                    matches,
+                    affirmative,
+                    predicted: affirmative >= MATCH_THRESHOLD,
+                }
+            })
+            .collect();
+        let validation = cross_validate(&CaptchaChallenge::Bus, &scored, 4);
+        assert_eq!(validation.folds.len(), 4);
+        assert_eq!(
+            validation
+                .folds
+                .iter()
+                .map(|fold| fold.evaluation.images)
+                .sum::<usize>(),
+            20
+        );
+        for fold in &validation.folds {
+            assert!(fold.threshold > 0.6 && fold.threshold < 0.75, "{:?}", fold);
+        }
+        assert!((validation.mean_accuracy - 1.0).abs() < 1e-9);
+        assert!(validation.accuracy_std.abs() < 1e-9);
+        assert_eq!(
+            cross_validate(&CaptchaChallenge::Bus, &scored, 4),
+            validation
+        );
+    }
+
+    #[test]
+    fn counts_each_kind_of_mistake() -> errors::Result<()> {
+        let root = std::env::temp_dir().join(format!("nocap-evaluation-{}", std::process::id()));
+        let bus = root.join("3x3/bus");
+        fs::create_dir_all(bus.join("matches"))?;
+        fs::create_dir_all(bus.join("not matches"))?;
+        fs::write(bus.join("matches/a.png"), b"bus")?;
+        fs::write(bus.join("matches/b.png"), b"missed bus")?;
+        fs::write(bus.join("not matches/c.png"), b"road")?;
+        fs::write(bus.join("not matches/d.png"), b"truck")?;
+        let mock = MockPredictor::new()
+            .with_image("bus", Prediction::new(0.9, 0.1))
+            .with_image("missed bus", Prediction::new(0.2, 0.8))
+            .with_image("road", Prediction::new(0.1, 0.9))
+            .with_image("truck", Prediction::new(0.7, 0.3));
+
+        let evaluation = evaluate(&mock, &root, &CaptchaChallenge::Bus)?;
+        assert_eq!(
+            (
+                evaluation.images,
+                evaluation.correct,
+                evaluation.false_positives,
+                evaluation.false_negatives
+            ),
+            (4, 2, 1, 1)
+        );
+        assert!((evaluation.accuracy - 0.5).abs() < 1e-9);
+
+        let mut batches = 0;
+        let streamed = evaluate_streaming(&mock, &root, &CaptchaChallenge::Bus, |_| batches += 1)?;
+        assert_eq!((streamed, batches), (evaluation, 1));
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+use crate::errors;
+use image::{DynamicImage, GenericImage, GenericImageView, ImageOutputFormat, Rgba};
+use serde_derive::Serialize;
+
+/// DEFAULT_CELLS is how many cells per side explain occludes by default
+pub const DEFAULT_CELLS: u32 = 8;
+
+/// OCCLUDER is the flat grey painted over a cell, chosen to be neither of the extremes a
+/// model might have learned to key on
+const OCCLUDER: Rgba<u8> = Rgba([127, 127, 127, 255]);
+
+/// SaliencyMap is a coarse explanation of a prediction: each cell's weight is how much the
+/// affirmative confidence dropped when that cell was hidden. Negative weights mark regions
+/// that argued against the challenge
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SaliencyMap {
+    pub cells: u32,
+    pub baseline: f32,
+    /// weights holds cells * cells values in row-major order
+    pub weights: Vec<f32>,
+}
+
+impl SaliencyMap {
+    pub fn weight(&self, row: u32, column: u32) -> f32 {
+        self.weights[(row * self.cells + column) as usize]
+    }
+
+    /// overlay tints 'image' red in proportion to each cell's positive weight and returns it
+    /// PNG encoded
+    pub fn overlay(&self, image: &[u8]) -> errors::Result<Vec<u8>> {
+        let mut image = image::load_from_memory(image)?;
+        let peak = self.weights.iter().cloned().fold(0.0, f32::max);
+        if peak > 0.0 {
+            for (row, column, bounds) in cells(&image, self.cells) {
+                let strength = (self.weight(row, column) / peak).max(0.0);
+                let (x, y, width, height) = bounds;
+                for py in y..y + height {
+                    for px in x..x + width {
+                        let Rgba([r, g, b, a]) = image.get_pixel(px, py);
+                        let tint = |channel: u8, target: f32| {
+                            (f32::from(channel) * (1.0 - strength * 0.6) + target * strength * 0.6)
+                                as u8
+                        };
+                        image.put_pixel(
+                            px,
+                            py,
+                            Rgba([tint(r, 255.0), tint(g, 0.0), tint(b, 0.0), a]),
+                        );
+                    }
+                }
+            }
+        }
+        let mut encoded = Vec::new();
+        image.write_to(&mut encoded, ImageOutputFormat::Png)?;
+        Ok(encoded)
+    }
+}
+
+/// occlusions decodes 'image' and returns one copy per cell with that cell greyed out, PNG
+/// encoded and in row-major order, along with the number of cells per side actually used
+pub(crate) fn occlusions(image: &[u8], cells_per_side: u32) -> errors::Result<(u32, Vec<Vec<u8>>)> {
+    let decoded = image::load_from_memory(image)?;
+    let cells_per_side = clamp_cells(&decoded, cells_per_side);
+    let mut occluded = Vec::with_capacity((cells_per_side * cells_per_side) as usize);
+    for (_, _, (x, y, width, height)) in cells(&decoded, cells_per_side) {
+        let mut copy = decoded.clone();
+        for py in y..y + height {
+            for px in x..x + width {
+                copy.put_pixel(px, py, OCCLUDER);
+            }
+        }
+        let mut encoded = Vec::new();
+        copy.write_to(&mut encoded, ImageOutputFormat::Png)?;
+        occluded.push(encoded);
+    }
+    Ok((cells_per_side, occluded))
+}
+
+/// clamp_cells keeps every cell at least a pixel wide
+fn clamp_cells(image: &DynamicImage, cells_per_side: u32) -> u32 {
+    let (width, height) = image.dimensions();
+    cells_per_side.max(1).min(width.min(height).max(1))
+}
+
+/// cells splits an image into a cells_per_side square grid, yielding (row, column, (x, y,
+/// width, height)). The last row and column absorb any remainder
+fn cells(
+    image: &DynamicImage,
+    cells_per_side: u32,
+) -> impl Iterator<Item = (u32, u32, (u32, u32, u32, u32))> {
+    let (width, height) = image.dimensions();
+    let cells_per_side = clamp_cells(image, cells_per_side);
+    let (cell_width, cell_height) = (width / cells_per_side, height / cells_per_side);
+    (0..cells_per_side).flat_map(move |row| {
+        (0..cells_per_side).map(move |column| {
+            let x = column * cell_width;
+            let y = row * cell_height;
+            let w = if column + 1 == cells_per_side {
+                width - x
+            } else {
+                cell_width
+            };
+            let h = if row + 1 == cells_per_side {
+                height - y
+            } else {
+                cell_height
+            };
+            (row, column, (x, y, w, h))
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cells_cover_image() {
+        let image = DynamicImage::new_rgb8(10, 7);
+        let covered: u32 = cells(&image, 3)
+            .map(|(_, _, (_, _, width, height))| width * height)
+            .sum();
+        assert_eq!(covered, 70);
+        assert_eq!(cells(&image, 3).count(), 9);
+    }
+
+    #[test]
+    fn one_occlusion_per_cell() -> errors::Result<()> {
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(16, 16).write_to(&mut png, ImageOutputFormat::Png)?;
+        let (cells_per_side, occluded) = occlusions(&png, 4)?;
+        assert_eq!((cells_per_side, occluded.len()), (4, 16));
+        assert_eq!(occlusions(&png, 64)?.0, 16);
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+use crate::{ensemble, errors, CaptchaChallenge};
+use std::{
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// ServingExport configures export_tf_serving
+#[derive(Debug, Clone)]
+pub struct ServingExport {
+    /// version is the numeric version directory every model is exported under
+    pub version: u64,
+    /// base_path is where the exported directory will live from TF Serving's point of view,
+    /// e.g. /models inside its container. Defaults to the output directory itself
+    pub base_path: Option<PathBuf>,
+}
+
+impl Default for ServingExport {
+    fn default() -> ServingExport {
+        ServingExport {
+            version: 1,
+            base_path: None,
+        }
+    }
+}
+
+/// ExportedModel is one model written by export_tf_serving
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportedModel {
+    pub challenge: CaptchaChallenge,
+    /// name is the TF Serving model name: the challenge, suffixed with the member's directory
+    /// name for ensembles (e.g. bus_resnet)
+    pub name: String,
+    pub path: PathBuf,
+}
+
+/// export_tf_serving copies a models/ directory into TF Serving's layout and writes the
+/// models.config listing every model:
+///
+/// ```text
+/// <out>/<model name>/<version>/saved_model.pb
+/// <out>/models.config
+/// ```
+pub fn export_tf_serving<P, Q>(
+    models_dir: P,
+    out_dir: Q,
+    export: &ServingExport,
+) -> errors::Result<Vec<ExportedModel>>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let out_dir = out_dir.as_ref();
+    let mut exported = Vec::new();
+    for (challenge, name, member) in named_models(models_dir.as_ref())? {
+        let path = out_dir.join(&name).join(export.version.to_string());
+        copy_dir(&member, &path)?;
+        exported.push(ExportedModel {
+            challenge,
+            name,
+            path,
+        });
+    }
+    exported.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let base_path = export
+        .base_path
+        .clone()
+        .unwrap_or_else(|| out_dir.to_path_buf());
+    fs::write(
+        out_dir.join("models.config"),
+        models_config(&exported, &base_path),
+    )?;
+    Ok(exported)
+}
+
+/// named_models lists every model under 'models_dir' as (challenge, name, directory). A model
+/// is named after its challenge, suffixed with the member's directory name for ensembles
+pub(crate) fn named_models(
+    models_dir: &Path,
+) -> errors::Result<Vec<(CaptchaChallenge, String, PathBuf)>> {
+    let mut models = Vec::new();
+    for entry in models_dir.read_dir()? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let challenge = match entry.file_name().to_str().map(CaptchaChallenge::from_str) {
+            Some(Ok(challenge)) => challenge,
+            _ => continue,
+        };
+        let challenge_dir = entry.path();
+        for member in ensemble::members(&challenge_dir)? {
+            let name = if member == challenge_dir {
+                challenge.to_string()
+            } else {
+                format!(
+                    "{}_{}",
+                    challenge,
+                    member.file_name().unwrap_or_default().to_string_lossy()
+                )
+            };
+            models.push((challenge.clone(), name, member));
+        }
+    }
+    Ok(models)
+}
+
+/// models_config renders TF Serving's model config file, which is a text protobuf
+fn models_config(models: &[ExportedModel], base_path: &Path) -> String {
+    let mut config = String::from("model_config_list {\n");
+    for model in models {
+        let _ = write!(
+            config,
+            "  config {{\n    name: '{}'\n    base_path: '{}'\n    model_platform: 'tensorflow'\n  }}\n",
+            model.name,
+            base_path.join(&model.name).display()
+        );
+    }
+    config.push_str("}\n");
+    config
+}
+
+fn copy_dir(from: &Path, to: &Path) -> errors::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in from.read_dir()? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            let _ = fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exports_single_models_and_ensembles() -> errors::Result<()> {
+        let root = std::env::temp_dir().join(format!("nocap-export-{}", std::process::id()));
+        let models = root.join("models");
+        fs::create_dir_all(models.join("bus"))?;
+        fs::write(models.join("bus/saved_model.pb"), b"bus")?;
+        fs::create_dir_all(models.join("taxis/resnet/variables"))?;
+        fs::write(models.join("taxis/resnet/saved_model.pb"), b"resnet")?;
+        fs::write(models.join("taxis/resnet/variables/variables.index"), b"")?;
+        fs::create_dir_all(models.join("not_a_challenge"))?;
+
+        let out = root.join("serving");
+        let export = ServingExport {
+            version: 3,
+            base_path: Some(PathBuf::from("/models")),
+        };
+        let exported = export_tf_serving(&models, &out, &export)?;
+        let names: Vec<&str> = exported.iter().map(|model| model.name.as_str()).collect();
+        assert_eq!(names, vec!["bus", "taxis_resnet"]);
+        assert!(out.join("bus/3/saved_model.pb").exists());
+        assert!(out
+            .join("taxis_resnet/3/variables/variables.index")
+            .exists());
+
+        let config = fs::read_to_string(out.join("models.config"))?;
+        assert!(config.contains("name: 'taxis_resnet'"));
+        assert!(config.contains("base_path: '/models/bus'"));
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+use crate::CaptchaChallenge;
+use serde_derive::Serialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Mutex, PoisonError},
+};
+
+/// MAX_VERDICTS is how many images' verdicts FeedbackLog keeps per challenge. Past it the
+/// image that first received feedback longest ago is forgotten, so accuracy follows the most
+/// recent feedback and memory stays bounded however many images clients report on
+pub const MAX_VERDICTS: usize = 100_000;
+
+/// Accuracy tallies how often a challenge's predictions were confirmed correct
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Accuracy {
+    pub correct: u64,
+    pub incorrect: u64,
+}
+
+impl Accuracy {
+    pub fn total(&self) -> u64 {
+        self.correct + self.incorrect
+    }
+
+    /// rate is the fraction of correct predictions, or None before any feedback arrived
+    pub fn rate(&self) -> Option<f64> {
+        match self.total() {
+            0 => None,
+            total => Some(self.correct as f64 / total as f64),
+        }
+    }
+}
+
+/// FeedbackLog keeps the latest verdict for every (challenge, image hash) pair, so repeated
+/// feedback about the same image corrects the earlier verdict instead of counting twice. At
+/// most MAX_VERDICTS are kept per challenge
+#[derive(Debug, Default)]
+pub struct FeedbackLog {
+    verdicts: Mutex<HashMap<CaptchaChallenge, Verdicts>>,
+}
+
+impl FeedbackLog {
+    pub fn record(&self, challenge: &CaptchaChallenge, image_hash: &str, was_correct: bool) {
+        self.verdicts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(challenge.clone())
+            .or_default()
+            .insert(image_hash, was_correct, MAX_VERDICTS);
+    }
+
+    pub fn accuracy(&self, challenge: &CaptchaChallenge) -> Accuracy {
+        self.verdicts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(challenge)
+            .map(Verdicts::tally)
+            .unwrap_or_default()
+    }
+
+    /// report returns the accuracy of every challenge that has received feedback
+    pub fn report(&self) -> HashMap<CaptchaChallenge, Accuracy> {
+        self.verdicts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(challenge, verdicts)| (challenge.clone(), verdicts.tally()))
+            .collect()
+    }
+}
+
+/// Verdicts are one challenge's verdicts by image hash, with the hashes in the order they
+/// first received feedback
+#[derive(Debug, Default)]
+struct Verdicts {
+    latest: HashMap<String, bool>,
+    order: VecDeque<String>,
+}
+
+impl Verdicts {
+    fn insert(&mut self, image_hash: &str, was_correct: bool, max: usize) {
+        if let Some(verdict) = self.latest.get_mut(image_hash) {
+            *verdict = was_correct;
+            return;
+        }
+        while self.order.len() >= max {
+            match self.order.pop_front() {
+                Some(oldest) => {
+                    let _ = self.latest.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+        let _ = self.latest.insert(image_hash.to_owned(), was_correct);
+        self.order.push_back(image_hash.to_owned());
+    }
+
+    fn tally(&self) -> Accuracy {
+        let correct = self.latest.values().filter(|&&correct| correct).count() as u64;
+        Accuracy {
+            correct,
+            incorrect: self.latest.len() as u64 - correct,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latest_verdict_wins() {
+        let log = FeedbackLog::default();
+        log.record(&CaptchaChallenge::Bus, "a", true);
+        log.record(&CaptchaChallenge::Bus, "b", true);
+        log.record(&CaptchaChallenge::Bus, "b", false);
+        log.record(&CaptchaChallenge::Cars, "a", false);
+
+        let bus = log.accuracy(&CaptchaChallenge::Bus);
+        assert_eq!(
+            bus,
+            Accuracy {
+                correct: 1,
+                incorrect: 1
+            }
+        );
+        assert_eq!(bus.rate(), Some(0.5));
+        assert_eq!(log.accuracy(&CaptchaChallenge::Taxis).rate(), None);
+        assert_eq!(log.report().len(), 2);
+    }
+
+    #[test]
+    fn forgets_the_oldest_images_past_the_cap() {
+        let mut verdicts = Verdicts::default();
+        verdicts.insert("a", false, 2);
+        verdicts.insert("b", true, 2);
+        verdicts.insert("a", true, 2);
+        verdicts.insert("c", true, 2);
+        assert_eq!(
+            verdicts.tally(),
+            Accuracy {
+                correct: 2,
+                incorrect: 0
+            }
+        );
+        assert!(!verdicts.latest.contains_key("a"));
+    }
+}
@@ -0,0 +1,190 @@
+use crate::errors;
+use image::{DynamicImage, GenericImageView, ImageFormat, ImageOutputFormat, Pixel, RgbImage};
+use std::{borrow::Cow, io::Cursor};
+
+/// TileFormat is an image format tiles are accepted in, detected from the leading bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileFormat {
+    Png,
+    Jpeg,
+    Gif,
+    WebP,
+}
+
+impl TileFormat {
+    /// detect identifies the format from the magic bytes, ignoring any claimed content type
+    pub fn detect(image: &[u8]) -> Option<TileFormat> {
+        match image::guess_format(image) {
+            Ok(ImageFormat::Png) => Some(TileFormat::Png),
+            Ok(ImageFormat::Jpeg) => Some(TileFormat::Jpeg),
+            Ok(ImageFormat::Gif) => Some(TileFormat::Gif),
+            Ok(ImageFormat::WebP) => Some(TileFormat::WebP),
+            _ => None,
+        }
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            TileFormat::Png => "image/png",
+            TileFormat::Jpeg => "image/jpeg",
+            TileFormat::Gif => "image/gif",
+            TileFormat::WebP => "image/webp",
+        }
+    }
+
+    /// is_model_native is whether the model graphs decode the format themselves. The rest
+    /// have to be converted first: the graphs' decoder knows nothing of WebP, and decodes GIFs
+    /// to an extra frame dimension the models don't expect
+    pub fn is_model_native(self) -> bool {
+        matches!(self, TileFormat::Png | TileFormat::Jpeg)
+    }
+}
+
+/// for_model returns 'images' normalized the way the models were trained: PNG or JPEG,
+/// upright, and 8 bit RGB. Images in other formats, with an EXIF orientation, or in grayscale,
+/// CMYK or with an alpha channel are decoded, rotated and re-encoded as RGB PNGs; when none
+/// need it, nothing is copied. Images of unknown format are passed through untouched
+pub fn for_model(images: &[Vec<u8>]) -> errors::Result<Cow<'_, [Vec<u8>]>> {
+    let needs_conversion = |image: &Vec<u8>| match TileFormat::detect(image) {
+        Some(format) => {
+            !format.is_model_native() || !is_plain_rgb(image, format) || orientation(image) != 1
+        }
+        None => false,
+    };
+    if !images.iter().any(needs_conversion) {
+        return Ok(Cow::Borrowed(images));
+    }
+    let mut converted = Vec::with_capacity(images.len());
+    for image in images {
+        converted.push(if needs_conversion(image) {
+            normalize(image)?
+        } else {
+            image.clone()
+        });
+    }
+    Ok(Cow::Owned(converted))
+}
+
+fn normalize(image: &[u8]) -> errors::Result<Vec<u8>> {
+    let decoded = image::load_from_memory(image)?;
+    let upright = match orientation(image) {
+        2 => decoded.fliph(),
+        3 => decoded.rotate180(),
+        4 => decoded.flipv(),
+        5 => decoded.rotate90().fliph(),
+        6 => decoded.rotate90(),
+        7 => decoded.rotate270().fliph(),
+        8 => decoded.rotate270(),
+        _ => decoded,
+    };
+    // dropping any alpha channel, and expanding grayscale
+    let (width, height) = upright.dimensions();
+    let rgb = RgbImage::from_fn(width, height, |x, y| upright.get_pixel(x, y).to_rgb());
+    let mut encoded = Vec::new();
+    DynamicImage::ImageRgb8(rgb).write_to(&mut encoded, ImageOutputFormat::Png)?;
+    Ok(encoded)
+}
+
+/// orientation reads the EXIF orientation tag, 1 meaning upright, as phone screenshots and
+/// photos store rotation there instead of in the pixels
+fn orientation(image: &[u8]) -> u32 {
+    exif::Reader::new()
+        .read_from_container(&mut Cursor::new(image))
+        .ok()
+        .and_then(|exif| {
+            exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?
+                .value
+                .get_uint(0)
+        })
+        .unwrap_or(1)
+}
+
+/// is_plain_rgb checks the header for 8 bit, three channel color without decoding anything
+fn is_plain_rgb(image: &[u8], format: TileFormat) -> bool {
+    match format {
+        // IHDR is always the first chunk: bit depth at byte 24, color type at 25 (2 = RGB)
+        TileFormat::Png => image.get(24) == Some(&8) && image.get(25) == Some(&2),
+        TileFormat::Jpeg => jpeg_components(image) == Some(3),
+        _ => false,
+    }
+}
+
+/// jpeg_components finds the number of color components in a JPEG's start of frame: 1 for
+/// grayscale, 3 for YCbCr and 4 for CMYK
+fn jpeg_components(image: &[u8]) -> Option<u8> {
+    let mut offset = 2;
+    while offset + 4 <= image.len() {
+        if image[offset] != 0xFF {
+            return None;
+        }
+        let marker = image[offset + 1];
+        let length = usize::from(u16::from_be_bytes([image[offset + 2], image[offset + 3]]));
+        let is_start_of_frame =
+            (0xC0..=0xCF).contains(&marker) && ![0xC4, 0xC8, 0xCC].contains(&marker);
+        if is_start_of_frame {
+            return image.get(offset + 9).copied();
+        }
+        offset += 2 + length;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(format: ImageOutputFormat) -> Vec<u8> {
+        encode(DynamicImage::new_rgb8(8, 8), format)
+    }
+
+    fn encode(image: DynamicImage, format: ImageOutputFormat) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        image.write_to(&mut encoded, format).unwrap();
+        encoded
+    }
+
+    /// with_orientation inserts an EXIF APP1 segment holding 'orientation' after a JPEG's SOI
+    fn with_orientation(mut jpeg: Vec<u8>, orientation: u8) -> Vec<u8> {
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01".to_vec();
+        exif.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, orientation, 0, 0]);
+        exif.extend_from_slice(&[0, 0, 0, 0]);
+        let mut segment = vec![0xFF, 0xE1];
+        segment.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(&exif);
+        let _ = jpeg.splice(2..2, segment);
+        jpeg
+    }
+
+    #[test]
+    fn converts_only_foreign_formats() -> errors::Result<()> {
+        let png = encoded(ImageOutputFormat::Png);
+        let jpeg = encoded(ImageOutputFormat::Jpeg(90));
+        let native = vec![png.clone(), jpeg];
+        assert!(matches!(for_model(&native)?, Cow::Borrowed(_)));
+
+        let gif = encoded(ImageOutputFormat::Gif);
+        assert_eq!(TileFormat::detect(&gif), Some(TileFormat::Gif));
+        let mixed = vec![png.clone(), gif];
+        let converted = for_model(&mixed)?;
+        assert_eq!(converted[0], png);
+        assert_eq!(TileFormat::detect(&converted[1]), Some(TileFormat::Png));
+        Ok(())
+    }
+
+    #[test]
+    fn normalizes_orientation_and_color() -> errors::Result<()> {
+        let wide = encode(DynamicImage::new_rgb8(16, 8), ImageOutputFormat::Jpeg(90));
+        assert_eq!(jpeg_components(&wide), Some(3));
+        let rotated = with_orientation(wide, 6);
+        assert_eq!(orientation(&rotated), 6);
+
+        let gray = encode(DynamicImage::new_luma8(8, 8), ImageOutputFormat::Png);
+        let converted = for_model(&[rotated, gray])?;
+        let upright = image::load_from_memory(&converted[0])?;
+        assert_eq!(upright.dimensions(), (8, 16));
+        assert!(converted
+            .iter()
+            .all(|image| is_plain_rgb(image, TileFormat::Png)));
+        Ok(())
+    }
+}
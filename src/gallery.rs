@@ -0,0 +1,247 @@
+use crate::{embedding::Embedder, errors, Prediction};
+use instant_distance::{Builder, HnswMap, Point, Search};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+};
+
+/// GalleryEntry is one labeled embedding, stored one JSON object per line
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GalleryEntry {
+    pub label: String,
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Debug, Clone)]
+struct Embedding(Vec<f32>);
+
+impl Point for Embedding {
+    /// distance is the cosine distance, which ignores the activations' overall magnitude
+    fn distance(&self, other: &Embedding) -> f32 {
+        let (mut dot, mut left, mut right) = (0.0, 0.0, 0.0);
+        for (a, b) in self.0.iter().zip(&other.0) {
+            dot += a * b;
+            left += a * a;
+            right += b * b;
+        }
+        if left == 0.0 || right == 0.0 {
+            return 1.0;
+        }
+        1.0 - dot / (left.sqrt() * right.sqrt())
+    }
+}
+
+/// Gallery is an HNSW index over labeled embeddings. It answers "does this image show a
+/// <label>?" by looking at the labels of the image's nearest neighbors, which gives a usable
+/// prediction for categories no dedicated model has been trained on yet
+pub struct Gallery {
+    index: HnswMap<Embedding, String>,
+    neighbors: usize,
+    /// dimensions is the length of every embedding in the gallery, None when it is empty
+    dimensions: Option<usize>,
+}
+
+impl std::fmt::Debug for Gallery {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Gallery")
+            .field("neighbors", &self.neighbors)
+            .finish()
+    }
+}
+
+impl Gallery {
+    /// DEFAULT_NEIGHBORS is how many nearest neighbors vote on a prediction
+    pub const DEFAULT_NEIGHBORS: usize = 10;
+
+    /// from_entries indexes 'entries', which must all have embeddings of the same length
+    pub fn from_entries(entries: Vec<GalleryEntry>) -> errors::Result<Gallery> {
+        let dimensions = entries.first().map(|entry| entry.embedding.len());
+        if let Some(expected) = dimensions {
+            if let Some(entry) = entries
+                .iter()
+                .find(|entry| entry.embedding.len() != expected)
+            {
+                return Err(errors::Error::EmbeddingLength {
+                    expected,
+                    found: entry.embedding.len(),
+                });
+            }
+        }
+        let (points, labels): (Vec<Embedding>, Vec<String>) = entries
+            .into_iter()
+            .map(|entry| (Embedding(entry.embedding), entry.label))
+            .unzip();
+        Ok(Gallery {
+            index: Builder::default().build(points, labels),
+            neighbors: Self::DEFAULT_NEIGHBORS,
+            dimensions,
+        })
+    }
+
+    pub fn with_neighbors(mut self, neighbors: usize) -> Gallery {
+        self.neighbors = neighbors.max(1);
+        self
+    }
+
+    /// load reads a gallery saved with save_entries
+    pub fn load<P>(path: P) -> errors::Result<Gallery>
+    where
+        P: AsRef<Path>,
+    {
+        let mut entries = Vec::new();
+        for line in BufReader::new(fs::File::open(path)?).lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                entries.push(serde_json::from_str(&line)?);
+            }
+        }
+        Gallery::from_entries(entries)
+    }
+
+    pub fn save_entries<P>(path: P, entries: &[GalleryEntry]) -> errors::Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let mut file = fs::File::create(path)?;
+        for entry in entries {
+            serde_json::to_writer(&mut file, entry)?;
+            file.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    /// embed_directory embeds a labeled image directory laid out as `<dir>/<label>/<image>`
+    pub fn embed_directory<P>(embedder: &Embedder, dir: P) -> errors::Result<Vec<GalleryEntry>>
+    where
+        P: AsRef<Path>,
+    {
+        let mut entries = Vec::new();
+        for label_dir in dir.as_ref().read_dir()? {
+            let label_dir = label_dir?;
+            if !label_dir.file_type()?.is_dir() {
+                continue;
+            }
+            let label = label_dir.file_name().to_string_lossy().into_owned();
+            let mut images = Vec::new();
+            for image in label_dir.path().read_dir()? {
+                images.push(fs::read(image?.path())?);
+            }
+            for embedding in embedder.embed(&images)? {
+                entries.push(GalleryEntry {
+                    label: label.clone(),
+                    embedding,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    /// predict scores how strongly an embedding's neighborhood agrees with 'label'. Closer
+    /// neighbors count for more. The embedding must be as long as the gallery's
+    pub fn predict(&self, label: &str, embedding: Vec<f32>) -> errors::Result<Prediction> {
+        match self.dimensions {
+            Some(expected) if expected != embedding.len() => {
+                return Err(errors::Error::EmbeddingLength {
+                    expected,
+                    found: embedding.len(),
+                })
+            }
+            _ => {}
+        }
+        let mut search = Search::default();
+        let (mut agreeing, mut total) = (0.0, 0.0);
+        for neighbor in self
+            .index
+            .search(&Embedding(embedding), &mut search)
+            .take(self.neighbors)
+        {
+            let weight = 1.0 / (neighbor.distance + 1e-6);
+            total += weight;
+            if neighbor.value == label {
+                agreeing += weight;
+            }
+        }
+        let affirmative_confidence = if total > 0.0 { agreeing / total } else { 0.0 };
+        Ok(Prediction {
+            affirmative_confidence,
+            negative_confidence: 1.0 - affirmative_confidence,
+        })
+    }
+}
+
+/// Fallback pairs an embedder with a gallery, standing in for challenges that have no model
+#[derive(Debug)]
+pub struct Fallback {
+    pub embedder: Embedder,
+    pub gallery: Gallery,
+}
+
+impl Fallback {
+    pub fn predict(&self, label: &str, images: &[Vec<u8>]) -> errors::Result<Vec<Prediction>> {
+        self.embedder
+            .embed(images)?
+            .into_iter()
+            .map(|embedding| self.gallery.predict(label, embedding))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(label: &str, embedding: &[f32]) -> GalleryEntry {
+        GalleryEntry {
+            label: label.to_string(),
+            embedding: embedding.to_vec(),
+        }
+    }
+
+    #[test]
+    fn cosine_distance() {
+        let distance =
+            |a: &[f32], b: &[f32]| Embedding(a.to_vec()).distance(&Embedding(b.to_vec()));
+        assert!(distance(&[1.0, 0.0], &[2.0, 0.0]).abs() < 1e-6);
+        assert!((distance(&[1.0, 0.0], &[0.0, 3.0]) - 1.0).abs() < 1e-6);
+        assert!((distance(&[1.0, 0.0], &[-1.0, 0.0]) - 2.0).abs() < 1e-6);
+        assert_eq!(distance(&[0.0, 0.0], &[1.0, 0.0]), 1.0);
+    }
+
+    #[test]
+    fn neighbors_vote_by_closeness() -> errors::Result<()> {
+        let gallery = Gallery::from_entries(vec![
+            entry("bus", &[1.0, 0.0]),
+            entry("bus", &[0.9, 0.1]),
+            entry("car", &[0.0, 1.0]),
+        ])?
+        .with_neighbors(3);
+        let bus = gallery.predict("bus", vec![1.0, 0.05])?;
+        assert!(bus.affirmative_confidence() > 0.9);
+        let car = gallery.predict("car", vec![0.1, 1.0])?;
+        assert!(car.affirmative_confidence() > 0.5);
+        assert_eq!(
+            gallery
+                .predict("boat", vec![1.0, 0.0])?
+                .affirmative_confidence(),
+            0.0
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_embeddings_of_another_length() -> errors::Result<()> {
+        let mixed = Gallery::from_entries(vec![entry("bus", &[1.0, 0.0]), entry("car", &[1.0])]);
+        assert!(matches!(
+            mixed,
+            Err(errors::Error::EmbeddingLength {
+                expected: 2,
+                found: 1
+            })
+        ));
+        let gallery = Gallery::from_entries(vec![entry("bus", &[1.0, 0.0])])?;
+        assert!(gallery.predict("bus", vec![1.0, 0.0, 0.0]).is_err());
+        Ok(())
+    }
+}
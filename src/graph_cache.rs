@@ -0,0 +1,211 @@
+use crate::{cache, errors, integrity};
+use serde_derive::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// FREEZE_GRAPH restores the SavedModel in argv[1], folds its variables into constants and
+/// strips everything 'scores' doesn't depend on, writing the resulting GraphDef to argv[2].
+/// Op names are kept, so the frozen graph is fed and fetched like the original
+const FREEZE_GRAPH: &str = r#"
+import sys
+import tensorflow.compat.v1 as tf
+
+tf.disable_eager_execution()
+with tf.Session(graph=tf.Graph()) as session:
+    tf.saved_model.loader.load(session, ["serve"], sys.argv[1])
+    graph_def = tf.graph_util.convert_variables_to_constants(
+        session, session.graph.as_graph_def(), ["scores"]
+    )
+    graph_def = tf.graph_util.remove_training_nodes(graph_def)
+open(sys.argv[2], "wb").write(graph_def.SerializeToString())
+"#;
+
+/// GraphCacheStats is what a GraphCache's directory holds
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GraphCacheStats {
+    pub dir: PathBuf,
+    pub graphs: usize,
+    pub bytes: u64,
+    /// failures counts the models recorded as failing to freeze
+    pub failures: usize,
+}
+
+/// GraphCache keeps a frozen copy of each model's graph in a directory, so later loads import
+/// one GraphDef instead of restoring the SavedModel and its variables. Freezing shells out to
+/// TensorFlow's Python package, which 'python' must be able to import; it only happens the
+/// first time a model is loaded, and a model that fails to freeze is simply loaded uncached
+/// (see freeze for when it is tried again)
+#[derive(Debug, Clone)]
+pub struct GraphCache {
+    pub dir: PathBuf,
+    pub python: PathBuf,
+    /// python_missing is set once 'python' couldn't be started, and shared between clones
+    python_missing: Arc<AtomicBool>,
+}
+
+impl GraphCache {
+    pub fn new<P>(dir: P) -> GraphCache
+    where
+        P: Into<PathBuf>,
+    {
+        GraphCache {
+            dir: dir.into(),
+            python: PathBuf::from("python3"),
+            python_missing: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// cached_graph is where the frozen graph of the SavedModel in 'model_dir' is kept. The
+    /// name is derived from the model's path and its integrity::fingerprint, so a changed
+    /// model is frozen again instead of loading a stale graph
+    pub fn cached_graph(&self, model_dir: &Path) -> errors::Result<PathBuf> {
+        let mut hasher = Sha256::new();
+        hasher.input(model_dir.canonicalize()?.to_string_lossy().as_bytes());
+        hasher.input(integrity::fingerprint(model_dir)?.as_bytes());
+        let key: String = hasher
+            .result()
+            .iter()
+            .take(16)
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        Ok(self.dir.join(format!("{}.pb", key)))
+    }
+
+    /// freeze writes the frozen graph of the SavedModel in 'model_dir' to its cached_graph. A
+    /// model that fails to freeze gets a .failed file holding the error in place of its graph,
+    /// and isn't tried again until it changes or that file is removed. Once 'python' can't be
+    /// started at all, no other model is tried for the life of the cache
+    pub fn freeze(&self, model_dir: &Path) -> errors::Result<PathBuf> {
+        let cached = self.cached_graph(model_dir)?;
+        if self.python_missing.load(Ordering::Relaxed) {
+            return Err(errors::Error::ConversionFailed(format!(
+                "{} could not be started",
+                self.python.display()
+            )));
+        }
+        let failed = cached.with_extension("failed");
+        if let Ok(failure) = fs::read_to_string(&failed) {
+            return Err(errors::Error::ConversionFailed(failure));
+        }
+        fs::create_dir_all(&self.dir)?;
+        // frozen beside the destination and renamed into place, so an interrupted freeze is
+        // never mistaken for a cached graph
+        let partial = cache::partial_path(&cached);
+        let result = Command::new(&self.python)
+            .arg("-c")
+            .arg(FREEZE_GRAPH)
+            .arg(model_dir)
+            .arg(&partial)
+            .output();
+        let result = match result {
+            Ok(result) => result,
+            Err(err) => {
+                if err.kind() == io::ErrorKind::NotFound {
+                    self.python_missing.store(true, Ordering::Relaxed);
+                }
+                return Err(err.into());
+            }
+        };
+        if !result.status.success() {
+            let _ = fs::remove_file(&partial);
+            let failure = format!(
+                "{} failed: {}",
+                self.python.display(),
+                String::from_utf8_lossy(&result.stderr).trim()
+            );
+            // losing the record only means trying again on the next load
+            let _ = fs::write(&failed, &failure);
+            return Err(errors::Error::ConversionFailed(failure));
+        }
+        fs::rename(&partial, &cached)?;
+        Ok(cached)
+    }
+
+    /// stats counts the frozen graphs in the cache and their size. Graphs of models that have
+    /// since changed stay until removed by hand, so they are counted too
+    pub fn stats(&self) -> errors::Result<GraphCacheStats> {
+        let mut stats = GraphCacheStats {
+            dir: self.dir.clone(),
+            graphs: 0,
+            bytes: 0,
+            failures: 0,
+        };
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            // nothing has been frozen yet
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(stats),
+            Err(err) => return Err(err.into()),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some("pb") => {
+                    stats.graphs += 1;
+                    stats.bytes += fs::metadata(&path)?.len();
+                }
+                Some("failed") => stats.failures += 1,
+                _ => {}
+            }
+        }
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changed_models_get_a_new_cache_entry() -> errors::Result<()> {
+        let dir = std::env::temp_dir().join(format!("nocap-graph-cache-{}", std::process::id()));
+        let model = dir.join("bus");
+        fs::create_dir_all(model.join("variables"))?;
+        fs::write(model.join("saved_model.pb"), b"graph")?;
+        let cache = GraphCache::new(dir.join("cache"));
+
+        let cached = cache.cached_graph(&model)?;
+        assert_eq!(cache.cached_graph(&model)?, cached);
+        assert_eq!(cache.stats()?.graphs, 0);
+        fs::create_dir_all(&cache.dir)?;
+        fs::write(&cached, b"frozen")?;
+        assert_eq!((cache.stats()?.graphs, cache.stats()?.bytes), (1, 6));
+        fs::write(model.join("variables/variables.index"), b"index")?;
+        assert_ne!(cache.cached_graph(&model)?, cached);
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn remembers_failed_freezes() -> errors::Result<()> {
+        let dir = std::env::temp_dir().join(format!("nocap-graph-freeze-{}", std::process::id()));
+        let model = dir.join("bus");
+        fs::create_dir_all(&model)?;
+        fs::write(model.join("saved_model.pb"), b"graph")?;
+
+        let mut cache = GraphCache::new(dir.join("cache"));
+        cache.python = PathBuf::from("false");
+        assert!(cache.freeze(&model).is_err());
+        assert!(cache
+            .cached_graph(&model)?
+            .with_extension("failed")
+            .exists());
+        assert_eq!(cache.stats()?.failures, 1);
+
+        let mut missing = GraphCache::new(dir.join("cache"));
+        missing.python = dir.join("no-such-python");
+        fs::write(model.join("labels.txt"), b"bus")?;
+        assert!(missing.freeze(&model).is_err());
+        assert!(missing.python_missing.load(Ordering::Relaxed));
+        assert!(missing.clone().freeze(&model).is_err());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,333 @@
+use crate::{errors, GridSize};
+use image::{GenericImageView, Rgb, RgbImage};
+use serde_derive::Serialize;
+
+/// Bounds is a rectangle of pixels, 'right' and 'bottom' exclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Bounds {
+    pub left: u32,
+    pub top: u32,
+    pub right: u32,
+    pub bottom: u32,
+}
+
+/// Layout is the geometry of a challenge's grid, as found in a screenshot by locate
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Layout {
+    pub grid: GridSize,
+    /// header_height is how far below the top of the screenshot the grid starts, the header
+    /// banner and the margin under it included
+    pub header_height: u32,
+    /// tiles are the bounds of the tiles, without the borders between them, in row-major order
+    pub tiles: Vec<Bounds>,
+}
+
+/// split cuts a screenshot of a challenge into its tiles, in row-major order. The screenshot
+/// may include the widget around the grid: the blue header banner is skipped, the grid is
+/// taken to be as tall as it is wide (leaving out the footer below it), and the white borders
+/// between tiles are trimmed off each tile. A screenshot of the bare grid splits the same way
+pub fn split(screenshot: &[u8], grid: GridSize) -> errors::Result<Vec<RgbImage>> {
+    let rgb = decode(screenshot)?;
+    let side = grid.tiles_per_side();
+    let bounds = grid_bounds(&rgb).filter(|bounds| bounds.right - bounds.left >= side);
+    let bounds = bounds.ok_or(errors::Error::GridNotFound)?;
+    let size = bounds.right - bounds.left;
+    let mut tiles = Vec::with_capacity((side * side) as usize);
+    for row in 0..side {
+        for column in 0..side {
+            let cell = Bounds {
+                left: bounds.left + size * column / side,
+                right: bounds.left + size * (column + 1) / side,
+                top: bounds.top + size * row / side,
+                bottom: (bounds.top + size * (row + 1) / side).min(bounds.bottom),
+            };
+            tiles.push(crop(&rgb, trim_white(&rgb, cell)));
+        }
+    }
+    Ok(tiles)
+}
+
+/// locate finds the grid's size and the bounds of its tiles in a screenshot of a challenge,
+/// from the white borders between the tiles, so neither has to be known beforehand. Like
+/// split, it skips the header banner and the footer below the grid
+pub fn locate(screenshot: &[u8]) -> errors::Result<Layout> {
+    locate_in(&decode(screenshot)?).ok_or(errors::Error::GridNotFound)
+}
+
+/// split_located cuts a screenshot into its tiles where locate finds them
+pub fn split_located(screenshot: &[u8]) -> errors::Result<(Layout, Vec<RgbImage>)> {
+    let rgb = decode(screenshot)?;
+    let layout = locate_in(&rgb).ok_or(errors::Error::GridNotFound)?;
+    let tiles = layout.tiles.iter().map(|tile| crop(&rgb, *tile)).collect();
+    Ok((layout, tiles))
+}
+
+pub(crate) fn crop(image: &RgbImage, bounds: Bounds) -> RgbImage {
+    image
+        .view(
+            bounds.left,
+            bounds.top,
+            bounds.right - bounds.left,
+            bounds.bottom - bounds.top,
+        )
+        .to_image()
+}
+
+/// banner crops the blue header banner, holding the challenge's instructions, out of a
+/// screenshot of the widget
+pub fn banner(screenshot: &[u8]) -> errors::Result<RgbImage> {
+    let rgb = decode(screenshot)?;
+    let (top, bottom) = banner_rows(&rgb).ok_or(errors::Error::BannerNotFound)?;
+    Ok(rgb.view(0, top, rgb.width(), bottom - top).to_image())
+}
+
+pub(crate) fn decode(screenshot: &[u8]) -> errors::Result<RgbImage> {
+    let decoded = image::load_from_memory(screenshot)?;
+    let (width, height) = decoded.dimensions();
+    Ok(RgbImage::from_fn(width, height, |x, y| {
+        let [r, g, b, _] = decoded.get_pixel(x, y).0;
+        Rgb([r, g, b])
+    }))
+}
+
+fn is_white(pixel: &Rgb<u8>) -> bool {
+    pixel.0.iter().all(|channel| *channel >= 235)
+}
+
+/// is_banner matches the saturated blue of the challenge's header banner
+fn is_banner(pixel: &Rgb<u8>) -> bool {
+    let [r, _, b] = pixel.0;
+    b >= 180 && i32::from(b) - i32::from(r) >= 80
+}
+
+/// banner_rows finds the rows of the header banner, starting in the top third of the image,
+/// as (top, bottom) with 'bottom' exclusive
+fn banner_rows(image: &RgbImage) -> Option<(u32, u32)> {
+    let (width, height) = image.dimensions();
+    let mostly_banner = |y: u32| {
+        (0..width)
+            .filter(|x| is_banner(image.get_pixel(*x, y)))
+            .count()
+            * 2
+            >= width as usize
+    };
+    let top = (0..height / 3).find(|y| mostly_banner(*y))?;
+    let bottom = (top..height).find(|y| !mostly_banner(*y)).unwrap_or(height);
+    Some((top, bottom))
+}
+
+/// grid_bounds finds the square of tiles below any header banner
+fn grid_bounds(image: &RgbImage) -> Option<Bounds> {
+    let (width, height) = image.dimensions();
+    let below_banner = match banner_rows(image) {
+        Some((_, bottom)) if bottom < height => bottom,
+        Some(_) => return None,
+        None => 0,
+    };
+    let top =
+        (below_banner..height).find(|y| (0..width).any(|x| !is_white(image.get_pixel(x, *y))))?;
+    let has_content = |x: u32| (top..height).any(|y| !is_white(image.get_pixel(x, y)));
+    let left = (0..width).find(|x| has_content(*x))?;
+    let right = (left..width).rev().find(|x| has_content(*x))? + 1;
+    Some(Bounds {
+        left,
+        top,
+        right,
+        bottom: (top + right - left).min(height),
+    })
+}
+
+/// locate_in tries a 4x4 grid, then a 3x3 one, and takes the first whose borders are all
+/// found. The grid is sized by its width, as in split, but its last row ends where the tiles
+/// do rather than where a square grid would
+pub(crate) fn locate_in(image: &RgbImage) -> Option<Layout> {
+    let bounds = grid_bounds(image)?;
+    let white_column =
+        |x: u32| (bounds.top..bounds.bottom).all(|y| is_white(image.get_pixel(x, y)));
+    let white_row = |y: u32| (bounds.left..bounds.right).all(|x| is_white(image.get_pixel(x, y)));
+    [GridSize::FourByFour, GridSize::ThreeByThree]
+        .iter()
+        .find_map(|grid| {
+            let side = grid.tiles_per_side();
+            let columns = spans(bounds.left, bounds.right, side, white_column)?;
+            let mut rows = spans(bounds.top, bounds.bottom, side, white_row)?;
+            let first_height = rows[0].1 - rows[0].0;
+            if let Some(last) = rows.last_mut() {
+                // past its middle, so the tile's own white rows aren't taken for its end
+                last.1 = (last.0 + first_height / 2..image.height())
+                    .find(|y| white_row(*y))
+                    .unwrap_or_else(|| image.height());
+            }
+            let tiles = rows
+                .iter()
+                .flat_map(|(top, bottom)| {
+                    columns.iter().map(move |(left, right)| Bounds {
+                        left: *left,
+                        top: *top,
+                        right: *right,
+                        bottom: *bottom,
+                    })
+                })
+                .collect();
+            Some(Layout {
+                grid: *grid,
+                header_height: bounds.top,
+                tiles,
+            })
+        })
+}
+
+/// spans splits [start, end) into 'side' spans at the white lines nearest to where equal
+/// spans would meet, returning their (start, end) with the white lines left out. It is None
+/// when some seam has no white line near it, as happens when there are fewer tiles
+fn spans(start: u32, end: u32, side: u32, white: impl Fn(u32) -> bool) -> Option<Vec<(u32, u32)>> {
+    let size = end.checked_sub(start)?;
+    // layouts shift a few pixels with the theme and the device's pixel ratio
+    let slack = size / side / 8;
+    let mut spans = Vec::with_capacity(side as usize);
+    let mut span_start = start;
+    for seam in 1..side {
+        let expected = start + size * seam / side;
+        let line = (expected.saturating_sub(slack)..=expected + slack)
+            .filter(|line| (span_start..end).contains(line) && white(*line))
+            .min_by_key(|line| line.abs_diff(expected))?;
+        let border_start = (span_start..line)
+            .rev()
+            .find(|line| !white(*line))
+            .map_or(span_start, |line| line + 1);
+        let border_end = (line..end).find(|line| !white(*line)).unwrap_or(end);
+        if border_start == span_start {
+            return None;
+        }
+        spans.push((span_start, border_start));
+        span_start = border_end;
+    }
+    if span_start >= end {
+        return None;
+    }
+    spans.push((span_start, end));
+    Some(spans)
+}
+
+/// trim_white shrinks 'cell' past any white rows and columns along its edges
+fn trim_white(image: &RgbImage, mut cell: Bounds) -> Bounds {
+    let white_column =
+        |x: u32, cell: &Bounds| (cell.top..cell.bottom).all(|y| is_white(image.get_pixel(x, y)));
+    let white_row =
+        |y: u32, cell: &Bounds| (cell.left..cell.right).all(|x| is_white(image.get_pixel(x, y)));
+    let untrimmed = cell;
+    while cell.left < cell.right && white_column(cell.left, &cell) {
+        cell.left += 1;
+    }
+    while cell.right > cell.left && white_column(cell.right - 1, &cell) {
+        cell.right -= 1;
+    }
+    while cell.top < cell.bottom && white_row(cell.top, &cell) {
+        cell.top += 1;
+    }
+    while cell.bottom > cell.top && white_row(cell.bottom - 1, &cell) {
+        cell.bottom -= 1;
+    }
+    if cell.left == cell.right || cell.top == cell.bottom {
+        // a blank tile is kept whole rather than trimmed away to nothing
+        return untrimmed;
+    }
+    cell
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageOutputFormat};
+
+    /// screenshot draws a widget: a banner, a 3x3 grid of 96px tiles with 4px borders, each
+    /// tile a different gray, and a footer icon under the grid
+    fn screenshot() -> errors::Result<Vec<u8>> {
+        widget(3, 96)
+    }
+
+    /// widget draws a widget with a 'side' by 'side' grid of 'tile' pixel tiles, as screenshot
+    fn widget(side: u32, tile: u32) -> errors::Result<Vec<u8>> {
+        let cell = tile + 4;
+        let grid = cell * side;
+        let image = RgbImage::from_fn(grid + 40, grid + 180, |x, y| {
+            if y < 80 {
+                return Rgb([74, 144, 226]);
+            }
+            if (grid + 120..grid + 140).contains(&y) && (20..40).contains(&x) {
+                return Rgb([90, 90, 90]);
+            }
+            let (gx, gy) = (x.wrapping_sub(20), y.wrapping_sub(100));
+            if gx < grid && gy < grid && gx % cell < tile && gy % cell < tile {
+                let index = (gy / cell * side + gx / cell) as u8;
+                return Rgb([index * 10; 3]);
+            }
+            Rgb([255, 255, 255])
+        });
+        let mut encoded = Vec::new();
+        DynamicImage::ImageRgb8(image).write_to(&mut encoded, ImageOutputFormat::Png)?;
+        Ok(encoded)
+    }
+
+    #[test]
+    fn skips_the_banner_borders_and_footer() -> errors::Result<()> {
+        let tiles = split(&screenshot()?, GridSize::ThreeByThree)?;
+        assert_eq!(tiles.len(), 9);
+        for (index, tile) in tiles.iter().enumerate() {
+            assert_eq!(tile.dimensions(), (96, 96));
+            assert_eq!(*tile.get_pixel(0, 0), Rgb([index as u8 * 10; 3]));
+            assert_eq!(*tile.get_pixel(95, 95), Rgb([index as u8 * 10; 3]));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn locates_either_grid() -> errors::Result<()> {
+        let layout = locate(&screenshot()?)?;
+        assert_eq!(layout.grid, GridSize::ThreeByThree);
+        assert_eq!(layout.header_height, 100);
+        assert_eq!(
+            layout.tiles[4],
+            Bounds {
+                left: 120,
+                top: 200,
+                right: 216,
+                bottom: 296,
+            }
+        );
+
+        let (layout, tiles) = split_located(&widget(4, 71)?)?;
+        assert_eq!(layout.grid, GridSize::FourByFour);
+        assert_eq!(tiles.len(), 16);
+        for (index, tile) in tiles.iter().enumerate() {
+            assert_eq!(tile.dimensions(), (71, 71));
+            assert_eq!(*tile.get_pixel(70, 70), Rgb([index as u8 * 10; 3]));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn crops_the_banner() -> errors::Result<()> {
+        let banner = banner(&screenshot()?)?;
+        assert_eq!(banner.dimensions(), (340, 80));
+        assert_eq!(*banner.get_pixel(0, 0), Rgb([74, 144, 226]));
+        Ok(())
+    }
+
+    #[test]
+    fn blank_screenshots_have_no_grid() -> errors::Result<()> {
+        let mut encoded = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(50, 50, Rgb([255; 3])))
+            .write_to(&mut encoded, ImageOutputFormat::Png)?;
+        assert!(matches!(
+            split(&encoded, GridSize::FourByFour),
+            Err(errors::Error::GridNotFound)
+        ));
+        assert!(matches!(
+            banner(&encoded),
+            Err(errors::Error::BannerNotFound)
+        ));
+        assert!(matches!(locate(&encoded), Err(errors::Error::GridNotFound)));
+        Ok(())
+    }
+}
@@ -0,0 +1,529 @@
+use crate::{
+    errors,
+    hash::{HashKind, ImageHash},
+    image_hash, CaptchaChallenge, GridSize, Prediction,
+};
+use serde_derive::Serialize;
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// INDEX_FILE is the per-challenge JSON lines file recording every harvested sample
+const INDEX_FILE: &str = "harvest.jsonl";
+
+/// UNLABELED_DIR is the folder of samples recorded without a verdict
+const UNLABELED_DIR: &str = "unlabeled";
+
+/// Retention bounds how much a Harvester keeps around. Both limits apply per challenge
+#[derive(Debug, Clone, Default)]
+pub struct Retention {
+    pub max_age: Option<Duration>,
+    pub max_samples: Option<usize>,
+}
+
+/// Sample is one image seen in the wild along with what the model made of it and, when a
+/// human has confirmed it, whether it really matched the challenge
+#[derive(Debug)]
+pub struct Sample<'a> {
+    pub image: &'a [u8],
+    pub challenge: &'a CaptchaChallenge,
+    pub grid: GridSize,
+    pub prediction: &'a Prediction,
+    pub verdict: Option<bool>,
+}
+
+/// Unlabeled is a harvested image still waiting for a human verdict (see Harvester::label)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unlabeled {
+    pub path: PathBuf,
+    pub grid: GridSize,
+    pub challenge: CaptchaChallenge,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    file: &'a str,
+    hash: &'a str,
+    /// dhash is the image's hash::difference_hash, recorded when near duplicates are skipped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dhash: Option<String>,
+    challenge: &'a CaptchaChallenge,
+    prediction: &'a Prediction,
+    verdict: Option<bool>,
+    harvested_at: u64,
+}
+
+/// Harvester stores samples using the same layout as test_data/, so a harvest directory can be
+/// evaluated or trained on directly:
+///
+/// ```text
+/// <root>/<grid>/<challenge>/matches/<sha256>.png
+/// <root>/<grid>/<challenge>/not matches/<sha256>.png
+/// <root>/<grid>/<challenge>/unlabeled/<sha256>.png
+/// <root>/<grid>/<challenge>/harvest.jsonl
+/// ```
+///
+/// Images are named by content hash, so the same tile is only ever stored once per folder
+#[derive(Debug)]
+pub struct Harvester {
+    root: PathBuf,
+    retention: Retention,
+    index_lock: Mutex<()>,
+    near_duplicates: Option<u32>,
+    // the difference hashes of the images in each label folder, read from the index on first use
+    seen: Mutex<HashMap<PathBuf, Vec<(ImageHash, PathBuf)>>>,
+}
+
+impl Harvester {
+    pub fn new<P>(root: P, retention: Retention) -> Harvester
+    where
+        P: Into<PathBuf>,
+    {
+        Harvester {
+            root: root.into(),
+            retention,
+            index_lock: Mutex::new(()),
+            near_duplicates: None,
+            seen: Mutex::default(),
+        }
+    }
+
+    /// skip_near_duplicates stops record storing an image whose hash::difference_hash is at
+    /// most 'max_distance' from one already in the same label folder, as when a tile is seen
+    /// again recompressed. record returns the stored image's path instead
+    pub fn skip_near_duplicates(mut self, max_distance: u32) -> Harvester {
+        self.near_duplicates = Some(max_distance);
+        self
+    }
+
+    /// record persists 'sample', returning the path its image was stored at
+    pub fn record(&self, sample: &Sample) -> errors::Result<PathBuf> {
+        let challenge_dir = self.challenge_dir(sample.grid, sample.challenge);
+        let label_dir = match sample.verdict {
+            Some(true) => "matches",
+            Some(false) => "not matches",
+            None => UNLABELED_DIR,
+        };
+        let hash = image_hash(sample.image);
+        let file = format!("{}/{}.{}", label_dir, hash, extension_of(sample.image));
+        let image_path = challenge_dir.join(&file);
+        // an image that can't be decoded has no difference hash and is stored as usual
+        let dhash = match self.near_duplicates {
+            Some(_) => HashKind::Difference.hash(sample.image).ok(),
+            None => None,
+        };
+        if let (Some(max_distance), Some(dhash)) = (self.near_duplicates, dhash) {
+            let label_path = challenge_dir.join(label_dir);
+            let mut seen = self.seen.lock()?;
+            if !seen.contains_key(&label_path) {
+                let hashes = index_hashes(&challenge_dir, label_dir)?;
+                let _ = seen.insert(label_path.clone(), hashes);
+            }
+            let hashes = seen.entry(label_path).or_default();
+            if let Some((_, stored)) = hashes
+                .iter()
+                .find(|(stored, path)| stored.distance(dhash) <= max_distance && path.exists())
+            {
+                return Ok(stored.clone());
+            }
+            hashes.push((dhash, image_path.clone()));
+        }
+        // held from writing the image to indexing it, so prune can't remove one before the other
+        let _guard = self.index_lock.lock()?;
+        if !image_path.exists() {
+            fs::create_dir_all(challenge_dir.join(label_dir))?;
+            fs::write(&image_path, sample.image)?;
+        }
+
+        let record = Record {
+            file: &file,
+            hash: &hash,
+            dhash: dhash.map(|dhash| dhash.to_string()),
+            challenge: sample.challenge,
+            prediction: sample.prediction,
+            verdict: sample.verdict,
+            harvested_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(challenge_dir.join(INDEX_FILE))?
+            .write_all(&line)?;
+        Ok(image_path)
+    }
+
+    /// prune applies the retention policy to every challenge in the harvest, removing images
+    /// older than max_age and then the oldest images beyond max_samples. Returns how many
+    /// images were removed
+    pub fn prune(&self) -> errors::Result<usize> {
+        let _guard = self.index_lock.lock()?;
+        let mut removed = 0;
+        for grid in read_dirs(&self.root)? {
+            for challenge_dir in read_dirs(&grid)? {
+                removed += self.prune_challenge(&challenge_dir)?;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn prune_challenge(&self, challenge_dir: &Path) -> errors::Result<usize> {
+        let mut images = Vec::new();
+        for label_dir in read_dirs(challenge_dir)? {
+            for entry in label_dir.read_dir()? {
+                let entry = entry?;
+                images.push((entry.metadata()?.modified()?, entry.path()));
+            }
+        }
+        // newest first, so everything past max_samples is the oldest
+        images.sort_by(|a, b| b.0.cmp(&a.0));
+
+        let now = SystemTime::now();
+        let mut removed_files = Vec::new();
+        for (index, (modified, path)) in images.into_iter().enumerate() {
+            let too_old = self
+                .retention
+                .max_age
+                .is_some_and(|max_age| now.duration_since(modified).unwrap_or_default() > max_age);
+            let too_many = self
+                .retention
+                .max_samples
+                .is_some_and(|max_samples| index >= max_samples);
+            if too_old || too_many {
+                fs::remove_file(&path)?;
+                if let Ok(relative) = path.strip_prefix(challenge_dir) {
+                    removed_files.push(relative.to_string_lossy().into_owned());
+                }
+            }
+        }
+
+        if !removed_files.is_empty() {
+            prune_index(&challenge_dir.join(INDEX_FILE), &removed_files)?;
+        }
+        Ok(removed_files.len())
+    }
+
+    /// unlabeled lists the images recorded without a verdict, in path order
+    pub fn unlabeled(&self) -> errors::Result<Vec<Unlabeled>> {
+        let mut images = Vec::new();
+        for grid_dir in read_dirs(&self.root)? {
+            let grid = match dir_name(&grid_dir).parse::<GridSize>() {
+                Ok(grid) => grid,
+                Err(_) => continue,
+            };
+            for challenge_dir in read_dirs(&grid_dir)? {
+                let challenge = match dir_name(&challenge_dir)
+                    .replace(' ', "_")
+                    .parse::<CaptchaChallenge>()
+                {
+                    Ok(challenge) => challenge,
+                    Err(_) => continue,
+                };
+                let unlabeled_dir = challenge_dir.join(UNLABELED_DIR);
+                if !unlabeled_dir.is_dir() {
+                    continue;
+                }
+                for entry in unlabeled_dir.read_dir()? {
+                    let entry = entry?;
+                    if entry.file_type()?.is_file() {
+                        images.push(Unlabeled {
+                            path: entry.path(),
+                            grid,
+                            challenge: challenge.clone(),
+                        });
+                    }
+                }
+            }
+        }
+        images.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(images)
+    }
+
+    /// label moves an unlabeled image into the folder for its verdict and records the verdict
+    /// in the challenge's index, returning the image's new path
+    pub fn label(&self, image: &Unlabeled, matches: bool) -> errors::Result<PathBuf> {
+        let challenge_dir = self.challenge_dir(image.grid, &image.challenge);
+        let label_dir = if matches { "matches" } else { "not matches" };
+        let name = image.path.file_name().unwrap_or_default().to_string_lossy();
+        let (from, to) = (
+            format!("{}/{}", UNLABELED_DIR, name),
+            format!("{}/{}", label_dir, name),
+        );
+        let labeled_path = challenge_dir.join(&to);
+        let _guard = self.index_lock.lock()?;
+        if labeled_path.exists() {
+            // named by content hash, so the image was already labeled
+            fs::remove_file(&image.path)?;
+        } else {
+            fs::create_dir_all(challenge_dir.join(label_dir))?;
+            fs::rename(&image.path, &labeled_path)?;
+        }
+        relabel_index(&challenge_dir.join(INDEX_FILE), &from, &to, matches)?;
+        Ok(labeled_path)
+    }
+
+    fn challenge_dir(&self, grid: GridSize, challenge: &CaptchaChallenge) -> PathBuf {
+        self.root
+            .join(grid.to_string())
+            .join(challenge.to_string().replace('_', " "))
+    }
+}
+
+/// index_hashes reads the difference hashes of the images in 'label_dir' from a challenge's
+/// index, hashing the images recorded without one
+fn index_hashes(
+    challenge_dir: &Path,
+    label_dir: &str,
+) -> errors::Result<Vec<(ImageHash, PathBuf)>> {
+    let index = challenge_dir.join(INDEX_FILE);
+    let mut hashes = Vec::new();
+    if !index.exists() {
+        return Ok(hashes);
+    }
+    for line in BufReader::new(fs::File::open(index)?).lines() {
+        let record: serde_json::Value = serde_json::from_str(&line?)?;
+        let file = record["file"].as_str().unwrap_or_default();
+        let path = challenge_dir.join(file);
+        if !file.starts_with(&format!("{}/", label_dir)) || !path.exists() {
+            continue;
+        }
+        let recorded = record["dhash"]
+            .as_str()
+            .and_then(|dhash| dhash.parse().ok());
+        let dhash = match recorded {
+            Some(dhash) => Some(dhash),
+            None => HashKind::Difference.hash(&fs::read(&path)?).ok(),
+        };
+        if let Some(dhash) = dhash {
+            hashes.push((dhash, path));
+        }
+    }
+    Ok(hashes)
+}
+
+/// relabel_index points the records of an image moved from 'from' to 'to' at its new file and
+/// verdict
+fn relabel_index(index: &Path, from: &str, to: &str, matches: bool) -> errors::Result<()> {
+    if !index.exists() {
+        return Ok(());
+    }
+    let mut lines = Vec::new();
+    for line in BufReader::new(fs::File::open(index)?).lines() {
+        let line = line?;
+        let mut record: serde_json::Value = serde_json::from_str(&line)?;
+        if record["file"].as_str() == Some(from) {
+            record["file"] = to.into();
+            record["verdict"] = matches.into();
+            lines.push(serde_json::to_string(&record)?);
+        } else {
+            lines.push(line);
+        }
+    }
+    let mut contents = lines.join("\n");
+    if !contents.is_empty() {
+        contents.push('\n');
+    }
+    fs::write(index, contents)?;
+    Ok(())
+}
+
+fn dir_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// prune_index drops the records of removed images from a challenge's index
+fn prune_index(index: &Path, removed_files: &[String]) -> errors::Result<()> {
+    if !index.exists() {
+        return Ok(());
+    }
+    let mut kept = Vec::new();
+    for line in BufReader::new(fs::File::open(index)?).lines() {
+        let line = line?;
+        let record: serde_json::Value = serde_json::from_str(&line)?;
+        let file = record["file"].as_str().unwrap_or_default();
+        if !removed_files.iter().any(|removed| removed == file) {
+            kept.push(line);
+        }
+    }
+    let mut contents = kept.join("\n");
+    if !contents.is_empty() {
+        contents.push('\n');
+    }
+    fs::write(index, contents)?;
+    Ok(())
+}
+
+fn read_dirs(path: &Path) -> errors::Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    if !path.is_dir() {
+        return Ok(dirs);
+    }
+    for entry in path.read_dir()? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            dirs.push(entry.path());
+        }
+    }
+    Ok(dirs)
+}
+
+/// extension_of guesses a file extension from the image's magic bytes
+pub(crate) fn extension_of(image: &[u8]) -> &'static str {
+    if image.starts_with(b"\x89PNG") {
+        "png"
+    } else if image.starts_with(b"\xff\xd8\xff") {
+        "jpg"
+    } else if image.starts_with(b"RIFF") && image.get(8..12) == Some(&b"WEBP"[..]) {
+        "webp"
+    } else if image.starts_with(b"GIF8") {
+        "gif"
+    } else {
+        "bin"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageOutputFormat, Rgb, RgbImage};
+
+    fn harvest_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "nocap-harvest-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn record(harvester: &Harvester, image: &[u8], verdict: Option<bool>) -> PathBuf {
+        harvester
+            .record(&Sample {
+                image,
+                challenge: &CaptchaChallenge::Bus,
+                grid: GridSize::ThreeByThree,
+                prediction: &Prediction::new(0.9, 0.1),
+                verdict,
+            })
+            .unwrap()
+    }
+
+    fn index(root: &Path) -> Vec<serde_json::Value> {
+        let index = root.join("3x3").join("bus").join(INDEX_FILE);
+        fs::read_to_string(index)
+            .unwrap_or_default()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    fn png(image: &RgbImage) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        DynamicImage::ImageRgb8(image.clone())
+            .write_to(&mut encoded, ImageOutputFormat::Png)
+            .unwrap();
+        encoded
+    }
+
+    #[test]
+    fn records_and_labels_samples() -> errors::Result<()> {
+        let root = harvest_dir("label");
+        let harvester = Harvester::new(&root, Retention::default());
+        let stored = record(&harvester, b"\x89PNG bus", None);
+        assert_eq!(stored.parent(), Some(&*root.join("3x3/bus/unlabeled")));
+        assert_eq!(record(&harvester, b"\x89PNG bus", None), stored);
+        assert_eq!(index(&root).len(), 2);
+
+        let unlabeled = harvester.unlabeled()?;
+        assert_eq!(
+            unlabeled,
+            vec![Unlabeled {
+                path: stored.clone(),
+                grid: GridSize::ThreeByThree,
+                challenge: CaptchaChallenge::Bus,
+            }]
+        );
+        let labeled = harvester.label(&unlabeled[0], true)?;
+        assert_eq!(labeled.parent(), Some(&*root.join("3x3/bus/matches")));
+        assert!(labeled.exists() && !stored.exists());
+        assert!(harvester.unlabeled()?.is_empty());
+        for record in index(&root) {
+            assert!(record["file"].as_str().unwrap().starts_with("matches/"));
+            assert_eq!(record["verdict"], true);
+        }
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn prunes_by_count_then_age() -> errors::Result<()> {
+        let root = harvest_dir("prune");
+        let harvester = Harvester::new(&root, Retention::default());
+        for image in &[&b"\x89PNG a"[..], b"\x89PNG b", b"\x89PNG c"] {
+            let _ = record(&harvester, image, Some(false));
+        }
+        let by_count = Harvester::new(
+            &root,
+            Retention {
+                max_samples: Some(2),
+                ..Retention::default()
+            },
+        );
+        assert_eq!(by_count.prune()?, 1);
+        assert_eq!(index(&root).len(), 2);
+
+        let kept = root
+            .join("3x3/bus")
+            .join(index(&root)[0]["file"].as_str().unwrap());
+        let hours_ago = SystemTime::now() - Duration::from_secs(2 * 60 * 60);
+        OpenOptions::new()
+            .write(true)
+            .open(&kept)?
+            .set_modified(hours_ago)?;
+        let by_age = Harvester::new(
+            &root,
+            Retention {
+                max_age: Some(Duration::from_secs(60 * 60)),
+                ..Retention::default()
+            },
+        );
+        assert_eq!(by_age.prune()?, 1);
+        assert!(!kept.exists());
+        assert_eq!(index(&root).len(), 1);
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn skips_near_duplicates() -> errors::Result<()> {
+        let root = harvest_dir("duplicates");
+        let gradient = RgbImage::from_fn(32, 32, |x, y| Rgb([(x * 8) as u8, (y * 8) as u8, 0]));
+        let mut recompressed = gradient.clone();
+        recompressed.put_pixel(5, 5, Rgb([41, 39, 1]));
+        let reversed = RgbImage::from_fn(32, 32, |x, _| Rgb([255 - (x * 8) as u8, 0, 0]));
+
+        let harvester = Harvester::new(&root, Retention::default()).skip_near_duplicates(4);
+        let stored = record(&harvester, &png(&gradient), Some(true));
+        assert_eq!(record(&harvester, &png(&recompressed), Some(true)), stored);
+        assert_ne!(record(&harvester, &png(&reversed), Some(true)), stored);
+
+        // a new harvester reads the hashes back from the index
+        let reopened = Harvester::new(&root, Retention::default()).skip_near_duplicates(4);
+        assert_eq!(record(&reopened, &png(&recompressed), Some(true)), stored);
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
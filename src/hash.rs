@@ -0,0 +1,162 @@
+use crate::errors;
+use image::{imageops::FilterType, DynamicImage, GenericImageView};
+use std::{f64::consts::PI, fmt, str::FromStr};
+
+/// ImageHash is a 64 bit perceptual hash of an image. Unlike image_hash, which changes with any
+/// byte of the file, similar looking images have hashes a small distance apart, so a tile is
+/// still recognized after being recompressed or resized
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ImageHash(pub u64);
+
+impl ImageHash {
+    /// distance is the number of bits that differ between the hashes, 0 for identical images
+    /// and about 32 for unrelated ones
+    pub fn distance(self, other: ImageHash) -> u32 {
+        (self.0 ^ other.0).count_ones()
+    }
+}
+
+impl fmt::Display for ImageHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl FromStr for ImageHash {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(hex: &str) -> Result<ImageHash, Self::Err> {
+        u64::from_str_radix(hex, 16).map(ImageHash)
+    }
+}
+
+/// HashKind picks between the hashes this module computes. Perceptual is the slowest and the
+/// most robust to recompression; Difference is nearly as robust and much cheaper
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashKind {
+    Average,
+    Difference,
+    Perceptual,
+}
+
+impl HashKind {
+    /// hash decodes 'image' and hashes it
+    pub fn hash(self, image: &[u8]) -> errors::Result<ImageHash> {
+        let decoded = image::load_from_memory(image)?;
+        Ok(match self {
+            HashKind::Average => average_hash(&decoded),
+            HashKind::Difference => difference_hash(&decoded),
+            HashKind::Perceptual => perceptual_hash(&decoded),
+        })
+    }
+}
+
+/// average_hash (aHash) sets a bit for each pixel of the image shrunk to 8x8 grayscale that is
+/// brighter than the mean
+pub fn average_hash(image: &DynamicImage) -> ImageHash {
+    let pixels = luma(image, 8, 8);
+    let mean = pixels.iter().sum::<f64>() / pixels.len() as f64;
+    bits(pixels.iter().map(|pixel| *pixel > mean))
+}
+
+/// difference_hash (dHash) sets a bit for each pixel of the image shrunk to 9x8 grayscale that
+/// is brighter than its right-hand neighbour
+pub fn difference_hash(image: &DynamicImage) -> ImageHash {
+    let pixels = luma(image, 9, 8);
+    bits((0..8).flat_map(|y| {
+        let row = &pixels[y * 9..(y + 1) * 9];
+        (0..8).map(move |x| row[x] > row[x + 1])
+    }))
+}
+
+/// perceptual_hash (pHash) sets a bit for each of the 8x8 lowest frequencies of the discrete
+/// cosine transform of the image shrunk to 32x32 grayscale that is above their median
+pub fn perceptual_hash(image: &DynamicImage) -> ImageHash {
+    const SIZE: usize = 32;
+    let pixels = luma(image, SIZE as u32, SIZE as u32);
+    let cosines: Vec<f64> = (0..8 * SIZE)
+        .map(|index| {
+            let (frequency, position) = (index / SIZE, index % SIZE);
+            ((2 * position + 1) as f64 * frequency as f64 * PI / (2 * SIZE) as f64).cos()
+        })
+        .collect();
+    let mut coefficients = Vec::with_capacity(64);
+    for v in 0..8 {
+        for u in 0..8 {
+            let mut sum = 0.0;
+            for y in 0..SIZE {
+                for x in 0..SIZE {
+                    sum += pixels[y * SIZE + x] * cosines[u * SIZE + x] * cosines[v * SIZE + y];
+                }
+            }
+            coefficients.push(sum);
+        }
+    }
+    // the DC term is the image's overall brightness, left out of the median so it can't skew it
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let median = sorted[sorted.len() / 2];
+    bits(coefficients.iter().map(|coefficient| *coefficient > median))
+}
+
+/// luma shrinks 'image' to 'width' x 'height' and returns its brightness in row-major order
+fn luma(image: &DynamicImage, width: u32, height: u32) -> Vec<f64> {
+    let small = image
+        .grayscale()
+        .resize_exact(width, height, FilterType::Triangle);
+    small
+        .pixels()
+        .map(|(_, _, pixel)| f64::from(pixel.0[0]))
+        .collect()
+}
+
+fn bits<I>(bits: I) -> ImageHash
+where
+    I: IntoIterator<Item = bool>,
+{
+    ImageHash(
+        bits.into_iter()
+            .take(64)
+            .fold(0, |hash, bit| hash << 1 | u64::from(bit)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageOutputFormat, Rgb, RgbImage};
+
+    fn gradient(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            let value = ((x * 255 / width + y * 64 / height) % 256) as u8;
+            Rgb([value, value / 2, 255 - value])
+        }))
+    }
+
+    #[test]
+    fn survives_recompression_and_resizing() -> errors::Result<()> {
+        let original = gradient(100, 100);
+        let mut jpeg = Vec::new();
+        original
+            .resize_exact(60, 60, FilterType::Triangle)
+            .write_to(&mut jpeg, ImageOutputFormat::Jpeg(40))?;
+        let mut png = Vec::new();
+        original.write_to(&mut png, ImageOutputFormat::Png)?;
+        let mut unrelated = Vec::new();
+        gradient(100, 100)
+            .rotate90()
+            .write_to(&mut unrelated, ImageOutputFormat::Png)?;
+
+        for kind in &[
+            HashKind::Average,
+            HashKind::Difference,
+            HashKind::Perceptual,
+        ] {
+            let (original, copy) = (kind.hash(&png)?, kind.hash(&jpeg)?);
+            assert!(original.distance(copy) <= 6, "{:?}", kind);
+            assert!(original.distance(kind.hash(&unrelated)?) > 6, "{:?}", kind);
+            assert_eq!(original.to_string().parse::<ImageHash>(), Ok(original));
+        }
+        Ok(())
+    }
+}
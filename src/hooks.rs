@@ -0,0 +1,123 @@
+use crate::{errors, CaptchaChallenge, Prediction};
+use std::{fmt, sync::Mutex};
+
+/// PreHook sees every image before it is predicted. It may rewrite the image, or veto the
+/// whole batch by returning an error such as Error::Vetoed
+pub type PreHook = Box<dyn FnMut(&CaptchaChallenge, &mut Vec<u8>) -> errors::Result<()> + Send>;
+
+/// PostHook sees every prediction before it is returned, and may replace it or fail the batch
+pub type PostHook = Box<dyn FnMut(&CaptchaChallenge, &mut Prediction) -> errors::Result<()> + Send>;
+
+/// Hooks is a middleware chain run around predictions (see RegistryBuilder::before_prediction
+/// and RegistryBuilder::after_prediction). Hooks run in the order they were added, each seeing
+/// what the one before it left, and a hook added for a single challenge only runs for it.
+/// Each hook is locked while it runs, so a slow hook holds up concurrent batches
+#[derive(Default)]
+pub struct Hooks {
+    pre: Vec<(Option<CaptchaChallenge>, Mutex<PreHook>)>,
+    post: Vec<(Option<CaptchaChallenge>, Mutex<PostHook>)>,
+}
+
+impl Hooks {
+    /// add_pre appends 'hook' to the chain run before predicting images for 'challenge', or
+    /// for every challenge if it is None
+    pub fn add_pre(&mut self, challenge: Option<CaptchaChallenge>, hook: PreHook) {
+        self.pre.push((challenge, Mutex::new(hook)));
+    }
+
+    /// add_post appends 'hook' to the chain run on predictions for 'challenge', or for every
+    /// challenge if it is None
+    pub fn add_post(&mut self, challenge: Option<CaptchaChallenge>, hook: PostHook) {
+        self.post.push((challenge, Mutex::new(hook)));
+    }
+
+    /// before runs the pre-prediction chain over 'images', stopping at the first error
+    pub fn before(
+        &self,
+        challenge: &CaptchaChallenge,
+        images: &mut [Vec<u8>],
+    ) -> errors::Result<()> {
+        for (only, hook) in &self.pre {
+            if only.as_ref().is_none_or(|only| only == challenge) {
+                let mut hook = hook.lock()?;
+                for image in images.iter_mut() {
+                    hook(challenge, image)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// after runs the post-prediction chain over 'predictions', stopping at the first error
+    pub fn after(
+        &self,
+        challenge: &CaptchaChallenge,
+        predictions: &mut [Prediction],
+    ) -> errors::Result<()> {
+        for (only, hook) in &self.post {
+            if only.as_ref().is_none_or(|only| only == challenge) {
+                let mut hook = hook.lock()?;
+                for prediction in predictions.iter_mut() {
+                    hook(challenge, prediction)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("pre", &self.pre.len())
+            .field("post", &self.post.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chains_in_order_and_vetoes() -> errors::Result<()> {
+        let mut hooks = Hooks::default();
+        hooks.add_pre(
+            None,
+            Box::new(|_, image: &mut Vec<u8>| {
+                image.push(b'!');
+                Ok(())
+            }),
+        );
+        hooks.add_pre(
+            Some(CaptchaChallenge::Taxis),
+            Box::new(|_, image: &mut Vec<u8>| match image.as_slice() {
+                b"blocked!" => Err(errors::Error::Vetoed(String::from("blocked"))),
+                _ => Ok(()),
+            }),
+        );
+        hooks.add_post(
+            Some(CaptchaChallenge::Bus),
+            Box::new(|_, prediction: &mut Prediction| {
+                *prediction = Prediction::new(1.0, 0.0);
+                Ok(())
+            }),
+        );
+
+        let mut images = vec![b"blocked".to_vec()];
+        hooks.before(&CaptchaChallenge::Bus, &mut images)?;
+        assert_eq!(images, vec![b"blocked!".to_vec()]);
+        images = vec![b"blocked".to_vec()];
+        assert!(matches!(
+            hooks.before(&CaptchaChallenge::Taxis, &mut images),
+            Err(errors::Error::Vetoed(_))
+        ));
+
+        let mut predictions = vec![Prediction::new(0.2, 0.8)];
+        hooks.after(&CaptchaChallenge::Taxis, &mut predictions)?;
+        assert!(!predictions[0].is_mainly_affirmative());
+        hooks.after(&CaptchaChallenge::Bus, &mut predictions)?;
+        assert!(predictions[0].is_mainly_affirmative());
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+use crate::errors;
+use serde_derive::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io,
+    path::Path,
+    time::UNIX_EPOCH,
+};
+
+/// CHECKSUMS is the optional file in a models directory listing the SHA-256 of model files.
+/// An archive is listed by its own checksum, a model directory by a table of its files:
+///
+/// ```toml
+/// "crosswalks.tar.gz" = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+///
+/// [bus]
+/// "saved_model.pb" = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+/// "variables/variables.index" = "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae"
+/// ```
+///
+/// Entries that aren't listed are loaded without verification
+pub const CHECKSUMS: &str = "checksums.toml";
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    File(String),
+    Directory(BTreeMap<String, String>),
+}
+
+/// Checksums is a parsed CHECKSUMS file
+#[derive(Debug, Default)]
+pub struct Checksums {
+    entries: BTreeMap<String, Entry>,
+}
+
+impl Checksums {
+    /// load reads the CHECKSUMS file of 'models_dir', or returns None if there isn't one
+    pub fn load(models_dir: &Path) -> errors::Result<Option<Checksums>> {
+        let path = models_dir.join(CHECKSUMS);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let entries =
+            toml::from_str(&fs::read_to_string(&path)?).map_err(errors::Error::InvalidChecksums)?;
+        Ok(Some(Checksums { entries }))
+    }
+
+    /// verify checks the entry of the models directory at 'path' (a model directory or an
+    /// archive) against its listed checksums, if it is listed
+    pub fn verify(&self, path: &Path) -> errors::Result<()> {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        match self.entries.get(&name) {
+            Some(Entry::File(expected)) => verify_file(path, expected),
+            Some(Entry::Directory(files)) => {
+                for (file, expected) in files {
+                    verify_file(&path.join(file), expected)?;
+                }
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+fn verify_file(path: &Path, expected: &str) -> errors::Result<()> {
+    let actual = match sha256_file(path) {
+        Ok(actual) => Some(actual),
+        // a file missing from a partial copy is reported like a corrupted one
+        Err(errors::Error::IOError(err)) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err),
+    };
+    if actual.as_deref() != Some(&expected.to_lowercase()) {
+        return Err(errors::Error::ChecksumMismatch {
+            path: path.to_path_buf(),
+            expected: expected.to_owned(),
+            actual,
+        });
+    }
+    Ok(())
+}
+
+/// sha256_file is the hex encoded SHA-256 of a file's contents, read in a streaming fashion so
+/// large variables files aren't loaded whole
+pub fn sha256_file(path: &Path) -> errors::Result<String> {
+    let mut hasher = Sha256::new();
+    let _ = io::copy(&mut File::open(path)?, &mut HashWriter(&mut hasher))?;
+    Ok(hasher
+        .result()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// fingerprint is a digest of the relative path, size and mtime of every file under 'dir'.
+/// Unlike a checksum it reads no file contents, so it is cheap enough to take of every model on
+/// each load, yet changes whenever a file is added, removed or replaced
+pub fn fingerprint(dir: &Path) -> errors::Result<String> {
+    let mut hasher = Sha256::new();
+    fingerprint_into(dir, &mut hasher)?;
+    Ok(hasher
+        .result()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// fingerprint_into feeds the entries under 'dir' to 'hasher' in a stable order
+fn fingerprint_into(dir: &Path, hasher: &mut Sha256) -> errors::Result<()> {
+    let mut entries = Vec::new();
+    for entry in dir.read_dir()? {
+        entries.push(entry?);
+    }
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let metadata = entry.metadata()?;
+        hasher.input(entry.file_name().to_string_lossy().as_bytes());
+        if metadata.is_dir() {
+            fingerprint_into(&entry.path(), hasher)?;
+            continue;
+        }
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos());
+        hasher.input(&metadata.len().to_be_bytes());
+        hasher.input(&modified.to_be_bytes());
+    }
+    Ok(())
+}
+
+struct HashWriter<'a>(&'a mut Sha256);
+
+impl io::Write for HashWriter<'_> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.input(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_listed_entries() -> errors::Result<()> {
+        let dir = std::env::temp_dir().join(format!("nocap-integrity-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("bus"))?;
+        fs::write(dir.join("bus/saved_model.pb"), b"graph")?;
+        fs::write(
+            dir.join(CHECKSUMS),
+            r#"
+                [bus]
+                "saved_model.pb" = "0DB6BFCEA2C3E45A5C27F1BD40E2BC7F4BA4A5F7AE1B9E1D6AD1A1F7E81E3A53"
+            "#,
+        )?;
+        let checksums = Checksums::load(&dir)?.unwrap_or_default();
+        assert!(matches!(
+            checksums.verify(&dir.join("bus")),
+            Err(errors::Error::ChecksumMismatch {
+                actual: Some(_),
+                ..
+            })
+        ));
+
+        let graph = sha256_file(&dir.join("bus/saved_model.pb"))?;
+        fs::write(
+            dir.join(CHECKSUMS),
+            format!("[bus]\n\"saved_model.pb\" = \"{}\"\n", graph),
+        )?;
+        let checksums = Checksums::load(&dir)?.unwrap_or_default();
+        checksums.verify(&dir.join("bus"))?;
+        checksums.verify(&dir.join("cars"))?;
+
+        fs::write(
+            dir.join(CHECKSUMS),
+            "[bus]\n\"variables/variables.index\" = \"00\"\n",
+        )?;
+        let checksums = Checksums::load(&dir)?.unwrap_or_default();
+        assert!(matches!(
+            checksums.verify(&dir.join("bus")),
+            Err(errors::Error::ChecksumMismatch { actual: None, .. })
+        ));
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
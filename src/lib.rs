@@ -1,15 +1,86 @@
-use rayon::prelude::*;
 use serde_derive::{Deserialize, Serialize};
-use std::{collections::HashMap, fs, str::FromStr, sync::Mutex};
-use strum::VariantNames;
-use strum_macros::{Display, EnumString, EnumVariantNames, IntoStaticStr};
-use tensorflow::{Graph, Session, Tensor};
+use sha2::{Digest, Sha256};
+use strum_macros::{Display, EnumIter, EnumString, EnumVariantNames, IntoStaticStr};
 
+mod archive;
+pub mod augment;
+pub mod benchmark;
+mod cache;
+pub mod cluster;
+pub mod concurrency;
+#[cfg(feature = "tensorflow")]
+pub mod convert;
+pub mod dataset;
+#[cfg(feature = "tensorflow")]
+pub mod deployment;
+#[cfg(feature = "tensorflow")]
+pub mod detect;
+pub mod devices;
+pub mod discovery;
+pub mod embedded;
+#[cfg(feature = "tensorflow")]
+pub mod embedding;
+pub mod ensemble;
 pub mod errors;
+pub mod evaluation;
+pub mod explain;
+pub mod export;
+pub mod feedback;
+pub mod format;
+#[cfg(feature = "ann")]
+pub mod gallery;
+pub mod graph_cache;
+pub mod grid;
+pub mod harvest;
+pub mod hash;
+pub mod hooks;
+pub mod integrity;
+pub mod loading;
+pub mod memory;
+pub mod multicrop;
+pub mod names;
+#[cfg(feature = "plugins")]
+pub mod plugins;
+pub mod prediction_log;
+pub mod predictor;
+pub mod prompt;
+#[cfg(feature = "tensorflow")]
+pub mod quantize;
+#[cfg(feature = "tensorflow")]
+mod registry;
+#[cfg(feature = "remote")]
+pub mod remote;
+pub mod report;
+pub mod resize;
+pub mod retry;
+pub mod review;
+pub mod sanitize;
+pub mod scheduling;
+pub mod schema;
+pub mod self_test;
+#[cfg(feature = "remote")]
+pub mod serving;
+pub mod session;
+#[cfg(unix)]
+pub mod sidecar;
+#[cfg(feature = "otel")]
+pub mod telemetry;
+#[cfg(feature = "train")]
+pub mod train;
+pub mod watch;
+pub mod worker;
 
-fn silence_tensorflow() {
-    std::env::set_var("TF_CPP_MIN_LOG_LEVEL", "3");
-}
+pub use predictor::{CancellationToken, MockPredictor, PredictOptions, Predictor};
+#[cfg(feature = "tensorflow")]
+pub use registry::{
+    CaptchaModel, CaptchaRegistry, ChallengeStats, LogLevel, RegistryBuilder, RegistryStats,
+};
+#[cfg(feature = "remote")]
+pub use remote::RemoteRegistry;
+pub use scheduling::Priority;
+#[cfg(feature = "remote")]
+pub use serving::TfServingRegistry;
+pub use worker::WorkerPool;
 
 #[deny(
     missing_debug_implementations,
@@ -40,22 +111,13 @@ fn silence_tensorflow() {
     unused_parens,
     while_true
 )]
-#[derive(
-    Debug,
-    Eq,
-    PartialEq,
-    Display,
-    Hash,
-    IntoStaticStr,
-    EnumVariantNames,
-    EnumString,
-    Serialize,
-    Deserialize,
-)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, IntoStaticStr, EnumVariantNames, EnumIter)]
 #[strum(serialize_all = "snake_case")]
 #[allow(missing_docs)]
-#[serde(rename_all = "snake_case")]
-/// CaptchaChallenge represents all accepted reCaptcha challenge types
+/// CaptchaChallenge represents all accepted reCaptcha challenge types. Parsing it, through
+/// FromStr or serde, accepts the snake_case names and any alias (see names::register_alias).
+/// Other snake_case names parse as Other, so models for challenges this enum doesn't know yet
+/// can still be loaded and served. It displays and serializes as its snake_case name
 pub enum CaptchaChallenge {
     AFireHydrant,
     Bridges,
@@ -73,172 +135,138 @@ pub enum CaptchaChallenge {
     Statues,
     Taxis,
     TrafficLights,
+    Boats,
+    Trucks,
+    Chimneys,
+    Trees,
+    TrafficSigns,
+    /// Other is a challenge known only by its name, e.g. a model directory added ahead of
+    /// support in this enum
+    Other(String),
 }
 
-impl CaptchaChallenge {
-    /// is_valid_challenge_os_str is the same as is_valid_challenge but will
-    /// return 'false' if the source string cannot be converted to utf8
-    fn is_valid_challenge_os_str<S>(name: S) -> bool
-    where
-        S: AsRef<std::ffi::OsStr>,
-    {
-        if let Some(string) = name.as_ref().to_str() {
-            return Self::is_valid_challenge(string);
-        }
-        false
-    }
+/// GridSize is the layout of a challenge's tiles, spelled the way test_data/ names its folders
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Display, EnumString, Serialize, Deserialize)]
+pub enum GridSize {
+    #[strum(serialize = "3x3")]
+    #[serde(rename = "3x3")]
+    ThreeByThree,
+    #[strum(serialize = "4x4")]
+    #[serde(rename = "4x4")]
+    FourByFour,
+}
 
-    /// is_valid_challenge tests 'name' for its prescence in the EnumVariant
-    fn is_valid_challenge<S>(name: S) -> bool
-    where
-        S: AsRef<str>,
-    {
-        for var in CaptchaChallenge::VARIANTS {
-            if name.as_ref() == *var {
-                return true;
-            }
+impl GridSize {
+    pub fn tiles_per_side(self) -> u32 {
+        match self {
+            GridSize::ThreeByThree => 3,
+            GridSize::FourByFour => 4,
         }
-        false
     }
 }
 
-/// SavedModelMap employs a mutex around Session because running sessions performs interior
-/// mutability
-type SavedModelMap = HashMap<CaptchaChallenge, Mutex<CaptchaModel>>;
-
-#[derive(Debug)]
-pub struct CaptchaModel {
-    session: Session,
-    graph: Graph,
-}
+/// MATCH_THRESHOLD is the score a prediction is decided at (see Prediction::is_mainly_affirmative)
+pub const MATCH_THRESHOLD: f32 = 0.5;
 
-#[derive(Debug)]
-pub struct CaptchaRegistry {
-    items: SavedModelMap,
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Prediction {
+    affirmative_confidence: f32,
+    negative_confidence: f32,
 }
 
-impl CaptchaRegistry {
-    pub fn load_from_models_dir<P>(path: P) -> errors::Result<CaptchaRegistry>
-    where
-        P: AsRef<std::path::Path>,
-    {
-        let model_directories: Vec<fs::DirEntry> = {
-            let mut vec = Vec::new();
-            for dir in path.as_ref().read_dir()? {
-                vec.push(dir?);
-            }
-            vec
-        };
-
-        let model_count = model_directories.len();
-        silence_tensorflow();
-        Ok(CaptchaRegistry {
-            items: model_directories
-                .into_par_iter()
-                .filter(|dir: &fs::DirEntry| {
-                    CaptchaChallenge::is_valid_challenge_os_str(dir.file_name())
-                })
-                .try_fold(
-                    || SavedModelMap::new(),
-                    |mut acc, dir: fs::DirEntry| {
-                        let saved_model_file = dir.path().join("saved_model.pb");
-                        let challenge = CaptchaChallenge::from_str(
-                            dir.file_name()
-                                .to_str()
-                                .expect("Could not retrieve Model's name"),
-                        )
-                        .unwrap();
-                        if !saved_model_file.exists() {
-                            return Err(errors::Error::ModelLoad(challenge));
-                        } else {
-                            let mut graph = Graph::new();
-                            let session = Session::from_saved_model(
-                                &tensorflow::SessionOptions::new(),
-                                &["serve"],
-                                &mut graph,
-                                dir.path(),
-                            )?;
-                            acc.insert(challenge, Mutex::new(CaptchaModel { session, graph }));
-                        }
-                        Ok(acc)
-                    },
-                )
-                .try_reduce(
-                    || SavedModelMap::with_capacity(model_count),
-                    |mut m, t| {
-                        for (k, v) in t.into_iter() {
-                            m.insert(k, v);
-                        }
-                        Ok(m)
-                    },
-                )?,
-        })
+impl Prediction {
+    pub fn new(affirmative_confidence: f32, negative_confidence: f32) -> Prediction {
+        Prediction {
+            affirmative_confidence,
+            negative_confidence,
+        }
     }
 
-    pub fn predict(
-        &self,
-        challenge: &CaptchaChallenge,
-        image: String,
-    ) -> errors::Result<Prediction> {
-        let model = self
-            .items
-            .get(challenge)
-            .expect("This should not happen")
-            .lock()?;
-
-        // inptus
-        let input_operation = model.graph.operation_by_name_required("Placeholder")?;
-        let input_tensor = Tensor::new(&[1u64]).with_values(&[image])?;
-
-        let mut output_step = tensorflow::SessionRunArgs::new();
-        output_step.add_feed(&input_operation, 0, &input_tensor);
-
-        let scores_out =
-            output_step.request_fetch(&model.graph.operation_by_name_required("scores")?, 0);
-
-        model.session.run(&mut output_step)?;
-        let predictions: Tensor<f32> = output_step.fetch(scores_out)?;
-
-        Ok(Prediction {
-            affirmative_confidence: predictions[0],
-            negative_confidence: predictions[1],
-        })
+    pub fn affirmative_confidence(&self) -> f32 {
+        self.affirmative_confidence
     }
-}
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Prediction {
-    affirmative_confidence: f32,
-    negative_confidence: f32,
-}
+    pub fn negative_confidence(&self) -> f32 {
+        self.negative_confidence
+    }
 
-impl Prediction {
     // TODO(haze): better signals
     pub fn is_mainly_affirmative(&self) -> bool {
-        self.affirmative_confidence >= 0.50 && self.negative_confidence < 0.50
+        self.affirmative_confidence >= MATCH_THRESHOLD && self.negative_confidence < MATCH_THRESHOLD
     }
 }
 
-#[cfg(test)]
+/// image_hash is the hex encoded SHA-256 of an image's bytes, used to identify images across
+/// harvesting and feedback
+pub fn image_hash<B>(image: B) -> String
+where
+    B: AsRef<[u8]>,
+{
+    Sha256::digest(image.as_ref())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(all(test, feature = "tensorflow"))]
 mod tests {
     use super::*;
-    use std::path;
+    use std::{fs, path, str::FromStr};
 
     #[test]
     fn load_models() -> errors::Result<()> {
         CaptchaRegistry::load_from_models_dir(path::Path::new("models/")).map(|_| ())
     }
 
-    fn load_image_into_string<A>(path: A) -> errors::Result<String>
+    #[test]
+    fn load_from_paths() -> errors::Result<()> {
+        let mut paths = std::collections::HashMap::new();
+        let _ = paths.insert(CaptchaChallenge::Bus, path::PathBuf::from("models/bus"));
+        let registry = CaptchaRegistry::from_paths(paths)?;
+        let _ = registry.predict(&CaptchaChallenge::Bus, load_image("./bus.jpg")?)?;
+        Ok(())
+    }
+
+    #[test]
+    fn evicted_models_reload() -> errors::Result<()> {
+        let mut paths = std::collections::HashMap::new();
+        let _ = paths.insert(CaptchaChallenge::Bus, path::PathBuf::from("models/bus"));
+        let registry = CaptchaRegistry::builder()
+            .idle_ttl(std::time::Duration::from_secs(0))
+            .load_from_paths(paths)?;
+        assert_eq!(registry.evict_idle(), 1);
+        assert_eq!(registry.evict_idle(), 0);
+        let _ = registry.predict(&CaptchaChallenge::Bus, load_image("./bus.jpg")?)?;
+        assert_eq!(registry.evict_idle(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn detects_updated_models() -> errors::Result<()> {
+        let dir = std::env::temp_dir().join(format!("nocap-updates-{}", std::process::id()));
+        fs::create_dir_all(dir.join("bus"))?;
+        let _ = fs::copy("models/bus/saved_model.pb", dir.join("bus/saved_model.pb"))?;
+        let mut paths = std::collections::HashMap::new();
+        let _ = paths.insert(CaptchaChallenge::Bus, dir.join("bus"));
+        let registry = CaptchaRegistry::from_paths(paths)?;
+        assert!(registry.check_for_updates()?.is_empty());
+        fs::write(dir.join("bus/labels.txt"), "bus\n")?;
+        assert_eq!(registry.check_for_updates()?, vec![CaptchaChallenge::Bus]);
+        assert!(registry.refresh()?.check_for_updates()?.is_empty());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    fn load_image<A>(path: A) -> errors::Result<Vec<u8>>
     where
         A: AsRef<path::Path>,
     {
-        Ok(unsafe { String::from_utf8_unchecked(std::fs::read(path)?) })
+        Ok(std::fs::read(path)?)
     }
 
     #[test]
     fn prediction() -> errors::Result<()> {
-        let test_image = load_image_into_string("./bus.png")?;
+        let test_image = load_image("./bus.jpg")?;
         let registry: CaptchaRegistry =
             CaptchaRegistry::load_from_models_dir(path::Path::new("models/"))?;
         let prediction = registry.predict(&CaptchaChallenge::Bus, test_image);
@@ -335,8 +363,7 @@ mod tests {
         let (mut correct, mut incorrect) = (0.0, 0.0);
         for file in files {
             println!("[{}] {:?}", challenge, &file.path());
-            let results: Prediction =
-                registry.predict(challenge, load_image_into_string(file.path())?)?;
+            let results: Prediction = registry.predict(challenge, load_image(file.path())?)?;
             if results.is_mainly_affirmative() {
                 if expecting_correct {
                     correct += 1.0;
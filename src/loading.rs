@@ -0,0 +1,196 @@
+use crate::errors;
+#[cfg(feature = "parallel")]
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use serde_derive::Deserialize;
+#[cfg(feature = "parallel")]
+use std::sync::Arc;
+use std::{
+    fs,
+    path::Path,
+    sync::{Condvar, Mutex},
+};
+
+/// LoadLimits bounds how many models are loaded at once. TensorFlow needs several times a
+/// model's size while restoring it, so loading every model concurrently can spike peak memory
+/// far above what the loaded registry holds. Both limits are off by default
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct LoadLimits {
+    /// concurrency caps the models loaded at once, 1 loading them sequentially
+    pub concurrency: Option<usize>,
+    /// memory_budget caps the combined on-disk size, in bytes, of the models being loaded at
+    /// once. A model larger than the budget is still loaded, alone
+    pub memory_budget: Option<u64>,
+}
+
+impl LoadLimits {
+    /// sequential loads one model at a time
+    pub fn sequential() -> LoadLimits {
+        LoadLimits {
+            concurrency: Some(1),
+            memory_budget: None,
+        }
+    }
+}
+
+/// LoadPool is the rayon pool models are loaded on. Applications with rayon work of their own
+/// should load on a separate pool, so loading doesn't occupy the workers that work waits on.
+/// Without the parallel feature models are loaded one after another on the calling thread
+#[cfg(feature = "parallel")]
+#[derive(Debug, Clone, Default)]
+pub enum LoadPool {
+    /// Global is rayon's global pool
+    #[default]
+    Global,
+    /// Threads loads on a pool of that many threads, built for each load
+    Threads(usize),
+    /// Pool loads on a pool supplied by the caller
+    Pool(Arc<ThreadPool>),
+}
+
+#[cfg(feature = "parallel")]
+impl LoadPool {
+    /// install runs 'op' on the pool, so parallel iterators inside it use the pool's threads
+    pub(crate) fn install<R, F>(&self, op: F) -> errors::Result<R>
+    where
+        R: Send,
+        F: FnOnce() -> R + Send,
+    {
+        match self {
+            LoadPool::Global => Ok(op()),
+            LoadPool::Threads(threads) => Ok(ThreadPoolBuilder::new()
+                .num_threads(*threads)
+                .thread_name(|index| format!("nocap-load-{}", index))
+                .build()?
+                .install(op)),
+            LoadPool::Pool(pool) => Ok(pool.install(op)),
+        }
+    }
+}
+
+/// LoadGate makes loads wait until they fit within LoadLimits
+#[derive(Debug)]
+pub(crate) struct LoadGate {
+    limits: LoadLimits,
+    // models and bytes currently loading
+    in_flight: Mutex<(usize, u64)>,
+    released: Condvar,
+}
+
+impl LoadGate {
+    pub(crate) fn new(limits: LoadLimits) -> LoadGate {
+        LoadGate {
+            limits,
+            in_flight: Mutex::new((0, 0)),
+            released: Condvar::new(),
+        }
+    }
+
+    /// acquire blocks until a model costing 'cost' bytes may load, and holds its share of the
+    /// limits until the returned permit is dropped
+    pub(crate) fn acquire(&self, cost: u64) -> errors::Result<LoadPermit<'_>> {
+        let cost = self
+            .limits
+            .memory_budget
+            .map_or(cost, |budget| cost.min(budget));
+        let mut in_flight = self.in_flight.lock()?;
+        while !self.fits(*in_flight, cost) {
+            in_flight = self.released.wait(in_flight)?;
+        }
+        in_flight.0 += 1;
+        in_flight.1 += cost;
+        Ok(LoadPermit { gate: self, cost })
+    }
+
+    fn fits(&self, (models, bytes): (usize, u64), cost: u64) -> bool {
+        // the first load always fits, so a limit of zero can't stall loading forever
+        models == 0
+            || (self.limits.concurrency.is_none_or(|limit| models < limit)
+                && self
+                    .limits
+                    .memory_budget
+                    .is_none_or(|budget| bytes + cost <= budget))
+    }
+}
+
+pub(crate) struct LoadPermit<'a> {
+    gate: &'a LoadGate,
+    cost: u64,
+}
+
+impl Drop for LoadPermit<'_> {
+    fn drop(&mut self) {
+        // a poisoned lock means another load panicked, which takes the whole load down anyway
+        if let Ok(mut in_flight) = self.gate.in_flight.lock() {
+            in_flight.0 -= 1;
+            in_flight.1 -= self.cost;
+        }
+        self.gate.released.notify_all();
+    }
+}
+
+/// model_size is the combined size of the files under a model directory, the estimate of what
+/// loading it costs
+pub(crate) fn model_size(dir: &Path) -> errors::Result<u64> {
+    let mut size = 0;
+    for entry in dir.read_dir()? {
+        let entry = entry?;
+        let metadata = fs::metadata(entry.path())?;
+        size += if metadata.is_dir() {
+            model_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    fn peak_concurrency(limits: LoadLimits, costs: &[u64]) -> usize {
+        let gate = LoadGate::new(limits);
+        let (loading, peak) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        thread::scope(|scope| {
+            for &cost in costs {
+                let _ = scope.spawn(|| {
+                    let _permit = gate.acquire(cost).unwrap();
+                    let now = loading.fetch_add(1, Ordering::SeqCst) + 1;
+                    let _ = peak.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(20));
+                    let _ = loading.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        peak.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn bounds_concurrent_loads() {
+        assert_eq!(peak_concurrency(LoadLimits::sequential(), &[1; 4]), 1);
+
+        let budget = LoadLimits {
+            concurrency: None,
+            memory_budget: Some(100),
+        };
+        assert!(peak_concurrency(budget, &[50, 50, 50, 50]) <= 2);
+        // larger than the budget, but still loaded
+        assert_eq!(peak_concurrency(budget, &[500, 10]), 1);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn installs_on_the_given_pool() -> errors::Result<()> {
+        let name = LoadPool::Threads(1).install(|| thread::current().name().map(String::from))?;
+        assert_eq!(name.as_deref(), Some("nocap-load-0"));
+        let pool = Arc::new(ThreadPoolBuilder::new().num_threads(3).build()?);
+        assert_eq!(LoadPool::Pool(pool).install(rayon::current_num_threads)?, 3);
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+use crate::errors;
+use serde_derive::Serialize;
+#[cfg(feature = "alloc-stats")]
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use std::{fs, io};
+
+/// ResidentSet is the process's resident memory, in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResidentSet {
+    pub current: u64,
+    /// peak is the high water mark since the process started or reset_peak_rss was last called
+    pub peak: u64,
+}
+
+/// resident_set reads the process's resident memory from /proc, so it is None on platforms
+/// other than Linux
+pub fn resident_set() -> Option<ResidentSet> {
+    parse_status(&fs::read_to_string("/proc/self/status").ok()?)
+}
+
+/// reset_peak_rss resets the peak reported by resident_set to the current resident memory, so
+/// a phase's peak can be told apart from an earlier one's
+pub fn reset_peak_rss() -> io::Result<()> {
+    fs::write("/proc/self/clear_refs", "5")
+}
+
+fn parse_status(status: &str) -> Option<ResidentSet> {
+    let field = |name: &str| -> Option<u64> {
+        let line = status.lines().find(|line| line.starts_with(name))?;
+        let kilobytes = line[name.len()..].trim().trim_end_matches("kB").trim();
+        Some(kilobytes.parse::<u64>().ok()? * 1024)
+    };
+    Some(ResidentSet {
+        current: field("VmRSS:")?,
+        peak: field("VmHWM:")?,
+    })
+}
+
+/// AllocationStats are the heap allocations counted by CountingAllocator
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct AllocationStats {
+    pub allocations: u64,
+    pub allocated_bytes: u64,
+    /// live_bytes are allocated and not yet freed
+    pub live_bytes: u64,
+    /// peak_bytes is the most live_bytes have been since the peak was last reset
+    pub peak_bytes: u64,
+}
+
+#[cfg(feature = "alloc-stats")]
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+#[cfg(feature = "alloc-stats")]
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+#[cfg(feature = "alloc-stats")]
+static LIVE_BYTES: AtomicU64 = AtomicU64::new(0);
+#[cfg(feature = "alloc-stats")]
+static PEAK_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// CountingAllocator is the system allocator, counting every allocation. It only counts once a
+/// binary installs it:
+///
+/// ```ignore
+/// #[global_allocator]
+/// static ALLOCATOR: no_captcha::memory::CountingAllocator = no_captcha::memory::CountingAllocator;
+/// ```
+#[cfg(feature = "alloc-stats")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CountingAllocator;
+
+#[cfg(feature = "alloc-stats")]
+impl CountingAllocator {
+    pub fn stats() -> AllocationStats {
+        AllocationStats {
+            allocations: ALLOCATIONS.load(Ordering::Relaxed),
+            allocated_bytes: ALLOCATED_BYTES.load(Ordering::Relaxed),
+            live_bytes: LIVE_BYTES.load(Ordering::Relaxed),
+            peak_bytes: PEAK_BYTES.load(Ordering::Relaxed),
+        }
+    }
+
+    /// reset_peak resets peak_bytes to the bytes live now
+    pub fn reset_peak() {
+        PEAK_BYTES.store(LIVE_BYTES.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    fn allocated(size: usize) {
+        let _ = ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        let _ = ALLOCATED_BYTES.fetch_add(size as u64, Ordering::Relaxed);
+        let live = LIVE_BYTES.fetch_add(size as u64, Ordering::Relaxed) + size as u64;
+        let _ = PEAK_BYTES.fetch_max(live, Ordering::Relaxed);
+    }
+
+    fn freed(size: usize) {
+        let _ = LIVE_BYTES.fetch_sub(size as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(feature = "alloc-stats")]
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            CountingAllocator::allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            CountingAllocator::allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        CountingAllocator::freed(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        // a failed realloc leaves the old block where it was
+        if !new_ptr.is_null() {
+            CountingAllocator::freed(layout.size());
+            CountingAllocator::allocated(new_size);
+        }
+        new_ptr
+    }
+}
+
+/// PhaseMemory is the memory one profiled phase used. Resident memory is missing off Linux, and
+/// the allocation counts unless CountingAllocator is the global allocator
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PhaseMemory {
+    pub phase: String,
+    /// peak_rss_bytes is the peak resident memory during the phase
+    pub peak_rss_bytes: Option<u64>,
+    /// rss_bytes is the resident memory once the phase is over
+    pub rss_bytes: Option<u64>,
+    pub allocations: Option<u64>,
+    pub allocated_bytes: Option<u64>,
+    /// peak_heap_bytes is the most heap the process had allocated during the phase
+    pub peak_heap_bytes: Option<u64>,
+}
+
+/// profile runs 'phase' and measures the memory it used. Phases should be profiled one at a
+/// time: the peaks are the process's, not the phase's own
+pub fn profile<T, F>(phase: &str, run: F) -> errors::Result<(T, PhaseMemory)>
+where
+    F: FnOnce() -> errors::Result<T>,
+{
+    // without the reset, the peak could be an earlier phase's
+    let peak_reset = reset_peak_rss().is_ok();
+    let before = heap_stats();
+    let value = run()?;
+    let after = heap_stats();
+    let resident = resident_set();
+    let heap = before.zip(after);
+    Ok((
+        value,
+        PhaseMemory {
+            phase: phase.to_string(),
+            peak_rss_bytes: resident
+                .filter(|_| peak_reset)
+                .map(|resident| resident.peak),
+            rss_bytes: resident.map(|resident| resident.current),
+            allocations: heap.map(|(before, after)| after.allocations - before.allocations),
+            allocated_bytes: heap
+                .map(|(before, after)| after.allocated_bytes - before.allocated_bytes),
+            peak_heap_bytes: heap.map(|(_, after)| after.peak_bytes),
+        },
+    ))
+}
+
+/// heap_stats resets the heap's peak and returns its stats, or None if CountingAllocator isn't
+/// counting
+#[cfg(feature = "alloc-stats")]
+fn heap_stats() -> Option<AllocationStats> {
+    let stats = CountingAllocator::stats();
+    CountingAllocator::reset_peak();
+    // any process has allocated by the time it profiles, so no allocations means not installed
+    Some(stats).filter(|stats| stats.allocations > 0)
+}
+
+#[cfg(not(feature = "alloc-stats"))]
+fn heap_stats() -> Option<AllocationStats> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_proc_status() {
+        let status = "Name:\tnocap\nVmPeak:\t  900 kB\nVmHWM:\t    512 kB\nVmRSS:\t    256 kB\n";
+        assert_eq!(
+            parse_status(status),
+            Some(ResidentSet {
+                current: 256 * 1024,
+                peak: 512 * 1024,
+            })
+        );
+        assert_eq!(parse_status("Name:\tnocap\n"), None);
+    }
+}
@@ -0,0 +1,224 @@
+use crate::{
+    ensemble::Aggregation,
+    errors,
+    grid::{self, Bounds, Layout},
+    CaptchaChallenge, GridSize, Prediction, Predictor,
+};
+use image::{DynamicImage, ImageOutputFormat};
+use serde_derive::Deserialize;
+
+/// MultiCropConfig is how predict_grid crops a 4x4 grid
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct MultiCropConfig {
+    /// scales are the sides of the crops, in tiles: 1.0 crops a tile's worth, 2.0 a 2x2 block
+    pub scales: Vec<f32>,
+    /// stride is how far apart neighbouring crops start, as a fraction of their side, so 0.5
+    /// overlaps each crop with half of the next
+    pub stride: f32,
+    /// min_overlap is the fraction of a tile a crop has to cover to count towards its score
+    pub min_overlap: f32,
+    /// aggregation merges a tile's own prediction with those of the crops counting towards it
+    pub aggregation: Aggregation,
+}
+
+impl Default for MultiCropConfig {
+    fn default() -> MultiCropConfig {
+        MultiCropConfig {
+            scales: vec![1.0, 2.0],
+            stride: 0.5,
+            min_overlap: 0.25,
+            aggregation: Aggregation::Max,
+        }
+    }
+}
+
+/// predict_grid locates the tiles of a challenge screenshot (see grid::locate) and scores each
+/// of them. The tiles of a 4x4 grid are parts of one picture, where an object cut by a border
+/// can be missed by every tile it spans, so the grid is also predicted on overlapping crops at
+/// each of config.scales, and every tile's score merges its own prediction with those of the
+/// crops covering it. The tiles of a 3x3 grid are separate pictures and are only predicted
+/// one by one
+pub fn predict_grid<P>(
+    predictor: &P,
+    challenge: &CaptchaChallenge,
+    screenshot: &[u8],
+    config: &MultiCropConfig,
+) -> errors::Result<(Layout, Vec<Prediction>)>
+where
+    P: Predictor + ?Sized,
+{
+    let image = grid::decode(screenshot)?;
+    let layout = grid::locate_in(&image).ok_or(errors::Error::GridNotFound)?;
+    let crops = match layout.grid {
+        GridSize::FourByFour => crops(&layout.tiles, layout.grid.tiles_per_side(), config),
+        GridSize::ThreeByThree => Vec::new(),
+    };
+    let images = layout
+        .tiles
+        .iter()
+        .chain(&crops)
+        .map(|bounds| encode(DynamicImage::ImageRgb8(grid::crop(&image, *bounds))))
+        .collect::<errors::Result<Vec<Vec<u8>>>>()?;
+    let predictions = predictor.predict_batch(challenge, images)?;
+    if predictions.len() != layout.tiles.len() + crops.len() {
+        return Err(errors::Error::MalformedOutput);
+    }
+    let (own, cropped) = predictions.split_at(layout.tiles.len());
+    let merged = merge(&layout.tiles, own, &crops, cropped, config);
+    Ok((layout, merged))
+}
+
+fn encode(image: DynamicImage) -> errors::Result<Vec<u8>> {
+    let mut encoded = Vec::new();
+    image.write_to(&mut encoded, ImageOutputFormat::Png)?;
+    Ok(encoded)
+}
+
+/// crops lays crops of each scale over the grid 'tiles' make up, 'side' tiles to a side.
+/// Scales wider than the grid are skipped
+fn crops(tiles: &[Bounds], side: u32, config: &MultiCropConfig) -> Vec<Bounds> {
+    let (first, last) = match (tiles.first(), tiles.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return Vec::new(),
+    };
+    let (width, height) = (last.right - first.left, last.bottom - first.top);
+    let mut crops = Vec::new();
+    for scale in &config.scales {
+        let scale = scale / side as f32;
+        if scale <= 0.0 || scale > 1.0 {
+            continue;
+        }
+        let crop_width = ((width as f32 * scale) as u32).max(1);
+        let crop_height = ((height as f32 * scale) as u32).max(1);
+        let columns = offsets(first.left, last.right, crop_width, config.stride);
+        let rows = offsets(first.top, last.bottom, crop_height, config.stride);
+        for top in &rows {
+            for left in &columns {
+                crops.push(Bounds {
+                    left: *left,
+                    top: *top,
+                    right: left + crop_width,
+                    bottom: top + crop_height,
+                });
+            }
+        }
+    }
+    crops
+}
+
+/// offsets are where crops 'size' long start along [start, end), 'stride' of their size
+/// apart, the last ending at 'end' so none of the grid is left out
+fn offsets(start: u32, end: u32, size: u32, stride: f32) -> Vec<u32> {
+    let step = ((size as f32 * stride) as usize).max(1);
+    let last = end - size;
+    let mut offsets: Vec<u32> = (start..=last).step_by(step).collect();
+    if offsets.last() != Some(&last) {
+        offsets.push(last);
+    }
+    offsets
+}
+
+/// merge aggregates each tile's own prediction with those of the crops covering at least
+/// config.min_overlap of it
+fn merge(
+    tiles: &[Bounds],
+    own: &[Prediction],
+    crops: &[Bounds],
+    cropped: &[Prediction],
+    config: &MultiCropConfig,
+) -> Vec<Prediction> {
+    tiles
+        .iter()
+        .zip(own)
+        .map(|(tile, prediction)| {
+            let mut members = vec![*prediction];
+            members.extend(
+                crops
+                    .iter()
+                    .zip(cropped)
+                    .filter(|(crop, _)| {
+                        area(&overlap(tile, crop)) as f32 >= area(tile) as f32 * config.min_overlap
+                    })
+                    .map(|(_, prediction)| *prediction),
+            );
+            config.aggregation.aggregate(&members)
+        })
+        .collect()
+}
+
+fn overlap(a: &Bounds, b: &Bounds) -> Bounds {
+    Bounds {
+        left: a.left.max(b.left),
+        top: a.top.max(b.top),
+        right: a.right.min(b.right),
+        bottom: a.bottom.min(b.bottom),
+    }
+}
+
+fn area(bounds: &Bounds) -> u64 {
+    u64::from(bounds.right.saturating_sub(bounds.left))
+        * u64::from(bounds.bottom.saturating_sub(bounds.top))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tiles(side: u32, size: u32) -> Vec<Bounds> {
+        (0..side * side)
+            .map(|index| Bounds {
+                left: index % side * size,
+                top: index / side * size,
+                right: (index % side + 1) * size,
+                bottom: (index / side + 1) * size,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn crops_overlap_and_cover_the_grid() {
+        assert_eq!(
+            offsets(0, 400, 100, 0.5),
+            vec![0, 50, 100, 150, 200, 250, 300]
+        );
+        assert_eq!(offsets(10, 110, 60, 0.5), vec![10, 40, 50]);
+        assert_eq!(offsets(0, 100, 100, 0.5), vec![0]);
+
+        let config = MultiCropConfig::default();
+        let crops = crops(&tiles(4, 100), 4, &config);
+        // 7x7 crops of a tile and 3x3 of a 2x2 block
+        assert_eq!(crops.len(), 49 + 9);
+        assert!(crops
+            .iter()
+            .all(|crop| crop.right <= 400 && crop.bottom <= 400));
+    }
+
+    #[test]
+    fn merges_the_crops_covering_a_tile() {
+        let tiles = tiles(2, 100);
+        let own = vec![Prediction::new(0.2, 0.8); 4];
+        // straddles the border between the first two tiles, covering half of each
+        let crops = [Bounds {
+            left: 50,
+            top: 0,
+            right: 150,
+            bottom: 100,
+        }];
+        let cropped = [Prediction::new(0.9, 0.1)];
+        let config = MultiCropConfig::default();
+        let merged = merge(&tiles, &own, &crops, &cropped, &config);
+        let scores: Vec<f32> = merged
+            .iter()
+            .map(Prediction::affirmative_confidence)
+            .collect();
+        assert_eq!(scores, vec![0.9, 0.9, 0.2, 0.2]);
+
+        let strict = MultiCropConfig {
+            min_overlap: 0.75,
+            ..config
+        };
+        let merged = merge(&tiles, &own, &crops, &cropped, &strict);
+        assert!(merged.iter().all(|p| p.affirmative_confidence() == 0.2));
+    }
+}
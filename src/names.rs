@@ -0,0 +1,437 @@
+use crate::{prompt, CaptchaChallenge, GridSize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::{OnceLock, PoisonError, RwLock},
+};
+use strum::{IntoEnumIterator, VariantNames};
+
+/// BUILTIN_ALIASES are English spellings of challenges beyond their snake_case names, in the
+/// same format as the locale tables. Every locale's phrases are aliases too
+static BUILTIN_ALIASES: &str = include_str!("../locales/aliases.json");
+
+/// MAX_SUGGESTIONS bounds how many close matches UnknownChallenge offers
+const MAX_SUGGESTIONS: usize = 3;
+
+/// LEADING_ARTICLES are dropped before comparing names, so "fire hydrant" finds
+/// a_fire_hydrant
+const LEADING_ARTICLES: &[&str] = &["a", "an", "the"];
+
+/// UnknownChallenge is returned by parse_lenient for names that match no challenge, with the
+/// closest challenges by edit distance, best first
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownChallenge {
+    pub input: String,
+    pub suggestions: Vec<CaptchaChallenge>,
+}
+
+impl fmt::Display for UnknownChallenge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown challenge {:?}", self.input)?;
+        if !self.suggestions.is_empty() {
+            let names: Vec<String> = self.suggestions.iter().map(|c| c.to_string()).collect();
+            write!(f, ", did you mean {}?", names.join(" or "))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for UnknownChallenge {}
+
+impl CaptchaChallenge {
+    /// parse_lenient parses a challenge name the way people write them, ignoring case,
+    /// separators (spaces, hyphens, underscores), leading articles and plurals, so
+    /// "Traffic-Light", "traffic lights" and "traffic_lights" are all TrafficLights. Aliases
+    /// are accepted as well. Unlike from_str it never returns Other, since a name that matches
+    /// nothing is more likely a typo than a new challenge
+    pub fn parse_lenient<S>(name: S) -> Result<CaptchaChallenge, UnknownChallenge>
+    where
+        S: AsRef<str>,
+    {
+        if let Some(challenge) = strict(name.as_ref()).or_else(|| resolve_alias(name.as_ref())) {
+            return Ok(challenge);
+        }
+        let key = lenient_key(name.as_ref());
+        let mut candidates: Vec<(usize, CaptchaChallenge)> = Vec::new();
+        for challenge in CaptchaChallenge::all() {
+            let distance = edit_distance(&key, &lenient_key(challenge.name()));
+            if distance == 0 {
+                return Ok(challenge);
+            }
+            candidates.push((distance, challenge));
+        }
+
+        // anything needing more edits than half the name is a different word, not a typo
+        let threshold = (key.chars().count() / 2).max(1);
+        candidates.sort_by_key(|(distance, _)| *distance);
+        Err(UnknownChallenge {
+            input: name.as_ref().to_owned(),
+            suggestions: candidates
+                .into_iter()
+                .filter(|(distance, _)| *distance <= threshold)
+                .take(MAX_SUGGESTIONS)
+                .map(|(_, challenge)| challenge)
+                .collect(),
+        })
+    }
+
+    /// name is the challenge's snake_case name, which for Other is the name it was parsed from
+    pub fn name(&self) -> &str {
+        match self {
+            CaptchaChallenge::Other(name) => name,
+            known => known.into(),
+        }
+    }
+
+    /// is_known is false for Other
+    pub fn is_known(&self) -> bool {
+        !matches!(self, CaptchaChallenge::Other(_))
+    }
+
+    /// all iterates over every known challenge, leaving out Other
+    pub fn all() -> impl Iterator<Item = CaptchaChallenge> {
+        CaptchaChallenge::iter().filter(CaptchaChallenge::is_known)
+    }
+
+    /// display_name is the challenge as a person would write it, e.g. "Fire hydrant"
+    pub fn display_name(&self) -> String {
+        let name = match self {
+            CaptchaChallenge::AFireHydrant => "Fire hydrant",
+            CaptchaChallenge::Bridges => "Bridges",
+            CaptchaChallenge::Cars => "Cars",
+            CaptchaChallenge::Motorcycles => "Motorcycles",
+            CaptchaChallenge::PalmTrees => "Palm trees",
+            CaptchaChallenge::Stairs => "Stairs",
+            CaptchaChallenge::StoreFront => "Storefront",
+            CaptchaChallenge::Tractors => "Tractors",
+            CaptchaChallenge::Bicycles => "Bicycles",
+            CaptchaChallenge::Bus => "Bus",
+            CaptchaChallenge::Crosswalks => "Crosswalks",
+            CaptchaChallenge::MountainsOrHills => "Mountains or hills",
+            CaptchaChallenge::ParkingMeters => "Parking meters",
+            CaptchaChallenge::Statues => "Statues",
+            CaptchaChallenge::Taxis => "Taxis",
+            CaptchaChallenge::TrafficLights => "Traffic lights",
+            CaptchaChallenge::Boats => "Boats",
+            CaptchaChallenge::Trucks => "Trucks",
+            CaptchaChallenge::Chimneys => "Chimneys",
+            CaptchaChallenge::Trees => "Trees",
+            CaptchaChallenge::TrafficSigns => "Traffic signs",
+            CaptchaChallenge::Other(name) => {
+                let words = name.replace('_', " ");
+                let mut chars = words.chars();
+                return match chars.next() {
+                    Some(first) => first.to_uppercase().chain(chars).collect(),
+                    None => words,
+                };
+            }
+        };
+        name.to_owned()
+    }
+
+    /// expected_grid is the grid the challenge is usually served in. Challenges asking to
+    /// select every square containing part of an object tend to come as 4x4 grids of one
+    /// image, the rest as 3x3 grids of separate images. Either can show up in practice, and
+    /// nothing is known about Other
+    pub fn expected_grid(&self) -> Option<GridSize> {
+        match self {
+            CaptchaChallenge::AFireHydrant
+            | CaptchaChallenge::Bicycles
+            | CaptchaChallenge::Bus
+            | CaptchaChallenge::Cars
+            | CaptchaChallenge::Crosswalks
+            | CaptchaChallenge::Motorcycles
+            | CaptchaChallenge::Stairs
+            | CaptchaChallenge::TrafficLights => Some(GridSize::FourByFour),
+            CaptchaChallenge::Other(_) => None,
+            _ => Some(GridSize::ThreeByThree),
+        }
+    }
+}
+
+impl fmt::Display for CaptchaChallenge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for CaptchaChallenge {
+    type Err = strum::ParseError;
+
+    /// from_str accepts a challenge's snake_case name or any of its aliases, and any other
+    /// snake_case name as Other
+    fn from_str(name: &str) -> Result<CaptchaChallenge, Self::Err> {
+        strict(name)
+            .or_else(|| resolve_alias(name))
+            .or_else(|| other(name))
+            .ok_or(strum::ParseError::VariantNotFound)
+    }
+}
+
+impl Serialize for CaptchaChallenge {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for CaptchaChallenge {
+    fn deserialize<D>(deserializer: D) -> Result<CaptchaChallenge, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let name = String::deserialize(deserializer)?;
+        CaptchaChallenge::from_str(&name)
+            .map_err(|_| de::Error::unknown_variant(&name, CaptchaChallenge::VARIANTS))
+    }
+}
+
+/// strict matches only the exact snake_case names of known challenges
+fn strict(name: &str) -> Option<CaptchaChallenge> {
+    CaptchaChallenge::all().find(|challenge| challenge.name() == name)
+}
+
+/// other accepts names spelled like model directories, lowercase letters, digits and
+/// underscores, as Other
+fn other(name: &str) -> Option<CaptchaChallenge> {
+    let snake_case = name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if snake_case {
+        Some(CaptchaChallenge::Other(name.to_owned()))
+    } else {
+        None
+    }
+}
+
+/// register_alias makes 'alias' parse as 'challenge' from now on, process wide. Aliases are
+/// compared ignoring case and punctuation, and registered ones take precedence over the
+/// embedded table
+pub fn register_alias<S>(alias: S, challenge: CaptchaChallenge)
+where
+    S: AsRef<str>,
+{
+    let _ = user_aliases()
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(alias_key(alias.as_ref()), challenge);
+}
+
+/// resolve_alias looks 'alias' up among the registered and embedded aliases
+pub fn resolve_alias<S>(alias: S) -> Option<CaptchaChallenge>
+where
+    S: AsRef<str>,
+{
+    let key = alias_key(alias.as_ref());
+    let registered = user_aliases()
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(&key)
+        .cloned();
+    registered.or_else(|| builtin_aliases().get(&key).cloned())
+}
+
+fn user_aliases() -> &'static RwLock<HashMap<String, CaptchaChallenge>> {
+    static ALIASES: OnceLock<RwLock<HashMap<String, CaptchaChallenge>>> = OnceLock::new();
+    ALIASES.get_or_init(RwLock::default)
+}
+
+fn builtin_aliases() -> &'static HashMap<String, CaptchaChallenge> {
+    static ALIASES: OnceLock<HashMap<String, CaptchaChallenge>> = OnceLock::new();
+    ALIASES.get_or_init(|| {
+        let mut aliases = HashMap::new();
+        let tables = std::iter::once(BUILTIN_ALIASES)
+            .chain(prompt::BUILTIN_LOCALES.iter().map(|(_, json)| *json));
+        for json in tables {
+            // keys are read as strings and matched strictly, since parsing them as challenges
+            // would come back here before the table exists
+            let table: HashMap<String, Vec<String>> = match serde_json::from_str(json) {
+                Ok(table) => table,
+                Err(_) => continue,
+            };
+            for (name, phrases) in table {
+                if let Some(challenge) = strict(&name) {
+                    for phrase in phrases {
+                        let _ = aliases
+                            .entry(alias_key(&phrase))
+                            .or_insert_with(|| challenge.clone());
+                    }
+                }
+            }
+        }
+        aliases
+    })
+}
+
+/// alias_key normalizes an alias to its lowercase words separated by single spaces
+fn alias_key(alias: &str) -> String {
+    prompt::tokenize(alias).join(" ")
+}
+
+/// lenient_key reduces a name to its lowercase singular words run together
+fn lenient_key(name: &str) -> String {
+    let lowercase = name.to_lowercase();
+    let mut words: Vec<&str> = lowercase
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect();
+    if words.len() > 1 && LEADING_ARTICLES.contains(&words[0]) {
+        let _ = words.remove(0);
+    }
+    words.into_iter().map(singular).collect()
+}
+
+/// singular undoes the English plurals challenge names use
+fn singular(word: &str) -> String {
+    if let Some(stem) = word.strip_suffix("ies") {
+        return format!("{}y", stem);
+    }
+    for suffix in &["ses", "xes", "ches", "shes"] {
+        if word.ends_with(suffix) {
+            return word[..word.len() - 2].to_owned();
+        }
+    }
+    if word.ends_with('s') && !word.ends_with("ss") && !word.ends_with("us") {
+        return word[..word.len() - 1].to_owned();
+    }
+    word.to_owned()
+}
+
+/// edit_distance is the Levenshtein distance between 'a' and 'b'
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_natural_spellings() {
+        for name in &[
+            "traffic lights",
+            "Traffic-Light",
+            "TRAFFIC_LIGHTS",
+            "traffic light",
+        ] {
+            assert_eq!(
+                CaptchaChallenge::parse_lenient(name),
+                Ok(CaptchaChallenge::TrafficLights)
+            );
+        }
+        assert_eq!(
+            CaptchaChallenge::parse_lenient("fire hydrants"),
+            Ok(CaptchaChallenge::AFireHydrant)
+        );
+        assert_eq!(
+            CaptchaChallenge::parse_lenient("buses"),
+            Ok(CaptchaChallenge::Bus)
+        );
+        assert_eq!(
+            CaptchaChallenge::parse_lenient("Storefronts"),
+            Ok(CaptchaChallenge::StoreFront)
+        );
+        assert_eq!(
+            CaptchaChallenge::parse_lenient("mountain or hill"),
+            Ok(CaptchaChallenge::MountainsOrHills)
+        );
+    }
+
+    #[test]
+    fn suggests_close_names() {
+        let err = CaptchaChallenge::parse_lenient("trafic lihgts").unwrap_err();
+        assert_eq!(
+            err.suggestions.first(),
+            Some(&CaptchaChallenge::TrafficLights)
+        );
+
+        let err = CaptchaChallenge::parse_lenient("submarines").unwrap_err();
+        assert!(err.suggestions.is_empty());
+    }
+
+    #[test]
+    fn unknown_names_are_other() {
+        let other = CaptchaChallenge::Other(String::from("hot_air_balloons"));
+        assert_eq!(
+            "hot_air_balloons".parse::<CaptchaChallenge>(),
+            Ok(other.clone())
+        );
+        assert_eq!(other.to_string(), "hot_air_balloons");
+        assert_eq!(
+            serde_json::to_string(&other).ok().as_deref(),
+            Some(r#""hot_air_balloons""#)
+        );
+        assert!("Hot Air Balloons!".parse::<CaptchaChallenge>().is_err());
+        assert!(CaptchaChallenge::parse_lenient("hot_air_balloons").is_err());
+        assert_eq!(
+            "traffic_signs".parse::<CaptchaChallenge>(),
+            Ok(CaptchaChallenge::TrafficSigns)
+        );
+    }
+
+    #[test]
+    fn metadata() {
+        assert_eq!(
+            CaptchaChallenge::all().count(),
+            CaptchaChallenge::VARIANTS.len() - 1
+        );
+        assert!(CaptchaChallenge::all().all(|challenge| challenge.is_known()));
+        assert_eq!(
+            CaptchaChallenge::AFireHydrant.display_name(),
+            "Fire hydrant"
+        );
+        assert_eq!(
+            CaptchaChallenge::Other(String::from("hot_air_balloons")).display_name(),
+            "Hot air balloons"
+        );
+        assert_eq!(
+            CaptchaChallenge::TrafficLights.expected_grid(),
+            Some(GridSize::FourByFour)
+        );
+        assert_eq!(
+            CaptchaChallenge::Bridges.expected_grid(),
+            Some(GridSize::ThreeByThree)
+        );
+    }
+
+    #[test]
+    fn resolves_aliases() {
+        assert_eq!(
+            "fire hydrant".parse::<CaptchaChallenge>(),
+            Ok(CaptchaChallenge::AFireHydrant)
+        );
+        assert_eq!(
+            "Borne d'incendie".parse::<CaptchaChallenge>(),
+            Ok(CaptchaChallenge::AFireHydrant)
+        );
+        assert_eq!(
+            "zeppelins".parse::<CaptchaChallenge>(),
+            Ok(CaptchaChallenge::Other(String::from("zeppelins")))
+        );
+
+        register_alias("Zeppelins", CaptchaChallenge::Bus);
+        assert_eq!(
+            serde_json::from_str::<CaptchaChallenge>(r#""zeppelins""#).ok(),
+            Some(CaptchaChallenge::Bus)
+        );
+        assert_eq!(
+            serde_json::from_str::<CaptchaChallenge>(r#""traffic_lights""#).ok(),
+            Some(CaptchaChallenge::TrafficLights)
+        );
+    }
+}
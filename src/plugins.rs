@@ -0,0 +1,169 @@
+use crate::{errors, CaptchaChallenge, Prediction};
+use libloading::Library;
+use std::{borrow::Cow, collections::HashMap, fmt, path::Path};
+
+/// API_VERSION is bumped whenever ChallengeHandler, Plugins or PluginDeclaration change in a
+/// way that breaks plugins built against an earlier version
+pub const API_VERSION: u32 = 2;
+
+/// CORE_VERSION is the version of no_captcha a plugin was built against. Plugins share Rust
+/// types with the host, so they must be built with the same no_captcha and the same rustc
+pub const CORE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// ChallengeHandler predicts a challenge in place of a model directory, usually one the
+/// CaptchaChallenge enum doesn't know. Images reach it after the registry's input checks, as
+/// format::for_model leaves them
+pub trait ChallengeHandler: Send + Sync {
+    /// preprocess prepares images for predict_batch, passing them through unchanged by default
+    fn preprocess<'a>(&self, images: &'a [Vec<u8>]) -> errors::Result<Cow<'a, [Vec<u8>]>> {
+        Ok(Cow::Borrowed(images))
+    }
+
+    /// predict_batch returns one Prediction per image in the order they were given
+    fn predict_batch(&self, images: &[Vec<u8>]) -> errors::Result<Vec<Prediction>>;
+}
+
+/// PluginDeclaration is what a plugin library exports, through export_plugin!
+#[derive(Debug, Clone, Copy)]
+pub struct PluginDeclaration {
+    pub api_version: u32,
+    pub core_version: &'static str,
+    pub register: fn(&mut Plugins) -> errors::Result<()>,
+}
+
+/// export_plugin! declares a cdylib crate a no_captcha plugin. 'register' is given the host's
+/// Plugins to add its handlers to:
+///
+/// ```ignore
+/// fn register(plugins: &mut Plugins) -> errors::Result<()> {
+///     plugins.register("hot_air_balloons", BalloonHandler::load()?)
+/// }
+///
+/// no_captcha::export_plugin!(register);
+/// ```
+#[macro_export]
+macro_rules! export_plugin {
+    ($register:expr) => {
+        #[doc(hidden)]
+        #[no_mangle]
+        pub static nocap_plugin_declaration: $crate::plugins::PluginDeclaration =
+            $crate::plugins::PluginDeclaration {
+                api_version: $crate::plugins::API_VERSION,
+                core_version: $crate::plugins::CORE_VERSION,
+                register: $register,
+            };
+    };
+}
+
+/// Plugins holds the ChallengeHandlers registered for a registry (see
+/// RegistryBuilder::plugins), along with the libraries they were loaded from. A registry only
+/// asks a handler for challenges it has no model for
+#[derive(Default)]
+pub struct Plugins {
+    // fields drop in order, so handlers go before the libraries holding their code
+    handlers: HashMap<CaptchaChallenge, Box<dyn ChallengeHandler>>,
+    libraries: Vec<Library>,
+}
+
+impl Plugins {
+    pub fn new() -> Plugins {
+        Plugins::default()
+    }
+
+    /// register handles the challenge named 'challenge' with 'handler', replacing any handler
+    /// registered for it before. Any snake_case name is accepted (see CaptchaChallenge::Other)
+    pub fn register<H>(&mut self, challenge: &str, handler: H) -> errors::Result<()>
+    where
+        H: ChallengeHandler + 'static,
+    {
+        let _ = self.handlers.insert(challenge.parse()?, Box::new(handler));
+        Ok(())
+    }
+
+    /// handler returns the handler registered for 'challenge', if any
+    pub fn handler(&self, challenge: &CaptchaChallenge) -> Option<&dyn ChallengeHandler> {
+        self.handlers.get(challenge).map(|handler| &**handler)
+    }
+
+    /// load loads the plugin library at 'path' and lets it register its handlers. Plugins
+    /// declaring another API_VERSION or CORE_VERSION are refused
+    ///
+    /// # Safety
+    ///
+    /// Loading a library runs its initialisers, and nothing checks that it exports a real
+    /// PluginDeclaration or was built with the host's rustc. Only load plugins you trust
+    pub unsafe fn load(&mut self, path: &Path) -> errors::Result<()> {
+        let library = Library::new(path)?;
+        let declaration =
+            **library.get::<*const PluginDeclaration>(b"nocap_plugin_declaration\0")?;
+        if declaration.api_version != API_VERSION || declaration.core_version != CORE_VERSION {
+            return Err(errors::Error::IncompatiblePlugin(
+                path.to_path_buf(),
+                format!(
+                    "built for API {} of no_captcha {}, this is API {} of no_captcha {}",
+                    declaration.api_version, declaration.core_version, API_VERSION, CORE_VERSION
+                ),
+            ));
+        }
+        // kept before registering, so the handlers never outlive their code
+        self.libraries.push(library);
+        (declaration.register)(self)
+    }
+
+    /// load_dir loads every library in 'dir' with the platform's extension (.so, .dylib or
+    /// .dll), in name order
+    ///
+    /// # Safety
+    ///
+    /// As for load, every library in 'dir' must be a trusted plugin
+    pub unsafe fn load_dir(&mut self, dir: &Path) -> errors::Result<()> {
+        let mut paths = Vec::new();
+        for entry in dir.read_dir()? {
+            let path = entry?.path();
+            if path.extension() == Some(std::env::consts::DLL_EXTENSION.as_ref()) {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        for path in paths {
+            self.load(&path)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Plugins {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Plugins")
+            .field("handlers", &self.handlers.keys().collect::<Vec<_>>())
+            .field("libraries", &self.libraries.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Constant(Prediction);
+
+    impl ChallengeHandler for Constant {
+        fn predict_batch(&self, images: &[Vec<u8>]) -> errors::Result<Vec<Prediction>> {
+            Ok(vec![self.0; images.len()])
+        }
+    }
+
+    #[test]
+    fn registers_unknown_challenges() -> errors::Result<()> {
+        let mut plugins = Plugins::new();
+        plugins.register("hot_air_balloons", Constant(Prediction::new(0.9, 0.1)))?;
+        let balloons = CaptchaChallenge::Other(String::from("hot_air_balloons"));
+        let handler = plugins
+            .handler(&balloons)
+            .ok_or(errors::Error::ModelLoad(balloons))?;
+        assert_eq!(handler.predict_batch(&[Vec::new(), Vec::new()])?.len(), 2);
+        assert!(plugins.handler(&CaptchaChallenge::Bus).is_none());
+        assert!(unsafe { plugins.load(Path::new("missing_plugin.so")) }.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,232 @@
+use crate::{errors, CaptchaChallenge, Prediction};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// CSV_HEADER is the first line of every CSV prediction log, naming LogRecord's fields
+const CSV_HEADER: &str = "timestamp_ms,challenge,image_hash,affirmative,negative,latency_us\n";
+
+/// LogFormat is how a PredictionLog writes its records
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Jsonl writes each LogRecord as a JSON object on its own line
+    #[default]
+    Jsonl,
+    /// Csv writes each LogRecord as a row under CSV_HEADER
+    Csv,
+}
+
+/// Rotation bounds the disk a PredictionLog uses. Once the log reaches max_bytes it is renamed
+/// to <path>.1, older logs shift up one number, and the log numbered max_files is deleted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct Rotation {
+    pub max_bytes: u64,
+    pub max_files: usize,
+}
+
+impl Default for Rotation {
+    fn default() -> Rotation {
+        Rotation {
+            max_bytes: 64 * 1024 * 1024,
+            max_files: 5,
+        }
+    }
+}
+
+/// PredictionLogConfig configures a PredictionLog
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct PredictionLogConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub format: LogFormat,
+    #[serde(default)]
+    pub rotation: Rotation,
+}
+
+/// LogRecord is one inference. image_hash is the image_hash of the image as it was given, so
+/// records can be joined with harvest and feedback data
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LogRecord {
+    pub timestamp_ms: u64,
+    pub challenge: CaptchaChallenge,
+    pub image_hash: String,
+    pub affirmative: f32,
+    pub negative: f32,
+    /// latency_us is how long the batch the image was predicted in took, in microseconds
+    pub latency_us: u64,
+}
+
+impl LogRecord {
+    pub fn new(
+        challenge: &CaptchaChallenge,
+        image_hash: String,
+        prediction: &Prediction,
+        latency: Duration,
+    ) -> LogRecord {
+        LogRecord {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            challenge: challenge.clone(),
+            image_hash,
+            affirmative: prediction.affirmative_confidence(),
+            negative: prediction.negative_confidence(),
+            latency_us: latency.as_micros() as u64,
+        }
+    }
+
+    fn to_line(&self, format: LogFormat) -> errors::Result<String> {
+        Ok(match format {
+            LogFormat::Jsonl => serde_json::to_string(self)? + "\n",
+            // challenge names are snake_case and hashes hex, so no field needs quoting
+            LogFormat::Csv => format!(
+                "{},{},{},{},{},{}\n",
+                self.timestamp_ms,
+                self.challenge,
+                self.image_hash,
+                self.affirmative,
+                self.negative,
+                self.latency_us
+            ),
+        })
+    }
+}
+
+/// PredictionLog appends a LogRecord per inference to a file (see
+/// RegistryBuilder::prediction_log), rotating it as configured. Records are written as they
+/// are made, one line at a time, so a crash loses at most the line being written
+#[derive(Debug)]
+pub struct PredictionLog {
+    config: PredictionLogConfig,
+    file: Mutex<Option<(File, u64)>>,
+}
+
+impl PredictionLog {
+    pub fn new(config: PredictionLogConfig) -> PredictionLog {
+        PredictionLog {
+            config,
+            file: Mutex::new(None),
+        }
+    }
+
+    /// record appends 'records' to the log, rotating it first if they would take it past
+    /// Rotation::max_bytes
+    pub fn record(&self, records: &[LogRecord]) -> errors::Result<()> {
+        let mut lines = String::new();
+        for record in records {
+            lines.push_str(&record.to_line(self.config.format)?);
+        }
+        let mut file = self.file.lock()?;
+        if let Some((_, written)) = &*file {
+            if *written > 0 && written + lines.len() as u64 > self.config.rotation.max_bytes {
+                *file = None;
+                self.rotate()?;
+            }
+        }
+        if file.is_none() {
+            *file = Some(self.open()?);
+        }
+        if let Some((file, written)) = &mut *file {
+            file.write_all(lines.as_bytes())?;
+            *written += lines.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// open opens the log for appending, writing the CSV header if it is new
+    fn open(&self) -> errors::Result<(File, u64)> {
+        let path = &self.config.path;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut written = file.metadata()?.len();
+        if written == 0 && self.config.format == LogFormat::Csv {
+            file.write_all(CSV_HEADER.as_bytes())?;
+            written = CSV_HEADER.len() as u64;
+        }
+        Ok((file, written))
+    }
+
+    fn rotate(&self) -> errors::Result<()> {
+        let max_files = self.config.rotation.max_files;
+        let _ = fs::remove_file(self.rotated(max_files));
+        for number in (1..max_files).rev() {
+            // gaps left by a smaller max_files or a manual cleanup are skipped over
+            let _ = fs::rename(self.rotated(number), self.rotated(number + 1));
+        }
+        if max_files == 0 {
+            fs::remove_file(&self.config.path)?;
+        } else {
+            fs::rename(&self.config.path, self.rotated(1))?;
+        }
+        Ok(())
+    }
+
+    /// rotated is the path of the log rotated 'number' times, <path>.<number>
+    fn rotated(&self, number: usize) -> PathBuf {
+        let mut path = self.config.path.clone().into_os_string();
+        path.push(format!(".{}", number));
+        PathBuf::from(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_csv_and_rotates() -> errors::Result<()> {
+        let dir = std::env::temp_dir().join(format!("nocap-prediction-log-{}", std::process::id()));
+        let log = PredictionLog::new(PredictionLogConfig {
+            path: dir.join("predictions.csv"),
+            format: LogFormat::Csv,
+            rotation: Rotation {
+                max_bytes: 200,
+                max_files: 1,
+            },
+        });
+        let record = LogRecord::new(
+            &CaptchaChallenge::Bus,
+            String::from("ab12"),
+            &Prediction::new(0.75, 0.25),
+            Duration::from_micros(1500),
+        );
+        log.record(&[record.clone(), record.clone()])?;
+        let contents = fs::read_to_string(dir.join("predictions.csv"))?;
+        assert!(contents.starts_with(CSV_HEADER));
+        assert!(contents.ends_with(",bus,ab12,0.75,0.25,1500\n"));
+        assert_eq!(contents.lines().count(), 3);
+
+        log.record(&[record.clone(), record.clone()])?;
+        log.record(&[record])?;
+        assert!(dir.join("predictions.csv.1").exists());
+        assert!(!dir.join("predictions.csv.2").exists());
+        assert!(fs::read_to_string(dir.join("predictions.csv"))?.starts_with(CSV_HEADER));
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn writes_json_lines() -> errors::Result<()> {
+        let record = LogRecord::new(
+            &CaptchaChallenge::TrafficLights,
+            String::from("ab12"),
+            &Prediction::new(0.5, 0.5),
+            Duration::from_millis(2),
+        );
+        let line = record.to_line(LogFormat::Jsonl)?;
+        let json: serde_json::Value = serde_json::from_str(&line)?;
+        assert_eq!(json["challenge"], "traffic_lights");
+        assert_eq!(json["latency_us"], 2000);
+        Ok(())
+    }
+}
@@ -0,0 +1,278 @@
+use crate::{errors, image_hash, scheduling::Priority, CaptchaChallenge, Prediction};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+/// Predictor is anything that can score images against a challenge
+pub trait Predictor: Send + Sync {
+    /// predict_batch returns one Prediction per image in the order they were given
+    fn predict_batch(
+        &self,
+        challenge: &CaptchaChallenge,
+        images: Vec<Vec<u8>>,
+    ) -> errors::Result<Vec<Prediction>>;
+
+    /// predict_batch_prioritized is predict_batch at 'priority', for predictors whose callers
+    /// compete for the same models. Others ignore the priority, as this default does
+    fn predict_batch_prioritized(
+        &self,
+        challenge: &CaptchaChallenge,
+        images: Vec<Vec<u8>>,
+        _priority: Priority,
+    ) -> errors::Result<Vec<Prediction>> {
+        self.predict_batch(challenge, images)
+    }
+
+    fn predict(&self, challenge: &CaptchaChallenge, image: Vec<u8>) -> errors::Result<Prediction> {
+        self.predict_batch(challenge, vec![image])?
+            .pop()
+            .ok_or(errors::Error::MalformedOutput)
+    }
+
+    /// predict_with predicts 'images' in batches of options.chunk_size, checking the deadline
+    /// and cancellation token before each one. Work already running isn't interrupted, so a
+    /// smaller chunk_size gives up sooner at the cost of smaller batches
+    fn predict_with(
+        &self,
+        challenge: &CaptchaChallenge,
+        images: Vec<Vec<u8>>,
+        options: &PredictOptions,
+    ) -> errors::Result<Vec<Prediction>> {
+        let mut predictions = Vec::with_capacity(images.len());
+        for chunk in images.chunks(options.chunk_size.max(1)) {
+            options.check()?;
+            predictions.extend(self.predict_batch_prioritized(
+                challenge,
+                chunk.to_vec(),
+                options.priority,
+            )?);
+        }
+        Ok(predictions)
+    }
+}
+
+/// CancellationToken lets one thread abandon predictions another is making, e.g. once the
+/// captcha they were for has expired. Clones share the same state
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// PredictOptions bounds the work Predictor::predict_with may do
+#[derive(Debug, Clone)]
+pub struct PredictOptions {
+    /// deadline fails predictions with Error::DeadlineExceeded once it has passed
+    pub deadline: Option<Instant>,
+    /// cancellation fails predictions with Error::Cancelled once it is cancelled
+    pub cancellation: Option<CancellationToken>,
+    /// chunk_size is how many images are predicted between checks, 1 by default so a grid is
+    /// abandoned between tiles
+    pub chunk_size: usize,
+    /// priority is Interactive by default; background work should pass Batch
+    pub priority: Priority,
+}
+
+impl Default for PredictOptions {
+    fn default() -> PredictOptions {
+        PredictOptions {
+            deadline: None,
+            cancellation: None,
+            chunk_size: 1,
+            priority: Priority::default(),
+        }
+    }
+}
+
+impl PredictOptions {
+    /// check fails if the deadline has passed or the token was cancelled
+    pub fn check(&self) -> errors::Result<()> {
+        if let Some(token) = &self.cancellation {
+            if token.is_cancelled() {
+                return Err(errors::Error::Cancelled);
+            }
+        }
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(errors::Error::DeadlineExceeded),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// MockPredictor answers from a script instead of a model, so code built on a Predictor can be
+/// tested without libtensorflow or any models. Images are matched first by content, then by
+/// challenge. Anything unscripted gets a prediction derived from the image's hash, which is
+/// arbitrary but stable across runs
+#[derive(Debug, Default)]
+pub struct MockPredictor {
+    by_image: HashMap<String, Prediction>,
+    by_challenge: HashMap<CaptchaChallenge, Prediction>,
+    failing: HashSet<CaptchaChallenge>,
+    calls: Mutex<Vec<(CaptchaChallenge, usize)>>,
+}
+
+impl MockPredictor {
+    pub fn new() -> MockPredictor {
+        MockPredictor::default()
+    }
+
+    /// with_image answers 'prediction' whenever 'image' is predicted, whatever the challenge
+    pub fn with_image<B>(mut self, image: B, prediction: Prediction) -> MockPredictor
+    where
+        B: AsRef<[u8]>,
+    {
+        let _ = self.by_image.insert(image_hash(image), prediction);
+        self
+    }
+
+    /// with_challenge answers 'prediction' for every image predicted against 'challenge'
+    pub fn with_challenge(
+        mut self,
+        challenge: CaptchaChallenge,
+        prediction: Prediction,
+    ) -> MockPredictor {
+        let _ = self.by_challenge.insert(challenge, prediction);
+        self
+    }
+
+    /// failing makes every prediction against 'challenge' fail as if it had no model
+    pub fn failing(mut self, challenge: CaptchaChallenge) -> MockPredictor {
+        let _ = self.failing.insert(challenge);
+        self
+    }
+
+    /// calls lists every batch predicted so far as (challenge, number of images)
+    pub fn calls(&self) -> Vec<(CaptchaChallenge, usize)> {
+        self.calls
+            .lock()
+            .map(|calls| calls.clone())
+            .unwrap_or_default()
+    }
+
+    fn answer(&self, challenge: &CaptchaChallenge, image: &[u8]) -> Prediction {
+        let hash = image_hash(image);
+        if let Some(prediction) = self.by_image.get(&hash) {
+            return *prediction;
+        }
+        if let Some(prediction) = self.by_challenge.get(challenge) {
+            return *prediction;
+        }
+        let affirmative_confidence = u8::from_str_radix(&hash[..2], 16).unwrap_or(0) as f32 / 255.0;
+        Prediction::new(affirmative_confidence, 1.0 - affirmative_confidence)
+    }
+}
+
+impl Predictor for MockPredictor {
+    fn predict_batch(
+        &self,
+        challenge: &CaptchaChallenge,
+        images: Vec<Vec<u8>>,
+    ) -> errors::Result<Vec<Prediction>> {
+        self.calls.lock()?.push((challenge.clone(), images.len()));
+        if self.failing.contains(challenge) {
+            return Err(errors::Error::ModelLoad(challenge.clone()));
+        }
+        Ok(images
+            .iter()
+            .map(|image| self.answer(challenge, image))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripted_answers() -> errors::Result<()> {
+        let yes = Prediction::new(0.9, 0.1);
+        let no = Prediction::new(0.2, 0.8);
+        let mock = MockPredictor::new()
+            .with_image("bus tile", yes)
+            .with_challenge(CaptchaChallenge::Bus, no)
+            .failing(CaptchaChallenge::Taxis);
+
+        let predictions = mock.predict_batch(
+            &CaptchaChallenge::Bus,
+            vec![b"bus tile".to_vec(), b"sky tile".to_vec()],
+        )?;
+        assert!(predictions[0].is_mainly_affirmative());
+        assert!(!predictions[1].is_mainly_affirmative());
+        assert!(mock
+            .predict(&CaptchaChallenge::Taxis, b"taxi tile".to_vec())
+            .is_err());
+        assert_eq!(
+            mock.calls(),
+            vec![(CaptchaChallenge::Bus, 2), (CaptchaChallenge::Taxis, 1)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn predict_with_stops_between_chunks() -> errors::Result<()> {
+        let mock = MockPredictor::new();
+        let images = vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()];
+        let options = PredictOptions {
+            chunk_size: 2,
+            ..PredictOptions::default()
+        };
+        assert_eq!(
+            mock.predict_with(&CaptchaChallenge::Bus, images.clone(), &options)?
+                .len(),
+            3
+        );
+        assert_eq!(
+            mock.calls(),
+            vec![(CaptchaChallenge::Bus, 2), (CaptchaChallenge::Bus, 1)]
+        );
+
+        let token = CancellationToken::new();
+        token.cancel();
+        let cancelled = PredictOptions {
+            cancellation: Some(token),
+            ..PredictOptions::default()
+        };
+        assert!(matches!(
+            mock.predict_with(&CaptchaChallenge::Bus, images.clone(), &cancelled),
+            Err(errors::Error::Cancelled)
+        ));
+        let expired = PredictOptions {
+            deadline: Some(Instant::now()),
+            ..PredictOptions::default()
+        };
+        assert!(matches!(
+            mock.predict_with(&CaptchaChallenge::Bus, images, &expired),
+            Err(errors::Error::DeadlineExceeded)
+        ));
+        assert_eq!(mock.calls().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn unscripted_answers_are_stable() -> errors::Result<()> {
+        let mock = MockPredictor::new();
+        let first = mock.predict(&CaptchaChallenge::Cars, b"tile".to_vec())?;
+        let second = mock.predict(&CaptchaChallenge::Cars, b"tile".to_vec())?;
+        assert_eq!(
+            first.affirmative_confidence(),
+            second.affirmative_confidence()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,280 @@
+use crate::{errors, CaptchaChallenge};
+use std::{collections::HashMap, sync::OnceLock};
+
+/// BUILTIN_LOCALES are the phrase tables shipped with the crate, one JSON file per language
+/// mapping each challenge to the singular and plural wordings reCAPTCHA uses for it
+pub(crate) static BUILTIN_LOCALES: &[(&str, &str)] = &[
+    ("en", include_str!("../locales/en.json")),
+    ("es", include_str!("../locales/es.json")),
+    ("fr", include_str!("../locales/fr.json")),
+    ("de", include_str!("../locales/de.json")),
+    ("pt", include_str!("../locales/pt.json")),
+    ("ru", include_str!("../locales/ru.json")),
+    ("ja", include_str!("../locales/ja.json")),
+];
+
+/// Locale is a named table of phrases that identify challenges in instruction text
+#[derive(Debug, Clone)]
+pub struct Locale {
+    name: String,
+    phrases: Vec<(Vec<String>, CaptchaChallenge)>,
+}
+
+impl Locale {
+    pub fn new<S>(name: S) -> Locale
+    where
+        S: Into<String>,
+    {
+        Locale {
+            name: name.into(),
+            phrases: Vec::new(),
+        }
+    }
+
+    /// builtin returns one of the embedded locales by language code ("en", "es", "fr", "de",
+    /// "pt", "ru", "ja")
+    pub fn builtin(name: &str) -> Option<Locale> {
+        BUILTIN_LOCALES
+            .iter()
+            .find(|(code, _)| *code == name)
+            .and_then(|(code, json)| Locale::from_json(*code, json).ok())
+    }
+
+    /// from_json reads a locale in the same format as the embedded tables, e.g.
+    /// `{"bus": ["autobús", "autobuses"]}`
+    pub fn from_json<S>(name: S, json: &str) -> errors::Result<Locale>
+    where
+        S: Into<String>,
+    {
+        let table: HashMap<CaptchaChallenge, Vec<String>> = serde_json::from_str(json)?;
+        let mut locale = Locale::new(name);
+        for (challenge, phrases) in table {
+            for phrase in phrases {
+                let _ = locale.add_phrase(challenge.clone(), phrase);
+            }
+        }
+        Ok(locale)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn add_phrase<S>(&mut self, challenge: CaptchaChallenge, phrase: S) -> &mut Locale
+    where
+        S: AsRef<str>,
+    {
+        let words = tokenize(phrase.as_ref());
+        if !words.is_empty() {
+            self.phrases.push((words, challenge));
+        }
+        self
+    }
+
+    pub fn parse<S>(&self, prompt: S) -> Option<CaptchaChallenge>
+    where
+        S: AsRef<str>,
+    {
+        best_match(&tokenize(prompt.as_ref()), &self.phrases).map(|(_, _, challenge)| challenge)
+    }
+}
+
+/// PromptParser resolves instruction text against several locales at once, so callers don't
+/// need to know which language the challenge page was served in
+#[derive(Debug, Clone)]
+pub struct PromptParser {
+    locales: Vec<Locale>,
+}
+
+impl Default for PromptParser {
+    /// default includes every embedded locale
+    fn default() -> PromptParser {
+        PromptParser {
+            locales: BUILTIN_LOCALES
+                .iter()
+                .filter_map(|(code, _)| Locale::builtin(code))
+                .collect(),
+        }
+    }
+}
+
+impl PromptParser {
+    /// empty creates a parser without any locales, for callers who only want their own tables
+    pub fn empty() -> PromptParser {
+        PromptParser {
+            locales: Vec::new(),
+        }
+    }
+
+    /// add_locale registers 'locale', merging it into an existing locale of the same name
+    pub fn add_locale(&mut self, locale: Locale) -> &mut PromptParser {
+        match self.locales.iter_mut().find(|l| l.name == locale.name) {
+            Some(existing) => existing.phrases.extend(locale.phrases),
+            None => self.locales.push(locale),
+        }
+        self
+    }
+
+    pub fn locale(&self, name: &str) -> Option<&Locale> {
+        self.locales.iter().find(|l| l.name == name)
+    }
+
+    /// parse finds the challenge named by a prompt in any registered locale. When several
+    /// phrases appear, the longest one wins so "traffic lights" is never mistaken for something
+    /// shorter
+    pub fn parse<S>(&self, prompt: S) -> Option<CaptchaChallenge>
+    where
+        S: AsRef<str>,
+    {
+        let words = tokenize(prompt.as_ref());
+        self.locales
+            .iter()
+            .filter_map(|locale| best_match(&words, &locale.phrases))
+            .min_by_key(|&(length, position, _)| (std::cmp::Reverse(length), position))
+            .map(|(_, _, challenge)| challenge)
+    }
+}
+
+/// parse finds the challenge named by a reCAPTCHA instruction such as "Select all images with
+/// crosswalks" or "Selecciona todas las imágenes que contengan semáforos", using every embedded
+/// locale
+pub fn parse<S>(prompt: S) -> Option<CaptchaChallenge>
+where
+    S: AsRef<str>,
+{
+    static DEFAULT: OnceLock<PromptParser> = OnceLock::new();
+    DEFAULT.get_or_init(PromptParser::default).parse(prompt)
+}
+
+/// best_match returns the (length, position, challenge) of the longest phrase found in 'words',
+/// preferring the earliest one on ties
+fn best_match(
+    words: &[String],
+    phrases: &[(Vec<String>, CaptchaChallenge)],
+) -> Option<(usize, usize, CaptchaChallenge)> {
+    phrases
+        .iter()
+        .filter_map(|(phrase, challenge)| {
+            let position = words
+                .windows(phrase.len())
+                .position(|window| window == phrase.as_slice())?;
+            Some((phrase.len(), position, challenge))
+        })
+        .min_by_key(|&(length, position, _)| (std::cmp::Reverse(length), position))
+        .map(|(length, position, challenge)| (length, position, challenge.clone()))
+}
+
+/// tokenize lowercases text and splits it into words, treating punctuation as a separator
+/// ("traffic-lights." -> ["traffic", "lights"]). Japanese is written without spaces, so every
+/// kana and kanji becomes a word of its own and phrases match as character sequences
+pub(crate) fn tokenize(text: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    for c in text.to_lowercase().chars() {
+        if is_cjk(c) {
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            words.push(c.to_string());
+        } else if c.is_alphanumeric() {
+            word.push(c);
+        } else if !word.is_empty() {
+            words.push(std::mem::take(&mut word));
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30ff}' // hiragana and katakana
+        | '\u{3400}'..='\u{4dbf}' // CJK extension A
+        | '\u{4e00}'..='\u{9fff}' // CJK unified ideographs
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_common_prompts() {
+        assert_eq!(
+            parse("Select all images with crosswalks"),
+            Some(CaptchaChallenge::Crosswalks)
+        );
+        assert_eq!(
+            parse("Select all squares with a fire hydrant"),
+            Some(CaptchaChallenge::AFireHydrant)
+        );
+        assert_eq!(
+            parse("Select all images with\nTraffic Lights\nClick verify once there are none left."),
+            Some(CaptchaChallenge::TrafficLights)
+        );
+        assert_eq!(
+            parse("Select all squares with motorcycles. If there are none, click skip"),
+            Some(CaptchaChallenge::Motorcycles)
+        );
+        assert_eq!(
+            parse("Select all images with mountains or hills"),
+            Some(CaptchaChallenge::MountainsOrHills)
+        );
+        assert_eq!(
+            parse("Select all images with chimneys"),
+            Some(CaptchaChallenge::Chimneys)
+        );
+    }
+
+    #[test]
+    fn parses_localized_prompts() {
+        assert_eq!(
+            parse("Selecciona todas las imágenes que contengan pasos de peatones"),
+            Some(CaptchaChallenge::Crosswalks)
+        );
+        assert_eq!(
+            parse("Sélectionnez toutes les images contenant une bouche d'incendie"),
+            Some(CaptchaChallenge::AFireHydrant)
+        );
+        assert_eq!(
+            parse("Wählen Sie alle Bilder mit Ampeln aus"),
+            Some(CaptchaChallenge::TrafficLights)
+        );
+        assert_eq!(
+            parse("Selecione todas as imagens com ônibus"),
+            Some(CaptchaChallenge::Bus)
+        );
+        assert_eq!(
+            parse("Выберите все изображения, где есть велосипеды"),
+            Some(CaptchaChallenge::Bicycles)
+        );
+        assert_eq!(
+            parse("自転車の画像をすべて選択してください"),
+            Some(CaptchaChallenge::Bicycles)
+        );
+        assert_eq!(
+            parse("横断歩道のある画像をすべて選択してください"),
+            Some(CaptchaChallenge::Crosswalks)
+        );
+    }
+
+    #[test]
+    fn custom_locales() -> errors::Result<()> {
+        let mut parser = PromptParser::empty();
+        let _ = parser.add_locale(Locale::from_json("nl", r#"{"bus": ["bussen"]}"#)?);
+        assert_eq!(
+            parser.parse("Selecteer alle afbeeldingen met bussen"),
+            Some(CaptchaChallenge::Bus)
+        );
+        assert_eq!(parser.parse("Select all images with buses"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_unknown_prompts() {
+        assert_eq!(parse("Select all images with submarines"), None);
+        assert_eq!(parse(""), None);
+    }
+}
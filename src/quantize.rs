@@ -0,0 +1,234 @@
+use crate::{
+    dataset::{self, LabeledImage},
+    errors, export, format, CaptchaChallenge, CaptchaModel, Prediction,
+};
+use std::{
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// TFLITE_CONVERT converts the SavedModel in argv[1] into a TFLite model at argv[2] with the
+/// precision named in argv[3]. Ops TFLite lacks, like image decoding, fall back to TF ops
+const TFLITE_CONVERT: &str = r#"
+import sys
+import tensorflow as tf
+
+converter = tf.lite.TFLiteConverter.from_saved_model(sys.argv[1])
+converter.target_spec.supported_ops = [
+    tf.lite.OpsSet.TFLITE_BUILTINS,
+    tf.lite.OpsSet.SELECT_TF_OPS,
+]
+converter.optimizations = [tf.lite.Optimize.DEFAULT]
+if sys.argv[3] == "fp16":
+    converter.target_spec.supported_types = [tf.float16]
+open(sys.argv[2], "wb").write(converter.convert())
+"#;
+
+/// TFLITE_SCORES runs the TFLite model in argv[1] over the images in argv[2:] and prints the
+/// scores as JSON, one [affirmative, negative] pair per image
+const TFLITE_SCORES: &str = r#"
+import json, sys
+import numpy as np
+import tensorflow as tf
+
+interpreter = tf.lite.Interpreter(model_path=sys.argv[1])
+runner = interpreter.get_signature_runner()
+input_name = next(iter(runner.get_input_details()))
+scores = []
+for path in sys.argv[2:]:
+    outputs = runner(**{input_name: np.array([open(path, "rb").read()], dtype=object)})
+    output = outputs.get("scores", next(iter(outputs.values())))
+    scores.append(np.asarray(output, dtype=float).reshape(-1).tolist())
+print(json.dumps(scores))
+"#;
+
+/// EVAL_BATCH_SIZE bounds how many test images the original model predicts at once
+const EVAL_BATCH_SIZE: usize = 64;
+
+/// Precision is a reduced precision a model can be quantized to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precision {
+    /// Float16 halves the weights' size with little accuracy cost
+    Float16,
+    /// Int8 quantizes the weights to 8 bit integers (dynamic range quantization)
+    Int8,
+}
+
+impl Precision {
+    fn name(self) -> &'static str {
+        match self {
+            Precision::Float16 => "fp16",
+            Precision::Int8 => "int8",
+        }
+    }
+}
+
+/// Quantization configures quantize. Quantizing produces TFLite models through 'python',
+/// which must have tensorflow installed
+#[derive(Debug, Clone)]
+pub struct Quantization {
+    pub python: PathBuf,
+    pub precisions: Vec<Precision>,
+    /// test_data is a dataset laid out like test_data/ to measure the accuracy cost on.
+    /// Without it, models are quantized but not evaluated
+    pub test_data: Option<PathBuf>,
+}
+
+impl Default for Quantization {
+    fn default() -> Quantization {
+        Quantization {
+            python: PathBuf::from("python3"),
+            precisions: vec![Precision::Float16, Precision::Int8],
+            test_data: None,
+        }
+    }
+}
+
+/// QuantizedModel is one variant written by quantize
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuantizedModel {
+    pub challenge: CaptchaChallenge,
+    pub precision: Precision,
+    pub path: PathBuf,
+    pub size: u64,
+    pub original_size: u64,
+    /// accuracy and original_accuracy are the fractions of the challenge's test images the
+    /// variant and the original SavedModel got right, when evaluated
+    pub accuracy: Option<f64>,
+    pub original_accuracy: Option<f64>,
+}
+
+impl QuantizedModel {
+    /// accuracy_delta is how much accuracy quantizing cost (negative) or gained (positive)
+    pub fn accuracy_delta(&self) -> Option<f64> {
+        Some(self.accuracy? - self.original_accuracy?)
+    }
+}
+
+/// quantize writes '<out_dir>/<name>.<precision>.tflite' for every model under 'models_dir'
+/// and precision requested, named the same way export::export_tf_serving names them
+pub fn quantize<P, Q>(
+    models_dir: P,
+    out_dir: Q,
+    quantization: &Quantization,
+) -> errors::Result<Vec<QuantizedModel>>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let out_dir = out_dir.as_ref();
+    fs::create_dir_all(out_dir)?;
+    let mut quantized = Vec::new();
+    for (challenge, name, member) in export::named_models(models_dir.as_ref())? {
+        let original_size = dir_size(&member)?;
+        let test_images = match &quantization.test_data {
+            Some(test_data) => dataset::labeled_images(test_data, &challenge)?,
+            None => Vec::new(),
+        };
+        let original_accuracy = if test_images.is_empty() {
+            None
+        } else {
+            Some(original_accuracy(&member, &test_images)?)
+        };
+
+        for &precision in &quantization.precisions {
+            let path = out_dir.join(format!("{}.{}.tflite", name, precision.name()));
+            run_python(
+                &quantization.python,
+                TFLITE_CONVERT,
+                &[
+                    member.as_os_str(),
+                    path.as_os_str(),
+                    OsStr::new(precision.name()),
+                ],
+            )?;
+            let accuracy = if test_images.is_empty() {
+                None
+            } else {
+                Some(tflite_accuracy(&quantization.python, &path, &test_images)?)
+            };
+            quantized.push(QuantizedModel {
+                challenge: challenge.clone(),
+                precision,
+                size: fs::metadata(&path)?.len(),
+                path,
+                original_size,
+                accuracy,
+                original_accuracy,
+            });
+        }
+    }
+    Ok(quantized)
+}
+
+fn original_accuracy(saved_model: &Path, test_images: &[LabeledImage]) -> errors::Result<f64> {
+    let model = CaptchaModel::load(saved_model)?;
+    let mut predictions = Vec::with_capacity(test_images.len());
+    for batch in test_images.chunks(EVAL_BATCH_SIZE) {
+        let mut images = Vec::with_capacity(batch.len());
+        for image in batch {
+            images.push(dataset::read_image(&image.path)?);
+        }
+        predictions.extend(model.run(&format::for_model(&images)?)?);
+    }
+    Ok(accuracy(test_images, &predictions))
+}
+
+fn tflite_accuracy(
+    python: &Path,
+    tflite: &Path,
+    test_images: &[LabeledImage],
+) -> errors::Result<f64> {
+    let mut args = vec![tflite.as_os_str()];
+    args.extend(test_images.iter().map(|image| image.path.as_os_str()));
+    let stdout = run_python(python, TFLITE_SCORES, &args)?;
+    let scores: Vec<Vec<f32>> = serde_json::from_slice(&stdout)?;
+    if scores.len() != test_images.len() || scores.iter().any(|pair| pair.len() != 2) {
+        return Err(errors::Error::MalformedOutput);
+    }
+    let predictions: Vec<Prediction> = scores
+        .iter()
+        .map(|pair| Prediction::new(pair[0], pair[1]))
+        .collect();
+    Ok(accuracy(test_images, &predictions))
+}
+
+fn accuracy(test_images: &[LabeledImage], predictions: &[Prediction]) -> f64 {
+    let correct = test_images
+        .iter()
+        .zip(predictions)
+        .filter(|(image, prediction)| prediction.is_mainly_affirmative() == image.matches)
+        .count();
+    correct as f64 / test_images.len().max(1) as f64
+}
+
+fn run_python(python: &Path, script: &str, args: &[&OsStr]) -> errors::Result<Vec<u8>> {
+    let result = Command::new(python)
+        .arg("-c")
+        .arg(script)
+        .args(args)
+        .output()?;
+    if !result.status.success() {
+        return Err(errors::Error::ConversionFailed(format!(
+            "{} failed: {}",
+            python.display(),
+            String::from_utf8_lossy(&result.stderr).trim()
+        )));
+    }
+    Ok(result.stdout)
+}
+
+fn dir_size(dir: &Path) -> errors::Result<u64> {
+    let mut size = 0;
+    for entry in dir.read_dir()? {
+        let entry = entry?;
+        size += if entry.file_type()?.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            entry.metadata()?.len()
+        };
+    }
+    Ok(size)
+}
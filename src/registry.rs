@@ -0,0 +1,1196 @@
+#[cfg(feature = "ann")]
+use crate::gallery;
+use crate::{
+    archive, augment, concurrency, deployment, devices, discovery, embedded, ensemble, errors,
+    explain, feedback, format,
+    graph_cache::{GraphCache, GraphCacheStats},
+    hooks, image_hash, integrity, loading, prediction_log, resize, retry, review, sanitize,
+    scheduling::{Priority, PriorityMutex},
+    self_test, CaptchaChallenge, Prediction, Predictor,
+};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+use tensorflow::{Graph, Session, Tensor};
+
+/// LogLevel is the least severe TensorFlow log message printed, set through
+/// RegistryBuilder::tensorflow_log_level
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Info,
+    Warning,
+    Error,
+    Fatal,
+}
+
+impl LogLevel {
+    /// apply sets TF_CPP_MIN_LOG_LEVEL, which TensorFlow reads for the whole process
+    fn apply(self) {
+        let level = match self {
+            LogLevel::Info => "0",
+            LogLevel::Warning => "1",
+            LogLevel::Error => "2",
+            LogLevel::Fatal => "3",
+        };
+        std::env::set_var("TF_CPP_MIN_LOG_LEVEL", level);
+    }
+}
+
+/// SharedModel employs a mutex around Session because running sessions performs interior
+/// mutability. The mutex serves Interactive predictions before Batch ones (see
+/// scheduling::Priority). Models are reference counted so unchanged ones can be shared across
+/// reloads
+type SharedModel = Arc<PriorityMutex<ModelSlot>>;
+
+/// SavedModelMap maps each challenge to the members of its ensemble, which is a single model
+/// unless the challenge's directory holds several model directories
+type SavedModelMap = HashMap<CaptchaChallenge, Vec<SharedModel>>;
+
+#[derive(Debug)]
+pub struct CaptchaModel {
+    pub(crate) session: Session,
+    pub(crate) graph: Graph,
+    /// path is the SavedModel directory this model was loaded from
+    path: PathBuf,
+    /// input_size is the resolution inputs are resized to before running, when known
+    input_size: Option<resize::InputSize>,
+    resize: resize::ResizeOptions,
+}
+
+impl CaptchaModel {
+    /// load restores the SavedModel in 'dir'
+    pub(crate) fn load<P>(dir: P) -> errors::Result<CaptchaModel>
+    where
+        P: AsRef<std::path::Path>,
+    {
+        let mut graph = Graph::new();
+        let session = Session::from_saved_model(
+            &tensorflow::SessionOptions::new(),
+            &["serve"],
+            &mut graph,
+            dir.as_ref(),
+        )?;
+        CaptchaModel::new(session, graph, dir.as_ref())
+    }
+
+    /// load_frozen imports a graph frozen by GraphCache in place of the SavedModel in 'dir',
+    /// which is still what the model reports as its path. The graph is placed on 'gpu' when
+    /// it is given
+    pub(crate) fn load_frozen(
+        graph_def: &Path,
+        dir: &Path,
+        gpu: Option<usize>,
+    ) -> errors::Result<CaptchaModel> {
+        let mut graph = Graph::new();
+        let mut import = tensorflow::ImportGraphDefOptions::new();
+        let mut session_options = tensorflow::SessionOptions::new();
+        if let Some(gpu) = gpu {
+            import.set_default_device(&format!("/device:GPU:{}", gpu))?;
+            // a ConfigProto setting only allow_soft_placement, so operations without a GPU
+            // kernel fall back to the CPU rather than failing placement
+            session_options.set_config(&[0x38, 0x01])?;
+        }
+        graph.import_graph_def(&fs::read(graph_def)?, &import)?;
+        let session = Session::new(&session_options, &graph)?;
+        CaptchaModel::new(session, graph, dir)
+    }
+
+    /// load_cached loads the SavedModel in 'dir' from its frozen graph in 'cache' when there
+    /// is one, and otherwise restores it and freezes it for next time. A model pinned to
+    /// 'gpu' can only be placed through its frozen graph, so it is frozen before loading
+    pub(crate) fn load_cached(
+        dir: &Path,
+        cache: Option<&GraphCache>,
+        gpu: Option<usize>,
+    ) -> errors::Result<CaptchaModel> {
+        let cache = match (cache, gpu) {
+            (Some(cache), _) => cache,
+            (None, None) => return CaptchaModel::load(dir),
+            (None, Some(_)) => return Err(errors::Error::UnfrozenPinnedModel(dir.to_path_buf())),
+        };
+        let cached = cache.cached_graph(dir)?;
+        #[cfg(feature = "otel")]
+        crate::telemetry::record_graph_cache_lookup(cached.exists());
+        if cached.exists() {
+            return CaptchaModel::load_frozen(&cached, dir, gpu);
+        }
+        if gpu.is_some() {
+            return CaptchaModel::load_frozen(&cache.freeze(dir)?, dir, gpu);
+        }
+        let model = CaptchaModel::load(dir)?;
+        // a model that can't be frozen is served all the same, only without the speedup
+        let _ = cache.freeze(dir);
+        Ok(model)
+    }
+
+    fn new(session: Session, graph: Graph, dir: &Path) -> errors::Result<CaptchaModel> {
+        let input_size = match resize::read_manifest(dir)? {
+            Some(size) => Some(size),
+            None => graph_input_size(&graph),
+        };
+        Ok(CaptchaModel {
+            session,
+            graph,
+            path: dir.to_path_buf(),
+            input_size,
+            resize: resize::ResizeOptions::default(),
+        })
+    }
+
+    pub(crate) fn with_resize(mut self, resize: resize::ResizeOptions) -> CaptchaModel {
+        self.resize = resize;
+        self
+    }
+
+    /// run feeds every image through the model in a single session run. The images must
+    /// already be in a format the graph decodes (see format::for_model): the registry converts
+    /// them once on the way in, and other callers have to do the same
+    pub(crate) fn run(&self, images: &[Vec<u8>]) -> errors::Result<Vec<Prediction>> {
+        let resized = match self.input_size {
+            Some(size) => resize::resize_all(images, size, self.resize)?,
+            None => Cow::Borrowed(images),
+        };
+        let images = resized.as_ref();
+        // inptus
+        let input_operation = self.graph.operation_by_name_required("Placeholder")?;
+        let input_tensor = image_tensor(images)?;
+
+        let mut output_step = tensorflow::SessionRunArgs::new();
+        output_step.add_feed(&input_operation, 0, &input_tensor);
+
+        let scores_out =
+            output_step.request_fetch(&self.graph.operation_by_name_required("scores")?, 0);
+
+        self.session.run(&mut output_step)?;
+        let predictions: Tensor<f32> = output_step.fetch(scores_out)?;
+        if predictions.len() != images.len() * 2 {
+            return Err(errors::Error::MalformedOutput);
+        }
+
+        Ok(predictions
+            .chunks(2)
+            .map(|scores| Prediction {
+                affirmative_confidence: scores[0],
+                negative_confidence: scores[1],
+            })
+            .collect())
+    }
+}
+
+/// image_tensor packs encoded images into the string tensor fed to a model's Placeholder.
+/// TensorFlow strings are arbitrary bytes, but the tensorflow crate only builds them from
+/// String, so this is the one place image bytes are passed off as one without being UTF-8:
+/// they're only ever copied into the tensor, never read as text
+pub(crate) fn image_tensor(images: &[Vec<u8>]) -> errors::Result<Tensor<String>> {
+    let strings: Vec<String> = images
+        .iter()
+        .map(|image| unsafe { String::from_utf8_unchecked(image.clone()) })
+        .collect();
+    Ok(Tensor::new(&[strings.len() as u64]).with_values(&strings)?)
+}
+
+/// ModelSlot holds a registry's model along with what is needed to load it again, so an idle
+/// model can be evicted (see RegistryBuilder::idle_ttl) and reloaded by its next prediction
+#[derive(Debug)]
+struct ModelSlot {
+    model: Option<CaptchaModel>,
+    path: PathBuf,
+    /// fingerprint is the integrity::fingerprint of 'path' when the model was loaded
+    fingerprint: String,
+    resize: resize::ResizeOptions,
+    graph_cache: Option<GraphCache>,
+    gpu: Option<usize>,
+    last_used: Instant,
+}
+
+impl ModelSlot {
+    fn new(
+        model: CaptchaModel,
+        fingerprint: String,
+        graph_cache: Option<GraphCache>,
+        gpu: Option<usize>,
+    ) -> ModelSlot {
+        ModelSlot {
+            path: model.path.clone(),
+            fingerprint,
+            resize: model.resize,
+            graph_cache,
+            gpu,
+            model: Some(model),
+            last_used: Instant::now(),
+        }
+    }
+
+    fn run(&mut self, images: &[Vec<u8>]) -> errors::Result<Vec<Prediction>> {
+        self.last_used = Instant::now();
+        let model = match self.model.take() {
+            Some(model) => model,
+            None => {
+                // the directory may have changed while the model was evicted
+                let fingerprint = integrity::fingerprint(&self.path)?;
+                let model =
+                    CaptchaModel::load_cached(&self.path, self.graph_cache.as_ref(), self.gpu)?
+                        .with_resize(self.resize);
+                self.fingerprint = fingerprint;
+                model
+            }
+        };
+        self.model.insert(model).run(images)
+    }
+
+    /// evict_if_idle unloads the model if it has gone unused for 'ttl', returning whether it did
+    fn evict_if_idle(&mut self, ttl: Duration) -> bool {
+        if self.model.is_some() && self.last_used.elapsed() >= ttl {
+            self.model = None;
+            return true;
+        }
+        false
+    }
+}
+
+/// graph_input_size finds the size a graph resizes its decoded input to, from the constant
+/// fed to its first resize operation
+fn graph_input_size(graph: &Graph) -> Option<resize::InputSize> {
+    graph.operation_iter().find_map(|operation| {
+        if !operation.op_type().ok()?.starts_with("Resize") {
+            return None;
+        }
+        let (size, _) = operation.input(1);
+        if size.op_type().ok()? != "Const" {
+            return None;
+        }
+        let value: Tensor<i32> = size.get_attr_tensor("value").ok()?;
+        match *value {
+            [height, width] if height > 0 && width > 0 => Some(resize::InputSize {
+                width: width as u32,
+                height: height as u32,
+            }),
+            _ => None,
+        }
+    })
+}
+
+/// RegistryOptions holds the optional behaviour configured through RegistryBuilder. It is
+/// shared with registries created by reloading
+#[derive(Debug, Default)]
+struct RegistryOptions {
+    aggregation: ensemble::Aggregation,
+    aggregation_overrides: HashMap<CaptchaChallenge, ensemble::Aggregation>,
+    candidates: HashMap<CaptchaChallenge, deployment::Candidate>,
+    augmentation: HashMap<CaptchaChallenge, augment::TestTimeAugmentation>,
+    review_queue: Option<review::ReviewQueue>,
+    prediction_log: Option<prediction_log::PredictionLog>,
+    input_limits: sanitize::InputLimits,
+    resize: resize::ResizeOptions,
+    load_limits: loading::LoadLimits,
+    discovery: discovery::Discovery,
+    /// challenges, when set, are the only challenges loaded from a models directory
+    challenges: Option<HashSet<CaptchaChallenge>>,
+    #[cfg(feature = "parallel")]
+    load_pool: loading::LoadPool,
+    idle_ttl: Option<Duration>,
+    log_level: Option<LogLevel>,
+    retry: Option<retry::RetryPolicy>,
+    graph_cache: Option<GraphCache>,
+    devices: devices::DevicePlacement,
+    hooks: hooks::Hooks,
+    concurrency: concurrency::Limiter,
+    #[cfg(feature = "plugins")]
+    plugins: crate::plugins::Plugins,
+    #[cfg(feature = "ann")]
+    fallback: Option<gallery::Fallback>,
+}
+
+/// ChallengeStats is a snapshot of one challenge's models and the predictions using them
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChallengeStats {
+    pub challenge: CaptchaChallenge,
+    /// models counts the challenge's ensemble members, and loaded those not evicted for being
+    /// idle
+    pub models: usize,
+    pub loaded: usize,
+    /// running counts the predictions being made, and waiting those queued behind the
+    /// registry's concurrency limits
+    pub running: usize,
+    pub waiting: usize,
+    pub accuracy: feedback::Accuracy,
+}
+
+/// RegistryStats is a snapshot of a registry, for operators checking on a running server
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RegistryStats {
+    pub challenges: Vec<ChallengeStats>,
+    /// graph_cache is None for a registry without a GraphCache
+    pub graph_cache: Option<GraphCacheStats>,
+    /// review_queue is None for a registry without a ReviewQueue
+    pub review_queue: Option<review::ReviewQueueStats>,
+}
+
+/// RegistryBuilder configures optional registry behaviour before the models are loaded
+#[derive(Debug, Default)]
+pub struct RegistryBuilder {
+    options: RegistryOptions,
+}
+
+impl RegistryBuilder {
+    /// aggregation sets how ensemble members' predictions are combined (Mean by default)
+    pub fn aggregation(mut self, aggregation: ensemble::Aggregation) -> RegistryBuilder {
+        self.options.aggregation = aggregation;
+        self
+    }
+
+    /// aggregation_for overrides the aggregation for a single challenge
+    pub fn aggregation_for(
+        mut self,
+        challenge: CaptchaChallenge,
+        aggregation: ensemble::Aggregation,
+    ) -> RegistryBuilder {
+        let _ = self
+            .options
+            .aggregation_overrides
+            .insert(challenge, aggregation);
+        self
+    }
+
+    /// candidate deploys a candidate model for 'challenge' next to its primary model, either
+    /// taking a share of the traffic or shadowing it (see deployment::Deployment)
+    pub fn candidate(
+        mut self,
+        challenge: CaptchaChallenge,
+        candidate: deployment::Candidate,
+    ) -> RegistryBuilder {
+        let _ = self.options.candidates.insert(challenge, candidate);
+        self
+    }
+
+    /// test_time_augmentation predicts every image for 'challenge' by running each of the
+    /// augmentations through the model and aggregating the scores
+    pub fn test_time_augmentation(
+        mut self,
+        challenge: CaptchaChallenge,
+        augmentation: augment::TestTimeAugmentation,
+    ) -> RegistryBuilder {
+        let _ = self.options.augmentation.insert(challenge, augmentation);
+        self
+    }
+
+    /// review_queue queues every prediction that falls inside the queue's uncertainty band for
+    /// human review
+    pub fn review_queue(mut self, queue: review::ReviewQueue) -> RegistryBuilder {
+        self.options.review_queue = Some(queue);
+        self
+    }
+
+    /// prediction_log records every prediction made through predict_batch to 'log'
+    pub fn prediction_log(mut self, log: prediction_log::PredictionLog) -> RegistryBuilder {
+        self.options.prediction_log = Some(log);
+        self
+    }
+
+    /// input_limits replaces the default limits every image must pass before it reaches a
+    /// model
+    pub fn input_limits(mut self, limits: sanitize::InputLimits) -> RegistryBuilder {
+        self.options.input_limits = limits;
+        self
+    }
+
+    /// resize sets how inputs are resized to each model's input size, which is read from the
+    /// model's manifest.json or else its graph (bilinear and padded by default)
+    pub fn resize(mut self, resize: resize::ResizeOptions) -> RegistryBuilder {
+        self.options.resize = resize;
+        self
+    }
+
+    /// load_limits bounds how many models load at once, for machines without the memory to
+    /// load every model concurrently. Reloads are bounded the same way
+    pub fn load_limits(mut self, limits: loading::LoadLimits) -> RegistryBuilder {
+        self.options.load_limits = limits;
+        self
+    }
+
+    /// model_discovery sets how the models directory is searched, only its top level by
+    /// default. Reloads search the same way
+    pub fn model_discovery(mut self, discovery: discovery::Discovery) -> RegistryBuilder {
+        self.options.discovery = discovery;
+        self
+    }
+
+    /// only_challenges loads just 'challenges' from a models directory, skipping the models of
+    /// every other challenge found there. Reloads load the same challenges
+    pub fn only_challenges<I>(mut self, challenges: I) -> RegistryBuilder
+    where
+        I: IntoIterator<Item = CaptchaChallenge>,
+    {
+        self.options.challenges = Some(challenges.into_iter().collect());
+        self
+    }
+
+    /// load_pool sets the rayon pool models are loaded on, rayon's global pool by default.
+    /// Reloads use the same pool
+    #[cfg(feature = "parallel")]
+    pub fn load_pool(mut self, pool: loading::LoadPool) -> RegistryBuilder {
+        self.options.load_pool = pool;
+        self
+    }
+
+    /// idle_ttl lets CaptchaRegistry::evict_idle unload models that haven't served a
+    /// prediction for 'ttl'. An evicted model is loaded again by its next prediction, which
+    /// waits for the load
+    pub fn idle_ttl(mut self, ttl: Duration) -> RegistryBuilder {
+        self.options.idle_ttl = Some(ttl);
+        self
+    }
+
+    /// tensorflow_log_level sets TensorFlow's log level before models are loaded. Because
+    /// TensorFlow only reads it from the environment, this changes it for the whole process;
+    /// without it the environment is left alone
+    pub fn tensorflow_log_level(mut self, level: LogLevel) -> RegistryBuilder {
+        self.options.log_level = Some(level);
+        self
+    }
+
+    /// retry_policy retries a model run that failed with a transient TensorFlow error (see
+    /// errors::Error::is_transient), such as running short of GPU memory. Other failures are
+    /// returned at once. Without a policy nothing is retried
+    pub fn retry_policy(mut self, retry: retry::RetryPolicy) -> RegistryBuilder {
+        self.options.retry = Some(retry);
+        self
+    }
+
+    /// device_placement pins the models of each challenge to a GPU. Pinned models are loaded
+    /// from frozen graphs, so it needs a graph_cache as well
+    pub fn device_placement(mut self, placement: devices::DevicePlacement) -> RegistryBuilder {
+        self.options.devices = placement;
+        self
+    }
+
+    /// graph_cache loads models from frozen graphs kept in 'cache', freezing each model the
+    /// first time it is loaded, which makes later startups much faster (see GraphCache)
+    pub fn graph_cache(mut self, cache: GraphCache) -> RegistryBuilder {
+        self.options.graph_cache = Some(cache);
+        self
+    }
+
+    /// fallback answers predictions for challenges without a model of their own by comparing
+    /// the image against a labeled gallery, using the challenge's name as the label
+    #[cfg(feature = "ann")]
+    pub fn fallback(mut self, fallback: gallery::Fallback) -> RegistryBuilder {
+        self.options.fallback = Some(fallback);
+        self
+    }
+
+    /// plugins serves the challenges that have no model with the handlers in 'plugins'
+    #[cfg(feature = "plugins")]
+    pub fn plugins(mut self, plugins: crate::plugins::Plugins) -> RegistryBuilder {
+        self.options.plugins = plugins;
+        self
+    }
+
+    /// concurrency_limits caps the inferences running at once, per challenge and overall
+    pub fn concurrency_limits(mut self, limits: concurrency::ConcurrencyLimits) -> RegistryBuilder {
+        self.options.concurrency = concurrency::Limiter::new(limits);
+        self
+    }
+
+    /// before_prediction adds a hook run on every image before it is predicted, which may
+    /// rewrite the image or veto the batch (see hooks::Hooks)
+    pub fn before_prediction<F>(mut self, hook: F) -> RegistryBuilder
+    where
+        F: FnMut(&CaptchaChallenge, &mut Vec<u8>) -> errors::Result<()> + Send + 'static,
+    {
+        self.options.hooks.add_pre(None, Box::new(hook));
+        self
+    }
+
+    /// before_prediction_for adds a before_prediction hook for a single challenge
+    pub fn before_prediction_for<F>(
+        mut self,
+        challenge: CaptchaChallenge,
+        hook: F,
+    ) -> RegistryBuilder
+    where
+        F: FnMut(&CaptchaChallenge, &mut Vec<u8>) -> errors::Result<()> + Send + 'static,
+    {
+        self.options.hooks.add_pre(Some(challenge), Box::new(hook));
+        self
+    }
+
+    /// after_prediction adds a hook run on every prediction before it is returned
+    pub fn after_prediction<F>(mut self, hook: F) -> RegistryBuilder
+    where
+        F: FnMut(&CaptchaChallenge, &mut Prediction) -> errors::Result<()> + Send + 'static,
+    {
+        self.options.hooks.add_post(None, Box::new(hook));
+        self
+    }
+
+    /// after_prediction_for adds an after_prediction hook for a single challenge
+    pub fn after_prediction_for<F>(
+        mut self,
+        challenge: CaptchaChallenge,
+        hook: F,
+    ) -> RegistryBuilder
+    where
+        F: FnMut(&CaptchaChallenge, &mut Prediction) -> errors::Result<()> + Send + 'static,
+    {
+        self.options.hooks.add_post(Some(challenge), Box::new(hook));
+        self
+    }
+
+    pub fn load_from_models_dir<P>(self, path: P) -> errors::Result<CaptchaRegistry>
+    where
+        P: AsRef<std::path::Path>,
+    {
+        CaptchaRegistry::load_with(path, Arc::new(self.options), |_, _, _| None)
+    }
+
+    /// load_embedded loads models compiled into the binary with embed_models!, extracting
+    /// them to a models directory in the user's cache first (see embedded::extract)
+    pub fn load_embedded(
+        self,
+        files: &[embedded::EmbeddedFile],
+    ) -> errors::Result<CaptchaRegistry> {
+        let dir = embedded::extract(files)?;
+        self.load_from_models_dir(dir)
+    }
+
+    /// load_from_paths loads each challenge's model from the directory given for it, which
+    /// can be named anything. A directory holding several models is loaded as an ensemble, as
+    /// in a models directory
+    pub fn load_from_paths(
+        self,
+        paths: HashMap<CaptchaChallenge, PathBuf>,
+    ) -> errors::Result<CaptchaRegistry> {
+        CaptchaRegistry::load_sources(
+            paths.into_iter().collect(),
+            Arc::new(self.options),
+            |_, _, _| None,
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct CaptchaRegistry {
+    items: SavedModelMap,
+    /// sources are the directories each challenge was loaded from, for check_for_updates
+    sources: Vec<(CaptchaChallenge, PathBuf)>,
+    feedback: Arc<feedback::FeedbackLog>,
+    options: Arc<RegistryOptions>,
+}
+
+impl CaptchaRegistry {
+    pub fn builder() -> RegistryBuilder {
+        RegistryBuilder::default()
+    }
+
+    pub fn load_from_models_dir<P>(path: P) -> errors::Result<CaptchaRegistry>
+    where
+        P: AsRef<std::path::Path>,
+    {
+        Self::builder().load_from_models_dir(path)
+    }
+
+    /// from_paths loads each challenge's model from an explicit directory rather than from a
+    /// models directory named after the challenges (see RegistryBuilder::load_from_paths)
+    pub fn from_paths(
+        paths: HashMap<CaptchaChallenge, PathBuf>,
+    ) -> errors::Result<CaptchaRegistry> {
+        Self::builder().load_from_paths(paths)
+    }
+
+    /// reload builds a new registry from 'path' with this registry's options and feedback
+    pub fn reload<P>(&self, path: P) -> errors::Result<CaptchaRegistry>
+    where
+        P: AsRef<std::path::Path>,
+    {
+        let registry = Self::load_with(path, Arc::clone(&self.options), |_, _, _| None)?;
+        Ok(registry.sharing_feedback_with(self))
+    }
+
+    /// reload_changed builds a new registry from 'path', sharing this registry's models whose
+    /// files have not changed since they were loaded (see integrity::fingerprint) and loading
+    /// the rest afresh. Ensemble members are matched by directory
+    pub fn reload_changed<P>(&self, path: P) -> errors::Result<CaptchaRegistry>
+    where
+        P: AsRef<std::path::Path>,
+    {
+        let registry = Self::load_with(
+            path,
+            Arc::clone(&self.options),
+            |challenge, member, fingerprint| self.unchanged(challenge, member, fingerprint),
+        )?;
+        Ok(registry.sharing_feedback_with(self))
+    }
+
+    /// check_for_updates lists the challenges whose model directories have changed on disk
+    /// since they were loaded: a member was replaced, added or removed. Only the directories
+    /// this registry was loaded from are checked, so a model archive or a challenge that is
+    /// new to the models directory is only picked up by reload
+    pub fn check_for_updates(&self) -> errors::Result<Vec<CaptchaChallenge>> {
+        let mut updated = Vec::new();
+        for (challenge, dir) in &self.sources {
+            if self.has_changed(challenge, dir)? {
+                updated.push(challenge.clone());
+            }
+        }
+        updated.sort_by_key(ToString::to_string);
+        Ok(updated)
+    }
+
+    fn has_changed(&self, challenge: &CaptchaChallenge, dir: &Path) -> errors::Result<bool> {
+        let members = ensemble::members(dir)?;
+        if members.len() != self.items.get(challenge).map_or(0, Vec::len) {
+            return Ok(true);
+        }
+        for member in members {
+            let fingerprint = integrity::fingerprint(&member)?;
+            if self.unchanged(challenge, &member, &fingerprint).is_none() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// refresh builds a new registry from the directories this registry was loaded from,
+    /// loading the models check_for_updates would list and sharing the rest. Like reload, it
+    /// leaves this registry untouched, so either every changed model is swapped in or none is
+    pub fn refresh(&self) -> errors::Result<CaptchaRegistry> {
+        let registry = Self::load_sources(
+            self.sources.clone(),
+            Arc::clone(&self.options),
+            |challenge, member, fingerprint| self.unchanged(challenge, member, fingerprint),
+        )?;
+        Ok(registry.sharing_feedback_with(self))
+    }
+
+    /// unchanged returns this registry's model for 'member' of 'challenge' if it was loaded
+    /// from files with the same 'fingerprint'
+    fn unchanged(
+        &self,
+        challenge: &CaptchaChallenge,
+        member: &Path,
+        fingerprint: &str,
+    ) -> Option<SharedModel> {
+        self.items.get(challenge)?.iter().find_map(|model| {
+            let loaded = model.lock(Priority::Interactive).ok()?;
+            if loaded.path == member && loaded.fingerprint == fingerprint {
+                Some(Arc::clone(model))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// sharing_feedback_with makes this registry share 'other's feedback, so accuracy figures
+    /// survive a reload
+    fn sharing_feedback_with(mut self, other: &CaptchaRegistry) -> CaptchaRegistry {
+        self.feedback = Arc::clone(&other.feedback);
+        self
+    }
+
+    /// load_with loads every model directory and model archive (see archive::extract) under
+    /// 'path', asking 'reuse' first whether an already loaded model can stand in for the one
+    /// on disk
+    fn load_with<P, F>(
+        path: P,
+        options: Arc<RegistryOptions>,
+        reuse: F,
+    ) -> errors::Result<CaptchaRegistry>
+    where
+        P: AsRef<std::path::Path>,
+        F: Fn(&CaptchaChallenge, &Path, &str) -> Option<SharedModel> + Sync,
+    {
+        let checksums = integrity::Checksums::load(path.as_ref())?.unwrap_or_default();
+        let mut found = discovery::discover(path.as_ref(), &options.discovery)?;
+        if let Some(only) = &options.challenges {
+            found
+                .directories
+                .retain(|challenge, _| only.contains(challenge));
+            found
+                .archives
+                .retain(|challenge, _| only.contains(challenge));
+        }
+        let mut sources = HashMap::new();
+        for (challenge, dir) in found.directories {
+            checksums.verify(&dir)?;
+            let _ = sources.insert(challenge, dir);
+        }
+        // a model directory wins over an archive for the same challenge
+        for (challenge, archive) in found.archives {
+            if !sources.contains_key(&challenge) {
+                checksums.verify(&archive)?;
+                let _ = sources.insert(challenge, archive::extract(&archive)?);
+            }
+        }
+        Self::load_sources(sources.into_iter().collect(), options, reuse)
+    }
+
+    /// load_sources loads the model directory given for each challenge, asking 'reuse' first
+    /// whether an already loaded model can stand in for the one on disk
+    fn load_sources<F>(
+        sources: Vec<(CaptchaChallenge, PathBuf)>,
+        options: Arc<RegistryOptions>,
+        reuse: F,
+    ) -> errors::Result<CaptchaRegistry>
+    where
+        F: Fn(&CaptchaChallenge, &Path, &str) -> Option<SharedModel> + Sync,
+    {
+        let model_count = sources.len();
+        let loaded_from = sources.clone();
+        let resize = options.resize;
+        let gate = loading::LoadGate::new(options.load_limits);
+        let graph_cache = options.graph_cache.as_ref();
+        if let Some(level) = options.log_level {
+            level.apply();
+        }
+        // the result type is spelled out as the sequential path gives '?' nothing to infer from
+        let load_one = |mut acc: SavedModelMap,
+                        (challenge, dir): (CaptchaChallenge, PathBuf)|
+         -> errors::Result<SavedModelMap> {
+            let members = ensemble::members(&dir)?;
+            if members.is_empty() {
+                return Err(errors::Error::ModelLoad(challenge));
+            }
+            let gpu = options.devices.gpu_for(&challenge);
+            let mut ensemble = Vec::with_capacity(members.len());
+            for member in members {
+                let fingerprint = integrity::fingerprint(&member)?;
+                ensemble.push(match reuse(&challenge, &member, &fingerprint) {
+                    Some(model) => model,
+                    None => {
+                        let _permit = gate.acquire(loading::model_size(&member)?)?;
+                        Arc::new(PriorityMutex::new(ModelSlot::new(
+                            CaptchaModel::load_cached(&member, graph_cache, gpu)?
+                                .with_resize(resize),
+                            fingerprint,
+                            graph_cache.cloned(),
+                            gpu,
+                        )))
+                    }
+                });
+            }
+            acc.insert(challenge, ensemble);
+            Ok(acc)
+        };
+        #[cfg(feature = "parallel")]
+        let items = options.load_pool.install(|| {
+            sources
+                .into_par_iter()
+                .try_fold(SavedModelMap::new, &load_one)
+                .try_reduce(
+                    || SavedModelMap::with_capacity(model_count),
+                    |mut m, t| {
+                        for (k, v) in t.into_iter() {
+                            m.insert(k, v);
+                        }
+                        Ok(m)
+                    },
+                )
+        })??;
+        #[cfg(not(feature = "parallel"))]
+        let items = sources
+            .into_iter()
+            .try_fold(SavedModelMap::with_capacity(model_count), load_one)?;
+        Ok(CaptchaRegistry {
+            items,
+            sources: loaded_from,
+            feedback: Arc::default(),
+            options,
+        })
+    }
+
+    /// record_feedback notes whether the prediction made for the image hashing to 'image_hash'
+    /// (see image_hash) turned out to be correct. Later feedback for the same image replaces
+    /// earlier feedback
+    pub fn record_feedback(
+        &self,
+        challenge: &CaptchaChallenge,
+        image_hash: &str,
+        was_correct: bool,
+    ) {
+        self.feedback.record(challenge, image_hash, was_correct)
+    }
+
+    /// accuracy reports the real-world accuracy of a challenge's model according to feedback
+    pub fn accuracy(&self, challenge: &CaptchaChallenge) -> feedback::Accuracy {
+        self.feedback.accuracy(challenge)
+    }
+
+    /// accuracy_report returns the accuracy of every challenge that has received feedback
+    pub fn accuracy_report(&self) -> HashMap<CaptchaChallenge, feedback::Accuracy> {
+        self.feedback.report()
+    }
+
+    /// stats takes a snapshot of every challenge's models and predictions, sorted by challenge
+    pub fn stats(&self) -> errors::Result<RegistryStats> {
+        let mut challenges: Vec<ChallengeStats> = self
+            .items
+            .iter()
+            .map(|(challenge, ensemble)| {
+                let (running, waiting) = self.options.concurrency.counts(challenge);
+                ChallengeStats {
+                    challenge: challenge.clone(),
+                    models: ensemble.len(),
+                    // a model busy predicting is loaded
+                    loaded: ensemble
+                        .iter()
+                        .filter(|model| model.try_lock().is_none_or(|slot| slot.model.is_some()))
+                        .count(),
+                    running,
+                    waiting,
+                    accuracy: self.feedback.accuracy(challenge),
+                }
+            })
+            .collect();
+        challenges.sort_by_key(|stats| stats.challenge.to_string());
+        Ok(RegistryStats {
+            challenges,
+            graph_cache: match &self.options.graph_cache {
+                Some(cache) => Some(cache.stats()?),
+                None => None,
+            },
+            review_queue: self
+                .options
+                .review_queue
+                .as_ref()
+                .map(|queue| queue.stats()),
+        })
+    }
+
+    /// evict_idle unloads every model that has gone unused for longer than the builder's
+    /// idle_ttl, returning how many it evicted. It does nothing without an idle_ttl. Models
+    /// busy predicting are skipped rather than waited for
+    pub fn evict_idle(&self) -> usize {
+        let ttl = match self.options.idle_ttl {
+            Some(ttl) => ttl,
+            None => return 0,
+        };
+        self.items
+            .values()
+            .flatten()
+            .filter(|model| match model.try_lock() {
+                Some(mut slot) => slot.evict_if_idle(ttl),
+                None => false,
+            })
+            .count()
+    }
+
+    /// idle_ttl is the builder's idle_ttl, if one was set
+    pub fn idle_ttl(&self) -> Option<Duration> {
+        self.options.idle_ttl
+    }
+
+    /// self_test runs every model on its canary tiles (see self_test::canary), or on a blank
+    /// tile when its challenge has none, and reports per model whether the scores were sane
+    /// and right. Every ensemble member is tested on its own
+    pub fn self_test(&self) -> errors::Result<Vec<self_test::ModelReport>> {
+        let mut reports = Vec::new();
+        for (challenge, ensemble) in &self.items {
+            let images = format::for_model(&self_test::test_images(challenge)?)?.into_owned();
+            for model in ensemble {
+                let path = model.lock(Priority::Batch)?.path.clone();
+                let predictions = self.run_member(model, &images, Priority::Batch);
+                reports.push(self_test::ModelReport {
+                    challenge: challenge.clone(),
+                    path,
+                    canary: self_test::canary(challenge).is_some(),
+                    failure: self_test::check(challenge, &predictions),
+                });
+            }
+        }
+        reports.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(reports)
+    }
+
+    /// candidate returns the candidate deployed for 'challenge', if any
+    pub fn candidate(&self, challenge: &CaptchaChallenge) -> Option<&deployment::Candidate> {
+        self.options.candidates.get(challenge)
+    }
+
+    pub fn predict(
+        &self,
+        challenge: &CaptchaChallenge,
+        image: Vec<u8>,
+    ) -> errors::Result<Prediction> {
+        self.predict_batch(challenge, vec![image])?
+            .pop()
+            .ok_or(errors::Error::MalformedOutput)
+    }
+
+    /// predict_batch feeds every image through the challenge's model in a single session run,
+    /// returning one Prediction per image in the order they were given. The registry's hooks
+    /// run around it (see RegistryBuilder::before_prediction)
+    pub fn predict_batch(
+        &self,
+        challenge: &CaptchaChallenge,
+        images: Vec<Vec<u8>>,
+    ) -> errors::Result<Vec<Prediction>> {
+        self.predict_batch_prioritized(challenge, images, Priority::default())
+    }
+
+    /// predict_batch_prioritized is predict_batch at 'priority': while Interactive predictions
+    /// are waiting for a model, Batch ones wait for them to finish
+    pub fn predict_batch_prioritized(
+        &self,
+        challenge: &CaptchaChallenge,
+        mut images: Vec<Vec<u8>>,
+        priority: Priority,
+    ) -> errors::Result<Vec<Prediction>> {
+        let started = Instant::now();
+        // hashed as given, before hooks can rewrite them, to match harvest and feedback
+        let hashes: Vec<String> = match &self.options.prediction_log {
+            Some(_) => images.iter().map(image_hash).collect(),
+            None => Vec::new(),
+        };
+        self.options.hooks.before(challenge, &mut images)?;
+        let slot = self.options.concurrency.acquire(challenge)?;
+        let mut predictions = self.run_traced(challenge, &images, priority)?;
+        drop(slot);
+        self.options.hooks.after(challenge, &mut predictions)?;
+        if let Some(log) = &self.options.prediction_log {
+            let latency = started.elapsed();
+            let records: Vec<prediction_log::LogRecord> = hashes
+                .into_iter()
+                .zip(&predictions)
+                .map(|(hash, prediction)| {
+                    prediction_log::LogRecord::new(challenge, hash, prediction, latency)
+                })
+                .collect();
+            // a full disk shouldn't cost the caller their prediction
+            let _ = log.record(&records);
+        }
+        if let Some(queue) = &self.options.review_queue {
+            for (image, prediction) in images.iter().zip(&predictions) {
+                queue.submit(challenge, image, prediction);
+            }
+        }
+        Ok(predictions)
+    }
+
+    /// explain computes an occlusion saliency map for 'image' using explain::DEFAULT_CELLS
+    /// cells per side
+    pub fn explain(
+        &self,
+        challenge: &CaptchaChallenge,
+        image: Vec<u8>,
+    ) -> errors::Result<explain::SaliencyMap> {
+        self.explain_with_cells(challenge, image, explain::DEFAULT_CELLS)
+    }
+
+    /// explain_with_cells greys out each cell of a cells x cells grid in turn and records how
+    /// far the affirmative confidence falls without it. Every occluded copy is predicted in
+    /// one batch, and none of them are offered to the review queue
+    pub fn explain_with_cells(
+        &self,
+        challenge: &CaptchaChallenge,
+        image: Vec<u8>,
+        cells: u32,
+    ) -> errors::Result<explain::SaliencyMap> {
+        let (cells, mut batch) = explain::occlusions(&image, cells)?;
+        batch.push(image);
+        let mut predictions = self.run_model(challenge, &batch, Priority::Interactive)?;
+        let baseline = predictions
+            .pop()
+            .ok_or(errors::Error::MalformedOutput)?
+            .affirmative_confidence;
+        Ok(explain::SaliencyMap {
+            cells,
+            baseline,
+            weights: predictions
+                .iter()
+                .map(|prediction| baseline - prediction.affirmative_confidence)
+                .collect(),
+        })
+    }
+
+    /// predict_unloaded predicts a challenge that has no model, with its plugin handler if
+    /// there is one and otherwise with the fallback
+    #[cfg(feature = "plugins")]
+    fn predict_unloaded(
+        &self,
+        challenge: &CaptchaChallenge,
+        images: &[Vec<u8>],
+    ) -> errors::Result<Vec<Prediction>> {
+        match self.options.plugins.handler(challenge) {
+            Some(handler) => handler.predict_batch(&handler.preprocess(images)?),
+            None => self.predict_fallback(challenge, images),
+        }
+    }
+
+    #[cfg(not(feature = "plugins"))]
+    fn predict_unloaded(
+        &self,
+        challenge: &CaptchaChallenge,
+        images: &[Vec<u8>],
+    ) -> errors::Result<Vec<Prediction>> {
+        self.predict_fallback(challenge, images)
+    }
+
+    #[cfg(feature = "ann")]
+    fn predict_fallback(
+        &self,
+        challenge: &CaptchaChallenge,
+        images: &[Vec<u8>],
+    ) -> errors::Result<Vec<Prediction>> {
+        match &self.options.fallback {
+            Some(fallback) => fallback.predict(&challenge.to_string(), images),
+            None => Err(errors::Error::ModelLoad(challenge.clone())),
+        }
+    }
+
+    #[cfg(not(feature = "ann"))]
+    fn predict_fallback(
+        &self,
+        challenge: &CaptchaChallenge,
+        _images: &[Vec<u8>],
+    ) -> errors::Result<Vec<Prediction>> {
+        Err(errors::Error::ModelLoad(challenge.clone()))
+    }
+
+    /// run_traced is run_model within a telemetry::PredictionSpan
+    #[cfg(feature = "otel")]
+    fn run_traced(
+        &self,
+        challenge: &CaptchaChallenge,
+        images: &[Vec<u8>],
+        priority: Priority,
+    ) -> errors::Result<Vec<Prediction>> {
+        let span = crate::telemetry::PredictionSpan::start(challenge, images.len());
+        let result = self.run_model(challenge, images, priority);
+        span.end(&result);
+        result
+    }
+
+    #[cfg(not(feature = "otel"))]
+    fn run_traced(
+        &self,
+        challenge: &CaptchaChallenge,
+        images: &[Vec<u8>],
+        priority: Priority,
+    ) -> errors::Result<Vec<Prediction>> {
+        self.run_model(challenge, images, priority)
+    }
+
+    fn run_model(
+        &self,
+        challenge: &CaptchaChallenge,
+        images: &[Vec<u8>],
+        priority: Priority,
+    ) -> errors::Result<Vec<Prediction>> {
+        self.options.input_limits.check_all(images)?;
+        let images = format::for_model(images)?;
+        match self.options.augmentation.get(challenge) {
+            Some(augmentation) => {
+                let expanded = augmentation.expand(&images)?;
+                Ok(augmentation.collapse(&self.run_deployed(challenge, &expanded, priority)?))
+            }
+            None => self.run_deployed(challenge, &images, priority),
+        }
+    }
+
+    fn run_deployed(
+        &self,
+        challenge: &CaptchaChallenge,
+        images: &[Vec<u8>],
+        priority: Priority,
+    ) -> errors::Result<Vec<Prediction>> {
+        match self.options.candidates.get(challenge) {
+            Some(candidate) => candidate.serve(challenge, images, |images| {
+                self.run_primary(challenge, images, priority)
+            }),
+            None => self.run_primary(challenge, images, priority),
+        }
+    }
+
+    fn run_primary(
+        &self,
+        challenge: &CaptchaChallenge,
+        images: &[Vec<u8>],
+        priority: Priority,
+    ) -> errors::Result<Vec<Prediction>> {
+        let ensemble = match self.items.get(challenge) {
+            Some(ensemble) => ensemble,
+            None => return self.predict_unloaded(challenge, images),
+        };
+        let mut member_predictions = Vec::with_capacity(ensemble.len());
+        for model in ensemble {
+            member_predictions.push(self.run_member(model, images, priority)?);
+        }
+        if member_predictions.len() == 1 {
+            return member_predictions
+                .pop()
+                .ok_or(errors::Error::MalformedOutput);
+        }
+
+        let aggregation = self
+            .options
+            .aggregation_overrides
+            .get(challenge)
+            .copied()
+            .unwrap_or(self.options.aggregation);
+        (0..images.len())
+            .map(|index| {
+                let members = member_predictions
+                    .iter()
+                    .map(|predictions| predictions.get(index).copied())
+                    .collect::<Option<Vec<Prediction>>>()
+                    .ok_or(errors::Error::MalformedOutput)?;
+                Ok(aggregation.aggregate(&members))
+            })
+            .collect()
+    }
+
+    /// run_member runs a single model, retrying transient failures under the retry policy.
+    /// The model is unlocked while waiting, so other requests can use it meanwhile
+    fn run_member(
+        &self,
+        model: &SharedModel,
+        images: &[Vec<u8>],
+        priority: Priority,
+    ) -> errors::Result<Vec<Prediction>> {
+        let mut attempt = 0;
+        loop {
+            let result = model.lock(priority)?.run(images);
+            match (result, self.options.retry) {
+                (Err(err), Some(retry)) if err.is_transient() && attempt < retry.max_retries => {
+                    attempt += 1;
+                    thread::sleep(retry.backoff(attempt));
+                }
+                (result, _) => return result,
+            }
+        }
+    }
+}
+
+impl Predictor for CaptchaRegistry {
+    fn predict_batch(
+        &self,
+        challenge: &CaptchaChallenge,
+        images: Vec<Vec<u8>>,
+    ) -> errors::Result<Vec<Prediction>> {
+        CaptchaRegistry::predict_batch(self, challenge, images)
+    }
+
+    fn predict_batch_prioritized(
+        &self,
+        challenge: &CaptchaChallenge,
+        images: Vec<Vec<u8>>,
+        priority: Priority,
+    ) -> errors::Result<Vec<Prediction>> {
+        CaptchaRegistry::predict_batch_prioritized(self, challenge, images, priority)
+    }
+
+    fn predict(&self, challenge: &CaptchaChallenge, image: Vec<u8>) -> errors::Result<Prediction> {
+        CaptchaRegistry::predict(self, challenge, image)
+    }
+}
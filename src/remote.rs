@@ -0,0 +1,583 @@
+use crate::{errors, schema::PredictionRecord, CaptchaChallenge, Prediction, Predictor, Priority};
+use base64::Engine;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+mod breaker;
+mod proxy;
+pub use crate::retry::RetryPolicy;
+use breaker::Breaker;
+pub use breaker::{BreakerConfig, BreakerState};
+pub use proxy::ProxyConfig;
+
+/// RemoteError is what can go wrong talking to an api_server
+#[derive(Debug, thiserror::Error)]
+pub enum RemoteError {
+    /// Transport covers connecting, timeouts and reading the response
+    #[error("could not reach the api_server")]
+    Transport(#[source] reqwest::Error),
+    /// Upstream is an error reported by the api_server itself, 'err' being its error code and
+    /// 'meta' its details (see the api_server's GET /errors)
+    #[error("the api_server answered {status} with {err}")]
+    Upstream {
+        status: u16,
+        err: String,
+        message: Option<String>,
+        meta: Option<serde_json::Value>,
+    },
+    /// Decode means the response was not something an api_server sends
+    #[error("unexpected response from the api_server")]
+    Decode(#[source] serde_json::Error),
+    /// NoUpstreams means the client was built without any upstream to send requests to
+    #[error("no upstreams configured")]
+    NoUpstreams,
+    /// CircuitOpen means every upstream's circuit breaker is open, so nothing was sent
+    #[error("every upstream's circuit breaker is open")]
+    CircuitOpen,
+    /// InvalidProxy means a ProxyConfig URL could not be parsed
+    #[error("invalid proxy URL")]
+    InvalidProxy(#[source] reqwest::Error),
+}
+
+impl RemoteError {
+    /// is_unavailable reports whether the upstream itself is down, as opposed to having
+    /// answered with an error, making the request worth sending elsewhere
+    fn is_unavailable(&self) -> bool {
+        match self {
+            RemoteError::Transport(err) => err.is_connect() || err.is_timeout(),
+            RemoteError::Upstream { status, .. } => *status == 502 || *status == 503,
+            _ => false,
+        }
+    }
+
+    /// is_retryable reports whether trying again later might succeed
+    fn is_retryable(&self) -> bool {
+        self.is_unavailable() || matches!(self, RemoteError::CircuitOpen)
+    }
+}
+
+impl From<reqwest::Error> for RemoteError {
+    fn from(error: reqwest::Error) -> RemoteError {
+        RemoteError::Transport(error)
+    }
+}
+
+#[derive(Serialize)]
+struct RecognitionRequest<'a> {
+    challenge: &'a CaptchaChallenge,
+    image_type: &'static str,
+    image: String,
+    /// priority is left out when Interactive, the api_server's default
+    #[serde(skip_serializing_if = "is_interactive")]
+    priority: Priority,
+}
+
+fn is_interactive(priority: &Priority) -> bool {
+    *priority == Priority::Interactive
+}
+
+#[derive(Deserialize)]
+enum RecognitionResponse {
+    Ok(ResponsePrediction),
+}
+
+/// ResponsePrediction accepts a schema::PredictionRecord, or the bare Prediction servers
+/// answered with before it
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ResponsePrediction {
+    Record(PredictionRecord),
+    Bare(Prediction),
+}
+
+impl From<ResponsePrediction> for Prediction {
+    fn from(response: ResponsePrediction) -> Prediction {
+        match response {
+            ResponsePrediction::Record(record) => Prediction::from(&record),
+            ResponsePrediction::Bare(prediction) => prediction,
+        }
+    }
+}
+
+/// UpstreamError is an api_server's error body, `{"code", "message", "details"}`, or the
+/// `{"err", "meta"}` of api_servers from before it
+#[derive(Deserialize)]
+struct UpstreamError {
+    #[serde(alias = "err")]
+    code: String,
+    #[serde(default)]
+    message: Option<String>,
+    #[serde(alias = "meta")]
+    details: Option<serde_json::Value>,
+}
+
+/// Balancing picks which healthy upstream serves the next request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Balancing {
+    #[default]
+    RoundRobin,
+    /// LeastLoaded picks the upstream with the fewest requests in flight from this client
+    LeastLoaded,
+}
+
+/// UpstreamMetrics is a snapshot of one upstream as seen by this client
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UpstreamMetrics {
+    pub base_url: String,
+    pub healthy: bool,
+    pub in_flight: usize,
+    pub breaker: BreakerState,
+    /// failures counts requests that found the upstream unavailable
+    pub failures: usize,
+}
+
+/// RemoteMetrics is a snapshot of a RemoteRegistry's upstreams and retries
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RemoteMetrics {
+    pub upstreams: Vec<UpstreamMetrics>,
+    pub retries: usize,
+}
+
+#[derive(Debug)]
+struct Upstream {
+    base_url: String,
+    healthy: AtomicBool,
+    in_flight: AtomicUsize,
+    failures: AtomicUsize,
+    breaker: Mutex<Breaker>,
+}
+
+impl Upstream {
+    fn new(base_url: String, breaker: BreakerConfig) -> Upstream {
+        Upstream {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            healthy: AtomicBool::new(true),
+            in_flight: AtomicUsize::new(0),
+            failures: AtomicUsize::new(0),
+            breaker: Mutex::new(Breaker::new(breaker)),
+        }
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    fn breaker(&self) -> MutexGuard<Breaker> {
+        self.breaker.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn metrics(&self) -> UpstreamMetrics {
+        UpstreamMetrics {
+            base_url: self.base_url.clone(),
+            healthy: self.is_healthy(),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            breaker: self.breaker().state(),
+            failures: self.failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Pool {
+    upstreams: Vec<Upstream>,
+    balancing: Balancing,
+    next: AtomicUsize,
+    retry: RetryPolicy,
+    retries: AtomicUsize,
+}
+
+impl Pool {
+    /// candidates orders the upstreams to try for one request: the balancer's pick first, then
+    /// the other healthy upstreams, then the unhealthy ones as a last resort
+    fn candidates(&self) -> Vec<&Upstream> {
+        let count = self.upstreams.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut ordered: Vec<&Upstream> = (0..count)
+            .map(|offset| &self.upstreams[(start + offset) % count])
+            .collect();
+        if self.balancing == Balancing::LeastLoaded {
+            // stable, so equally loaded upstreams keep their round robin order
+            ordered.sort_by_key(|upstream| upstream.in_flight.load(Ordering::Relaxed));
+        }
+        ordered.sort_by_key(|upstream| !upstream.is_healthy());
+        ordered
+    }
+}
+
+/// RemoteRegistry is a Predictor that offloads inference to one or more running api_servers,
+/// so processes without a GPU or libtensorflow can still predict. Connections are pooled and
+/// kept alive between predictions. Requests are spread over the upstreams by the configured
+/// Balancing, and an upstream that can't be reached is marked unhealthy and skipped until a
+/// health check finds it back up.
+///
+/// The client is blocking: call it from a plain thread or tokio's spawn_blocking, never
+/// directly from async code
+#[derive(Debug, Clone)]
+pub struct RemoteRegistry {
+    client: reqwest::blocking::Client,
+    pool: Arc<Pool>,
+}
+
+/// RemoteRegistryBuilder configures the HTTP client behind a RemoteRegistry
+#[derive(Debug, Clone)]
+pub struct RemoteRegistryBuilder {
+    upstreams: Vec<String>,
+    balancing: Balancing,
+    retry: RetryPolicy,
+    breaker: BreakerConfig,
+    health_check_interval: Option<Duration>,
+    timeout: Duration,
+    connect_timeout: Duration,
+    max_idle_connections: usize,
+    proxy: ProxyConfig,
+}
+
+impl RemoteRegistryBuilder {
+    /// upstream adds another api_server to spread requests over
+    pub fn upstream<S>(mut self, base_url: S) -> RemoteRegistryBuilder
+    where
+        S: Into<String>,
+    {
+        self.upstreams.push(base_url.into());
+        self
+    }
+
+    pub fn balancing(mut self, balancing: Balancing) -> RemoteRegistryBuilder {
+        self.balancing = balancing;
+        self
+    }
+
+    /// retry_policy decides how often and how patiently predictions are retried when no
+    /// upstream is available
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> RemoteRegistryBuilder {
+        self.retry = retry;
+        self
+    }
+
+    /// breaker configures the circuit breaker kept for each upstream
+    pub fn breaker(mut self, breaker: BreakerConfig) -> RemoteRegistryBuilder {
+        self.breaker = breaker;
+        self
+    }
+
+    /// health_check_interval is how often every upstream's /health is probed, 5 seconds by
+    /// default. None disables probing, leaving failed upstreams to be retried only once every
+    /// upstream has failed
+    pub fn health_check_interval(mut self, interval: Option<Duration>) -> RemoteRegistryBuilder {
+        self.health_check_interval = interval;
+        self
+    }
+
+    /// timeout bounds a whole prediction request, 10 seconds by default
+    pub fn timeout(mut self, timeout: Duration) -> RemoteRegistryBuilder {
+        self.timeout = timeout;
+        self
+    }
+
+    /// connect_timeout bounds establishing a connection, 2 seconds by default
+    pub fn connect_timeout(mut self, timeout: Duration) -> RemoteRegistryBuilder {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// max_idle_connections caps how many idle connections are pooled per upstream, 16 by
+    /// default
+    pub fn max_idle_connections(mut self, connections: usize) -> RemoteRegistryBuilder {
+        self.max_idle_connections = connections;
+        self
+    }
+
+    /// proxy sends every request, health checks included, through the configured proxies
+    pub fn proxy(mut self, proxy: ProxyConfig) -> RemoteRegistryBuilder {
+        self.proxy = proxy;
+        self
+    }
+
+    pub fn build(self) -> errors::Result<RemoteRegistry> {
+        let builder = reqwest::blocking::Client::builder()
+            .timeout(self.timeout)
+            .connect_timeout(self.connect_timeout)
+            .pool_max_idle_per_host(self.max_idle_connections);
+        let client = self
+            .proxy
+            .apply(builder)?
+            .build()
+            .map_err(RemoteError::from)?;
+        let breaker = self.breaker;
+        let pool = Arc::new(Pool {
+            upstreams: self
+                .upstreams
+                .into_iter()
+                .map(|base_url| Upstream::new(base_url, breaker))
+                .collect(),
+            balancing: self.balancing,
+            next: AtomicUsize::new(0),
+            retry: self.retry,
+            retries: AtomicUsize::new(0),
+        });
+        if let Some(interval) = self.health_check_interval {
+            spawn_health_checks(client.clone(), Arc::downgrade(&pool), interval);
+        }
+        Ok(RemoteRegistry { client, pool })
+    }
+}
+
+/// spawn_health_checks probes every upstream each 'interval' until the pool is dropped
+fn spawn_health_checks(client: reqwest::blocking::Client, pool: Weak<Pool>, interval: Duration) {
+    let _ = thread::spawn(move || loop {
+        thread::sleep(interval);
+        let pool = match pool.upgrade() {
+            Some(pool) => pool,
+            None => return,
+        };
+        for upstream in &pool.upstreams {
+            let healthy = client
+                .get(format!("{}/health", upstream.base_url))
+                .send()
+                .is_ok_and(|response| response.status().is_success());
+            upstream.healthy.store(healthy, Ordering::Relaxed);
+        }
+    });
+}
+
+impl RemoteRegistry {
+    /// builder starts configuring a client for the api_server at 'base_url', e.g.
+    /// http://gpu-box:5000
+    pub fn builder<S>(base_url: S) -> RemoteRegistryBuilder
+    where
+        S: Into<String>,
+    {
+        RemoteRegistryBuilder {
+            upstreams: vec![base_url.into()],
+            balancing: Balancing::default(),
+            retry: RetryPolicy::default(),
+            breaker: BreakerConfig::default(),
+            health_check_interval: Some(Duration::from_secs(5)),
+            timeout: Duration::from_secs(10),
+            connect_timeout: Duration::from_secs(2),
+            max_idle_connections: 16,
+            proxy: ProxyConfig::default(),
+        }
+    }
+
+    pub fn new<S>(base_url: S) -> errors::Result<RemoteRegistry>
+    where
+        S: Into<String>,
+    {
+        Self::builder(base_url).build()
+    }
+
+    /// healthy_upstreams lists the base URLs currently considered healthy
+    pub fn healthy_upstreams(&self) -> Vec<&str> {
+        self.pool
+            .upstreams
+            .iter()
+            .filter(|upstream| upstream.is_healthy())
+            .map(|upstream| upstream.base_url.as_str())
+            .collect()
+    }
+
+    /// metrics snapshots every upstream's health, load and circuit breaker
+    pub fn metrics(&self) -> RemoteMetrics {
+        RemoteMetrics {
+            upstreams: self.pool.upstreams.iter().map(Upstream::metrics).collect(),
+            retries: self.pool.retries.load(Ordering::Relaxed),
+        }
+    }
+
+    /// recognize makes attempts at the prediction until one succeeds, an upstream answers with
+    /// an error of its own, or the retry policy is exhausted
+    fn recognize(
+        &self,
+        challenge: &CaptchaChallenge,
+        image: &[u8],
+        priority: Priority,
+    ) -> Result<Prediction, RemoteError> {
+        let request = RecognitionRequest {
+            challenge,
+            image_type: "base64",
+            image: base64::engine::general_purpose::STANDARD.encode(image),
+            priority,
+        };
+        let retry = self.pool.retry;
+        let mut attempt = 0;
+        loop {
+            match self.attempt(&request) {
+                Err(err) if err.is_retryable() && attempt < retry.max_retries => {
+                    attempt += 1;
+                    let _ = self.pool.retries.fetch_add(1, Ordering::Relaxed);
+                    thread::sleep(retry.backoff(attempt));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// attempt tries the upstreams in the pool's order, failing over to the next one when an
+    /// upstream can't be reached or is unavailable. Upstreams whose circuit is open are skipped
+    fn attempt(&self, request: &RecognitionRequest) -> Result<Prediction, RemoteError> {
+        let mut last_error = None;
+        for upstream in self.pool.candidates() {
+            if !upstream.breaker().try_acquire(Instant::now()) {
+                continue;
+            }
+            let _ = upstream.in_flight.fetch_add(1, Ordering::Relaxed);
+            let result = self.recognize_on(upstream, request);
+            let _ = upstream.in_flight.fetch_sub(1, Ordering::Relaxed);
+            match result {
+                Err(err) if err.is_unavailable() => {
+                    let _ = upstream.failures.fetch_add(1, Ordering::Relaxed);
+                    upstream.healthy.store(false, Ordering::Relaxed);
+                    upstream.breaker().record_failure(Instant::now());
+                    last_error = Some(err);
+                }
+                result => {
+                    upstream.breaker().record_success();
+                    return result;
+                }
+            }
+        }
+        Err(last_error.unwrap_or(if self.pool.upstreams.is_empty() {
+            RemoteError::NoUpstreams
+        } else {
+            RemoteError::CircuitOpen
+        }))
+    }
+
+    fn recognize_on(
+        &self,
+        upstream: &Upstream,
+        request: &RecognitionRequest,
+    ) -> Result<Prediction, RemoteError> {
+        let response = self
+            .client
+            .post(format!("{}/recognize", upstream.base_url))
+            .json(request)
+            .send()?;
+        let status = response.status();
+        let body = response.bytes()?;
+        if !status.is_success() {
+            return Err(match serde_json::from_slice::<UpstreamError>(&body) {
+                Ok(upstream) => RemoteError::Upstream {
+                    status: status.as_u16(),
+                    err: upstream.code,
+                    message: upstream.message,
+                    meta: upstream.details,
+                },
+                Err(_) => RemoteError::Upstream {
+                    status: status.as_u16(),
+                    err: String::from_utf8_lossy(&body).into_owned(),
+                    message: None,
+                    meta: None,
+                },
+            });
+        }
+        match serde_json::from_slice(&body) {
+            Ok(RecognitionResponse::Ok(prediction)) => Ok(prediction.into()),
+            Err(err) => Err(RemoteError::Decode(err)),
+        }
+    }
+}
+
+impl Predictor for RemoteRegistry {
+    /// predict_batch sends one request per image over the pooled connections
+    fn predict_batch(
+        &self,
+        challenge: &CaptchaChallenge,
+        images: Vec<Vec<u8>>,
+    ) -> errors::Result<Vec<Prediction>> {
+        self.predict_batch_prioritized(challenge, images, Priority::Interactive)
+    }
+
+    /// predict_batch_prioritized sends the priority along, for the api_server to predict at
+    fn predict_batch_prioritized(
+        &self,
+        challenge: &CaptchaChallenge,
+        images: Vec<Vec<u8>>,
+        priority: Priority,
+    ) -> errors::Result<Vec<Prediction>> {
+        images
+            .iter()
+            .map(|image| Ok(self.recognize(challenge, image, priority)?))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(balancing: Balancing) -> Pool {
+        Pool {
+            upstreams: vec![
+                Upstream::new("http://a/".to_owned(), BreakerConfig::default()),
+                Upstream::new("http://b".to_owned(), BreakerConfig::default()),
+                Upstream::new("http://c".to_owned(), BreakerConfig::default()),
+            ],
+            balancing,
+            next: AtomicUsize::new(0),
+            retry: RetryPolicy::default(),
+            retries: AtomicUsize::new(0),
+        }
+    }
+
+    fn first_choice(pool: &Pool) -> &str {
+        &pool.candidates()[0].base_url
+    }
+
+    #[test]
+    fn round_robin_skips_unhealthy() {
+        let pool = pool(Balancing::RoundRobin);
+        pool.upstreams[1].healthy.store(false, Ordering::Relaxed);
+        let picks: Vec<&str> = (0..4).map(|_| first_choice(&pool)).collect();
+        assert_eq!(picks, vec!["http://a", "http://c", "http://c", "http://a"]);
+        assert_eq!(pool.candidates().last().unwrap().base_url, "http://b");
+    }
+
+    #[test]
+    fn least_loaded_prefers_idle_upstreams() {
+        let pool = pool(Balancing::LeastLoaded);
+        pool.upstreams[0].in_flight.store(3, Ordering::Relaxed);
+        pool.upstreams[1].in_flight.store(1, Ordering::Relaxed);
+        assert_eq!(first_choice(&pool), "http://c");
+    }
+
+    #[test]
+    fn decodes_api_server_responses() {
+        let body = br#"{"Ok":{"affirmative_confidence":0.75,"negative_confidence":0.25}}"#;
+        match serde_json::from_slice(body) {
+            Ok(RecognitionResponse::Ok(prediction)) => {
+                assert!(Prediction::from(prediction).is_mainly_affirmative())
+            }
+            Err(err) => panic!("{}", err),
+        }
+        let body = br#"{"Ok":{"schema_version":1,"challenge":"bus","match":false,
+            "scores":{"affirmative":0.25,"negative":0.75},"threshold":0.5}}"#;
+        match serde_json::from_slice(body) {
+            Ok(RecognitionResponse::Ok(prediction)) => {
+                assert!(!Prediction::from(prediction).is_mainly_affirmative())
+            }
+            Err(err) => panic!("{}", err),
+        }
+
+        let body = br#"{"code":"rejected_image","message":"image 0 was rejected",
+            "details":"unreadable image header"}"#;
+        let upstream: UpstreamError = serde_json::from_slice(body).unwrap();
+        assert_eq!(upstream.code, "rejected_image");
+        assert_eq!(upstream.message.as_deref(), Some("image 0 was rejected"));
+        let body = br#"{"err":"generic","meta":"Prediction failed"}"#;
+        let upstream: UpstreamError = serde_json::from_slice(body).unwrap();
+        assert_eq!(
+            (upstream.code.as_str(), upstream.message),
+            ("generic", None)
+        );
+    }
+}
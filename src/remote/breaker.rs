@@ -0,0 +1,131 @@
+use serde_derive::Serialize;
+use std::time::{Duration, Instant};
+
+/// BreakerConfig decides when an upstream's circuit opens and for how long
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BreakerConfig {
+    /// failure_threshold is how many consecutive failures open the circuit
+    pub failure_threshold: u32,
+    /// open_for is how long an open circuit rejects requests before letting a trial through
+    pub open_for: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> BreakerConfig {
+        BreakerConfig {
+            failure_threshold: 5,
+            open_for: Duration::from_secs(10),
+        }
+    }
+}
+
+/// BreakerState is where an upstream's circuit breaker stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// Closed lets every request through
+    Closed,
+    /// Open rejects requests without sending them
+    Open,
+    /// HalfOpen has let a single trial request through, whose outcome closes or reopens it
+    HalfOpen,
+}
+
+#[derive(Debug)]
+pub(super) struct Breaker {
+    config: BreakerConfig,
+    state: BreakerState,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+}
+
+impl Breaker {
+    pub(super) fn new(config: BreakerConfig) -> Breaker {
+        Breaker {
+            config,
+            state: BreakerState::Closed,
+            consecutive_failures: 0,
+            opened_at: None,
+        }
+    }
+
+    pub(super) fn state(&self) -> BreakerState {
+        self.state
+    }
+
+    /// try_acquire reports whether a request may be sent now. Once an open circuit has waited
+    /// out open_for, exactly one caller gets through as the half-open trial
+    pub(super) fn try_acquire(&mut self, now: Instant) -> bool {
+        match self.state {
+            BreakerState::Closed => true,
+            BreakerState::HalfOpen => false,
+            BreakerState::Open => {
+                let waited = self
+                    .opened_at
+                    .is_none_or(|opened_at| now >= opened_at + self.config.open_for);
+                if waited {
+                    self.state = BreakerState::HalfOpen;
+                }
+                waited
+            }
+        }
+    }
+
+    pub(super) fn record_success(&mut self) {
+        self.state = BreakerState::Closed;
+        self.consecutive_failures = 0;
+        self.opened_at = None;
+    }
+
+    pub(super) fn record_failure(&mut self, now: Instant) {
+        self.consecutive_failures += 1;
+        if self.state == BreakerState::HalfOpen
+            || self.consecutive_failures >= self.config.failure_threshold
+        {
+            self.state = BreakerState::Open;
+            self.opened_at = Some(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_after_threshold_and_recovers() {
+        let mut breaker = Breaker::new(BreakerConfig {
+            failure_threshold: 2,
+            open_for: Duration::from_secs(1),
+        });
+        let start = Instant::now();
+        assert!(breaker.try_acquire(start));
+        breaker.record_failure(start);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        breaker.record_failure(start);
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(!breaker.try_acquire(start));
+
+        let later = start + Duration::from_secs(1);
+        assert!(breaker.try_acquire(later));
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(!breaker.try_acquire(later));
+        breaker.record_success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn failed_trial_reopens() {
+        let mut breaker = Breaker::new(BreakerConfig {
+            failure_threshold: 1,
+            open_for: Duration::from_secs(1),
+        });
+        let start = Instant::now();
+        breaker.record_failure(start);
+        let later = start + Duration::from_secs(2);
+        assert!(breaker.try_acquire(later));
+        breaker.record_failure(later);
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(!breaker.try_acquire(later));
+    }
+}
@@ -0,0 +1,202 @@
+use crate::{
+    errors,
+    evaluation::{Evaluation, ScoredImage},
+};
+use base64::Engine;
+use image::ImageOutputFormat;
+use std::fmt::Write;
+
+/// MAX_GALLERY bounds how many misclassified images a challenge's gallery shows
+const MAX_GALLERY: usize = 48;
+
+/// THUMBNAIL_SIZE is the longest side, in pixels, of the gallery's thumbnails
+const THUMBNAIL_SIZE: u32 = 96;
+
+/// STYLE is the report's stylesheet, kept inline so the report is a single file
+const STYLE: &str = "body{font-family:sans-serif;margin:2em;color:#222}\
+table{border-collapse:collapse;margin:1em 0}\
+th,td{border:1px solid #ccc;padding:.3em .6em;text-align:right}\
+th:first-child,td:first-child{text-align:left}\
+.gallery{display:flex;flex-wrap:wrap;gap:.5em}\
+figure{margin:0;font-size:.75em;width:96px;word-break:break-all}\
+svg{border:1px solid #ccc;background:#fafafa}";
+
+/// ChallengeReport is what the report shows for one challenge
+#[derive(Debug, Clone)]
+pub struct ChallengeReport {
+    pub evaluation: Evaluation,
+    /// scored are the challenge's test images, from evaluation::score_images
+    pub scored: Vec<ScoredImage>,
+}
+
+/// html renders a self-contained HTML report: a summary table, then per challenge an ROC curve
+/// and a gallery of misclassified images with their thumbnails inlined. Images that can no
+/// longer be decoded are listed without a thumbnail
+pub fn html(reports: &[ChallengeReport]) -> errors::Result<String> {
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>nocap evaluation</title>\
+         <style>{}</style></head><body>\n<h1>Evaluation</h1>\n<table>\n<tr><th>challenge</th>\
+         <th>images</th><th>accuracy</th><th>false positives</th><th>false negatives</th>\
+         <th>ROC AUC</th></tr>\n",
+        STYLE
+    );
+    for report in reports {
+        let evaluation = &report.evaluation;
+        let _ = writeln!(
+            html,
+            "<tr><td><a href=\"#{0}\">{0}</a></td><td>{1}</td><td>{2:.4}</td><td>{3}</td>\
+             <td>{4}</td><td>{5:.4}</td></tr>",
+            escape(&evaluation.challenge.to_string()),
+            evaluation.images,
+            evaluation.accuracy,
+            evaluation.false_positives,
+            evaluation.false_negatives,
+            auc(&roc(&report.scored)),
+        );
+    }
+    html.push_str("</table>\n");
+    for report in reports {
+        let challenge = escape(&report.evaluation.challenge.to_string());
+        let _ = writeln!(html, "<h2 id=\"{0}\">{0}</h2>", challenge);
+        html.push_str(&roc_svg(&roc(&report.scored)));
+        let misclassified: Vec<&ScoredImage> = report
+            .scored
+            .iter()
+            .filter(|image| image.predicted != image.matches)
+            .collect();
+        let _ = writeln!(
+            html,
+            "<h3>Misclassified ({} shown of {})</h3>\n<div class=\"gallery\">",
+            misclassified.len().min(MAX_GALLERY),
+            misclassified.len()
+        );
+        for image in misclassified.into_iter().take(MAX_GALLERY) {
+            let label = if image.matches { "match" } else { "no match" };
+            let _ = write!(html, "<figure>");
+            if let Some(thumbnail) = thumbnail(image) {
+                let _ = write!(
+                    html,
+                    "<img src=\"data:image/png;base64,{}\" alt=\"\">",
+                    thumbnail
+                );
+            }
+            let _ = writeln!(
+                html,
+                "<figcaption>{} ({}, scored {:.3})</figcaption></figure>",
+                escape(&image.path.display().to_string()),
+                label,
+                image.affirmative
+            );
+        }
+        html.push_str("</div>\n");
+    }
+    html.push_str("</body></html>\n");
+    Ok(html)
+}
+
+/// roc is the ROC curve of the scores as (false positive rate, true positive rate) points,
+/// from the highest threshold to the lowest
+fn roc(scored: &[ScoredImage]) -> Vec<(f64, f64)> {
+    let positives = scored.iter().filter(|image| image.matches).count().max(1) as f64;
+    let negatives = scored.iter().filter(|image| !image.matches).count().max(1) as f64;
+    let mut sorted: Vec<&ScoredImage> = scored.iter().collect();
+    sorted.sort_by(|a, b| {
+        b.affirmative
+            .partial_cmp(&a.affirmative)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    let mut points = vec![(0.0, 0.0)];
+    let (mut true_positives, mut false_positives) = (0.0, 0.0);
+    for (index, image) in sorted.iter().enumerate() {
+        if image.matches {
+            true_positives += 1.0;
+        } else {
+            false_positives += 1.0;
+        }
+        // images with equal scores cross the threshold together
+        let tied = sorted
+            .get(index + 1)
+            .is_some_and(|next| next.affirmative == image.affirmative);
+        if !tied {
+            points.push((false_positives / negatives, true_positives / positives));
+        }
+    }
+    points
+}
+
+/// auc is the area under an ROC curve, by the trapezoidal rule
+fn auc(points: &[(f64, f64)]) -> f64 {
+    points
+        .windows(2)
+        .map(|pair| (pair[1].0 - pair[0].0) * (pair[1].1 + pair[0].1) / 2.0)
+        .sum()
+}
+
+fn roc_svg(points: &[(f64, f64)]) -> String {
+    const SIZE: f64 = 240.0;
+    let path: Vec<String> = points
+        .iter()
+        .map(|(x, y)| format!("{:.1},{:.1}", x * SIZE, SIZE - y * SIZE))
+        .collect();
+    format!(
+        "<svg width=\"{0}\" height=\"{0}\" viewBox=\"0 0 {0} {0}\">\
+         <line x1=\"0\" y1=\"{0}\" x2=\"{0}\" y2=\"0\" stroke=\"#bbb\" stroke-dasharray=\"4\"/>\
+         <polyline fill=\"none\" stroke=\"#2a6ad4\" stroke-width=\"2\" points=\"{1}\"/></svg>\
+         <p>ROC AUC {2:.4}</p>\n",
+        SIZE,
+        path.join(" "),
+        auc(points)
+    )
+}
+
+/// thumbnail is the image shrunk to THUMBNAIL_SIZE as base64 encoded PNG
+fn thumbnail(image: &ScoredImage) -> Option<String> {
+    let decoded = image::open(&image.path).ok()?;
+    let mut png = Vec::new();
+    decoded
+        .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        .write_to(&mut png, ImageOutputFormat::Png)
+        .ok()?;
+    Some(base64::engine::general_purpose::STANDARD.encode(png))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn scored(matches: bool, affirmative: f32) -> ScoredImage {
+        ScoredImage {
+            path: PathBuf::from("<missing>.png"),
+            matches,
+            affirmative,
+            predicted: affirmative >= 0.5,
+        }
+    }
+
+    #[test]
+    fn roc_area_ranks_scores() -> errors::Result<()> {
+        let perfect = [scored(true, 0.9), scored(true, 0.8), scored(false, 0.2)];
+        assert!((auc(&roc(&perfect)) - 1.0).abs() < 1e-9);
+        let inverted = [scored(true, 0.1), scored(false, 0.9)];
+        assert!(auc(&roc(&inverted)).abs() < 1e-9);
+
+        let report = ChallengeReport {
+            evaluation: Evaluation::from_scored(&crate::CaptchaChallenge::Bus, &inverted),
+            scored: inverted.to_vec(),
+        };
+        let html = html(&[report])?;
+        assert!(html.contains("Misclassified (2 shown of 2)"));
+        assert!(html.contains("&lt;missing&gt;.png"));
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+use crate::errors;
+use image::{imageops, io::Reader, DynamicImage, GenericImageView, ImageOutputFormat};
+use serde_derive::Deserialize;
+use std::{borrow::Cow, fs, io::Cursor, path::Path};
+
+/// MANIFEST is an optional file next to saved_model.pb describing the model, e.g.
+/// `{"input_width": 224, "input_height": 224}`
+pub const MANIFEST: &str = "manifest.json";
+
+/// InputSize is the resolution a model was trained on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct InputSize {
+    #[serde(rename = "input_width")]
+    pub width: u32,
+    #[serde(rename = "input_height")]
+    pub height: u32,
+}
+
+/// Interpolation is the filter used to scale images
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Interpolation {
+    Nearest,
+    Bilinear,
+    Bicubic,
+    Lanczos3,
+}
+
+impl Interpolation {
+    fn filter(self) -> imageops::FilterType {
+        match self {
+            Interpolation::Nearest => imageops::FilterType::Nearest,
+            Interpolation::Bilinear => imageops::FilterType::Triangle,
+            Interpolation::Bicubic => imageops::FilterType::CatmullRom,
+            Interpolation::Lanczos3 => imageops::FilterType::Lanczos3,
+        }
+    }
+}
+
+/// Fit is how an image of another aspect ratio is brought to the input size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fit {
+    /// Stretch scales each axis independently
+    Stretch,
+    /// Pad scales the image to fit inside the input size and centers it on black
+    Pad,
+}
+
+/// ResizeOptions configures how inputs are brought to a model's input size. Models whose size
+/// isn't known, from their manifest or their graph, get their inputs as they are
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct ResizeOptions {
+    pub interpolation: Interpolation,
+    pub fit: Fit,
+}
+
+impl Default for ResizeOptions {
+    fn default() -> ResizeOptions {
+        ResizeOptions {
+            interpolation: Interpolation::Bilinear,
+            fit: Fit::Pad,
+        }
+    }
+}
+
+/// read_manifest reads the input size from a model directory's MANIFEST, if it has one
+pub(crate) fn read_manifest(model_dir: &Path) -> errors::Result<Option<InputSize>> {
+    let path = model_dir.join(MANIFEST);
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice(&fs::read(path)?)?))
+}
+
+/// resize_all brings every image to 'size', re-encoded as PNG. Images already at that size are
+/// left alone, and when all of them are nothing is copied
+pub fn resize_all<'a>(
+    images: &'a [Vec<u8>],
+    size: InputSize,
+    options: ResizeOptions,
+) -> errors::Result<Cow<'a, [Vec<u8>]>> {
+    let needs_resize = |image: &Vec<u8>| dimensions(image) != Some((size.width, size.height));
+    if !images.iter().any(needs_resize) {
+        return Ok(Cow::Borrowed(images));
+    }
+    let mut resized = Vec::with_capacity(images.len());
+    for image in images {
+        resized.push(if needs_resize(image) {
+            resize(image, size, options)?
+        } else {
+            image.clone()
+        });
+    }
+    Ok(Cow::Owned(resized))
+}
+
+fn dimensions(image: &[u8]) -> Option<(u32, u32)> {
+    Reader::new(Cursor::new(image))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+fn resize(image: &[u8], size: InputSize, options: ResizeOptions) -> errors::Result<Vec<u8>> {
+    let decoded = image::load_from_memory(image)?;
+    let filter = options.interpolation.filter();
+    let resized = match options.fit {
+        Fit::Stretch => decoded.resize_exact(size.width, size.height, filter),
+        Fit::Pad => {
+            let scaled = decoded.resize(size.width, size.height, filter);
+            let mut canvas = DynamicImage::new_rgb8(size.width, size.height);
+            let (width, height) = (scaled.width(), scaled.height());
+            imageops::overlay(
+                &mut canvas,
+                &scaled,
+                (size.width - width) / 2,
+                (size.height - height) / 2,
+            );
+            canvas
+        }
+    };
+    let mut encoded = Vec::new();
+    resized.write_to(&mut encoded, ImageOutputFormat::Png)?;
+    Ok(encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        DynamicImage::new_rgb8(width, height)
+            .write_to(&mut encoded, ImageOutputFormat::Png)
+            .unwrap();
+        encoded
+    }
+
+    #[test]
+    fn resizes_to_the_input_size() -> errors::Result<()> {
+        let size = InputSize {
+            width: 32,
+            height: 32,
+        };
+        let exact = vec![png(32, 32)];
+        assert!(matches!(
+            resize_all(&exact, size, ResizeOptions::default())?,
+            Cow::Borrowed(_)
+        ));
+
+        for fit in &[Fit::Stretch, Fit::Pad] {
+            let options = ResizeOptions {
+                fit: *fit,
+                ..ResizeOptions::default()
+            };
+            let resized = resize_all(&[png(64, 16)], size, options)?;
+            assert_eq!(dimensions(&resized[0]), Some((32, 32)));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+use std::time::Duration;
+
+/// RetryPolicy retries an operation that failed in a way that may pass, waiting exponentially
+/// longer between attempts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// max_retries is how many times to retry after the first attempt
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// backoff is how long to wait before retry number 'retry', counting from 1
+    pub(crate) fn backoff(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        self.initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let retry = RetryPolicy {
+            max_retries: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(350),
+        };
+        let backoffs: Vec<u128> = (1..=4).map(|n| retry.backoff(n).as_millis()).collect();
+        assert_eq!(backoffs, vec![100, 200, 350, 350]);
+        assert_eq!(retry.backoff(64), Duration::from_millis(350));
+    }
+}
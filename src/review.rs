@@ -0,0 +1,374 @@
+use crate::{
+    errors,
+    harvest::{self, Harvester, Sample},
+    image_hash, CaptchaChallenge, GridSize, Prediction,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    error::Error as _,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, SyncSender, TrySendError},
+        Arc, Mutex, OnceLock, PoisonError,
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// BACKLOG is how many offers may wait for the writer before further ones are dropped
+const BACKLOG: usize = 1024;
+
+/// UncertaintyBand is the range of affirmative confidence the model can't be trusted on.
+/// Predictions inside it are queued for a human to label
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UncertaintyBand {
+    pub low: f32,
+    pub high: f32,
+}
+
+impl Default for UncertaintyBand {
+    fn default() -> UncertaintyBand {
+        UncertaintyBand {
+            low: 0.35,
+            high: 0.65,
+        }
+    }
+}
+
+impl UncertaintyBand {
+    pub fn contains(&self, prediction: &Prediction) -> bool {
+        prediction.affirmative_confidence >= self.low
+            && prediction.affirmative_confidence <= self.high
+    }
+}
+
+/// ReviewItem is a queued image awaiting a human verdict
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReviewItem {
+    pub challenge: CaptchaChallenge,
+    pub hash: String,
+    pub prediction: Prediction,
+    pub queued_at: u64,
+    image_file: String,
+    #[serde(skip)]
+    dir: PathBuf,
+}
+
+impl ReviewItem {
+    pub fn image_path(&self) -> PathBuf {
+        self.dir.join(&self.image_file)
+    }
+
+    fn metadata_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.json", self.hash))
+    }
+}
+
+/// Pending is what ReviewQueue::pending found: the items awaiting review, oldest first, and
+/// the metadata files that couldn't be read, which are left where they are
+#[derive(Debug, Default)]
+pub struct Pending {
+    pub items: Vec<ReviewItem>,
+    pub unreadable: Vec<PathBuf>,
+}
+
+/// ReviewQueueStats counts what became of the offers a registry made, so a queue directory
+/// that can't be written to shows up in RegistryStats rather than failing silently
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ReviewQueueStats {
+    pub queued: u64,
+    /// failed counts the offers that couldn't be written, or were dropped for the writer
+    /// falling BACKLOG offers behind
+    pub failed: u64,
+    pub last_error: Option<String>,
+}
+
+/// Offer is an image submitted for the writer to queue
+struct Offer {
+    challenge: CaptchaChallenge,
+    image: Vec<u8>,
+    prediction: Prediction,
+}
+
+/// ReviewQueue collects low-confidence predictions on disk, one folder per challenge holding
+/// each image next to a JSON file describing it:
+///
+/// ```text
+/// <root>/<challenge>/<sha256>.png
+/// <root>/<challenge>/<sha256>.json
+/// ```
+///
+/// Once a human has labeled an item, resolve moves it into a harvest so it joins the training
+/// data
+#[derive(Debug)]
+pub struct ReviewQueue {
+    root: PathBuf,
+    band: UncertaintyBand,
+    stats: Arc<Mutex<ReviewQueueStats>>,
+    /// writer is the thread submit hands offers to, started by the first of them
+    writer: OnceLock<SyncSender<Offer>>,
+}
+
+impl ReviewQueue {
+    pub fn new<P>(root: P, band: UncertaintyBand) -> ReviewQueue
+    where
+        P: Into<PathBuf>,
+    {
+        ReviewQueue {
+            root: root.into(),
+            band,
+            stats: Arc::default(),
+            writer: OnceLock::new(),
+        }
+    }
+
+    pub fn band(&self) -> UncertaintyBand {
+        self.band
+    }
+
+    pub fn stats(&self) -> ReviewQueueStats {
+        self.stats
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// submit queues the image in the background if its prediction falls inside the
+    /// uncertainty band, as a registry does with every prediction. Failures are counted in
+    /// stats rather than returned, so they can't cost the caller their prediction
+    pub fn submit(&self, challenge: &CaptchaChallenge, image: &[u8], prediction: &Prediction) {
+        if !self.band.contains(prediction) {
+            return;
+        }
+        let offer = Offer {
+            challenge: challenge.clone(),
+            image: image.to_vec(),
+            prediction: *prediction,
+        };
+        match self.writer().try_send(offer) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.record_failure("review_queue_full", "the review queue is backed up")
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                self.record_failure("review_queue_stopped", "the review queue's writer stopped")
+            }
+        }
+    }
+
+    fn writer(&self) -> &SyncSender<Offer> {
+        self.writer.get_or_init(|| {
+            let (sender, offers) = mpsc::sync_channel::<Offer>(BACKLOG);
+            let queue = ReviewQueue {
+                root: self.root.clone(),
+                band: self.band,
+                stats: Arc::clone(&self.stats),
+                writer: OnceLock::new(),
+            };
+            let _ = thread::spawn(move || {
+                for offer in offers {
+                    match queue.offer(&offer.challenge, &offer.image, &offer.prediction) {
+                        Ok(_) => {
+                            queue
+                                .stats
+                                .lock()
+                                .unwrap_or_else(PoisonError::into_inner)
+                                .queued += 1
+                        }
+                        Err(err) => queue.record_failure(err.error_code(), &describe(&err)),
+                    }
+                }
+            });
+            sender
+        })
+    }
+
+    fn record_failure(&self, code: &'static str, message: &str) {
+        #[cfg(feature = "otel")]
+        crate::telemetry::record_review_queue_failure(code);
+        #[cfg(not(feature = "otel"))]
+        let _ = code;
+        let mut stats = self.stats.lock().unwrap_or_else(PoisonError::into_inner);
+        stats.failed += 1;
+        stats.last_error = Some(message.to_string());
+    }
+
+    /// offer queues the image if its prediction falls inside the uncertainty band, returning
+    /// whether it was queued
+    pub fn offer(
+        &self,
+        challenge: &CaptchaChallenge,
+        image: &[u8],
+        prediction: &Prediction,
+    ) -> errors::Result<bool> {
+        if !self.band.contains(prediction) {
+            return Ok(false);
+        }
+        let dir = self.root.join(challenge.to_string());
+        fs::create_dir_all(&dir)?;
+        let hash = image_hash(image);
+        let image_file = format!("{}.{}", hash, harvest::extension_of(image));
+        fs::write(dir.join(&image_file), image)?;
+        let item = ReviewItem {
+            challenge: challenge.clone(),
+            hash,
+            prediction: *prediction,
+            queued_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            image_file,
+            dir,
+        };
+        fs::write(item.metadata_path(), serde_json::to_vec(&item)?)?;
+        Ok(true)
+    }
+
+    /// pending lists every item still waiting for review. A metadata file that can't be read
+    /// is reported in Pending::unreadable rather than failing the listing
+    pub fn pending(&self) -> errors::Result<Pending> {
+        let mut pending = Pending::default();
+        if !self.root.is_dir() {
+            return Ok(pending);
+        }
+        for challenge_dir in self.root.read_dir()? {
+            let challenge_dir = challenge_dir?.path();
+            if !challenge_dir.is_dir() {
+                continue;
+            }
+            for entry in challenge_dir.read_dir()? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|ext| ext == "json") {
+                    match read_item(&path, &challenge_dir) {
+                        Ok(item) => pending.items.push(item),
+                        Err(_) => pending.unreadable.push(path),
+                    }
+                }
+            }
+        }
+        pending.items.sort_by_key(|item| item.queued_at);
+        pending.unreadable.sort();
+        Ok(pending)
+    }
+
+    /// resolve records the human verdict for 'item' into 'harvester' and removes it from the
+    /// queue, returning where the labeled image was stored
+    pub fn resolve(
+        &self,
+        item: &ReviewItem,
+        verdict: bool,
+        grid: GridSize,
+        harvester: &Harvester,
+    ) -> errors::Result<PathBuf> {
+        let image = fs::read(item.image_path())?;
+        let stored = harvester.record(&Sample {
+            image: &image,
+            challenge: &item.challenge,
+            grid,
+            prediction: &item.prediction,
+            verdict: Some(verdict),
+        })?;
+        fs::remove_file(item.image_path())?;
+        fs::remove_file(item.metadata_path())?;
+        Ok(stored)
+    }
+}
+
+fn read_item(path: &Path, dir: &Path) -> errors::Result<ReviewItem> {
+    let mut item: ReviewItem = serde_json::from_slice(&fs::read(path)?)?;
+    item.dir = dir.to_path_buf();
+    Ok(item)
+}
+
+/// describe is the message of 'err' followed by those of its sources
+fn describe(err: &errors::Error) -> String {
+    let mut description = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        description.push_str(": ");
+        description.push_str(&err.to_string());
+        source = err.source();
+    }
+    description
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn queue_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("nocap-review-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn queues_and_resolves_uncertain_predictions() -> errors::Result<()> {
+        let root = queue_dir("resolve");
+        let queue = ReviewQueue::new(root.join("queue"), UncertaintyBand::default());
+        let bus = CaptchaChallenge::Bus;
+        assert!(!queue.offer(&bus, b"\x89PNG sure", &Prediction::new(0.9, 0.1))?);
+        assert!(queue.offer(&bus, b"\x89PNG unsure", &Prediction::new(0.5, 0.5))?);
+        fs::write(root.join("queue/bus/corrupt.json"), "{")?;
+
+        let pending = queue.pending()?;
+        assert_eq!(
+            pending.unreadable,
+            vec![root.join("queue/bus/corrupt.json")]
+        );
+        assert_eq!(pending.items.len(), 1);
+        let item = &pending.items[0];
+        assert_eq!(fs::read(item.image_path())?, b"\x89PNG unsure");
+
+        let harvester = Harvester::new(root.join("harvest"), Default::default());
+        let stored = queue.resolve(item, true, GridSize::ThreeByThree, &harvester)?;
+        assert_eq!(
+            stored.parent(),
+            Some(&*root.join("harvest/3x3/bus/matches"))
+        );
+        assert!(!item.image_path().exists());
+        assert!(queue.pending()?.items.is_empty());
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn counts_offers_submitted_in_the_background() -> errors::Result<()> {
+        let root = queue_dir("submit");
+        let stats = |queue: &ReviewQueue| {
+            for _ in 0..500 {
+                let stats = queue.stats();
+                if stats.queued + stats.failed > 0 {
+                    return stats;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            queue.stats()
+        };
+        let queue = ReviewQueue::new(&root, UncertaintyBand::default());
+        queue.submit(
+            &CaptchaChallenge::Bus,
+            b"\x89PNG",
+            &Prediction::new(0.5, 0.5),
+        );
+        assert_eq!(stats(&queue).queued, 1);
+
+        // a file where the queue's directory should be makes every write fail
+        let blocked = root.join("blocked");
+        fs::write(&blocked, "")?;
+        let queue = ReviewQueue::new(&blocked, UncertaintyBand::default());
+        queue.submit(
+            &CaptchaChallenge::Bus,
+            b"\x89PNG",
+            &Prediction::new(0.5, 0.5),
+        );
+        let stats = stats(&queue);
+        assert_eq!((stats.queued, stats.failed), (0, 1));
+        assert!(stats.last_error.is_some());
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
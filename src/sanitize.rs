@@ -0,0 +1,128 @@
+use crate::{errors, format::TileFormat};
+use image::io::Reader;
+use serde_derive::{Deserialize, Serialize};
+use std::io::Cursor;
+
+/// InputLimits bounds the images accepted for prediction. Only the image header is read to
+/// check them, so an image claiming enormous dimensions is rejected before anything tries to
+/// allocate room to decode it
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct InputLimits {
+    /// max_bytes caps the encoded size
+    pub max_bytes: usize,
+    pub max_width: u32,
+    pub max_height: u32,
+    /// max_pixels caps width * height, which bounds the memory needed to decode
+    pub max_pixels: u64,
+}
+
+impl Default for InputLimits {
+    fn default() -> InputLimits {
+        InputLimits {
+            max_bytes: 10 * 1024 * 1024,
+            max_width: 4096,
+            max_height: 4096,
+            max_pixels: 4096 * 4096,
+        }
+    }
+}
+
+/// Rejection is why an image was refused
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, thiserror::Error)]
+pub enum Rejection {
+    /// UnsupportedFormat means the magic bytes aren't PNG, JPEG, GIF or WebP
+    #[error("not a PNG, JPEG, GIF or WebP image")]
+    UnsupportedFormat,
+    /// Unreadable means the header could not be parsed
+    #[error("unreadable image header")]
+    Unreadable,
+    #[error("image is {0} bytes, over the limit")]
+    TooManyBytes(usize),
+    #[error("image is {width}x{height}, over the limit")]
+    TooLarge { width: u32, height: u32 },
+}
+
+impl InputLimits {
+    /// check validates 'image' against the limits without decoding its pixels
+    pub fn check(&self, image: &[u8]) -> Result<(), Rejection> {
+        if image.len() > self.max_bytes {
+            return Err(Rejection::TooManyBytes(image.len()));
+        }
+        if TileFormat::detect(image).is_none() {
+            return Err(Rejection::UnsupportedFormat);
+        }
+        let (width, height) = Reader::new(Cursor::new(image))
+            .with_guessed_format()
+            .map_err(|_| Rejection::Unreadable)?
+            .into_dimensions()
+            .map_err(|_| Rejection::Unreadable)?;
+        if width > self.max_width
+            || height > self.max_height
+            || u64::from(width) * u64::from(height) > self.max_pixels
+        {
+            return Err(Rejection::TooLarge { width, height });
+        }
+        Ok(())
+    }
+
+    /// check_all validates every image, failing on the first one rejected
+    pub fn check_all(&self, images: &[Vec<u8>]) -> errors::Result<()> {
+        for (index, image) in images.iter().enumerate() {
+            self.check(image)
+                .map_err(|rejection| errors::Error::RejectedImage(index, rejection))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageOutputFormat};
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        DynamicImage::new_rgb8(width, height)
+            .write_to(&mut encoded, ImageOutputFormat::Png)
+            .unwrap();
+        encoded
+    }
+
+    #[test]
+    fn accepts_ordinary_tiles() {
+        assert_eq!(InputLimits::default().check(&png(100, 100)), Ok(()));
+    }
+
+    #[test]
+    fn rejects_by_header_alone() {
+        let limits = InputLimits {
+            max_pixels: 50 * 50,
+            ..InputLimits::default()
+        };
+        assert_eq!(
+            limits.check(&png(60, 60)),
+            Err(Rejection::TooLarge {
+                width: 60,
+                height: 60
+            })
+        );
+
+        // a header claiming 100000x100000 with no pixel data behind it
+        let mut bomb = png(1, 1);
+        bomb[16..20].copy_from_slice(&100_000u32.to_be_bytes());
+        bomb[20..24].copy_from_slice(&100_000u32.to_be_bytes());
+        assert!(matches!(
+            InputLimits::default().check(&bomb),
+            Err(Rejection::TooLarge { .. }) | Err(Rejection::Unreadable)
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_formats() {
+        assert_eq!(
+            InputLimits::default().check(b"not an image at all"),
+            Err(Rejection::UnsupportedFormat)
+        );
+    }
+}
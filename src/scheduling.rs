@@ -0,0 +1,157 @@
+use crate::errors;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::{Condvar, Mutex, MutexGuard, PoisonError},
+};
+
+/// Priority orders predictions competing for the same model. Interactive work, such as a
+/// solver answering a live captcha, is run before any Batch work, such as re-scoring a harvest,
+/// that is waiting for the model
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    #[default]
+    Interactive,
+    Batch,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    held: bool,
+    interactive_waiting: usize,
+}
+
+/// PriorityMutex is a mutex whose Interactive waiters are served before its Batch ones. A Batch
+/// waiter only takes the lock while no Interactive waiter is queued, so background work can't
+/// starve live traffic, though a steady stream of live traffic can starve background work
+pub struct PriorityMutex<T> {
+    queue: Mutex<Queue>,
+    turn: Condvar,
+    value: Mutex<T>,
+}
+
+impl<T> PriorityMutex<T> {
+    pub fn new(value: T) -> PriorityMutex<T> {
+        PriorityMutex {
+            queue: Mutex::default(),
+            turn: Condvar::new(),
+            value: Mutex::new(value),
+        }
+    }
+
+    /// lock waits for the value, behind every Interactive waiter if 'priority' is Batch
+    pub fn lock(&self, priority: Priority) -> errors::Result<PriorityGuard<'_, T>> {
+        let mut queue = self.queue.lock()?;
+        if priority == Priority::Interactive {
+            queue.interactive_waiting += 1;
+        }
+        while queue.held || (priority == Priority::Batch && queue.interactive_waiting > 0) {
+            queue = self.turn.wait(queue)?;
+        }
+        if priority == Priority::Interactive {
+            queue.interactive_waiting -= 1;
+        }
+        queue.held = true;
+        drop(queue);
+        self.guard()
+    }
+
+    /// try_lock takes the value only if nobody holds it or is waiting for it
+    pub fn try_lock(&self) -> Option<PriorityGuard<'_, T>> {
+        let mut queue = self.queue.lock().ok()?;
+        if queue.held || queue.interactive_waiting > 0 {
+            return None;
+        }
+        queue.held = true;
+        drop(queue);
+        self.guard().ok()
+    }
+
+    fn guard(&self) -> errors::Result<PriorityGuard<'_, T>> {
+        match self.value.lock() {
+            Ok(value) => Ok(PriorityGuard { owner: self, value }),
+            Err(err) => {
+                self.release();
+                Err(err.into())
+            }
+        }
+    }
+
+    fn release(&self) {
+        self.queue
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .held = false;
+        self.turn.notify_all();
+    }
+}
+
+impl<T> fmt::Debug for PriorityMutex<T>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PriorityMutex")
+            .field("value", &self.value)
+            .finish()
+    }
+}
+
+/// PriorityGuard is a locked PriorityMutex, unlocked when dropped
+pub struct PriorityGuard<'a, T> {
+    owner: &'a PriorityMutex<T>,
+    value: MutexGuard<'a, T>,
+}
+
+impl<T> Deref for PriorityGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for PriorityGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<T> Drop for PriorityGuard<'_, T> {
+    fn drop(&mut self) {
+        // the value is unlocked just after this, so a woken waiter only briefly blocks on it
+        self.owner.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, thread, time::Duration};
+
+    #[test]
+    fn serves_interactive_waiters_first() -> errors::Result<()> {
+        let mutex = Arc::new(PriorityMutex::new(Vec::new()));
+        let held = mutex.lock(Priority::Batch)?;
+        let waiter = |priority, name| {
+            let mutex = Arc::clone(&mutex);
+            thread::spawn(move || -> errors::Result<()> {
+                mutex.lock(priority)?.push(name);
+                Ok(())
+            })
+        };
+        let batch = waiter(Priority::Batch, "batch");
+        thread::sleep(Duration::from_millis(50));
+        let interactive = waiter(Priority::Interactive, "interactive");
+        thread::sleep(Duration::from_millis(50));
+        assert!(mutex.try_lock().is_none());
+        drop(held);
+        for waiter in vec![batch, interactive] {
+            waiter.join().map_err(|_| errors::Error::MutexError)??;
+        }
+        assert_eq!(*mutex.lock(Priority::Batch)?, vec!["interactive", "batch"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+use crate::{CaptchaChallenge, Prediction, MATCH_THRESHOLD};
+use serde_derive::{Deserialize, Serialize};
+
+/// SCHEMA_VERSION is the version of PredictionRecord this crate writes
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// PredictionRecord is the public JSON form of a prediction, as the api_server answers it:
+///
+/// ```json
+/// {
+///   "schema_version": 1,
+///   "challenge": "bus",
+///   "match": true,
+///   "scores": { "affirmative": 0.93, "negative": 0.07 },
+///   "threshold": 0.5
+/// }
+/// ```
+///
+/// Within a schema_version fields are only ever added, never renamed, removed or given another
+/// meaning, so consumers should ignore fields they don't know. Anything else bumps the
+/// version. Prediction's own serialization is internal (worker protocol, review queue) and
+/// carries no such guarantee
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PredictionRecord {
+    pub schema_version: u32,
+    pub challenge: CaptchaChallenge,
+    /// is_match is whether the tile shows the challenge's object: the affirmative score is at
+    /// least the threshold and the negative score below it
+    #[serde(rename = "match")]
+    pub is_match: bool,
+    pub scores: Scores,
+    /// threshold is the score 'match' was decided at
+    pub threshold: f32,
+}
+
+/// Scores are the model's confidence that a tile does and doesn't match, summing to about 1
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Scores {
+    pub affirmative: f32,
+    pub negative: f32,
+}
+
+impl PredictionRecord {
+    pub fn new(challenge: CaptchaChallenge, prediction: &Prediction) -> PredictionRecord {
+        PredictionRecord {
+            schema_version: SCHEMA_VERSION,
+            challenge,
+            is_match: prediction.is_mainly_affirmative(),
+            scores: Scores {
+                affirmative: prediction.affirmative_confidence(),
+                negative: prediction.negative_confidence(),
+            },
+            threshold: MATCH_THRESHOLD,
+        }
+    }
+}
+
+impl From<&PredictionRecord> for Prediction {
+    fn from(record: &PredictionRecord) -> Prediction {
+        Prediction::new(record.scores.affirmative, record.scores.negative)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializes_the_documented_schema() -> serde_json::Result<()> {
+        let record = PredictionRecord::new(CaptchaChallenge::Bus, &Prediction::new(0.75, 0.25));
+        let json = serde_json::to_value(&record)?;
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "schema_version": 1,
+                "challenge": "bus",
+                "match": true,
+                "scores": { "affirmative": 0.75, "negative": 0.25 },
+                "threshold": 0.5,
+            })
+        );
+        let parsed: PredictionRecord = serde_json::from_value(json)?;
+        assert_eq!(parsed, record);
+        assert!(Prediction::from(&parsed).is_mainly_affirmative());
+        Ok(())
+    }
+}
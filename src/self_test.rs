@@ -0,0 +1,162 @@
+use crate::{errors, CaptchaChallenge, Prediction};
+use image::{DynamicImage, ImageOutputFormat};
+use serde_derive::Serialize;
+use std::path::PathBuf;
+
+/// Canary is a pair of tiles whose answer is known, compiled in from test_data/ so a model can
+/// be checked before it serves traffic
+#[derive(Debug, Clone, Copy)]
+pub struct Canary {
+    pub positive: &'static [u8],
+    pub negative: &'static [u8],
+}
+
+macro_rules! canary {
+    ($dir:literal, $positive:literal, $negative:literal) => {
+        Canary {
+            positive: include_bytes!(concat!("../test_data/", $dir, "/matches/", $positive)),
+            negative: include_bytes!(concat!("../test_data/", $dir, "/not matches/", $negative)),
+        }
+    };
+}
+
+/// canary returns the known tiles for 'challenge', for the challenges test_data/ has any for
+pub fn canary(challenge: &CaptchaChallenge) -> Option<Canary> {
+    Some(match challenge {
+        CaptchaChallenge::AFireHydrant => canary!(
+            "3x3/a fire hydrant",
+            "019ad382-3a34-4cdf-b4b0-446e1e1dfc52.png",
+            "01b45f78-8154-4770-8309-d9ac1312abfe.png"
+        ),
+        CaptchaChallenge::Bicycles => canary!(
+            "3x3/bicycles",
+            "0db43b8e-4271-4447-b78a-2337e182911d.png",
+            "09872fe3-f8b5-4d36-a9f0-16bbfd242010.png"
+        ),
+        CaptchaChallenge::Bridges => canary!(
+            "3x3/bridges",
+            "00deeb86-375b-425e-ac5c-ac81735146b6.png",
+            "0147a66c-fed1-478f-a919-dd717ad1851f.png"
+        ),
+        CaptchaChallenge::Bus => canary!(
+            "3x3/bus",
+            "004b4c20-dd06-463f-a8bd-160dc0625217.png",
+            "00329815-f14b-4888-9a77-9bfef63e1ff4.png"
+        ),
+        CaptchaChallenge::Crosswalks => canary!(
+            "3x3/crosswalks",
+            "00f3afb8-31c1-4a1d-90d3-0327a3bfd53d.png",
+            "015b72c4-73af-4a04-8d9f-57a3609cf91a.png"
+        ),
+        CaptchaChallenge::Motorcycles => canary!(
+            "4x4/motorcycles",
+            "02639b7d-4a64-48ab-91da-719195896c06.png",
+            "05744972-a63b-42ba-bd0a-2c2d61aff4b9.png"
+        ),
+        CaptchaChallenge::TrafficLights => canary!(
+            "3x3/traffic lights",
+            "05b219bc-ff0a-4505-9fdb-d73f6eab08b8.png",
+            "009aeadf-8c89-4d81-920e-776ee5f798df.png"
+        ),
+        _ => return None,
+    })
+}
+
+/// ModelReport is the outcome of the self test of one model
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ModelReport {
+    pub challenge: CaptchaChallenge,
+    pub path: PathBuf,
+    /// canary is whether the model was checked against a Canary, rather than only for sane
+    /// output on a blank tile
+    pub canary: bool,
+    /// failure is why the model failed, None if it passed
+    pub failure: Option<String>,
+}
+
+impl ModelReport {
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+/// test_images are the images a model is run on: its canary's positive and negative tile, or
+/// a single blank tile
+pub(crate) fn test_images(challenge: &CaptchaChallenge) -> errors::Result<Vec<Vec<u8>>> {
+    Ok(match canary(challenge) {
+        Some(canary) => vec![canary.positive.to_vec(), canary.negative.to_vec()],
+        None => {
+            let mut blank = Vec::new();
+            DynamicImage::new_rgb8(100, 100).write_to(&mut blank, ImageOutputFormat::Png)?;
+            vec![blank]
+        }
+    })
+}
+
+/// check finds what is wrong with a model's predictions for its test_images, if anything
+pub(crate) fn check(
+    challenge: &CaptchaChallenge,
+    predictions: &errors::Result<Vec<Prediction>>,
+) -> Option<String> {
+    let predictions = match predictions {
+        Ok(predictions) => predictions,
+        Err(err) => return Some(format!("prediction failed: {}", err)),
+    };
+    let expected = if canary(challenge).is_some() { 2 } else { 1 };
+    if predictions.len() != expected {
+        return Some(format!(
+            "expected {} predictions, got {}",
+            expected,
+            predictions.len()
+        ));
+    }
+    for prediction in predictions {
+        let (affirmative, negative) = (
+            prediction.affirmative_confidence(),
+            prediction.negative_confidence(),
+        );
+        let in_range = |score: f32| (0.0..=1.0).contains(&score);
+        // scores outside [0, 1], or not summing to 1, mean the wrong op was fetched or the
+        // model lacks its softmax
+        if !in_range(affirmative)
+            || !in_range(negative)
+            || (affirmative + negative - 1.0).abs() > 0.01
+        {
+            return Some(format!(
+                "scores ({}, {}) are not probabilities",
+                affirmative, negative
+            ));
+        }
+    }
+    if expected == 2 {
+        if !predictions[0].is_mainly_affirmative() {
+            return Some(String::from("missed the positive canary"));
+        }
+        if predictions[1].is_mainly_affirmative() {
+            return Some(String::from("matched the negative canary"));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_canaries_and_sanity() -> errors::Result<()> {
+        let bus = CaptchaChallenge::Bus;
+        assert_eq!(test_images(&bus)?.len(), 2);
+        let good = Ok(vec![Prediction::new(0.9, 0.1), Prediction::new(0.2, 0.8)]);
+        assert_eq!(check(&bus, &good), None);
+        let swapped = Ok(vec![Prediction::new(0.2, 0.8), Prediction::new(0.9, 0.1)]);
+        assert!(check(&bus, &swapped).is_some());
+
+        let taxis = CaptchaChallenge::Taxis;
+        assert_eq!(test_images(&taxis)?.len(), 1);
+        assert_eq!(check(&taxis, &Ok(vec![Prediction::new(0.3, 0.7)])), None);
+        let logits = Ok(vec![Prediction::new(4.2, -1.3)]);
+        assert!(check(&taxis, &logits).is_some());
+        Ok(())
+    }
+}
@@ -0,0 +1,236 @@
+use crate::{
+    errors, format,
+    remote::{ProxyConfig, RemoteError},
+    CaptchaChallenge, Prediction, Predictor,
+};
+use base64::Engine;
+use serde_derive::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
+
+#[derive(Serialize)]
+struct PredictRequest<'a> {
+    signature_name: &'a str,
+    instances: Vec<Instance>,
+}
+
+#[derive(Serialize)]
+struct Instance {
+    b64: String,
+}
+
+#[derive(Deserialize)]
+struct PredictResponse {
+    predictions: Vec<Output>,
+}
+
+/// Output is one instance's result: the bare scores when the signature has a single output,
+/// otherwise an object holding every output by name
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Output {
+    Scores(Vec<f32>),
+    Named { scores: Vec<f32> },
+}
+
+#[derive(Deserialize)]
+struct ServingError {
+    error: String,
+}
+
+/// TfServingRegistry is a Predictor backed by TensorFlow Serving's REST API, for deployments
+/// that already run TF Serving and would rather not embed libtensorflow. Each challenge maps to
+/// a served model of the same name (e.g. traffic_lights) unless mapped otherwise, and the
+/// model's signature must take the encoded image bytes and return "scores" like the
+/// SavedModels under models/.
+///
+/// The client is blocking: call it from a plain thread or tokio's spawn_blocking, never
+/// directly from async code
+#[derive(Debug, Clone)]
+pub struct TfServingRegistry {
+    client: reqwest::blocking::Client,
+    base_url: String,
+    signature_name: String,
+    model_names: HashMap<CaptchaChallenge, String>,
+}
+
+/// TfServingRegistryBuilder configures a TfServingRegistry
+#[derive(Debug, Clone)]
+pub struct TfServingRegistryBuilder {
+    base_url: String,
+    signature_name: String,
+    model_names: HashMap<CaptchaChallenge, String>,
+    timeout: Duration,
+    proxy: ProxyConfig,
+}
+
+impl TfServingRegistryBuilder {
+    /// model_name serves 'challenge' from the TF Serving model called 'name'
+    pub fn model_name<S>(mut self, challenge: CaptchaChallenge, name: S) -> TfServingRegistryBuilder
+    where
+        S: Into<String>,
+    {
+        let _ = self.model_names.insert(challenge, name.into());
+        self
+    }
+
+    /// signature_name selects the signature to call, serving_default by default
+    pub fn signature_name<S>(mut self, name: S) -> TfServingRegistryBuilder
+    where
+        S: Into<String>,
+    {
+        self.signature_name = name.into();
+        self
+    }
+
+    /// timeout bounds a whole predict request, 10 seconds by default
+    pub fn timeout(mut self, timeout: Duration) -> TfServingRegistryBuilder {
+        self.timeout = timeout;
+        self
+    }
+
+    /// proxy sends every request through the configured proxies
+    pub fn proxy(mut self, proxy: ProxyConfig) -> TfServingRegistryBuilder {
+        self.proxy = proxy;
+        self
+    }
+
+    pub fn build(self) -> errors::Result<TfServingRegistry> {
+        let builder = reqwest::blocking::Client::builder().timeout(self.timeout);
+        let client = self
+            .proxy
+            .apply(builder)?
+            .build()
+            .map_err(RemoteError::from)?;
+        Ok(TfServingRegistry {
+            client,
+            base_url: self.base_url.trim_end_matches('/').to_owned(),
+            signature_name: self.signature_name,
+            model_names: self.model_names,
+        })
+    }
+}
+
+impl TfServingRegistry {
+    /// builder starts configuring a client for the TF Serving REST endpoint at 'base_url', e.g.
+    /// http://serving:8501
+    pub fn builder<S>(base_url: S) -> TfServingRegistryBuilder
+    where
+        S: Into<String>,
+    {
+        TfServingRegistryBuilder {
+            base_url: base_url.into(),
+            signature_name: "serving_default".to_owned(),
+            model_names: HashMap::new(),
+            timeout: Duration::from_secs(10),
+            proxy: ProxyConfig::default(),
+        }
+    }
+
+    pub fn new<S>(base_url: S) -> errors::Result<TfServingRegistry>
+    where
+        S: Into<String>,
+    {
+        Self::builder(base_url).build()
+    }
+
+    fn predict_url(&self, challenge: &CaptchaChallenge) -> String {
+        let model = match self.model_names.get(challenge) {
+            Some(name) => name.clone(),
+            None => challenge.to_string(),
+        };
+        format!("{}/v1/models/{}:predict", self.base_url, model)
+    }
+
+    fn run(
+        &self,
+        challenge: &CaptchaChallenge,
+        images: &[Vec<u8>],
+    ) -> Result<Vec<Prediction>, RemoteError> {
+        let engine = base64::engine::general_purpose::STANDARD;
+        let request = PredictRequest {
+            signature_name: &self.signature_name,
+            instances: images
+                .iter()
+                .map(|image| Instance {
+                    b64: engine.encode(image),
+                })
+                .collect(),
+        };
+        let response = self
+            .client
+            .post(&self.predict_url(challenge))
+            .json(&request)
+            .send()?;
+        let status = response.status();
+        let body = response.bytes()?;
+        if !status.is_success() {
+            return Err(RemoteError::Upstream {
+                status: status.as_u16(),
+                err: serde_json::from_slice::<ServingError>(&body)
+                    .map(|error| error.error)
+                    .unwrap_or_else(|_| String::from_utf8_lossy(&body).into_owned()),
+                message: None,
+                meta: None,
+            });
+        }
+        let response: PredictResponse =
+            serde_json::from_slice(&body).map_err(RemoteError::Decode)?;
+        Ok(response
+            .predictions
+            .into_iter()
+            .filter_map(|output| {
+                let scores = match output {
+                    Output::Scores(scores) | Output::Named { scores } => scores,
+                };
+                match scores.as_slice() {
+                    [affirmative, negative] => Some(Prediction::new(*affirmative, *negative)),
+                    _ => None,
+                }
+            })
+            .collect())
+    }
+}
+
+impl Predictor for TfServingRegistry {
+    fn predict_batch(
+        &self,
+        challenge: &CaptchaChallenge,
+        images: Vec<Vec<u8>>,
+    ) -> errors::Result<Vec<Prediction>> {
+        // TF Serving runs the same graphs, which can't decode every format we accept
+        let predictions = self.run(challenge, &format::for_model(&images)?)?;
+        if predictions.len() != images.len() {
+            return Err(errors::Error::MalformedOutput);
+        }
+        Ok(predictions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_challenges_to_models() -> errors::Result<()> {
+        let serving = TfServingRegistry::builder("http://serving:8501/")
+            .model_name(CaptchaChallenge::Bus, "bus_v2")
+            .build()?;
+        assert_eq!(
+            serving.predict_url(&CaptchaChallenge::Bus),
+            "http://serving:8501/v1/models/bus_v2:predict"
+        );
+        assert_eq!(
+            serving.predict_url(&CaptchaChallenge::TrafficLights),
+            "http://serving:8501/v1/models/traffic_lights:predict"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn decodes_both_output_shapes() {
+        let body = br#"{"predictions": [[0.9, 0.1], {"scores": [0.2, 0.8], "classes": [1, 0]}]}"#;
+        let response: PredictResponse = serde_json::from_slice(body).unwrap();
+        assert_eq!(response.predictions.len(), 2);
+        assert!(matches!(response.predictions[1], Output::Named { .. }));
+    }
+}
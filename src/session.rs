@@ -0,0 +1,332 @@
+use crate::{
+    errors,
+    hash::{HashKind, ImageHash},
+    image_hash,
+    predictor::PredictOptions,
+    CaptchaChallenge, Prediction, Predictor, MATCH_THRESHOLD,
+};
+use std::collections::HashMap;
+
+/// Tile is the latest prediction for one grid position. 'generation' counts how many times the
+/// tile's image has been replaced since the session started
+#[derive(Debug)]
+pub struct Tile {
+    pub prediction: Prediction,
+    pub generation: usize,
+}
+
+/// ChallengeSession follows a dynamic challenge, where every tile that is clicked fades out and
+/// is replaced by a new image. Feed it the replacement images as they appear and it re-predicts
+/// them, until no tile matches the challenge any more
+#[derive(Debug)]
+pub struct ChallengeSession<'a, P: ?Sized> {
+    predictor: &'a P,
+    challenge: CaptchaChallenge,
+    tiles: Vec<Tile>,
+    options: PredictOptions,
+}
+
+impl<'a, P> ChallengeSession<'a, P>
+where
+    P: Predictor + ?Sized,
+{
+    /// new predicts every tile of the initial grid, given in row-major order, in one batch
+    pub fn new(
+        predictor: &'a P,
+        challenge: CaptchaChallenge,
+        images: Vec<Vec<u8>>,
+    ) -> errors::Result<ChallengeSession<'a, P>> {
+        let options = PredictOptions {
+            chunk_size: usize::MAX,
+            ..PredictOptions::default()
+        };
+        ChallengeSession::with_options(predictor, challenge, images, options)
+    }
+
+    /// with_options is new, predicting under 'options' for the life of the session, so the
+    /// session can be abandoned between tiles once its captcha expires
+    pub fn with_options(
+        predictor: &'a P,
+        challenge: CaptchaChallenge,
+        images: Vec<Vec<u8>>,
+        options: PredictOptions,
+    ) -> errors::Result<ChallengeSession<'a, P>> {
+        let tiles = predictor
+            .predict_with(&challenge, images, &options)?
+            .into_iter()
+            .map(|prediction| Tile {
+                prediction,
+                generation: 0,
+            })
+            .collect();
+        Ok(ChallengeSession {
+            predictor,
+            challenge,
+            tiles,
+            options,
+        })
+    }
+
+    pub fn challenge(&self) -> &CaptchaChallenge {
+        &self.challenge
+    }
+
+    pub fn tiles(&self) -> &[Tile] {
+        &self.tiles
+    }
+
+    /// matches lists the indices of the tiles that currently show the challenge's object
+    pub fn matches(&self) -> Vec<usize> {
+        self.tiles
+            .iter()
+            .enumerate()
+            .filter(|(_, tile)| tile.prediction.is_mainly_affirmative())
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// is_clean reports whether no tile matches any more, i.e. the challenge can be verified
+    pub fn is_clean(&self) -> bool {
+        self.matches().is_empty()
+    }
+
+    /// replace predicts the image that took the place of tile 'index'
+    pub fn replace(&mut self, index: usize, image: Vec<u8>) -> errors::Result<&Tile> {
+        self.replace_many(vec![(index, image)])?;
+        Ok(&self.tiles[index])
+    }
+
+    /// replace_many predicts several replacement images in one batch, as happens when more
+    /// than one tile was clicked before the new images faded in
+    pub fn replace_many(&mut self, replacements: Vec<(usize, Vec<u8>)>) -> errors::Result<()> {
+        if let Some(&(index, _)) = replacements
+            .iter()
+            .find(|(index, _)| *index >= self.tiles.len())
+        {
+            return Err(errors::Error::InvalidTile(index));
+        }
+        let (indices, images): (Vec<usize>, Vec<Vec<u8>>) = replacements.into_iter().unzip();
+        let predictions = self
+            .predictor
+            .predict_with(&self.challenge, images, &self.options)?;
+        for (index, prediction) in indices.into_iter().zip(predictions) {
+            let tile = &mut self.tiles[index];
+            tile.prediction = prediction;
+            tile.generation += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Hysteresis is how far a tile's pooled score has to move past MATCH_THRESHOLD before its
+/// verdict changes, so a tile scored near the threshold keeps one verdict across exposures
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hysteresis {
+    /// enter is the pooled affirmative score a tile not matching must reach to match
+    pub enter: f32,
+    /// exit is the pooled affirmative score a matching tile must fall below to stop matching
+    pub exit: f32,
+}
+
+impl Default for Hysteresis {
+    fn default() -> Hysteresis {
+        Hysteresis {
+            enter: MATCH_THRESHOLD + 0.1,
+            exit: MATCH_THRESHOLD - 0.1,
+        }
+    }
+}
+
+/// Verdict is a tile's decision after pooling every prediction made for its image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Verdict {
+    pub matches: bool,
+    /// affirmative is the mean affirmative confidence over the image's exposures
+    pub affirmative: f32,
+    pub exposures: usize,
+}
+
+/// SessionContext pools predictions for images that are shown more than once during a session,
+/// as challenges re-serve tiles. Predicting each exposure independently lets a tile scored near
+/// the threshold flip between verdicts; pooled, its verdict only changes with Hysteresis
+#[derive(Debug, Default)]
+pub struct SessionContext {
+    hysteresis: Hysteresis,
+    // keyed by challenge and image_hash, as one image can be asked about for several challenges
+    verdicts: HashMap<(CaptchaChallenge, String), Verdict>,
+    near_duplicates: Option<u32>,
+    // the perceptual hash of each image in verdicts, when near_duplicates is set
+    hashes: Vec<(ImageHash, (CaptchaChallenge, String))>,
+}
+
+impl SessionContext {
+    pub fn new(hysteresis: Hysteresis) -> SessionContext {
+        SessionContext {
+            hysteresis,
+            ..SessionContext::default()
+        }
+    }
+
+    /// matching_near pools an image with an earlier one whose perceptual hash (see
+    /// hash::difference_hash) is at most 'max_distance' away, so a tile re-served recompressed
+    /// is still recognized. Without it only byte-identical images are pooled
+    pub fn matching_near(mut self, max_distance: u32) -> SessionContext {
+        self.near_duplicates = Some(max_distance);
+        self
+    }
+
+    /// key is the key of the evidence for 'image', and its perceptual hash when that is needed
+    /// to register new evidence
+    fn key(
+        &self,
+        challenge: &CaptchaChallenge,
+        image: &[u8],
+    ) -> ((CaptchaChallenge, String), Option<ImageHash>) {
+        let key = (challenge.clone(), image_hash(image));
+        let max_distance = match self.near_duplicates {
+            Some(max_distance) if !self.verdicts.contains_key(&key) => max_distance,
+            _ => return (key, None),
+        };
+        // an image that can't be decoded can still be pooled with identical copies of itself
+        let hash = match HashKind::Difference.hash(image) {
+            Ok(hash) => hash,
+            Err(_) => return (key, None),
+        };
+        let near = self
+            .hashes
+            .iter()
+            .filter(|(_, (seen_for, _))| seen_for == challenge)
+            .min_by_key(|(known, _)| known.distance(hash))
+            .filter(|(known, _)| known.distance(hash) <= max_distance);
+        match near {
+            Some((_, key)) => (key.clone(), None),
+            None => (key, Some(hash)),
+        }
+    }
+
+    /// observe adds 'prediction' for 'image' to the evidence for it and returns the smoothed
+    /// verdict. An image's first exposure is decided as Prediction::is_mainly_affirmative does
+    pub fn observe(
+        &mut self,
+        challenge: &CaptchaChallenge,
+        image: &[u8],
+        prediction: &Prediction,
+    ) -> Verdict {
+        let affirmative = prediction.affirmative_confidence();
+        let (key, hash) = self.key(challenge, image);
+        if let Some(hash) = hash {
+            self.hashes.push((hash, key.clone()));
+        }
+        let hysteresis = self.hysteresis;
+        let verdict = self
+            .verdicts
+            .entry(key)
+            .and_modify(|verdict| {
+                verdict.exposures += 1;
+                verdict.affirmative +=
+                    (affirmative - verdict.affirmative) / verdict.exposures as f32;
+                if verdict.matches && verdict.affirmative < hysteresis.exit {
+                    verdict.matches = false;
+                } else if !verdict.matches && verdict.affirmative >= hysteresis.enter {
+                    verdict.matches = true;
+                }
+            })
+            .or_insert(Verdict {
+                matches: prediction.is_mainly_affirmative(),
+                affirmative,
+                exposures: 1,
+            });
+        *verdict
+    }
+
+    /// verdict is the current verdict for 'image', if it has been observed
+    pub fn verdict(&self, challenge: &CaptchaChallenge, image: &[u8]) -> Option<Verdict> {
+        self.verdicts.get(&self.key(challenge, image).0).copied()
+    }
+
+    /// predict predicts 'images' in one batch and observes each prediction
+    pub fn predict<P>(
+        &mut self,
+        predictor: &P,
+        challenge: &CaptchaChallenge,
+        images: Vec<Vec<u8>>,
+    ) -> errors::Result<Vec<Verdict>>
+    where
+        P: Predictor + ?Sized,
+    {
+        let predictions = predictor.predict_batch(challenge, images.clone())?;
+        Ok(images
+            .iter()
+            .zip(&predictions)
+            .map(|(image, prediction)| self.observe(challenge, image, prediction))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{predictor::CancellationToken, MockPredictor};
+
+    #[test]
+    fn replaced_tiles_are_repredicted() -> errors::Result<()> {
+        let mock = MockPredictor::new()
+            .with_image("bus", Prediction::new(0.9, 0.1))
+            .with_image("road", Prediction::new(0.1, 0.9));
+        let images = vec![b"bus".to_vec(), b"road".to_vec(), b"bus".to_vec()];
+        let mut session = ChallengeSession::new(&mock, CaptchaChallenge::Bus, images)?;
+        assert_eq!(session.matches(), vec![0, 2]);
+
+        session.replace_many(vec![(0, b"road".to_vec()), (2, b"road".to_vec())])?;
+        assert!(session.is_clean());
+        assert_eq!(session.tiles()[2].generation, 1);
+        assert!(session.replace(3, b"road".to_vec()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cancelled_sessions_stop_between_tiles() -> errors::Result<()> {
+        let mock = MockPredictor::new();
+        let token = CancellationToken::new();
+        let options = PredictOptions {
+            cancellation: Some(token.clone()),
+            ..PredictOptions::default()
+        };
+        let images = vec![b"bus".to_vec(), b"road".to_vec()];
+        let mut session =
+            ChallengeSession::with_options(&mock, CaptchaChallenge::Bus, images, options)?;
+        assert_eq!(mock.calls().len(), 2);
+
+        token.cancel();
+        assert!(matches!(
+            session.replace(0, b"road".to_vec()),
+            Err(errors::Error::Cancelled)
+        ));
+        assert_eq!(session.tiles()[0].generation, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn pooled_verdicts_change_only_past_the_hysteresis() {
+        let mut context = SessionContext::default();
+        let bus = CaptchaChallenge::Bus;
+        let observe = |context: &mut SessionContext, affirmative: f32| {
+            context
+                .observe(
+                    &bus,
+                    b"tile",
+                    &Prediction::new(affirmative, 1.0 - affirmative),
+                )
+                .matches
+        };
+        assert!(observe(&mut context, 0.7));
+        // pooled to 0.6, then 0.47: below the threshold, but above the exit
+        assert!(observe(&mut context, 0.5));
+        assert!(observe(&mut context, 0.2));
+        // pooled to 0.375, then 0.34: below the exit, and far from the enter
+        assert!(!observe(&mut context, 0.1));
+        assert!(!observe(&mut context, 0.2));
+        assert_eq!(context.verdict(&bus, b"tile").map(|v| v.exposures), Some(5));
+        assert!(context.verdict(&CaptchaChallenge::Cars, b"tile").is_none());
+    }
+}
@@ -0,0 +1,209 @@
+use crate::{errors, worker, CaptchaChallenge, Prediction, Predictor};
+use std::{
+    fs,
+    io::{self, BufReader, BufWriter},
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+};
+
+/// Sidecar serves one process's predictor to the other processes on a host over a Unix
+/// socket, so automation processes running side by side share one copy of the models instead
+/// of each loading its own. Requests are framed as they are for worker::WorkerPool, and each
+/// connection is served on its own thread
+#[derive(Debug)]
+pub struct Sidecar {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+/// SOCKET_MODE is the permission bits Sidecar::bind gives its socket: the owner and its group
+/// may connect
+pub const SOCKET_MODE: u32 = 0o660;
+
+impl Sidecar {
+    /// bind listens on a Unix socket at 'path' with SOCKET_MODE, replacing any socket a
+    /// previous sidecar left there
+    pub fn bind<P>(path: P) -> errors::Result<Sidecar>
+    where
+        P: Into<PathBuf>,
+    {
+        Sidecar::bind_with_mode(path, SOCKET_MODE)
+    }
+
+    /// bind_with_mode is bind with the socket's permission bits set to 'mode'. Anything at
+    /// 'path' that isn't a socket is left alone, and fails the bind
+    pub fn bind_with_mode<P>(path: P, mode: u32) -> errors::Result<Sidecar>
+    where
+        P: Into<PathBuf>,
+    {
+        let path = path.into();
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(&path)?,
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and isn't a socket", path.display()),
+                )
+                .into())
+            }
+            Err(_) => {}
+        }
+        let listener = UnixListener::bind(&path)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+        Ok(Sidecar { listener, path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// serve answers SidecarClients with 'predictor' until accepting a connection fails
+    pub fn serve<P>(&self, predictor: Arc<P>) -> errors::Result<()>
+    where
+        P: Predictor + ?Sized + 'static,
+    {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let predictor = Arc::clone(&predictor);
+            let _ = thread::spawn(move || {
+                // a client that hangs up or breaks the protocol only loses its own connection
+                let _ = serve_connection(&*predictor, stream);
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Sidecar {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn serve_connection<P>(predictor: &P, stream: UnixStream) -> errors::Result<()>
+where
+    P: Predictor + ?Sized,
+{
+    let mut reader = BufReader::new(stream.try_clone()?);
+    worker::serve(predictor, &mut reader, &mut BufWriter::new(stream))
+}
+
+#[derive(Debug)]
+struct Connection {
+    writer: BufWriter<UnixStream>,
+    reader: BufReader<UnixStream>,
+}
+
+impl Connection {
+    fn open(path: &Path) -> errors::Result<Connection> {
+        let stream = UnixStream::connect(path)?;
+        Ok(Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+}
+
+/// SidecarClient is a Predictor that has a Sidecar make its predictions. It keeps the
+/// connections it has opened for reuse, opening another whenever every one is in use
+#[derive(Debug)]
+pub struct SidecarClient {
+    path: PathBuf,
+    idle: Mutex<Vec<Connection>>,
+}
+
+impl SidecarClient {
+    /// connect checks that a sidecar is listening at 'path'
+    pub fn connect<P>(path: P) -> errors::Result<SidecarClient>
+    where
+        P: Into<PathBuf>,
+    {
+        let path = path.into();
+        let connection = Connection::open(&path)?;
+        Ok(SidecarClient {
+            path,
+            idle: Mutex::new(vec![connection]),
+        })
+    }
+}
+
+impl Predictor for SidecarClient {
+    fn predict_batch(
+        &self,
+        challenge: &CaptchaChallenge,
+        images: Vec<Vec<u8>>,
+    ) -> errors::Result<Vec<Prediction>> {
+        let idle = self
+            .idle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop();
+        let mut connection = match idle {
+            Some(connection) => connection,
+            None => Connection::open(&self.path)?,
+        };
+        let reply = worker::request(
+            &mut connection.writer,
+            &mut connection.reader,
+            challenge,
+            &images,
+        )
+        .map_err(|err| errors::Error::WorkerFailed(format!("sidecar connection lost: {}", err)))?;
+        // only a connection still in step with the protocol is worth keeping
+        self.idle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(connection);
+        reply.map_err(errors::Error::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockPredictor;
+    use image::{DynamicImage, ImageOutputFormat};
+
+    #[test]
+    fn clients_share_the_sidecar_predictor() -> errors::Result<()> {
+        let path = std::env::temp_dir().join(format!("nocap-sidecar-{}.sock", std::process::id()));
+        let sidecar = Sidecar::bind(&path)?;
+        assert_eq!(
+            fs::metadata(&path)?.permissions().mode() & 0o777,
+            SOCKET_MODE
+        );
+        // a real PNG, which isn't valid UTF-8, must reach the predictor byte for byte
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(4, 4).write_to(&mut png, ImageOutputFormat::Png)?;
+        assert!(String::from_utf8(png.clone()).is_err());
+        let mock = MockPredictor::new()
+            .with_image(&png, Prediction::new(0.9, 0.1))
+            .with_image("road", Prediction::new(0.1, 0.9));
+        let _ = thread::spawn(move || sidecar.serve(Arc::new(mock)));
+
+        let client = SidecarClient::connect(&path)?;
+        let predictions =
+            client.predict_batch(&CaptchaChallenge::Bus, vec![png, b"road".to_vec()])?;
+        assert!(predictions[0].is_mainly_affirmative());
+        assert!(!predictions[1].is_mainly_affirmative());
+        assert!(SidecarClient::connect(&path)?
+            .predict(&CaptchaChallenge::Bus, b"road".to_vec())
+            .is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn leaves_files_that_arent_sockets() -> errors::Result<()> {
+        let path = std::env::temp_dir().join(format!("nocap-sidecar-file-{}", std::process::id()));
+        fs::write(&path, b"not a socket")?;
+        assert!(Sidecar::bind(&path).is_err());
+        assert_eq!(fs::read(&path)?, b"not a socket");
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+use crate::{errors, CaptchaChallenge};
+use opentelemetry::{
+    global::{self, BoxedSpan},
+    metrics::{Counter, Histogram},
+    trace::{Span, Status, Tracer},
+    KeyValue,
+};
+use std::{sync::OnceLock, time::Instant};
+
+/// SCOPE is the instrumentation scope no_captcha's spans and metrics are reported under. They
+/// go to the global providers, so nothing is exported until the application installs some
+/// (as api_server does with its otel feature)
+pub const SCOPE: &str = "no_captcha";
+
+struct Instruments {
+    duration: Histogram<f64>,
+    images: Counter<u64>,
+    failures: Counter<u64>,
+    graph_cache: Counter<u64>,
+    review_queue_failures: Counter<u64>,
+}
+
+fn instruments() -> &'static Instruments {
+    static INSTRUMENTS: OnceLock<Instruments> = OnceLock::new();
+    INSTRUMENTS.get_or_init(|| {
+        let meter = global::meter(SCOPE);
+        Instruments {
+            duration: meter
+                .f64_histogram("nocap.prediction.duration")
+                .with_unit("s")
+                .with_description("Time taken to predict a batch of images")
+                .build(),
+            images: meter
+                .u64_counter("nocap.prediction.images")
+                .with_description("Images predicted")
+                .build(),
+            failures: meter
+                .u64_counter("nocap.prediction.failures")
+                .with_description("Batches that failed to predict, by error code")
+                .build(),
+            graph_cache: meter
+                .u64_counter("nocap.graph_cache.lookups")
+                .with_description(
+                    "Model loads that looked for a frozen graph, by whether one was cached",
+                )
+                .build(),
+            review_queue_failures: meter
+                .u64_counter("nocap.review_queue.failures")
+                .with_description("Uncertain images the review queue failed to keep, by error code")
+                .build(),
+        }
+    })
+}
+
+/// PredictionSpan traces the prediction of one batch and records its metrics when it ends
+pub(crate) struct PredictionSpan {
+    span: BoxedSpan,
+    challenge: KeyValue,
+    images: usize,
+    started: Instant,
+}
+
+impl PredictionSpan {
+    pub(crate) fn start(challenge: &CaptchaChallenge, images: usize) -> PredictionSpan {
+        let challenge = KeyValue::new("nocap.challenge", challenge.to_string());
+        let tracer = global::tracer(SCOPE);
+        let span = tracer
+            .span_builder("nocap.predict_batch")
+            .with_attributes(vec![
+                challenge.clone(),
+                KeyValue::new("nocap.images", images as i64),
+            ])
+            .start(&tracer);
+        PredictionSpan {
+            span,
+            challenge,
+            images,
+            started: Instant::now(),
+        }
+    }
+
+    pub(crate) fn end<T>(mut self, result: &errors::Result<T>) {
+        let instruments = instruments();
+        let attributes = [self.challenge.clone()];
+        instruments
+            .duration
+            .record(self.started.elapsed().as_secs_f64(), &attributes);
+        match result {
+            Ok(_) => instruments.images.add(self.images as u64, &attributes),
+            Err(err) => {
+                instruments.failures.add(
+                    1,
+                    &[
+                        self.challenge.clone(),
+                        KeyValue::new("nocap.error_code", err.error_code()),
+                    ],
+                );
+                self.span.set_status(Status::error(err.to_string()));
+            }
+        }
+        self.span.end();
+    }
+}
+
+/// record_graph_cache_lookup counts a GraphCache lookup, 'hit' if a frozen graph was cached
+pub(crate) fn record_graph_cache_lookup(hit: bool) {
+    instruments()
+        .graph_cache
+        .add(1, &[KeyValue::new("nocap.hit", hit)]);
+}
+
+/// record_review_queue_failure counts an image a ReviewQueue failed to keep
+pub(crate) fn record_review_queue_failure(code: &'static str) {
+    instruments()
+        .review_queue_failures
+        .add(1, &[KeyValue::new("nocap.error_code", code)]);
+}
@@ -0,0 +1,259 @@
+use crate::{dataset, embedding::Embedder, errors, CaptchaChallenge};
+use serde_derive::Serialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// REPLACE_DENSE loads the SavedModel in argv[1], overwrites its final dense layer with the
+/// kernel and bias in the JSON file argv[3] and saves the model to argv[2]. The layer is the
+/// MatMul and BiasAdd nearest upstream of the 'scores' op, and its variables are the ones those
+/// read, so no other variable of the same shape can be overwritten in its place
+const REPLACE_DENSE: &str = r#"
+import json, sys
+import numpy as np
+import tensorflow.compat.v1 as tf
+
+VARIABLE_OPS = ("VariableV2", "VarHandleOp", "Variable")
+
+def source_variable(tensor):
+    op = tensor.op
+    while op.type not in VARIABLE_OPS:
+        if op.type not in ("Identity", "ReadVariableOp") or not op.inputs:
+            sys.exit("%s doesn't read a variable" % tensor.name)
+        op = op.inputs[0].op
+    return op.name
+
+def dense_layer(graph):
+    pending, seen = [graph.get_operation_by_name("scores")], set()
+    while pending:
+        op = pending.pop(0)
+        if op.name in seen:
+            continue
+        seen.add(op.name)
+        if op.type == "BiasAdd" and op.inputs[0].op.type == "MatMul":
+            return source_variable(op.inputs[0].op.inputs[1]), source_variable(op.inputs[1])
+        pending.extend(tensor.op for tensor in op.inputs)
+    sys.exit("no dense layer feeds scores")
+
+tf.disable_eager_execution()
+weights = json.load(open(sys.argv[3]))
+with tf.Session(graph=tf.Graph()) as session:
+    meta_graph = tf.saved_model.loader.load(session, ["serve"], sys.argv[1])
+    variables = {v.op.name: v for v in tf.global_variables()}
+    for name, op_name in zip(("kernel", "bias"), dense_layer(session.graph)):
+        value = np.array(weights[name], dtype=np.float32)
+        variable = variables.get(op_name)
+        if variable is None or tuple(variable.shape) != value.shape:
+            sys.exit("the %s %s doesn't take a value of shape %s" % (name, op_name, value.shape))
+        variable.load(value, session)
+    builder = tf.saved_model.builder.SavedModelBuilder(sys.argv[2])
+    builder.add_meta_graph_and_variables(
+        session, ["serve"], signature_def_map=meta_graph.signature_def
+    )
+    builder.save()
+"#;
+
+/// BATCH_SIZE bounds how many images are embedded at once
+const BATCH_SIZE: usize = 64;
+
+/// FineTune configures fine_tune, which retrains a model's final dense layer on a labeled
+/// dataset. The rest of the model is frozen, so the layer's inputs are embedded once and the
+/// layer is fitted in process; only writing the new weights into the SavedModel goes through
+/// 'python', which must have tensorflow installed
+#[derive(Debug, Clone)]
+pub struct FineTune {
+    pub python: PathBuf,
+    /// embedding_operation is the operation feeding the final dense layer (see Embedder)
+    pub embedding_operation: String,
+    pub epochs: usize,
+    pub learning_rate: f32,
+    /// l2 is the weight decay, keeping the layer from overfitting small datasets
+    pub l2: f32,
+}
+
+impl Default for FineTune {
+    fn default() -> FineTune {
+        FineTune {
+            python: PathBuf::from("python3"),
+            embedding_operation: String::new(),
+            epochs: 200,
+            learning_rate: 0.01,
+            l2: 1e-4,
+        }
+    }
+}
+
+/// FineTuneReport is how the retrained layer fits the dataset it was trained on
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FineTuneReport {
+    pub challenge: CaptchaChallenge,
+    pub images: usize,
+    pub loss: f32,
+    pub train_accuracy: f64,
+}
+
+/// DenseLayer is a two class dense layer: a kernel of [inputs, 2] and a bias of [2], the
+/// columns being the affirmative and negative classes like the models' output
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct DenseLayer {
+    kernel: Vec<[f32; 2]>,
+    bias: [f32; 2],
+}
+
+/// fine_tune retrains the final dense layer of the model in 'model_dir' on the images for
+/// 'challenge' in a dataset laid out like test_data/, and saves the model to 'out'
+pub fn fine_tune<M, D, O>(
+    model_dir: M,
+    dataset_root: D,
+    challenge: &CaptchaChallenge,
+    out: O,
+    options: &FineTune,
+) -> errors::Result<FineTuneReport>
+where
+    M: AsRef<Path>,
+    D: AsRef<Path>,
+    O: AsRef<Path>,
+{
+    let labeled = dataset::labeled_images(dataset_root, challenge)?;
+    if labeled.is_empty() {
+        return Err(errors::Error::TrainingFailed(format!(
+            "no labeled images for {}",
+            challenge
+        )));
+    }
+    let embedder = Embedder::load(model_dir.as_ref(), options.embedding_operation.as_str())?;
+    let mut embeddings = Vec::with_capacity(labeled.len());
+    for batch in labeled.chunks(BATCH_SIZE) {
+        let images = batch
+            .iter()
+            .map(|image| dataset::read_image(&image.path))
+            .collect::<errors::Result<Vec<_>>>()?;
+        embeddings.extend(embedder.embed(&images)?);
+    }
+    let labels: Vec<bool> = labeled.iter().map(|image| image.matches).collect();
+    let (layer, loss) = fit(&embeddings, &labels, options);
+    let correct = embeddings
+        .iter()
+        .zip(&labels)
+        .filter(|(embedding, matches)| (layer.probability(embedding) >= 0.5) == **matches)
+        .count();
+
+    let weights = out.as_ref().with_extension("dense.json");
+    fs::write(&weights, serde_json::to_vec(&layer)?)?;
+    let result = Command::new(&options.python)
+        .arg("-c")
+        .arg(REPLACE_DENSE)
+        .arg(model_dir.as_ref())
+        .arg(out.as_ref())
+        .arg(&weights)
+        .output();
+    // the weights are only needed by the script
+    let _ = fs::remove_file(&weights);
+    let result = result?;
+    if !result.status.success() {
+        return Err(errors::Error::TrainingFailed(format!(
+            "saving {} failed: {}",
+            out.as_ref().display(),
+            String::from_utf8_lossy(&result.stderr).trim()
+        )));
+    }
+    Ok(FineTuneReport {
+        challenge: challenge.clone(),
+        images: labels.len(),
+        loss,
+        train_accuracy: correct as f64 / labels.len() as f64,
+    })
+}
+
+impl DenseLayer {
+    fn logits(&self, input: &[f32]) -> [f32; 2] {
+        let mut logits = self.bias;
+        for (value, weights) in input.iter().zip(&self.kernel) {
+            logits[0] += value * weights[0];
+            logits[1] += value * weights[1];
+        }
+        logits
+    }
+
+    /// probability is the softmax probability of the affirmative class
+    fn probability(&self, input: &[f32]) -> f32 {
+        let [affirmative, negative] = self.logits(input);
+        1.0 / (1.0 + (negative - affirmative).exp())
+    }
+}
+
+/// fit trains a DenseLayer from zero by full batch gradient descent on the cross entropy,
+/// returning it with its final mean loss
+fn fit(inputs: &[Vec<f32>], labels: &[bool], options: &FineTune) -> (DenseLayer, f32) {
+    let width = inputs.first().map_or(0, Vec::len);
+    let mut layer = DenseLayer {
+        kernel: vec![[0.0; 2]; width],
+        bias: [0.0; 2],
+    };
+    let count = inputs.len().max(1) as f32;
+    let mut loss = 0.0;
+    for _ in 0..options.epochs.max(1) {
+        let mut kernel_gradient = vec![[0.0f32; 2]; width];
+        let mut bias_gradient = [0.0f32; 2];
+        loss = 0.0;
+        for (input, matches) in inputs.iter().zip(labels) {
+            let affirmative = layer.probability(input);
+            let target = if *matches { 1.0 } else { 0.0 };
+            let likelihood = if *matches {
+                affirmative
+            } else {
+                1.0 - affirmative
+            };
+            loss -= likelihood.max(f32::MIN_POSITIVE).ln();
+            // the softmax gradient for each logit is its probability less its target
+            let error = [affirmative - target, target - affirmative];
+            for (gradient, value) in kernel_gradient.iter_mut().zip(input) {
+                gradient[0] += error[0] * value;
+                gradient[1] += error[1] * value;
+            }
+            bias_gradient[0] += error[0];
+            bias_gradient[1] += error[1];
+        }
+        loss /= count;
+        let step = |weight: &mut f32, gradient: f32, decay: f32| {
+            *weight -= options.learning_rate * (gradient / count + decay * *weight);
+        };
+        for (weights, gradients) in layer.kernel.iter_mut().zip(&kernel_gradient) {
+            for (weight, gradient) in weights.iter_mut().zip(gradients) {
+                step(weight, *gradient, options.l2);
+            }
+        }
+        // the bias is left out of the weight decay, as usual
+        for (bias, gradient) in layer.bias.iter_mut().zip(&bias_gradient) {
+            step(bias, *gradient, 0.0);
+        }
+    }
+    (layer, loss)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits_separable_embeddings() {
+        let inputs = vec![
+            vec![1.0, 0.2],
+            vec![0.9, 0.0],
+            vec![0.1, 1.0],
+            vec![0.0, 0.8],
+        ];
+        let labels = [true, true, false, false];
+        let options = FineTune {
+            learning_rate: 0.5,
+            ..FineTune::default()
+        };
+        let (layer, loss) = fit(&inputs, &labels, &options);
+        assert!(loss < 0.3);
+        for (input, matches) in inputs.iter().zip(&labels) {
+            assert_eq!(layer.probability(input) >= 0.5, *matches);
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use crate::{
+    dataset, errors, format::TileFormat, scheduling::Priority, schema::PredictionRecord,
+    CaptchaChallenge, Predictor,
+};
+use serde_derive::Serialize;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// MATCH_DIR and NO_MATCH_DIR are where Watch::sort moves predicted images, under Watch::dir
+pub const MATCH_DIR: &str = "match";
+pub const NO_MATCH_DIR: &str = "no_match";
+
+/// Watch describes a directory whose images are predicted against 'challenge' as they appear,
+/// with a JSON result written to 'out' for each
+#[derive(Debug, Clone)]
+pub struct Watch {
+    pub challenge: CaptchaChallenge,
+    pub dir: PathBuf,
+    pub out: PathBuf,
+    /// sort moves each predicted image into MATCH_DIR or NO_MATCH_DIR
+    pub sort: bool,
+}
+
+/// WatchResult is the JSON written for each image: its file name and a PredictionRecord
+#[derive(Debug, Clone, Serialize)]
+pub struct WatchResult {
+    pub image: String,
+    #[serde(flatten)]
+    pub prediction: PredictionRecord,
+}
+
+/// Watcher predicts the images that arrive in a Watch directory. An image is only picked up
+/// once its size has stayed the same between two scans, so files still being copied in aren't
+/// read half written, and only once: images with a result in 'out' are skipped. Files that
+/// aren't images are left alone
+#[derive(Debug)]
+pub struct Watcher {
+    watch: Watch,
+    sizes: HashMap<PathBuf, u64>,
+    /// ignored holds the files found not to be images, with their size at the time
+    ignored: HashMap<PathBuf, u64>,
+}
+
+impl Watcher {
+    pub fn new(watch: Watch) -> Watcher {
+        Watcher {
+            watch,
+            sizes: HashMap::new(),
+            ignored: HashMap::new(),
+        }
+    }
+
+    /// scan predicts the images that have settled since the last scan, as one batch. Callers
+    /// watch the directory by scanning it periodically
+    pub fn scan<P>(&mut self, predictor: &P) -> errors::Result<Vec<WatchResult>>
+    where
+        P: Predictor + ?Sized,
+    {
+        let mut settled = Vec::new();
+        let mut sizes = HashMap::new();
+        for entry in self.watch.dir.read_dir()? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !entry.file_type()?.is_file() || name.starts_with('.') {
+                continue;
+            }
+            if self.result_path(&name).exists() {
+                continue;
+            }
+            let size = entry.metadata()?.len();
+            if self.ignored.get(&entry.path()) == Some(&size) {
+                continue;
+            }
+            if self.sizes.get(&entry.path()) == Some(&size) {
+                settled.push((name, entry.path()));
+            } else {
+                let _ = sizes.insert(entry.path(), size);
+            }
+        }
+        self.sizes = sizes;
+
+        let mut names = Vec::with_capacity(settled.len());
+        let mut images = Vec::with_capacity(settled.len());
+        for (name, path) in settled {
+            let image = dataset::read_image(&path)?;
+            if TileFormat::detect(&image).is_some() {
+                names.push((name, path));
+                images.push(image);
+            } else {
+                let _ = self.ignored.insert(path, image.len() as u64);
+            }
+        }
+        if images.is_empty() {
+            return Ok(Vec::new());
+        }
+        let predictions =
+            predictor.predict_batch_prioritized(&self.watch.challenge, images, Priority::Batch)?;
+
+        fs::create_dir_all(&self.watch.out)?;
+        let mut results = Vec::with_capacity(names.len());
+        for ((name, path), prediction) in names.into_iter().zip(predictions.iter()) {
+            let result = WatchResult {
+                prediction: PredictionRecord::new(self.watch.challenge.clone(), prediction),
+                image: name,
+            };
+            fs::write(
+                self.result_path(&result.image),
+                serde_json::to_vec_pretty(&result)?,
+            )?;
+            if self.watch.sort {
+                self.sort(&path, &result)?;
+            }
+            results.push(result);
+        }
+        Ok(results)
+    }
+
+    fn result_path(&self, image: &str) -> PathBuf {
+        self.watch.out.join(format!("{}.json", image))
+    }
+
+    fn sort(&self, path: &Path, result: &WatchResult) -> errors::Result<()> {
+        let dir = self.watch.dir.join(if result.prediction.is_match {
+            MATCH_DIR
+        } else {
+            NO_MATCH_DIR
+        });
+        fs::create_dir_all(&dir)?;
+        fs::rename(path, dir.join(&result.image))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MockPredictor, Prediction};
+
+    #[test]
+    fn predicts_and_sorts_settled_images() -> errors::Result<()> {
+        let dir = std::env::temp_dir().join(format!("nocap-watch-{}", std::process::id()));
+        let incoming = dir.join("incoming");
+        fs::create_dir_all(&incoming)?;
+        fs::write(incoming.join("tile.png"), b"\x89PNG\r\n\x1a\n tile")?;
+        fs::write(incoming.join("notes.txt"), b"not an image")?;
+        let mut watcher = Watcher::new(Watch {
+            challenge: CaptchaChallenge::Bus,
+            dir: incoming.clone(),
+            out: dir.join("results"),
+            sort: true,
+        });
+        let mock =
+            MockPredictor::new().with_challenge(CaptchaChallenge::Bus, Prediction::new(0.8, 0.2));
+
+        assert!(watcher.scan(&mock)?.is_empty());
+        let results = watcher.scan(&mock)?;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].image, "tile.png");
+        assert!(dir.join("results/tile.png.json").exists());
+        assert!(incoming.join(MATCH_DIR).join("tile.png").exists());
+        assert!(incoming.join("notes.txt").exists());
+        assert!(watcher.scan(&mock)?.is_empty());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,431 @@
+use crate::{errors, sanitize::Rejection, CaptchaChallenge, Prediction, Predictor};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    ffi::OsString,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::PathBuf,
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, PoisonError,
+    },
+};
+
+/// MAX_FRAME bounds a single frame, so a corrupted length can't make either side allocate
+/// gigabytes
+const MAX_FRAME: usize = 64 * 1024 * 1024;
+
+/// Worker processes speak a framed protocol over stdin and stdout, every frame being a big
+/// endian u32 length followed by that many bytes. A request is a JSON RequestHeader frame
+/// followed by one frame per image; the reply is a single JSON frame holding either the
+/// predictions or a Failure
+#[derive(Serialize, Deserialize)]
+struct RequestHeader {
+    challenge: CaptchaChallenge,
+    images: usize,
+}
+
+pub(crate) type Reply = Result<Vec<Prediction>, Failure>;
+
+/// Failure is an errors::Error sent back over the protocol. The errors callers tell apart,
+/// such as a rejected image or a transient TensorFlow failure, are rebuilt as the variant they
+/// were, so a worker answers as the server itself would; the rest arrive as WorkerFailed with
+/// their message
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Failure {
+    RejectedImage(usize, Rejection),
+    ModelLoad(CaptchaChallenge),
+    UnknownChallenge,
+    InvalidImage(String),
+    InvalidTile(usize),
+    MalformedOutput,
+    Overloaded(CaptchaChallenge),
+    Vetoed(String),
+    Cancelled,
+    DeadlineExceeded,
+    /// Tensorflow carries the tensorflow::Code as its integer value
+    Tensorflow {
+        code: u32,
+        message: String,
+    },
+    Other(String),
+}
+
+impl From<&errors::Error> for Failure {
+    fn from(err: &errors::Error) -> Failure {
+        use errors::Error;
+        match err {
+            Error::RejectedImage(index, rejection) => {
+                Failure::RejectedImage(*index, rejection.clone())
+            }
+            Error::ModelLoad(challenge) => Failure::ModelLoad(challenge.clone()),
+            Error::StrumParseError(_) => Failure::UnknownChallenge,
+            Error::ImageError(err) => Failure::InvalidImage(err.to_string()),
+            Error::InvalidTile(tile) => Failure::InvalidTile(*tile),
+            Error::MalformedOutput => Failure::MalformedOutput,
+            Error::Overloaded(challenge) => Failure::Overloaded(challenge.clone()),
+            Error::Vetoed(reason) => Failure::Vetoed(reason.clone()),
+            Error::Cancelled => Failure::Cancelled,
+            Error::DeadlineExceeded => Failure::DeadlineExceeded,
+            #[cfg(feature = "tensorflow")]
+            Error::TensorflowError { code, message } => Failure::Tensorflow {
+                code: code.to_int(),
+                message: message.clone(),
+            },
+            err => Failure::Other(format!("{:?}", err)),
+        }
+    }
+}
+
+impl From<Failure> for errors::Error {
+    fn from(failure: Failure) -> errors::Error {
+        use errors::Error;
+        match failure {
+            Failure::RejectedImage(index, rejection) => Error::RejectedImage(index, rejection),
+            Failure::ModelLoad(challenge) => Error::ModelLoad(challenge),
+            Failure::UnknownChallenge => Error::StrumParseError(strum::ParseError::VariantNotFound),
+            Failure::InvalidImage(message) => Error::ImageError(image::ImageError::Decoding(
+                image::error::DecodingError::new(image::error::ImageFormatHint::Unknown, message),
+            )),
+            Failure::InvalidTile(tile) => Error::InvalidTile(tile),
+            Failure::MalformedOutput => Error::MalformedOutput,
+            Failure::Overloaded(challenge) => Error::Overloaded(challenge),
+            Failure::Vetoed(reason) => Error::Vetoed(reason),
+            Failure::Cancelled => Error::Cancelled,
+            Failure::DeadlineExceeded => Error::DeadlineExceeded,
+            #[cfg(feature = "tensorflow")]
+            Failure::Tensorflow { code, message } => Error::TensorflowError {
+                code: tensorflow::Code::from_int(code),
+                message,
+            },
+            #[cfg(not(feature = "tensorflow"))]
+            Failure::Tensorflow { message, .. } => Error::WorkerFailed(message),
+            Failure::Other(message) => Error::WorkerFailed(message),
+        }
+    }
+}
+
+fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(payload)
+}
+
+fn read_frame<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut length = [0; 4];
+    reader.read_exact(&mut length)?;
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "worker frame too large",
+        ));
+    }
+    let mut payload = vec![0; length];
+    reader.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+/// serve_worker answers requests from a WorkerPool on stdin and stdout until stdin closes. It
+/// is what a worker process runs once it has loaded its predictor
+pub fn serve_worker<P>(predictor: &P) -> errors::Result<()>
+where
+    P: Predictor + ?Sized,
+{
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    serve(
+        predictor,
+        &mut stdin.lock(),
+        &mut BufWriter::new(stdout.lock()),
+    )
+}
+
+/// serve answers framed requests read from 'reader' on 'writer' until 'reader' ends
+pub(crate) fn serve<P, R, W>(predictor: &P, reader: &mut R, writer: &mut W) -> errors::Result<()>
+where
+    P: Predictor + ?Sized,
+    R: Read,
+    W: Write,
+{
+    loop {
+        let header: RequestHeader = match read_frame(reader) {
+            Ok(frame) => serde_json::from_slice(&frame)?,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut images = Vec::with_capacity(header.images);
+        for _ in 0..header.images {
+            images.push(read_frame(reader)?);
+        }
+        let reply: Reply = predictor
+            .predict_batch(&header.challenge, images)
+            .map_err(|err| Failure::from(&err));
+        write_frame(writer, &serde_json::to_vec(&reply)?)?;
+        writer.flush()?;
+    }
+}
+
+/// request sends the images for 'challenge' as one framed request on 'writer' and reads the
+/// reply from 'reader'
+pub(crate) fn request<W, R>(
+    writer: &mut W,
+    reader: &mut R,
+    challenge: &CaptchaChallenge,
+    images: &[Vec<u8>],
+) -> io::Result<Reply>
+where
+    W: Write,
+    R: Read,
+{
+    let header = RequestHeader {
+        challenge: challenge.clone(),
+        images: images.len(),
+    };
+    write_frame(writer, &serde_json::to_vec(&header)?)?;
+    for image in images {
+        write_frame(writer, image)?;
+    }
+    writer.flush()?;
+    Ok(serde_json::from_slice(&read_frame(reader)?)?)
+}
+
+/// WorkerCommand is how to start a worker process: any program that loads a predictor and
+/// calls serve_worker
+#[derive(Debug, Clone)]
+pub struct WorkerCommand {
+    pub program: PathBuf,
+    pub args: Vec<OsString>,
+}
+
+#[derive(Debug)]
+struct Worker {
+    child: Child,
+    stdin: BufWriter<ChildStdin>,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Worker {
+    fn spawn(command: &WorkerCommand) -> io::Result<Worker> {
+        let mut child = Command::new(&command.program)
+            .args(&command.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().ok_or(io::ErrorKind::BrokenPipe)?;
+        let stdout = child.stdout.take().ok_or(io::ErrorKind::BrokenPipe)?;
+        Ok(Worker {
+            child,
+            stdin: BufWriter::new(stdin),
+            stdout: BufReader::new(stdout),
+        })
+    }
+
+    fn request(&mut self, challenge: &CaptchaChallenge, images: &[Vec<u8>]) -> io::Result<Reply> {
+        request(&mut self.stdin, &mut self.stdout, challenge, images)
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// WorkerPoolStats is a snapshot of a WorkerPool's health
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct WorkerPoolStats {
+    pub size: usize,
+    /// busy counts the workers handling a request, and idle those waiting for one
+    pub busy: usize,
+    pub idle: usize,
+    /// down counts the workers that have exited, or failed to respawn, since their last
+    /// request. They are respawned by their next
+    pub down: usize,
+    pub respawns: usize,
+}
+
+/// WorkerPool is a Predictor that runs inference in child processes, so a crash inside
+/// libtensorflow takes down a worker instead of the whole server. A worker that dies is
+/// respawned in its slot; the request it was handling fails with Error::WorkerFailed rather
+/// than being retried, since the request itself may be what crashed it
+#[derive(Debug)]
+pub struct WorkerPool {
+    command: WorkerCommand,
+    workers: Vec<Mutex<Option<Worker>>>,
+    next: AtomicUsize,
+    respawns: AtomicUsize,
+}
+
+impl WorkerPool {
+    /// spawn starts 'size' workers with 'command'
+    pub fn spawn(command: WorkerCommand, size: usize) -> errors::Result<WorkerPool> {
+        let mut workers = Vec::with_capacity(size.max(1));
+        for _ in 0..size.max(1) {
+            workers.push(Mutex::new(Some(Worker::spawn(&command)?)));
+        }
+        Ok(WorkerPool {
+            command,
+            workers,
+            next: AtomicUsize::new(0),
+            respawns: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// respawns counts the workers restarted after dying
+    pub fn respawns(&self) -> usize {
+        self.respawns.load(Ordering::Relaxed)
+    }
+
+    /// stats checks on every worker without waiting for the busy ones
+    pub fn stats(&self) -> WorkerPoolStats {
+        let mut stats = WorkerPoolStats {
+            size: self.size(),
+            busy: 0,
+            idle: 0,
+            down: 0,
+            respawns: self.respawns(),
+        };
+        for slot in &self.workers {
+            let mut slot = match slot.try_lock() {
+                Ok(slot) => slot,
+                Err(_) => {
+                    stats.busy += 1;
+                    continue;
+                }
+            };
+            match slot.as_mut().map(|worker| worker.child.try_wait()) {
+                Some(Ok(None)) => stats.idle += 1,
+                _ => stats.down += 1,
+            }
+        }
+        stats
+    }
+}
+
+impl Predictor for WorkerPool {
+    fn predict_batch(
+        &self,
+        challenge: &CaptchaChallenge,
+        images: Vec<Vec<u8>>,
+    ) -> errors::Result<Vec<Prediction>> {
+        // prefer an idle worker, falling back to waiting on the next one in turn
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let count = self.workers.len();
+        let mut slot = (0..count)
+            .find_map(|offset| self.workers[(start + offset) % count].try_lock().ok())
+            .unwrap_or_else(|| {
+                self.workers[start % count]
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+            });
+
+        let mut worker = match slot.take() {
+            Some(worker) => worker,
+            None => {
+                // the last respawn failed, so try again now
+                let _ = self.respawns.fetch_add(1, Ordering::Relaxed);
+                Worker::spawn(&self.command)?
+            }
+        };
+        match worker.request(challenge, &images) {
+            Ok(reply) => {
+                *slot = Some(worker);
+                reply.map_err(errors::Error::from)
+            }
+            Err(err) => {
+                // the worker is gone or out of step with the protocol; replace it before the
+                // next request
+                drop(worker);
+                let _ = self.respawns.fetch_add(1, Ordering::Relaxed);
+                *slot = Worker::spawn(&self.command).ok();
+                Err(errors::Error::WorkerFailed(format!("worker died: {}", err)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockPredictor;
+    use image::{DynamicImage, ImageOutputFormat};
+
+    #[test]
+    fn serves_framed_requests() -> errors::Result<()> {
+        let mut input = Vec::new();
+        let header = RequestHeader {
+            challenge: CaptchaChallenge::Bus,
+            images: 2,
+        };
+        write_frame(&mut input, &serde_json::to_vec(&header)?)?;
+        write_frame(&mut input, b"bus")?;
+        write_frame(&mut input, b"road")?;
+
+        let mock = MockPredictor::new()
+            .with_image("bus", Prediction::new(0.9, 0.1))
+            .with_image("road", Prediction::new(0.1, 0.9));
+        let mut output = Vec::new();
+        serve(&mock, &mut input.as_slice(), &mut output)?;
+
+        let reply: Reply = serde_json::from_slice(&read_frame(&mut output.as_slice())?)?;
+        let predictions = reply.map_err(errors::Error::from)?;
+        assert!(predictions[0].is_mainly_affirmative());
+        assert!(!predictions[1].is_mainly_affirmative());
+        Ok(())
+    }
+
+    #[test]
+    fn serves_images_that_arent_utf8() -> errors::Result<()> {
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(4, 4).write_to(&mut png, ImageOutputFormat::Png)?;
+        assert!(String::from_utf8(png.clone()).is_err());
+
+        let mut input = Vec::new();
+        let header = RequestHeader {
+            challenge: CaptchaChallenge::Bus,
+            images: 1,
+        };
+        write_frame(&mut input, &serde_json::to_vec(&header)?)?;
+        write_frame(&mut input, &png)?;
+
+        let mock = MockPredictor::new().with_image(&png, Prediction::new(0.9, 0.1));
+        let mut output = Vec::new();
+        serve(&mock, &mut input.as_slice(), &mut output)?;
+
+        let reply: Reply = serde_json::from_slice(&read_frame(&mut output.as_slice())?)?;
+        let predictions = reply.map_err(errors::Error::from)?;
+        assert!(predictions[0].is_mainly_affirmative());
+        Ok(())
+    }
+
+    #[test]
+    fn rebuilds_errors_across_the_pipe() -> errors::Result<()> {
+        let sent = errors::Error::RejectedImage(1, Rejection::UnsupportedFormat);
+        let failure: Failure = serde_json::from_slice(&serde_json::to_vec(&Failure::from(&sent))?)?;
+        match errors::Error::from(failure) {
+            errors::Error::RejectedImage(1, Rejection::UnsupportedFormat) => {}
+            err => panic!("rebuilt as {:?}", err),
+        }
+
+        let image = errors::Error::from(Failure::InvalidImage("truncated".to_string()));
+        assert_eq!(image.error_code(), "invalid_image");
+        let other = errors::Error::ConversionFailed("python exited".to_string());
+        assert_eq!(
+            errors::Error::from(Failure::from(&other)).error_code(),
+            "worker_failed"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_oversized_frames() {
+        let frame = (MAX_FRAME as u32 + 1).to_be_bytes();
+        assert!(read_frame(&mut &frame[..]).is_err());
+    }
+}